@@ -0,0 +1,120 @@
+use clap::{arg, command, value_parser};
+use lwext4_rs::FsType::{Ext2, Ext3, Ext4};
+use lwext4_rs::{
+    clone_filesystem, BlockDeviceConfig, DefaultInterface, FileSystem, FsBuilder, MountHandle,
+    RegisterHandle, SparseFileInterface,
+};
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-o --output <OUTPUT> "output img file path, created as a sparse file and formatted")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-s --size <SIZE> "output size in bytes, defaults to the size of the input")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(-b --blocksize <BLOCKSIZE> "block size of the output")
+                .required(false)
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(-l --label <LABEL> "fs label of the output")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(-j --journal <JOURNAL> "journal on the output")
+                .required(false)
+                .value_parser(value_parser!(bool)),
+        )
+        .arg(
+            arg!(-t --type <TYPE> "fs type of the output")
+                .required(false)
+                .value_parser(value_parser!(u8)),
+        )
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let output = matches.get_one::<PathBuf>("output").unwrap();
+    let label = matches
+        .get_one::<String>("label")
+        .unwrap_or(&"ext4fs".to_string())
+        .clone();
+    let journal = matches.get_one::<bool>("journal").unwrap_or(&true);
+    let block_size = matches.get_one::<u32>("blocksize").unwrap_or(&4096);
+    let ty = matches.get_one::<u8>("type").unwrap_or(&4);
+    let ty = match ty {
+        2 => Ext2,
+        3 => Ext3,
+        4 => Ext4,
+        _ => panic!("unsupported fs type"),
+    };
+    let file = File::open(path).unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let src = DefaultInterface::new_device(file, config);
+    let register_handler = RegisterHandle::register(src, "src".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/src/".to_string(), false, true).unwrap();
+    let src = FileSystem::new(mount_handler).unwrap();
+
+    let size = *matches.get_one::<u64>("size").unwrap_or(&meta.len());
+    let out = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)
+        .unwrap();
+    out.set_len(size).unwrap();
+    config.part_size = size;
+    config.block_count = config.part_size / bs;
+    let dst = SparseFileInterface::new_device(out, config);
+    let fs = FsBuilder::new()
+        .ty(ty)
+        .journal(*journal)
+        .block_size(*block_size)
+        .label(&label)
+        .build(dst)
+        .unwrap();
+    let register_handler = RegisterHandle::register(fs.take_device(), "dst".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/dst/".to_string(), false, false).unwrap();
+    let dst = FileSystem::new(mount_handler).unwrap();
+
+    let report = clone_filesystem(&src, &dst).unwrap();
+    println!(
+        "{} dirs, {} files ({} bytes, {} bytes of holes), {} symlinks, {} hard links, {} special files",
+        report.dirs,
+        report.files,
+        report.bytes,
+        report.holes,
+        report.symlinks,
+        report.hard_links,
+        report.special_files
+    );
+    for issue in &report.issues {
+        eprintln!("{}: {:?}: {:?}", issue.path, issue.kind, issue.error);
+    }
+    if !report.issues.is_empty() {
+        eprintln!("{} entries were not fully reproduced", report.issues.len());
+    }
+}
@@ -0,0 +1,189 @@
+mod shell;
+
+use clap::{arg, command, value_parser, ArgAction};
+use lwext4_rs::{BlockDeviceConfig, DefaultInterface, FileSystem, MountHandle, RegisterHandle};
+use shell::Shell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(<IMAGE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-w --writable "open the image read-write")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(-f --file <SCRIPT> "run the commands in the script file and exit")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("IMAGE").unwrap();
+    let writable = matches.get_flag("writable");
+    let script = matches.get_one::<PathBuf>("file");
+
+    let mut shell = Shell::new(open_image(path, writable));
+
+    let failed = match script {
+        Some(script) => {
+            let script = std::fs::File::open(script).unwrap();
+            run_lines(&mut shell, BufReader::new(script), false)
+        }
+        None => run_lines(&mut shell, std::io::stdin().lock(), true),
+    };
+    drop(shell);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Mount the image at `path` at `/`, read-only unless `writable`.
+fn open_image(path: &Path, writable: bool) -> FileSystem<DefaultInterface<File>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let blk = DefaultInterface::new_device(file, config);
+    let register_handler = RegisterHandle::register(blk, "debugfs".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/".to_string(), writable, !writable).unwrap();
+    FileSystem::new(mount_handler).unwrap()
+}
+
+/// Run every line of `input` through the shell, returning whether any command failed.
+fn run_lines<T, R>(shell: &mut Shell<T>, input: R, interactive: bool) -> bool
+where
+    T: lwext4_rs::BlockDeviceInterface,
+    R: BufRead,
+{
+    let mut failed = false;
+    let mut lines = input.lines();
+    loop {
+        if interactive {
+            print!("debugfs: ");
+            std::io::stdout().flush().unwrap();
+        }
+        let line = match lines.next() {
+            Some(line) => line.unwrap(),
+            None => break,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !interactive {
+            println!("debugfs: {}", line);
+        }
+        match shell.execute(line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("{}: {}", line, e);
+                failed = true;
+            }
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lwext4_rs::{FsBuilder, FsType};
+    use std::io::Cursor;
+
+    const SCRIPT: &str = "\
+# set up /etc
+mkdir /etc
+cd etc
+write ./debugfs_host hostname
+ln -s hostname name
+setfattr hostname user.note \"two words\"
+chmod 600 hostname
+cd ..
+ls -l etc
+";
+
+    fn run_script(shell: &mut Shell<DefaultInterface<File>>) -> bool {
+        let script = File::open("./debugfs_script").unwrap();
+        run_lines(shell, BufReader::new(script), false)
+    }
+
+    #[test]
+    fn script_test() {
+        let image = Path::new("./debugfs_image");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)
+            .unwrap();
+        file.set_len(1024 * 1024 * 8).unwrap();
+        let config = BlockDeviceConfig {
+            block_size: 512,
+            block_count: 1024 * 1024 * 8 / 512,
+            part_size: 1024 * 1024 * 8,
+            part_offset: 0,
+        };
+        FsBuilder::new()
+            .ty(FsType::Ext4)
+            .block_size(1024)
+            .build(DefaultInterface::new_device(file, config))
+            .unwrap();
+        std::fs::write("./debugfs_host", b"alpha").unwrap();
+        std::fs::write("./debugfs_script", SCRIPT).unwrap();
+
+        // without -w every change fails, but reading still works
+        let mut shell = Shell::new(open_image(image, false));
+        assert!(run_script(&mut shell));
+        assert!(shell.execute("ls /").unwrap());
+        drop(shell);
+
+        let mut shell = Shell::new(open_image(image, true));
+        assert!(!run_script(&mut shell));
+        let err = shell.execute("ls a b").unwrap_err();
+        assert_eq!(err.to_string(), "usage: ls [-l] [path]");
+        let err = shell.execute("chmod rw /etc").unwrap_err();
+        assert_eq!(err.to_string(), "usage: chmod <mode> <path>");
+        assert!(shell.execute("frobnicate").is_err());
+        assert!(shell.execute("cd /etc/hostname").is_err());
+        assert!(shell.execute("").unwrap());
+        // the script stops at quit
+        let script = "cd /etc\ndump hostname ./debugfs_out\nquit\nrm hostname\n";
+        assert!(!run_lines(&mut shell, Cursor::new(script), false));
+        drop(shell);
+        assert_eq!(std::fs::read("./debugfs_out").unwrap(), b"alpha");
+
+        let mut shell = Shell::new(open_image(image, false));
+        let script = "stat /etc/hostname\nstat /etc/name\ngetfattr /etc/hostname user.note\n";
+        assert!(!run_lines(&mut shell, Cursor::new(script), false));
+        drop(shell);
+
+        for path in [
+            "./debugfs_image",
+            "./debugfs_host",
+            "./debugfs_script",
+            "./debugfs_out",
+        ] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
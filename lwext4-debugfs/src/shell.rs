@@ -0,0 +1,454 @@
+use embedded_io::{Read as _, Write as _};
+use lwext4_rs::{BlockDeviceInterface, Error, FileSystem, MetaDataExt, Metadata, Permissions};
+use std::io::Write as _;
+
+pub type CmdResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const HELP: &str = "\
+ls [-l] [path]              list a directory
+cd <path>                   change the working directory
+pwd                         print the working directory
+cat <path>                  print a file
+stat <path>                 print the inode of a file
+mkdir <path>                create a directory
+rm [-r] <path>              remove a file, or a directory with -r
+ln [-s] <target> <link>     create a hard link, or a symbolic link with -s
+chmod <mode> <path>         set the permission bits (octal)
+chown <uid>[:<gid>] <path>  set the owner and group
+getfattr <path> [name]      print one or all extended attributes
+setfattr <path> <name> <value>
+                            set an extended attribute
+write <host> <path>         copy a host file into the image
+dump <path> <host>          copy a file out of the image
+df                          print the file system usage
+help                        print this help
+quit                        leave the shell";
+
+pub struct Shell<T: BlockDeviceInterface> {
+    fs: FileSystem<T>,
+    cwd: String,
+}
+
+impl<T: BlockDeviceInterface> Shell<T> {
+    pub fn new(fs: FileSystem<T>) -> Self {
+        Self {
+            fs,
+            cwd: "/".to_string(),
+        }
+    }
+
+    /// Execute one command line, returning `false` when the shell should exit.
+    pub fn execute(&mut self, line: &str) -> CmdResult<bool> {
+        let args = split_args(line)?;
+        let Some((cmd, args)) = args.split_first() else {
+            return Ok(true);
+        };
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        match cmd.as_str() {
+            "ls" => self.ls(&args)?,
+            "cd" => self.cd(&args)?,
+            "pwd" => println!("{}", self.cwd),
+            "cat" => self.cat(&args)?,
+            "stat" => self.stat(&args)?,
+            "mkdir" => self.mkdir(&args)?,
+            "rm" => self.rm(&args)?,
+            "ln" => self.ln(&args)?,
+            "chmod" => self.chmod(&args)?,
+            "chown" => self.chown(&args)?,
+            "getfattr" => self.getfattr(&args)?,
+            "setfattr" => self.setfattr(&args)?,
+            "write" => self.write(&args)?,
+            "dump" => self.dump(&args)?,
+            "df" => self.df()?,
+            "help" => println!("{}", HELP),
+            "quit" | "exit" | "q" => return Ok(false),
+            _ => return Err(format!("unknown command '{}', try 'help'", cmd).into()),
+        }
+        Ok(true)
+    }
+
+    /// Turn a possibly relative path into an absolute, normalized one.
+    fn resolve(&self, path: &str) -> String {
+        resolve(&self.cwd, path)
+    }
+
+    /// Get the metadata of an existing path.
+    ///
+    /// `FileSystem::metadata` does not fail for missing paths, so check first.
+    fn lookup(&self, path: &str) -> CmdResult<Metadata> {
+        if !self.fs.exists(path)? {
+            return Err(Error::NoEntry.into());
+        }
+        Ok(self.fs.metadata(path)?)
+    }
+
+    fn ls(&mut self, args: &[&str]) -> CmdResult<()> {
+        let long = args.contains(&"-l");
+        let paths: Vec<&str> = args.iter().copied().filter(|a| *a != "-l").collect();
+        let path = match paths.as_slice() {
+            [] => self.cwd.clone(),
+            [path] => self.resolve(path),
+            _ => return Err(usage("ls [-l] [path]")),
+        };
+        let meta = self.lookup(&path)?;
+        if !meta.is_dir() {
+            print_entry(&path, &meta, long);
+            return Ok(());
+        }
+        let dir = if path.ends_with('/') {
+            path
+        } else {
+            path + "/"
+        };
+        for entry in self.fs.readdir(&dir)? {
+            let meta = self.fs.metadata(entry.path())?;
+            print_entry(entry.name(), &meta, long);
+        }
+        Ok(())
+    }
+
+    fn cd(&mut self, args: &[&str]) -> CmdResult<()> {
+        let path = match args {
+            [] => "/".to_string(),
+            [path] => self.resolve(path),
+            _ => return Err(usage("cd <path>")),
+        };
+        if !self.lookup(&path)?.is_dir() {
+            return Err(Error::NotDirectory.into());
+        }
+        self.cwd = path;
+        Ok(())
+    }
+
+    fn cat(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [path] = args else {
+            return Err(usage("cat <path>"));
+        };
+        let path = self.resolve(path);
+        let mut file = self.fs.file_builder().read(true).open(&path)?;
+        let mut stdout = std::io::stdout().lock();
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            stdout.write_all(&buf[..read])?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn stat(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [path] = args else {
+            return Err(usage("stat <path>"));
+        };
+        let path = self.resolve(path);
+        let meta = self.lookup(&path)?;
+        println!(
+            "Inode: {}   Type: {:?}   Mode: {:04o}",
+            meta.ino(),
+            meta.file_type(),
+            meta.mode() & 0o7777
+        );
+        println!(
+            "Links: {}   User: {}   Group: {}",
+            meta.nlink(),
+            meta.uid(),
+            meta.gid()
+        );
+        println!("Size: {}   Blocks: {}", meta.size(), meta.blocks());
+        if meta.file_type().is_char_device() || meta.file_type().is_block_device() {
+            println!("Device: {:#x}", meta.rdev());
+        }
+        if meta.is_symlink() {
+            println!("Target: {}", self.fs.read_link(&path)?);
+        }
+        println!(" atime: {}.{:09}", meta.atime(), meta.atime_nsec());
+        println!(" mtime: {}.{:09}", meta.mtime(), meta.mtime_nsec());
+        println!(" ctime: {}.{:09}", meta.ctime(), meta.ctime_nsec());
+        Ok(())
+    }
+
+    fn mkdir(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [path] = args else {
+            return Err(usage("mkdir <path>"));
+        };
+        self.fs.create_dir(self.resolve(path))?;
+        Ok(())
+    }
+
+    fn rm(&mut self, args: &[&str]) -> CmdResult<()> {
+        let (recursive, path) = match args {
+            ["-r", path] => (true, path),
+            [path] => (false, path),
+            _ => return Err(usage("rm [-r] <path>")),
+        };
+        let path = self.resolve(path);
+        if self.lookup(&path)?.is_dir() {
+            if !recursive {
+                return Err(Error::IsDirectory.into());
+            }
+            self.fs.remove_dir(&path)?;
+        } else {
+            self.fs.remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    fn ln(&mut self, args: &[&str]) -> CmdResult<()> {
+        match args {
+            ["-s", target, link] => self.fs.soft_link(target, self.resolve(link))?,
+            [target, link] => self
+                .fs
+                .hard_link(self.resolve(target), self.resolve(link))?,
+            _ => return Err(usage("ln [-s] <target> <link>")),
+        }
+        Ok(())
+    }
+
+    fn chmod(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [mode, path] = args else {
+            return Err(usage("chmod <mode> <path>"));
+        };
+        let mode = u32::from_str_radix(mode, 8).map_err(|_| usage("chmod <mode> <path>"))?;
+        self.fs
+            .set_permissions(self.resolve(path), Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    fn chown(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [owner, path] = args else {
+            return Err(usage("chown <uid>[:<gid>] <path>"));
+        };
+        let parse = |id: &str| -> CmdResult<Option<u32>> {
+            if id.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(
+                id.parse()
+                    .map_err(|_| usage("chown <uid>[:<gid>] <path>"))?,
+            ))
+        };
+        let (uid, gid) = match owner.split_once(':') {
+            Some((uid, gid)) => (parse(uid)?, parse(gid)?),
+            None => (parse(owner)?, None),
+        };
+        let path = self.resolve(path);
+        let meta = self.lookup(&path)?;
+        self.fs.chown(
+            &path,
+            Some(uid.unwrap_or(meta.uid())),
+            Some(gid.unwrap_or(meta.gid())),
+        )?;
+        Ok(())
+    }
+
+    fn getfattr(&mut self, args: &[&str]) -> CmdResult<()> {
+        let (path, name) = match args {
+            [path] => (self.resolve(path), None),
+            [path, name] => (self.resolve(path), Some(*name)),
+            _ => return Err(usage("getfattr <path> [name]")),
+        };
+        let names = match name {
+            Some(name) => vec![name.to_string()],
+            None => self
+                .fs
+                .list_xattr(&path)?
+                .iter()
+                .map(|name| String::from_utf8_lossy(name).to_string())
+                .collect(),
+        };
+        for name in names {
+            let value = self.fs.get_xattr(&path, &name)?;
+            println!(
+                "{}=\"{}\"",
+                name,
+                String::from_utf8_lossy(&value).escape_debug()
+            );
+        }
+        Ok(())
+    }
+
+    fn setfattr(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [path, name, value] = args else {
+            return Err(usage("setfattr <path> <name> <value>"));
+        };
+        self.fs
+            .set_xattr(self.resolve(path), name, value.as_bytes())?;
+        Ok(())
+    }
+
+    fn write(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [host, path] = args else {
+            return Err(usage("write <host> <path>"));
+        };
+        let data = std::fs::read(host)?;
+        let mut file = self
+            .fs
+            .file_builder()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.resolve(path))?;
+        file.write_all(&data)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn dump(&mut self, args: &[&str]) -> CmdResult<()> {
+        let [path, host] = args else {
+            return Err(usage("dump <path> <host>"));
+        };
+        let mut file = self.fs.file_builder().read(true).open(self.resolve(path))?;
+        let mut out = std::fs::File::create(host)?;
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            out.write_all(&buf[..read])?;
+        }
+        Ok(())
+    }
+
+    fn df(&mut self) -> CmdResult<()> {
+        let stats = self.fs.mount_handle().stats()?;
+        let used_blocks = stats.blocks_count - stats.free_blocks_count;
+        let used_inodes = stats.inodes_count - stats.free_inodes_count;
+        println!("Block size: {}", stats.block_size);
+        println!(
+            "Blocks: {} total, {} used, {} free",
+            stats.blocks_count, used_blocks, stats.free_blocks_count
+        );
+        println!(
+            "Inodes: {} total, {} used, {} free",
+            stats.inodes_count, used_inodes, stats.free_inodes_count
+        );
+        Ok(())
+    }
+}
+
+fn usage(usage: &str) -> Box<dyn std::error::Error> {
+    format!("usage: {}", usage).into()
+}
+
+fn print_entry(name: &str, meta: &Metadata, long: bool) {
+    if !long {
+        println!("{}", name);
+        return;
+    }
+    println!(
+        "{:>8} {} {:>4} {:>6} {:>6} {:>12} {:>12} {}",
+        meta.ino(),
+        mode_string(meta),
+        meta.nlink(),
+        meta.uid(),
+        meta.gid(),
+        meta.size(),
+        meta.mtime(),
+        name
+    );
+}
+
+/// Render the mode like `ls -l` does, e.g. `drwxr-xr-x`.
+fn mode_string(meta: &Metadata) -> String {
+    let mode = meta.mode();
+    let mut s = String::with_capacity(10);
+    s.push(meta.file_type().as_char());
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+/// Turn `path`, possibly relative to `cwd`, into an absolute, normalized one.
+fn resolve(cwd: &str, path: &str) -> String {
+    let full = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", cwd, path)
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in full.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Split a command line on whitespace, keeping quoted strings together.
+fn split_args(line: &str) -> CmdResult<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.get_or_insert_with(String::new).push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            None if c.is_whitespace() => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                }
+            }
+            None => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".into());
+    }
+    if let Some(arg) = current {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_test() {
+        assert_eq!(resolve("/", "etc"), "/etc");
+        assert_eq!(resolve("/etc", "passwd"), "/etc/passwd");
+        assert_eq!(resolve("/etc", "/usr/bin"), "/usr/bin");
+        assert_eq!(resolve("/usr/bin", ".."), "/usr");
+        assert_eq!(resolve("/usr/bin", "../../.."), "/");
+        assert_eq!(resolve("/usr", "./bin/./ls"), "/usr/bin/ls");
+        assert_eq!(resolve("/usr", "bin//ls/"), "/usr/bin/ls");
+        assert_eq!(resolve("/", "."), "/");
+    }
+
+    #[test]
+    fn split_args_test() {
+        assert_eq!(split_args("").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            split_args("  ls   -l  /etc ").unwrap(),
+            ["ls", "-l", "/etc"]
+        );
+        assert_eq!(
+            split_args("setfattr f user.note \"two words\"").unwrap(),
+            ["setfattr", "f", "user.note", "two words"]
+        );
+        assert_eq!(
+            split_args("write 'my file' a\"b\"c").unwrap(),
+            ["write", "my file", "abc"]
+        );
+        assert_eq!(split_args("cat ''").unwrap(), ["cat", ""]);
+        assert_eq!(split_args("echo \"it's\"").unwrap(), ["echo", "it's"]);
+        assert!(split_args("cat \"unterminated").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use clap::{arg, command, value_parser, ArgAction, ArgGroup};
+use lwext4_rs::{
+    BlockDeviceConfig, DefaultInterface, FileSystem, MountHandle, RegisterHandle, TreeDiff,
+};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Mount the image at `path` read-only at `/<name>/`.
+fn mount(path: &Path, name: &str) -> FileSystem<DefaultInterface<File>> {
+    let file = File::open(path).unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let blk = DefaultInterface::new_device(file, config);
+    let register_handler = RegisterHandle::register(blk, name.to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, format!("/{}/", name), false, true).unwrap();
+    FileSystem::new(mount_handler).unwrap()
+}
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path of the old tree")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-n --new <FILE> "img file path of the new tree")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-H --host <DIR> "host directory of the new tree")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .group(ArgGroup::new("other").args(["new", "host"]).required(true))
+        .arg(
+            arg!(-d --dir <DIR> "directory of the images to compare, defaults to their root")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(-x --exclude <PATH> "skip PATH, relative to the compared directories, repeatable")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(arg!(-t --times "also compare modification times"))
+        .arg(arg!(-q --quick "do not compare the contents of files of the same size"))
+        .arg(arg!(-j --json "print the differences as JSON"))
+        .get_matches();
+
+    let dir = matches
+        .get_one::<String>("dir")
+        .map(|d| d.trim_matches('/'))
+        .unwrap_or("");
+    let mut diff = TreeDiff::new()
+        .times(matches.get_flag("times"))
+        .contents(!matches.get_flag("quick"));
+    for path in matches.get_many::<String>("exclude").into_iter().flatten() {
+        diff = diff.exclude(path);
+    }
+
+    let old = mount(matches.get_one::<PathBuf>("file").unwrap(), "old");
+    let old_dir = format!("/old/{}", dir);
+    let report = match matches.get_one::<PathBuf>("new") {
+        Some(new) => {
+            let new = mount(new, "new");
+            diff.images(&old, &old_dir, &new, &format!("/new/{}", dir))
+        }
+        None => diff.image_to_host(&old, &old_dir, matches.get_one::<PathBuf>("host").unwrap()),
+    }
+    .unwrap();
+    if matches.get_flag("json") {
+        print!("{}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+    if !report.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,52 @@
+use clap::{arg, command, value_parser};
+use lwext4_rs::{BlockDeviceConfig, DefaultInterface, FileSystem, MountHandle, RegisterHandle};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-o --output <DIR> "host directory to extract into")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let output = matches.get_one::<PathBuf>("output").unwrap();
+    let file = OpenOptions::new().read(true).open(path).unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let blk = DefaultInterface::new_device(file, config);
+    let register_handler = RegisterHandle::register(blk, "export".to_string()).unwrap();
+    let mount_handler = MountHandle::mount(register_handler, "/".to_string(), false, true).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+
+    let report = fs.export_to(output).unwrap();
+    println!(
+        "{} dirs, {} files ({} bytes), {} symlinks, {} hard links, {} special files",
+        report.dirs,
+        report.files,
+        report.bytes,
+        report.symlinks,
+        report.hard_links,
+        report.special_files
+    );
+    for issue in &report.issues {
+        eprintln!("{}: {:?}: {:?}", issue.path, issue.kind, issue.error);
+    }
+    if !report.issues.is_empty() {
+        eprintln!("{} entries were not fully reproduced", report.issues.len());
+    }
+}
@@ -0,0 +1,77 @@
+use clap::{arg, command, value_parser, ArgAction};
+use lwext4_rs::{BlockDeviceConfig, DefaultInterface, Fsck};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+/// Exit codes of e2fsck.
+const EXIT_OK: i32 = 0;
+const EXIT_CORRECTED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"no-checksums" "do not verify metadata checksums")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(-y --repair "repair the problems found")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let checksums = !matches.get_flag("no-checksums");
+    let repair = matches.get_flag("repair");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(repair)
+        .open(path)
+        .unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let mut blk = DefaultInterface::new_device(file, config);
+
+    let report = Fsck::new()
+        .checksums(checksums)
+        .repair(repair)
+        .check(&mut blk)
+        .unwrap();
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    for repair in &report.repairs {
+        println!("{}", repair);
+    }
+    println!(
+        "{}: {}/{} inodes, {}/{} blocks, {} directories",
+        path.display(),
+        report.inodes_used,
+        report.inodes_count,
+        report.blocks_used,
+        report.blocks_count,
+        report.directories
+    );
+    if report.is_clean() {
+        std::process::exit(EXIT_OK);
+    }
+    if repair && report.is_consistent() {
+        println!("{} problems repaired", report.problems.len());
+        std::process::exit(EXIT_CORRECTED);
+    }
+    println!("{} problems left", report.remaining.len());
+    std::process::exit(EXIT_UNCORRECTED);
+}
@@ -0,0 +1,61 @@
+use clap::{arg, command, value_parser};
+use lwext4_rs::{BlockDeviceConfig, DefaultInterface, ImageCopy, SparseFileInterface};
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-o --output <OUTPUT> "output img file path, created as a sparse file")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(arg!(-m --metadata "copy the file system metadata only, not file contents"))
+        .arg(arg!(-a --anonymize "scramble file names, link targets and attribute values (implies -m)"))
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let output = matches.get_one::<PathBuf>("output").unwrap();
+    let file = File::open(path).unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let mut src = DefaultInterface::new_device(file, config);
+
+    let out = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)
+        .unwrap();
+    out.set_len(meta.len()).unwrap();
+    let mut dst = SparseFileInterface::new_device(out, config);
+
+    let report = ImageCopy::new()
+        .metadata_only(matches.get_flag("metadata"))
+        .anonymize(matches.get_flag("anonymize"))
+        .copy(&mut src, &mut dst)
+        .unwrap();
+    println!(
+        "{}: {} of {} blocks of {} bytes copied to {}",
+        path.display(),
+        report.copied,
+        report.blocks,
+        report.block_size,
+        output.display()
+    );
+    if matches.get_flag("anonymize") {
+        println!("{} names scrambled", report.names);
+    }
+}
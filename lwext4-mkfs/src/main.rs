@@ -1,9 +1,46 @@
-use clap::{arg, command, value_parser};
+use clap::{arg, command, value_parser, ArgAction};
 use lwext4_rs::FsType::{Ext2, Ext3, Ext4};
-use lwext4_rs::{BlockDeviceConfig, DefaultInterface, FsBuilder};
+use lwext4_rs::{
+    BlockDeviceConfig, DefaultInterface, FsBuilder, MbrBuilder, MbrPartition, SharedDevice,
+};
 use std::fs::OpenOptions;
 use std::path::PathBuf;
 
+/// Parse a partition as `TYPE[:SIZE]`, the type a name or an MBR type in hex, the size
+/// in bytes with an optional K, M, G or T suffix.
+fn parse_partition(part: &str) -> Result<(u8, Option<u64>), String> {
+    let (kind, size) = match part.split_once(':') {
+        Some((kind, size)) => (kind, Some(size)),
+        None => (part, None),
+    };
+    let kind = match kind {
+        "fat32" => MbrPartition::FAT32,
+        "swap" => MbrPartition::LINUX_SWAP,
+        "linux" => MbrPartition::LINUX,
+        "efi" => MbrPartition::EFI_SYSTEM,
+        _ => u8::from_str_radix(kind.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("partition type {}: {}", kind, e))?,
+    };
+    let size = match size {
+        Some(size) => {
+            let (digits, shift) = match size.chars().last() {
+                Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+                Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+                Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+                Some('T') | Some('t') => (&size[..size.len() - 1], 40),
+                _ => (size, 0),
+            };
+            let n: u64 = digits.parse().map_err(|e| format!("{}", e))?;
+            let size = n
+                .checked_mul(1 << shift)
+                .ok_or_else(|| format!("partition size {} too large", size))?;
+            Some(size)
+        }
+        None => None,
+    };
+    Ok((kind, size))
+}
+
 fn main() {
     let matches = command!()
         .arg(
@@ -31,6 +68,25 @@ fn main() {
                 .required(false)
                 .value_parser(value_parser!(u8)),
         )
+        .arg(
+            arg!(-p --partition <PART> "write an MBR with this partition first, as TYPE[:SIZE] with TYPE fat32, linux, swap, efi or a hex MBR type and SIZE in bytes with a K/M/G/T suffix; repeat for up to four, the last may omit SIZE to fill the image")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(parse_partition),
+        )
+        .arg(
+            arg!(--boot <N> "mark partition N bootable")
+                .required(false)
+                .requires("partition")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(--format <N> "format partition N, repeatable; defaults to the linux partitions")
+                .required(false)
+                .requires("partition")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(u32)),
+        )
         .get_matches();
 
     let path = matches.get_one::<PathBuf>("file").unwrap();
@@ -60,13 +116,88 @@ fn main() {
     config.part_size = meta.len();
     config.part_offset = 0;
     config.block_count = config.part_size / bs;
-    let blk = DefaultInterface::new_device(file, config);
-    let fs = FsBuilder::new()
+    let builder = FsBuilder::new()
         .ty(ty)
         .journal(*journal)
         .block_size(*block_size)
-        .label(&label)
-        .build(blk)
-        .unwrap();
-    println!("{:#x?}", fs.fs_info().unwrap());
+        .label(&label);
+
+    let Some(parts) = matches.get_many::<(u8, Option<u64>)>("partition") else {
+        let blk = DefaultInterface::new_device(file, config);
+        let fs = builder.build(blk).unwrap();
+        println!("{:#x?}", fs.fs_info().unwrap());
+        return;
+    };
+    let boot = matches.get_one::<u32>("boot");
+    let mut mbr = MbrBuilder::new();
+    for (i, &(kind, size)) in parts.enumerate() {
+        mbr = mbr.partition(kind, boot == Some(&(i as u32 + 1)), size);
+    }
+    let disk = SharedDevice::new(DefaultInterface::new(file, config));
+    let partitions = mbr.write(&mut *disk.lock()).unwrap();
+    let format: Vec<u32> = match matches.get_many::<u32>("format") {
+        Some(numbers) => numbers.copied().collect(),
+        None => partitions
+            .iter()
+            .filter(|p| p.kind == MbrPartition::LINUX)
+            .map(|p| p.number)
+            .collect(),
+    };
+    for p in &partitions {
+        println!(
+            "partition {}: type {:#04x}, {} bytes at {}",
+            p.number,
+            p.kind,
+            p.size(),
+            p.offset()
+        );
+    }
+    for number in format {
+        let p = partitions
+            .iter()
+            .find(|p| p.number == number)
+            .unwrap_or_else(|| panic!("no partition {}", number));
+        let blk = disk.partition(p.offset(), p.size()).unwrap();
+        let fs = builder.clone().build(blk).unwrap();
+        println!("partition {}: {:#x?}", number, fs.fs_info().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_partition_test() {
+        assert_eq!(parse_partition("linux"), Ok((MbrPartition::LINUX, None)));
+        assert_eq!(
+            parse_partition("fat32:64M"),
+            Ok((MbrPartition::FAT32, Some(64 << 20)))
+        );
+        assert_eq!(
+            parse_partition("swap:512k"),
+            Ok((MbrPartition::LINUX_SWAP, Some(512 << 10)))
+        );
+        assert_eq!(
+            parse_partition("efi:1G"),
+            Ok((MbrPartition::EFI_SYSTEM, Some(1 << 30)))
+        );
+        assert_eq!(parse_partition("0x0c:2t"), Ok((0x0c, Some(2 << 40))));
+        assert_eq!(parse_partition("83:1000"), Ok((0x83, Some(1000))));
+        for bad in [
+            "ntfs",
+            "0x100",
+            "linux:",
+            "linux:M",
+            "linux:1P",
+            "linux:-1M",
+        ] {
+            assert!(parse_partition(bad).is_err(), "{} parsed", bad);
+        }
+        assert!(parse_partition("linux:16777216T").is_err());
+        assert_eq!(
+            parse_partition("linux:16777215T"),
+            Ok((MbrPartition::LINUX, Some(16777215 << 40)))
+        );
+    }
 }
@@ -0,0 +1,91 @@
+use clap::{arg, command, value_parser};
+use lwext4_rs::{BlockDeviceConfig, DefaultInterface, Resize};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+/// Parse a size as file system blocks, or as bytes with a K, M, G or T suffix.
+fn parse_size(size: &str) -> Result<(u64, bool), String> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+        Some('T') | Some('t') => (&size[..size.len() - 1], 40),
+        _ => {
+            return size
+                .parse()
+                .map(|n| (n, false))
+                .map_err(|e| format!("{}", e))
+        }
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{}", e))?;
+    Ok((n << shift, true))
+}
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-s --size <SIZE> "new size in blocks, or in bytes with a K/M/G/T suffix (the image file is extended if needed); defaults to the whole image")
+                .required(false),
+        )
+        .arg(arg!(-M --minimum "shrink to the minimum size").conflicts_with("size"))
+        .arg(arg!(-P --print "print the minimum size in blocks and exit"))
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let size = matches
+        .get_one::<String>("size")
+        .map(|s| parse_size(s).unwrap());
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut resize = Resize::new();
+    match size {
+        Some((bytes, true)) => {
+            if file.metadata().unwrap().len() < bytes {
+                file.set_len(bytes).unwrap();
+            }
+            resize = resize.bytes(bytes);
+        }
+        Some((blocks, false)) => resize = resize.blocks(blocks),
+        None => {}
+    }
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let mut blk = DefaultInterface::new_device(file, config);
+
+    if matches.get_flag("print") {
+        println!("{}", resize.minimum(&mut blk).unwrap());
+        return;
+    }
+    let report = match matches.get_flag("minimum") {
+        true => resize.shrink(&mut blk).unwrap(),
+        false => resize.run(&mut blk).unwrap(),
+    };
+    drop(blk);
+    if report.new_blocks < report.old_blocks {
+        let len = report.new_blocks * report.block_size as u64;
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len).unwrap();
+    }
+    println!(
+        "{}: {} -> {} blocks, {} -> {} groups",
+        path.display(),
+        report.old_blocks,
+        report.new_blocks,
+        report.old_groups,
+        report.new_groups
+    );
+}
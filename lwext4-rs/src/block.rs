@@ -1,4 +1,6 @@
 use crate::error::{errno_to_result, result_to_errno, Error, Result};
+use crate::feature::FeatureReport;
+use crate::probe::{probe, ProbeInfo};
 use crate::types::MountStats;
 use alloc::boxed::Box;
 use alloc::ffi::CString;
@@ -13,7 +15,7 @@ use core::ops::{Deref, DerefMut};
 use core::pin::Pin;
 use core::ptr::null_mut;
 use core::slice::{from_raw_parts, from_raw_parts_mut};
-use log::info;
+use log::{info, warn};
 use lwext4_sys::ext4::*;
 
 #[repr(transparent)]
@@ -128,6 +130,14 @@ impl<T: BlockDeviceInterface> RegisterHandle<T> {
     pub fn dev_name(&self) -> CName {
         self.dev_name.clone()
     }
+    /// Identify the file system on the device, see [probe](crate::probe).
+    pub fn probe(&mut self) -> Result<ProbeInfo> {
+        probe(self.device_mut())
+    }
+    fn device_mut(&mut self) -> &mut BlockDevice<T> {
+        // lwext4 holds a pointer to the device, which is fine as long as it is not moved.
+        unsafe { self.device.as_mut().get_unchecked_mut() }
+    }
 }
 
 impl<T: BlockDeviceInterface> Drop for RegisterHandle<T> {
@@ -143,18 +153,39 @@ pub struct MountHandle<T: BlockDeviceInterface> {
     #[allow(unused)]
     register_handle: RegisterHandle<T>,
     pub(super) mount_point: CName,
+    features: FeatureReport,
+    read_only: bool,
 }
 
 impl<T: BlockDeviceInterface> MountHandle<T> {
     /// Mount a block device to the file system at the provided mount point
+    ///
+    /// The features of the file system are checked first. Unsupported incompatible
+    /// features fail with [Error::NotSupported], `mmp` and unsupported read-only
+    /// compatible features mount it read-only without journal recovery. Either way the
+    /// features are logged, and [FeatureReport::read] lists them before mounting.
     pub fn mount(
-        register_handle: RegisterHandle<T>,
+        mut register_handle: RegisterHandle<T>,
         mount_point: String,
-        journal_recovery: bool,
-        read_only: bool,
+        mut journal_recovery: bool,
+        mut read_only: bool,
     ) -> Result<Self> {
         let c_mount_point = CName::new(mount_point)?;
         let dev_name = register_handle.dev_name();
+        let features = FeatureReport::read(register_handle.device_mut())?;
+        if !features.is_mountable() {
+            warn!("{}: unsupported {}", dev_name.as_str(), features);
+            return Err(Error::NotSupported);
+        }
+        if !features.is_writable() && !read_only {
+            warn!(
+                "{}: unsupported {}, mounting read-only",
+                dev_name.as_str(),
+                features
+            );
+            read_only = true;
+            journal_recovery = false;
+        }
         unsafe {
             errno_to_result(ext4_mount(
                 dev_name.as_ptr(),
@@ -168,9 +199,29 @@ impl<T: BlockDeviceInterface> MountHandle<T> {
         let handle = MountHandle {
             register_handle,
             mount_point: c_mount_point,
+            features,
+            read_only,
         };
         Ok(handle)
     }
+    /// Feature flags of the mounted file system.
+    pub fn features(&self) -> FeatureReport {
+        self.features
+    }
+    /// Whether the file system is mounted read-only, as requested or because of `mmp`
+    /// or unsupported read-only compatible features.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+    /// The mounted device, for access behind the back of lwext4 once its cache has
+    /// been flushed.
+    pub(crate) fn device_mut(&mut self) -> &mut BlockDevice<T> {
+        self.register_handle.device_mut()
+    }
+    /// The lwext4 file system, which `ext4_mount` hands to the device.
+    pub(crate) fn raw_fs(&self) -> *mut ext4_fs {
+        self.register_handle.device.raw.fs
+    }
     pub fn stats(&self) -> Result<MountStats> {
         let mut statfs = MountStats::new();
         unsafe {
@@ -207,6 +258,13 @@ pub trait BlockDeviceInterface {
     fn close(&mut self) -> Result<()>;
     fn lock(&mut self) -> Result<()>;
     fn unlock(&mut self) -> Result<()>;
+    /// Drop the data of `block_count` blocks from `block_id`, which read as zeros
+    /// afterwards. Devices that cannot do this fail with [Error::NotSupported], the
+    /// default, and zeros are written instead.
+    fn discard(&mut self, block_id: u64, block_count: u64) -> Result<()> {
+        let _ = (block_id, block_count);
+        Err(Error::NotSupported)
+    }
 }
 
 trait BlockDeviceInterfaceExt {
@@ -0,0 +1,257 @@
+use crate::error::{Error, Result};
+use crate::types::{FileTimes, MetaDataExt, Metadata, Permissions};
+use crate::{BlockDeviceInterface, File, FileSystem};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_io::{Read, Seek, SeekFrom, Write};
+use log::info;
+
+/// Blocks of the destination copied per read of a regular file.
+const CHUNK_BLOCKS: usize = 16;
+
+/// What part of an entry could not be reproduced on the destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloneIssueKind {
+    /// The entry itself could not be created or its contents could not be copied.
+    Create,
+    /// The permission bits could not be applied.
+    Permissions,
+    /// The owner and group could not be applied.
+    Ownership { uid: u32, gid: u32 },
+    /// The access, modification and change times could not be applied.
+    Timestamps,
+    /// The named extended attribute could not be read or applied.
+    Xattr(String),
+}
+
+/// An entry of the source that was not (fully) reproduced on the destination.
+#[derive(Debug, Clone)]
+pub struct CloneIssue {
+    /// Path of the entry in the source, including its mount point.
+    pub path: String,
+    pub kind: CloneIssueKind,
+    pub error: Error,
+}
+
+/// Summary of a [clone_filesystem] run.
+#[derive(Debug, Clone, Default)]
+pub struct CloneReport {
+    pub dirs: usize,
+    pub files: usize,
+    pub symlinks: usize,
+    pub hard_links: usize,
+    pub special_files: usize,
+    /// Bytes of file content written to the destination.
+    pub bytes: u64,
+    /// Bytes of all-zero blocks left as holes instead of being written.
+    pub holes: u64,
+    pub issues: Vec<CloneIssue>,
+}
+
+impl CloneReport {
+    fn issue(&mut self, path: &str, kind: CloneIssueKind, error: Error) {
+        self.issues.push(CloneIssue {
+            path: path.to_string(),
+            kind,
+            error,
+        });
+    }
+}
+
+struct Cloner<'a, S: BlockDeviceInterface, D: BlockDeviceInterface> {
+    src: &'a FileSystem<S>,
+    dst: &'a FileSystem<D>,
+    block_size: usize,
+    /// Destination path of the first cloned name of every multiply linked inode.
+    links: BTreeMap<u64, String>,
+    report: CloneReport,
+}
+
+/// Copy the whole tree of the mounted `src` into the mounted `dst`.
+///
+/// Both file systems are walked through their mount points, so the destination may
+/// have another block size, feature set or size than the source, as long as the files
+/// fit. Regular files (with all-zero blocks left as holes where lwext4 can seek over
+/// them), directories, symbolic and hard links, device nodes, fifos and sockets are
+/// recreated with their mode, owner, times and extended attributes. Directories that
+/// already exist on the destination, like `lost+found`, are reused. Everything that
+/// could not be reproduced is listed in the returned report instead of aborting the
+/// clone.
+pub fn clone_filesystem<S: BlockDeviceInterface, D: BlockDeviceInterface>(
+    src: &FileSystem<S>,
+    dst: &FileSystem<D>,
+) -> Result<CloneReport> {
+    let src_root = src.mount_handle().mount_point.as_str().to_string();
+    let dst_root = dst.mount_handle().mount_point.as_str().to_string();
+    let mut cloner = Cloner {
+        src,
+        dst,
+        block_size: dst.mount_handle().stats()?.block_size as usize,
+        links: BTreeMap::new(),
+        report: CloneReport::default(),
+    };
+    info!("Cloning {} to {}", src_root, dst_root);
+    cloner.clone_dir(&src_root, &dst_root)?;
+    let meta = src.metadata(&src_root)?;
+    cloner.apply_metadata(&src_root, &dst_root, &meta);
+    Ok(cloner.report)
+}
+
+impl<'a, S: BlockDeviceInterface, D: BlockDeviceInterface> Cloner<'a, S, D> {
+    /// Clone the entries of `dir` into `dst_dir`, both ending with a `/`.
+    fn clone_dir(&mut self, dir: &str, dst_dir: &str) -> Result<()> {
+        let entries = self
+            .src
+            .readdir(dir)?
+            .filter(|e| e.name() != "." && e.name() != "..")
+            .map(|e| (e.name().to_string(), e.path()))
+            .collect::<Vec<_>>();
+        for (name, path) in entries {
+            let dst_path = dst_dir.to_string() + &name;
+            let meta = self.src.metadata(&path)?;
+            if let Err(e) = self.clone_entry(&path, &dst_path, &meta) {
+                self.report.issue(&path, CloneIssueKind::Create, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn clone_entry(&mut self, path: &str, dst_path: &str, meta: &Metadata) -> Result<()> {
+        let ty = meta.file_type();
+        if !ty.is_dir() && meta.nlink() > 1 {
+            if let Some(first) = self.links.get(&meta.ino()) {
+                self.dst.hard_link(first, dst_path)?;
+                self.report.hard_links += 1;
+                return Ok(());
+            }
+        }
+        if ty.is_dir() {
+            match self.dst.create_dir(dst_path) {
+                Err(Error::FileExists) if self.dst.metadata(dst_path)?.is_dir() => {}
+                res => res?,
+            }
+            self.report.dirs += 1;
+            self.clone_dir(&(path.to_string() + "/"), &(dst_path.to_string() + "/"))?;
+        } else if ty.is_file() {
+            self.clone_file(path, dst_path)?;
+            self.report.files += 1;
+        } else if ty.is_symlink() {
+            self.dst.soft_link(self.src.read_link(path)?, dst_path)?;
+            self.report.symlinks += 1;
+        } else {
+            self.dst.mknod(dst_path, ty, meta.rdev())?;
+            self.report.special_files += 1;
+        }
+        // only a name that was created can be linked to by the later ones
+        if !ty.is_dir() && meta.nlink() > 1 {
+            self.links.insert(meta.ino(), dst_path.to_string());
+        }
+        self.apply_metadata(path, dst_path, meta);
+        Ok(())
+    }
+
+    /// Copy the contents of a regular file in blocks of the destination, seeking over
+    /// runs of all-zero blocks. lwext4 only seeks up to the end of a file, so a run
+    /// it refuses to skip is written out, and a file ending in zeros gets its last
+    /// block written to set its size.
+    fn clone_file(&mut self, path: &str, dst_path: &str) -> Result<()> {
+        let mut src = self.src.file_builder().read(true).open(path)?;
+        let mut dst = self
+            .dst
+            .file_builder()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dst_path)?;
+        let mut buf = vec![0u8; self.block_size * CHUNK_BLOCKS];
+        let mut zeros = 0u64;
+        loop {
+            let read = src.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            for chunk in buf[..read].chunks(self.block_size) {
+                if chunk.iter().all(|&b| b == 0) {
+                    zeros += chunk.len() as u64;
+                    continue;
+                }
+                self.skip_zeros(&mut dst, zeros)?;
+                zeros = 0;
+                dst.write_all(chunk)?;
+                self.report.bytes += chunk.len() as u64;
+            }
+        }
+        if zeros > 0 {
+            let last = zeros.min(self.block_size as u64);
+            self.skip_zeros(&mut dst, zeros - last)?;
+            self.write_zeros(&mut dst, last)?;
+        }
+        Ok(())
+    }
+
+    /// Leave `len` bytes of zeros as a hole, or write them if the seek is refused.
+    fn skip_zeros(&mut self, dst: &mut File, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        match dst.seek(SeekFrom::Current(len as i64)) {
+            Ok(_) => {
+                self.report.holes += len;
+                Ok(())
+            }
+            Err(_) => self.write_zeros(dst, len),
+        }
+    }
+
+    fn write_zeros(&mut self, dst: &mut File, len: u64) -> Result<()> {
+        let zeros = vec![0u8; self.block_size * CHUNK_BLOCKS];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(zeros.len() as u64) as usize;
+            dst.write_all(&zeros[..n])?;
+            remaining -= n as u64;
+        }
+        self.report.bytes += len;
+        Ok(())
+    }
+
+    fn apply_metadata(&mut self, path: &str, dst_path: &str, meta: &Metadata) {
+        for name in self.src.list_xattr(path).unwrap_or_default() {
+            let name = String::from_utf8_lossy(&name).to_string();
+            let res = self
+                .src
+                .get_xattr(path, &name)
+                .and_then(|value| self.dst.set_xattr(dst_path, &name, &value));
+            if let Err(e) = res {
+                self.report.issue(path, CloneIssueKind::Xattr(name), e);
+            }
+        }
+        if let Err(e) = self.dst.chown(dst_path, Some(meta.uid()), Some(meta.gid())) {
+            let kind = CloneIssueKind::Ownership {
+                uid: meta.uid(),
+                gid: meta.gid(),
+            };
+            self.report.issue(path, kind, e);
+        }
+        if !meta.is_symlink() {
+            let perm = Permissions(meta.mode() & 0o7777);
+            if let Err(e) = self.dst.set_permissions(dst_path, perm) {
+                self.report.issue(path, CloneIssueKind::Permissions, e);
+            }
+        }
+        let times = FileTimes::new()
+            .set_accessed(meta.accessed())
+            .set_modified(meta.modified())
+            .set_created(meta.created());
+        // nanoseconds the destination has no room for are dropped
+        let res = self.dst.keeps_nanos(dst_path).and_then(|keep| {
+            let times = if keep { times } else { times.whole_secs() };
+            self.dst.set_times(dst_path, times)
+        });
+        if let Err(e) = res {
+            self.report.issue(path, CloneIssueKind::Timestamps, e);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use crate::block::{BlockDevice, BlockDeviceConfig, BlockDeviceInterface};
+use crate::crc::crc32;
+use crate::disk::{le16, le32};
+use crate::error::{Error, Result};
+use log::warn;
+use miniz_oxide::inflate::core::inflate_flags::{
+    TINFL_FLAG_HAS_MORE_INPUT, TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+};
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use ruzstd::decoding::FrameDecoder;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+/// Number of frames, descriptor and magic at the very end of a seekable zstd file.
+const SEEK_FOOTER_SIZE: u64 = 9;
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+const FHCRC: u8 = 0x02;
+/// Largest chunk decompressed at once, a whole image compressed as a single frame or
+/// member is refused rather than held in memory.
+const MAX_CHUNK: u64 = 64 << 20;
+const DEFAULT_CACHE_CHUNKS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Zstd,
+    Gzip,
+}
+
+/// One independently compressed piece of the image.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    /// Offset in the uncompressed image.
+    start: u64,
+    size: u64,
+    /// Offset and size of the compressed data in the file, for gzip the deflate
+    /// stream between the member header and trailer.
+    offset: u64,
+    compressed: u64,
+    /// CRC32 of the uncompressed data, for gzip.
+    crc: Option<u32>,
+}
+
+/// A read-only block device over a compressed image that can be read from anywhere
+/// without decompressing what comes before:
+///
+/// - zstd in the [seekable format], frames followed by a seek table, as written by
+///   `t2sz` or the `zstd` seekable library;
+/// - gzip with several members, such as `bgzip` writes. Members without the BGZF
+///   size field are inflated once when the device is opened, to find where they end.
+///
+/// Chunks are decompressed when read and the last few are kept, 8 by default. A
+/// chunk larger than 64MiB fails with [Error::NotSupported], an image compressed
+/// with plain `zstd` or `gzip` is one such chunk. Writes fail with
+/// [Error::ReadOnly], the file system has to be mounted read-only.
+///
+/// [seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{CompressedInterface, FileSystem, MountHandle, RegisterHandle};
+/// let file = std::fs::File::open("rootfs.img.zst").unwrap();
+/// let blk = CompressedInterface::new_device(file, 4096);
+/// let register_handler = RegisterHandle::register(blk, "rootfs".to_string()).unwrap();
+/// let mount_handler =
+///     MountHandle::mount(register_handler, "/".to_string(), false, true).unwrap();
+/// let fs = FileSystem::new(mount_handler).unwrap();
+/// ```
+pub struct CompressedInterface<T: Read + Seek> {
+    inner: T,
+    block_size: u32,
+    cache_chunks: usize,
+    /// Filled in by `open`.
+    chunks: Vec<Chunk>,
+    format: Option<Format>,
+    /// Decompressed chunks by index, the most recently used last.
+    cache: Vec<(usize, Vec<u8>)>,
+}
+
+impl<T: Read + Seek> CompressedInterface<T> {
+    /// `block_size` is the block size reported to lwext4, the uncompressed image
+    /// must be a whole number of blocks.
+    pub fn new(inner: T, block_size: u32) -> Self {
+        Self {
+            inner,
+            block_size,
+            cache_chunks: DEFAULT_CACHE_CHUNKS,
+            chunks: Vec::new(),
+            format: None,
+            cache: Vec::new(),
+        }
+    }
+
+    pub fn new_device(inner: T, block_size: u32) -> Pin<Box<BlockDevice<Self>>> {
+        BlockDevice::new(Self::new(inner, block_size))
+    }
+
+    /// Keep up to `chunks` decompressed chunks, at least one.
+    pub fn cache_chunks(mut self, chunks: usize) -> Self {
+        self.cache_chunks = chunks.max(1);
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.inner
+            .seek(SeekFrom::Start(offset))
+            .map_err(|_| Error::Io)?;
+        self.inner.read_exact(buf).map_err(|_| Error::Io)
+    }
+
+    fn index(&mut self) -> Result<(Format, Vec<Chunk>)> {
+        let len = self.inner.seek(SeekFrom::End(0)).map_err(|_| Error::Io)?;
+        let mut magic = [0u8; 4];
+        if len < 4 {
+            return Err(Error::NotSupported);
+        }
+        self.read_at(0, &mut magic)?;
+        if magic[..3] == GZIP_MAGIC {
+            return Ok((Format::Gzip, self.index_gzip(len)?));
+        }
+        if u32::from_le_bytes(magic) == ZSTD_MAGIC {
+            return Ok((Format::Zstd, self.index_zstd(len)?));
+        }
+        Err(Error::NotSupported)
+    }
+
+    /// Read the seek table of a seekable zstd file, a skippable frame at the end.
+    fn index_zstd(&mut self, len: u64) -> Result<Vec<Chunk>> {
+        let mut footer = [0u8; SEEK_FOOTER_SIZE as usize];
+        if len < SEEK_FOOTER_SIZE + 8 {
+            return Err(Error::NotSupported);
+        }
+        self.read_at(len - SEEK_FOOTER_SIZE, &mut footer)?;
+        if le32(&footer, 5) != SEEKABLE_MAGIC {
+            warn!("zstd image without a seek table, it has to be recompressed seekable");
+            return Err(Error::NotSupported);
+        }
+        let frames = le32(&footer, 0) as u64;
+        let descriptor = footer[4];
+        if descriptor & 0x7f != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let entry_size = if descriptor & 0x80 != 0 { 12 } else { 8 };
+        let table_size = frames * entry_size + SEEK_FOOTER_SIZE;
+        let table_start = len
+            .checked_sub(table_size + 8)
+            .ok_or(Error::InvalidArgument)?;
+        let mut table = vec![0u8; table_size as usize + 8];
+        self.read_at(table_start, &mut table)?;
+        if le32(&table, 0) != SKIPPABLE_MAGIC || le32(&table, 4) as u64 != table_size {
+            return Err(Error::InvalidArgument);
+        }
+        let mut chunks = Vec::with_capacity(frames as usize);
+        let (mut start, mut offset) = (0, 0);
+        for entry in table[8..]
+            .chunks_exact(entry_size as usize)
+            .take(frames as usize)
+        {
+            let compressed = le32(entry, 0) as u64;
+            let size = le32(entry, 4) as u64;
+            if size > MAX_CHUNK {
+                warn!(
+                    "zstd frame of {} bytes, the image has to be recompressed in smaller frames",
+                    size
+                );
+                return Err(Error::NotSupported);
+            }
+            chunks.push(Chunk {
+                start,
+                size,
+                offset,
+                compressed,
+                crc: None,
+            });
+            start += size;
+            offset += compressed;
+        }
+        if offset != table_start {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(chunks)
+    }
+
+    /// Walk the members of a gzip file.
+    fn index_gzip(&mut self, len: u64) -> Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        let (mut start, mut member) = (0, 0);
+        while member < len {
+            let mut header = [0u8; 12];
+            self.read_at(member, &mut header)?;
+            if header[..3] != GZIP_MAGIC {
+                return Err(Error::InvalidArgument);
+            }
+            let flags = header[3];
+            let mut offset = member + 10;
+            let mut block_size = None;
+            if flags & FEXTRA != 0 {
+                let mut extra = vec![0u8; le16(&header, 10) as usize];
+                self.read_at(offset + 2, &mut extra)?;
+                block_size = bgzf_block_size(&extra);
+                offset += 2 + extra.len() as u64;
+            }
+            for flag in [FNAME, FCOMMENT] {
+                if flags & flag != 0 {
+                    offset = self.skip_string(offset)?;
+                }
+            }
+            if flags & FHCRC != 0 {
+                offset += 2;
+            }
+            let end = match block_size {
+                Some(size) => member + size,
+                None => self.inflate_end(offset)? + 8,
+            };
+            let mut trailer = [0u8; 8];
+            if end > len || end < offset + 8 {
+                return Err(Error::InvalidArgument);
+            }
+            self.read_at(end - 8, &mut trailer)?;
+            let size = le32(&trailer, 4) as u64;
+            if size > MAX_CHUNK {
+                warn!(
+                    "gzip member of {} bytes, the image has to be recompressed in chunks",
+                    size
+                );
+                return Err(Error::NotSupported);
+            }
+            // BGZF ends with an empty member.
+            if size != 0 {
+                chunks.push(Chunk {
+                    start,
+                    size,
+                    offset,
+                    compressed: end - 8 - offset,
+                    crc: Some(le32(&trailer, 0)),
+                });
+            }
+            start += size;
+            member = end;
+        }
+        Ok(chunks)
+    }
+
+    /// Offset just past the zero terminated string at `offset`.
+    fn skip_string(&mut self, mut offset: u64) -> Result<u64> {
+        let mut byte = [0u8];
+        loop {
+            self.read_at(offset, &mut byte)?;
+            offset += 1;
+            if byte[0] == 0 {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// Offset just past the deflate stream at `offset`, found by inflating it.
+    fn inflate_end(&mut self, offset: u64) -> Result<u64> {
+        let mut decompressor = DecompressorOxide::new();
+        let mut window = vec![0u8; 32 << 10];
+        let mut input = vec![0u8; 64 << 10];
+        let (mut pos, mut out_pos, mut produced) = (offset, 0, 0u64);
+        self.inner
+            .seek(SeekFrom::Start(pos))
+            .map_err(|_| Error::Io)?;
+        loop {
+            let read = self.inner.read(&mut input).map_err(|_| Error::Io)?;
+            let mut used = 0;
+            loop {
+                let flags = if read == 0 {
+                    0
+                } else {
+                    TINFL_FLAG_HAS_MORE_INPUT
+                };
+                let (status, consumed, written) = decompress(
+                    &mut decompressor,
+                    &input[used..read],
+                    &mut window,
+                    out_pos,
+                    flags,
+                );
+                used += consumed;
+                produced += written as u64;
+                out_pos = (out_pos + written) % window.len();
+                if produced > MAX_CHUNK {
+                    warn!(
+                        "gzip member over {} bytes, the image has to be recompressed in chunks",
+                        MAX_CHUNK
+                    );
+                    return Err(Error::NotSupported);
+                }
+                match status {
+                    TINFLStatus::Done => return Ok(pos + used as u64),
+                    TINFLStatus::HasMoreOutput => continue,
+                    TINFLStatus::NeedsMoreInput if read != 0 => break,
+                    _ => return Err(Error::InvalidArgument),
+                }
+            }
+            pos += read as u64;
+        }
+    }
+
+    /// Decompressed chunk `index`, from the cache if it is there.
+    fn chunk(&mut self, index: usize) -> Result<&[u8]> {
+        if let Some(at) = self.cache.iter().position(|&(i, _)| i == index) {
+            let entry = self.cache.remove(at);
+            self.cache.push(entry);
+        } else {
+            let chunk = self.chunks[index];
+            let mut compressed = vec![0u8; chunk.compressed as usize];
+            self.read_at(chunk.offset, &mut compressed)?;
+            let data = match self.format {
+                Some(Format::Zstd) => inflate_zstd(&compressed, chunk.size)?,
+                Some(Format::Gzip) => inflate_gzip(&compressed, chunk.size)?,
+                None => return Err(Error::Io),
+            };
+            if chunk.crc.is_some_and(|crc| crc32(!0, &data) != !crc) {
+                return Err(Error::Io);
+            }
+            if self.cache.len() >= self.cache_chunks {
+                self.cache.remove(0);
+            }
+            self.cache.push((index, data));
+        }
+        Ok(&self.cache.last().ok_or(Error::Io)?.1)
+    }
+}
+
+/// Compressed size of a BGZF block from its `BC` extra subfield.
+fn bgzf_block_size(mut extra: &[u8]) -> Option<u64> {
+    while extra.len() >= 4 {
+        let len = le16(extra, 2) as usize;
+        let data = extra.get(4..4 + len)?;
+        if extra[..2] == *b"BC" && len == 2 {
+            return Some(le16(data, 0) as u64 + 1);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn inflate_zstd(compressed: &[u8], size: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; size as usize];
+    let written = FrameDecoder::new()
+        .decode_all(compressed, &mut data)
+        .map_err(|_| Error::Io)?;
+    if written as u64 != size {
+        return Err(Error::Io);
+    }
+    Ok(data)
+}
+
+fn inflate_gzip(compressed: &[u8], size: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; size as usize];
+    let (status, _, written) = decompress(
+        &mut DecompressorOxide::new(),
+        compressed,
+        &mut data,
+        0,
+        TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    );
+    if status != TINFLStatus::Done || written as u64 != size {
+        return Err(Error::Io);
+    }
+    Ok(data)
+}
+
+impl<T: Read + Seek> BlockDeviceInterface for CompressedInterface<T> {
+    fn open(&mut self) -> Result<BlockDeviceConfig> {
+        if self.format.is_none() {
+            let (format, chunks) = self.index()?;
+            self.format = Some(format);
+            self.chunks = chunks;
+        }
+        let size = self.chunks.last().map_or(0, |c| c.start + c.size);
+        let bs = self.block_size as u64;
+        if bs == 0 || size == 0 || !size.is_multiple_of(bs) {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(BlockDeviceConfig {
+            block_size: self.block_size,
+            block_count: size / bs,
+            part_offset: 0,
+            part_size: size,
+        })
+    }
+
+    fn read_block(&mut self, buf: &mut [u8], block_id: u64, _block_count: u32) -> Result<usize> {
+        let start = block_id * self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = start + done as u64;
+            let index = self.chunks.partition_point(|c| c.start + c.size <= at);
+            let chunk_start = self.chunks.get(index).ok_or(Error::Io)?.start;
+            let data = &self.chunk(index)?[(at - chunk_start) as usize..];
+            let n = data.len().min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&data[..n]);
+            done += n;
+        }
+        Ok(buf.len())
+    }
+
+    fn write_block(&mut self, _buf: &[u8], _block_id: u64, _block_count: u32) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
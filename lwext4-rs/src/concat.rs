@@ -0,0 +1,166 @@
+use crate::block::{BlockDevice, BlockDeviceConfig, BlockDeviceInterface};
+use crate::disk::open_device;
+use crate::error::{Error, Result};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::pin::Pin;
+
+/// Several devices joined end to end into one, like a linear device mapper target,
+/// e.g. an image split into `disk.img.000`, `disk.img.001`, ...
+///
+/// Every part must have the same block size and a whole number of blocks. Requests
+/// crossing from one part to the next are split.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, Concat, DefaultInterface, RegisterHandle};
+/// let parts = ["disk.img.000", "disk.img.001", "disk.img.002"].map(|path| {
+///     let file = std::fs::OpenOptions::new()
+///         .read(true)
+///         .write(true)
+///         .open(path)
+///         .unwrap();
+///     let len = file.metadata().unwrap().len();
+///     let config = BlockDeviceConfig {
+///         block_size: 512,
+///         block_count: len / 512,
+///         part_size: len,
+///         part_offset: 0,
+///     };
+///     DefaultInterface::new(file, config)
+/// });
+/// let blk = Concat::new_device(parts.into());
+/// let register_handler = RegisterHandle::register(blk, "disk".to_string()).unwrap();
+/// ```
+pub struct Concat<T: BlockDeviceInterface> {
+    parts: Vec<T>,
+    /// Where each part lies in the joined device, filled in by `open`.
+    layout: Vec<Span>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    /// First block in the joined device.
+    start: u64,
+    blocks: u64,
+    /// First block in the part, after its `part_offset`.
+    first: u64,
+}
+
+impl<T: BlockDeviceInterface> Concat<T> {
+    pub fn new(parts: Vec<T>) -> Self {
+        Self {
+            parts,
+            layout: Vec::new(),
+        }
+    }
+
+    pub fn new_device(parts: Vec<T>) -> Pin<Box<BlockDevice<Self>>> {
+        BlockDevice::new(Self::new(parts))
+    }
+
+    /// Give the parts back.
+    pub fn into_parts(self) -> Vec<T> {
+        self.parts
+    }
+
+    /// Run `f` on each piece of the `count` blocks from `block`, with the part, the
+    /// block in it, the number of blocks and the offset of the piece in the request.
+    fn split(
+        &mut self,
+        block: u64,
+        count: u64,
+        mut f: impl FnMut(&mut T, u64, u64, u64) -> Result<()>,
+    ) -> Result<()> {
+        if self.layout.is_empty() {
+            return Err(Error::Io);
+        }
+        let mut done = 0;
+        while done < count {
+            let at = block + done;
+            let i = self.layout.partition_point(|s| s.start + s.blocks <= at);
+            let span = *self.layout.get(i).ok_or(Error::Io)?;
+            let n = (span.start + span.blocks - at).min(count - done);
+            f(&mut self.parts[i], span.first + at - span.start, n, done)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl<T: BlockDeviceInterface> BlockDeviceInterface for Concat<T> {
+    fn open(&mut self) -> Result<BlockDeviceConfig> {
+        let mut layout = Vec::with_capacity(self.parts.len());
+        let mut block_size = 0;
+        let mut start = 0;
+        for part in &mut self.parts {
+            let config = open_device(part)?;
+            let bs = config.block_size as u64;
+            let whole =
+                config.part_offset.is_multiple_of(bs) && config.part_size.is_multiple_of(bs);
+            if !whole || (block_size != 0 && config.block_size != block_size) {
+                return Err(Error::InvalidArgument);
+            }
+            block_size = config.block_size;
+            let blocks = config.part_size / bs;
+            layout.push(Span {
+                start,
+                blocks,
+                first: config.part_offset / bs,
+            });
+            start += blocks;
+        }
+        if start == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        self.layout = layout;
+        Ok(BlockDeviceConfig {
+            block_size,
+            block_count: start,
+            part_offset: 0,
+            part_size: start * block_size as u64,
+        })
+    }
+
+    fn read_block(&mut self, buf: &mut [u8], block_id: u64, block_count: u32) -> Result<usize> {
+        if block_count == 0 {
+            return Ok(0);
+        }
+        let bs = buf.len() / block_count as usize;
+        self.split(block_id, block_count as u64, |part, block, n, at| {
+            let piece = &mut buf[at as usize * bs..(at + n) as usize * bs];
+            part.read_block(piece, block, n as u32).map(|_| ())
+        })?;
+        Ok(buf.len())
+    }
+
+    fn write_block(&mut self, buf: &[u8], block_id: u64, block_count: u32) -> Result<usize> {
+        if block_count == 0 {
+            return Ok(0);
+        }
+        let bs = buf.len() / block_count as usize;
+        self.split(block_id, block_count as u64, |part, block, n, at| {
+            let piece = &buf[at as usize * bs..(at + n) as usize * bs];
+            part.write_block(piece, block, n as u32).map(|_| ())
+        })?;
+        Ok(buf.len())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.parts.iter_mut().try_for_each(|part| part.close())
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        self.parts.iter_mut().try_for_each(|part| part.lock())
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        self.parts.iter_mut().try_for_each(|part| part.unlock())
+    }
+
+    fn discard(&mut self, block_id: u64, block_count: u64) -> Result<()> {
+        self.split(block_id, block_count, |part, block, n, _| {
+            part.discard(block, n)
+        })
+    }
+}
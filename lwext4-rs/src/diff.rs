@@ -0,0 +1,614 @@
+use crate::error::Result;
+use crate::host::{self, host_dev, host_type, read_full};
+use crate::types::{FileType, MetaDataExt};
+use crate::{BlockDeviceInterface, FileSystem};
+use core::fmt::{self, Display, Formatter, Write as _};
+use embedded_io::Read as _;
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes compared per read of a regular file.
+const CHUNK: usize = 64 * 1024;
+
+/// An attribute that differs between the old and the new entry of a [DiffEntry].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffAttr {
+    /// The entry changed its type, given as in `ls -l`. Nothing else is compared.
+    Type {
+        old: char,
+        new: char,
+    },
+    /// The permission bits, including setuid, setgid and sticky.
+    Mode {
+        old: u32,
+        new: u32,
+    },
+    Owner {
+        old: (u32, u32),
+        new: (u32, u32),
+    },
+    /// The size of a regular file or symbolic link.
+    Size {
+        old: u64,
+        new: u64,
+    },
+    /// A regular file of the same size has other contents.
+    Content,
+    LinkTarget {
+        old: String,
+        new: String,
+    },
+    /// The device number of a device node, as stored in the inode.
+    Device {
+        old: u32,
+        new: u32,
+    },
+    /// The named extended attribute was added, removed or changed its value.
+    Xattr(String),
+    /// The modification time, only compared if [TreeDiff::times] is set.
+    Modified {
+        old: i64,
+        new: i64,
+    },
+}
+
+/// How an entry differs between the two trees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffChange {
+    /// The entry is only in the new tree. Entries below an added directory are not
+    /// listed.
+    Added,
+    /// The entry is only in the old tree. Entries below a removed directory are not
+    /// listed.
+    Removed,
+    Modified(Vec<DiffAttr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    /// Path of the entry relative to the compared directories, starting with `/`.
+    pub path: String,
+    pub change: DiffChange,
+}
+
+/// Result of a [TreeDiff], sorted by path.
+///
+/// It displays as one line per entry, `+` for added, `-` for removed and `M` for
+/// modified entries followed by what changed, and [to_json](Self::to_json) gives the
+/// same as JSON.
+#[derive(Debug, Clone, Default)]
+pub struct DiffReport {
+    pub entries: Vec<DiffEntry>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The entries as a JSON array of objects with a `path`, a `change` of `added`,
+    /// `removed` or `modified` and, for modified entries, a `attrs` array of objects
+    /// with the `attr` name and its `old` and `new` values where they apply.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("[");
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("\n  {\"path\": ");
+            json_str(&mut json, &entry.path);
+            match &entry.change {
+                DiffChange::Added => json.push_str(", \"change\": \"added\"}"),
+                DiffChange::Removed => json.push_str(", \"change\": \"removed\"}"),
+                DiffChange::Modified(attrs) => {
+                    json.push_str(", \"change\": \"modified\", \"attrs\": [");
+                    for (i, attr) in attrs.iter().enumerate() {
+                        if i > 0 {
+                            json.push_str(", ");
+                        }
+                        json_attr(&mut json, attr);
+                    }
+                    json.push_str("]}");
+                }
+            }
+        }
+        if !self.entries.is_empty() {
+            json.push('\n');
+        }
+        json.push_str("]\n");
+        json
+    }
+}
+
+impl Display for DiffAttr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffAttr::Type { old, new } => write!(f, "type {} -> {}", old, new),
+            DiffAttr::Mode { old, new } => write!(f, "mode {:04o} -> {:04o}", old, new),
+            DiffAttr::Owner { old, new } => {
+                write!(f, "owner {}:{} -> {}:{}", old.0, old.1, new.0, new.1)
+            }
+            DiffAttr::Size { old, new } => write!(f, "size {} -> {}", old, new),
+            DiffAttr::Content => write!(f, "content"),
+            DiffAttr::LinkTarget { old, new } => write!(f, "link {} -> {}", old, new),
+            DiffAttr::Device { old, new } => write!(f, "device {:#x} -> {:#x}", old, new),
+            DiffAttr::Xattr(name) => write!(f, "xattr {}", name),
+            DiffAttr::Modified { old, new } => write!(f, "mtime {} -> {}", old, new),
+        }
+    }
+}
+
+impl Display for DiffReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            match &entry.change {
+                DiffChange::Added => writeln!(f, "+ {}", entry.path)?,
+                DiffChange::Removed => writeln!(f, "- {}", entry.path)?,
+                DiffChange::Modified(attrs) => {
+                    write!(f, "M {}:", entry.path)?;
+                    for (i, attr) in attrs.iter().enumerate() {
+                        let sep = if i == 0 { " " } else { ", " };
+                        write!(f, "{}{}", sep, attr)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn json_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+fn json_attr(json: &mut String, attr: &DiffAttr) {
+    let _ = match attr {
+        DiffAttr::Type { old, new } => write!(
+            json,
+            "{{\"attr\": \"type\", \"old\": \"{}\", \"new\": \"{}\"}}",
+            old, new
+        ),
+        DiffAttr::Mode { old, new } => write!(
+            json,
+            "{{\"attr\": \"mode\", \"old\": \"{:04o}\", \"new\": \"{:04o}\"}}",
+            old, new
+        ),
+        DiffAttr::Owner { old, new } => write!(
+            json,
+            "{{\"attr\": \"owner\", \"old\": [{}, {}], \"new\": [{}, {}]}}",
+            old.0, old.1, new.0, new.1
+        ),
+        DiffAttr::Size { old, new } => write!(
+            json,
+            "{{\"attr\": \"size\", \"old\": {}, \"new\": {}}}",
+            old, new
+        ),
+        DiffAttr::Content => write!(json, "{{\"attr\": \"content\"}}"),
+        DiffAttr::LinkTarget { old, new } => {
+            json.push_str("{\"attr\": \"link\", \"old\": ");
+            json_str(json, old);
+            json.push_str(", \"new\": ");
+            json_str(json, new);
+            json.push('}');
+            Ok(())
+        }
+        DiffAttr::Device { old, new } => write!(
+            json,
+            "{{\"attr\": \"device\", \"old\": {}, \"new\": {}}}",
+            old, new
+        ),
+        DiffAttr::Xattr(name) => {
+            json.push_str("{\"attr\": \"xattr\", \"name\": ");
+            json_str(json, name);
+            json.push('}');
+            Ok(())
+        }
+        DiffAttr::Modified { old, new } => write!(
+            json,
+            "{{\"attr\": \"mtime\", \"old\": {}, \"new\": {}}}",
+            old, new
+        ),
+    };
+}
+
+/// Compare two directory trees, each in a mounted image or on the host.
+///
+/// Entries are matched by path. For entries on both sides the type, permission bits,
+/// owner, extended attributes and, depending on the type, the size and contents, the
+/// link target or the device number are compared. Directory sizes depend on the
+/// block size and are not compared, neither are times unless asked for. Host names
+/// that are not UTF-8 are skipped with a warning.
+///
+/// ```no_run
+/// # use lwext4_rs::{FileSystem, DefaultInterface, TreeDiff};
+/// # fn diff(fs: &FileSystem<DefaultInterface<std::fs::File>>) {
+/// let report = TreeDiff::new()
+///     .exclude("/lost+found")
+///     .image_to_host(fs, "/", "build/rootfs")
+///     .unwrap();
+/// print!("{}", report);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TreeDiff {
+    contents: bool,
+    times: bool,
+    exclude: BTreeSet<String>,
+}
+
+impl Default for TreeDiff {
+    fn default() -> Self {
+        Self {
+            contents: true,
+            times: false,
+            exclude: BTreeSet::new(),
+        }
+    }
+}
+
+impl TreeDiff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compare the contents of regular files of the same size, on by default.
+    pub fn contents(mut self, contents: bool) -> Self {
+        self.contents = contents;
+        self
+    }
+
+    /// Also compare modification times, off by default as builds rarely keep them.
+    pub fn times(mut self, times: bool) -> Self {
+        self.times = times;
+        self
+    }
+
+    /// Skip the entry at `path`, relative to the compared directories, and all
+    /// entries below it.
+    pub fn exclude<P: AsRef<str>>(mut self, path: P) -> Self {
+        let path = path.as_ref().trim_end_matches('/');
+        self.exclude
+            .insert(format!("/{}", path.trim_start_matches('/')));
+        self
+    }
+
+    /// Compare `old_dir` of the mounted `old` with `new_dir` of the mounted `new`.
+    pub fn images<S: BlockDeviceInterface, D: BlockDeviceInterface>(
+        &self,
+        old: &FileSystem<S>,
+        old_dir: &str,
+        new: &FileSystem<D>,
+        new_dir: &str,
+    ) -> Result<DiffReport> {
+        self.diff(&ImageTree::new(old, old_dir), &ImageTree::new(new, new_dir))
+    }
+
+    /// Compare `image_dir` of the mounted `image`, as the old tree, with `host_dir`
+    /// on the host, as the new one.
+    pub fn image_to_host<T: BlockDeviceInterface, P: AsRef<Path>>(
+        &self,
+        image: &FileSystem<T>,
+        image_dir: &str,
+        host_dir: P,
+    ) -> Result<DiffReport> {
+        let host = HostTree {
+            dir: host_dir.as_ref().to_path_buf(),
+        };
+        self.diff(&ImageTree::new(image, image_dir), &host)
+    }
+
+    fn diff(&self, old: &dyn Tree, new: &dyn Tree) -> Result<DiffReport> {
+        info!("Comparing {} with {}", old.name(), new.name());
+        let mut report = DiffReport::default();
+        self.diff_entry(old, new, "", &mut report)?;
+        Ok(report)
+    }
+
+    /// Compare the entry at `path`, which is on both sides, and below it.
+    fn diff_entry(
+        &self,
+        old: &dyn Tree,
+        new: &dyn Tree,
+        path: &str,
+        report: &mut DiffReport,
+    ) -> Result<()> {
+        let (a, b) = (old.entry(path)?, new.entry(path)?);
+        let mut attrs = Vec::new();
+        if a.ty != b.ty {
+            attrs.push(DiffAttr::Type {
+                old: a.ty.as_char(),
+                new: b.ty.as_char(),
+            });
+        } else {
+            self.diff_attrs(old, new, path, &a, &b, &mut attrs)?;
+        }
+        if !attrs.is_empty() {
+            report.entries.push(DiffEntry {
+                path: display_path(path),
+                change: DiffChange::Modified(attrs),
+            });
+        }
+        if !a.ty.is_dir() || !b.ty.is_dir() {
+            return Ok(());
+        }
+        let old_names = old.list(path)?;
+        let new_names = new.list(path)?;
+        for name in old_names.union(&new_names) {
+            let child = format!("{}/{}", path, name);
+            if self.exclude.contains(&child) {
+                continue;
+            }
+            let change = match (old_names.contains(name), new_names.contains(name)) {
+                (true, true) => {
+                    self.diff_entry(old, new, &child, report)?;
+                    continue;
+                }
+                (true, false) => DiffChange::Removed,
+                _ => DiffChange::Added,
+            };
+            report.entries.push(DiffEntry {
+                path: child,
+                change,
+            });
+        }
+        Ok(())
+    }
+
+    fn diff_attrs(
+        &self,
+        old: &dyn Tree,
+        new: &dyn Tree,
+        path: &str,
+        a: &TreeEntry,
+        b: &TreeEntry,
+        attrs: &mut Vec<DiffAttr>,
+    ) -> Result<()> {
+        // the permissions of symbolic links are not used and differ between hosts
+        if a.mode != b.mode && !a.ty.is_symlink() {
+            attrs.push(DiffAttr::Mode {
+                old: a.mode,
+                new: b.mode,
+            });
+        }
+        if a.owner != b.owner {
+            attrs.push(DiffAttr::Owner {
+                old: a.owner,
+                new: b.owner,
+            });
+        }
+        if a.ty.is_file() || a.ty.is_symlink() {
+            if a.size != b.size {
+                attrs.push(DiffAttr::Size {
+                    old: a.size,
+                    new: b.size,
+                });
+            } else if a.ty.is_file() && self.contents && !same_contents(old, new, path)? {
+                attrs.push(DiffAttr::Content);
+            }
+        }
+        if a.link != b.link {
+            attrs.push(DiffAttr::LinkTarget {
+                old: a.link.clone().unwrap_or_default(),
+                new: b.link.clone().unwrap_or_default(),
+            });
+        }
+        if a.rdev != b.rdev {
+            attrs.push(DiffAttr::Device {
+                old: a.rdev,
+                new: b.rdev,
+            });
+        }
+        let names: BTreeSet<&String> = a.xattrs.keys().chain(b.xattrs.keys()).collect();
+        for name in names {
+            if a.xattrs.get(name) != b.xattrs.get(name) {
+                attrs.push(DiffAttr::Xattr(name.clone()));
+            }
+        }
+        if self.times && a.mtime != b.mtime {
+            attrs.push(DiffAttr::Modified {
+                old: a.mtime,
+                new: b.mtime,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn display_path(path: &str) -> String {
+    match path.is_empty() {
+        true => "/".to_string(),
+        false => path.to_string(),
+    }
+}
+
+fn same_contents(old: &dyn Tree, new: &dyn Tree, path: &str) -> Result<bool> {
+    let mut a = old.open(path)?;
+    let mut b = new.open(path)?;
+    let mut buf_a = vec![0u8; CHUNK];
+    let mut buf_b = vec![0u8; CHUNK];
+    loop {
+        let len_a = read_full(&mut *a, &mut buf_a)?;
+        let len_b = read_full(&mut *b, &mut buf_b)?;
+        if buf_a[..len_a] != buf_b[..len_b] {
+            return Ok(false);
+        }
+        if len_a == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+type Reader<'a> = Box<dyn FnMut(&mut [u8]) -> Result<usize> + 'a>;
+
+/// What is compared of an entry, the same for both kinds of tree.
+struct TreeEntry {
+    ty: FileType,
+    mode: u32,
+    owner: (u32, u32),
+    size: u64,
+    mtime: i64,
+    link: Option<String>,
+    rdev: u32,
+    xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+/// A directory tree to compare, with paths relative to its top, `""` being the top
+/// itself.
+trait Tree {
+    fn name(&self) -> String;
+    fn list(&self, path: &str) -> Result<BTreeSet<String>>;
+    fn entry(&self, path: &str) -> Result<TreeEntry>;
+    fn open(&self, path: &str) -> Result<Reader<'_>>;
+}
+
+struct ImageTree<'a, T: BlockDeviceInterface> {
+    fs: &'a FileSystem<T>,
+    /// The compared directory, without its trailing `/`.
+    dir: String,
+}
+
+impl<'a, T: BlockDeviceInterface> ImageTree<'a, T> {
+    fn new(fs: &'a FileSystem<T>, dir: &str) -> Self {
+        Self {
+            fs,
+            dir: dir.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        match path.is_empty() {
+            true => host::image_dir(self.fs, &self.dir),
+            false => self.dir.clone() + path,
+        }
+    }
+}
+
+impl<'a, T: BlockDeviceInterface> Tree for ImageTree<'a, T> {
+    fn name(&self) -> String {
+        self.path("")
+    }
+
+    fn list(&self, path: &str) -> Result<BTreeSet<String>> {
+        Ok(self
+            .fs
+            .readdir(self.dir.clone() + path + "/")?
+            .map(|e| e.name().to_string())
+            .filter(|name| name != "." && name != "..")
+            .collect())
+    }
+
+    fn entry(&self, path: &str) -> Result<TreeEntry> {
+        let path = self.path(path);
+        let meta = self.fs.metadata(&path)?;
+        let ty = meta.file_type();
+        let mut xattrs = BTreeMap::new();
+        for name in self.fs.list_xattr(&path)? {
+            let name = String::from_utf8_lossy(&name).to_string();
+            let value = self.fs.get_xattr(&path, &name)?;
+            xattrs.insert(name, value);
+        }
+        Ok(TreeEntry {
+            ty,
+            mode: meta.mode() & 0o7777,
+            owner: (meta.uid(), meta.gid()),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            link: match ty.is_symlink() {
+                true => Some(self.fs.read_link(&path)?),
+                false => None,
+            },
+            rdev: match ty.is_char_device() || ty.is_block_device() {
+                true => meta.rdev(),
+                false => 0,
+            },
+            xattrs,
+        })
+    }
+
+    fn open(&self, path: &str) -> Result<Reader<'_>> {
+        let mut file = self.fs.file_builder().read(true).open(self.path(path))?;
+        Ok(Box::new(move |buf: &mut [u8]| file.read(buf)))
+    }
+}
+
+struct HostTree {
+    dir: PathBuf,
+}
+
+impl HostTree {
+    fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path.trim_start_matches('/'))
+    }
+}
+
+impl Tree for HostTree {
+    fn name(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    fn list(&self, path: &str) -> Result<BTreeSet<String>> {
+        let mut names = BTreeSet::new();
+        for entry in std::fs::read_dir(self.path(path))? {
+            match entry?.file_name().into_string() {
+                Ok(name) => {
+                    names.insert(name);
+                }
+                Err(name) => warn!(
+                    "{}/{}: skipped, the name is not UTF-8",
+                    path,
+                    name.to_string_lossy()
+                ),
+            }
+        }
+        Ok(names)
+    }
+
+    fn entry(&self, path: &str) -> Result<TreeEntry> {
+        let path = self.path(path);
+        let meta = std::fs::symlink_metadata(&path)?;
+        let ty = host_type(meta.file_type());
+        let mut xattrs = BTreeMap::new();
+        for name in xattr::list(&path)
+            .map(|n| n.collect::<Vec<_>>())
+            .unwrap_or_default()
+        {
+            let value = xattr::get(&path, &name)?.unwrap_or_default();
+            xattrs.insert(name.to_string_lossy().to_string(), value);
+        }
+        Ok(TreeEntry {
+            ty,
+            mode: meta.mode() & 0o7777,
+            owner: (meta.uid(), meta.gid()),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            link: match ty.is_symlink() {
+                true => Some(std::fs::read_link(&path)?.to_string_lossy().to_string()),
+                false => None,
+            },
+            rdev: host_dev(&meta, ty),
+            xattrs,
+        })
+    }
+
+    fn open(&self, path: &str) -> Result<Reader<'_>> {
+        let mut file = std::fs::File::open(self.path(path))?;
+        Ok(Box::new(move |buf: &mut [u8]| Ok(file.read(buf)?)))
+    }
+}
@@ -0,0 +1,1878 @@
+//! Direct access to the on-disk structures of an unmounted ext2/3/4 file system.
+//!
+//! lwext4 only exposes a mounted file system, so offline tools (checking, resizing,
+//! imaging, ...) go through the [BlockDeviceInterface] themselves and decode the
+//! little-endian structures below.
+use crate::block::{BlockDevice, BlockDeviceConfig, BlockDeviceInterface};
+use crate::crc::{crc16, crc32c};
+use crate::error::{Error, Result};
+use crate::types::FileType;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub(crate) const SUPERBLOCK_OFFSET: u64 = 1024;
+pub(crate) const SUPERBLOCK_SIZE: usize = 1024;
+pub(crate) const EXT4_MAGIC: u16 = 0xef53;
+
+pub(crate) const BAD_INO: u32 = 1;
+pub(crate) const ROOT_INO: u32 = 2;
+pub(crate) const RESIZE_INO: u32 = 7;
+pub(crate) const JOURNAL_INO: u32 = 8;
+pub(crate) const GOOD_OLD_INODE_SIZE: usize = 128;
+pub(crate) const GOOD_OLD_FIRST_INO: u32 = 11;
+
+pub(crate) const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+pub(crate) const COMPAT_RESIZE_INODE: u32 = 0x0010;
+pub(crate) const COMPAT_SPARSE_SUPER2: u32 = 0x0200;
+
+pub(crate) const INCOMPAT_FILETYPE: u32 = 0x0002;
+pub(crate) const INCOMPAT_RECOVER: u32 = 0x0004;
+pub(crate) const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+pub(crate) const INCOMPAT_META_BG: u32 = 0x0010;
+pub(crate) const INCOMPAT_EXTENTS: u32 = 0x0040;
+pub(crate) const INCOMPAT_64BIT: u32 = 0x0080;
+pub(crate) const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+
+pub(crate) const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub(crate) const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+pub(crate) const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+pub(crate) const RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+pub(crate) const RO_COMPAT_DIR_NLINK: u32 = 0x0020;
+pub(crate) const RO_COMPAT_BIGALLOC: u32 = 0x0200;
+pub(crate) const RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+
+pub(crate) const BG_INODE_UNINIT: u16 = 0x0001;
+pub(crate) const BG_BLOCK_UNINIT: u16 = 0x0002;
+pub(crate) const BG_INODE_ZEROED: u16 = 0x0004;
+
+pub(crate) const INODE_FLAG_ENCRYPT: u32 = 0x0000_0800;
+pub(crate) const INODE_FLAG_INDEX: u32 = 0x0000_1000;
+pub(crate) const INODE_FLAG_HUGE_FILE: u32 = 0x0004_0000;
+pub(crate) const INODE_FLAG_EXTENTS: u32 = 0x0008_0000;
+pub(crate) const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+pub(crate) const INODE_FLAG_CASEFOLD: u32 = 0x4000_0000;
+
+/// `s_flags`: htree hashes treat names as unsigned chars.
+const FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXTENT_MAX_DEPTH: u16 = 5;
+pub(crate) const XATTR_MAGIC: u32 = 0xea02_0000;
+const DIR_TAIL_FILE_TYPE: u8 = 0xde;
+const DIR_TAIL_SIZE: usize = 12;
+const N_DIRECT_BLOCKS: usize = 12;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+/// `s_jnl_backup_type` when `s_jnl_blocks` holds a copy of the journal's `i_block`.
+const JNL_BACKUP_BLOCKS: u8 = 1;
+
+pub(crate) fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+pub(crate) fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+pub(crate) fn le64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+pub(crate) fn put16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// The primary superblock, kept as raw bytes so that unknown fields survive a rewrite.
+#[derive(Clone)]
+pub(crate) struct Superblock {
+    pub(crate) raw: [u8; SUPERBLOCK_SIZE],
+}
+
+impl Superblock {
+    pub(crate) fn inodes_count(&self) -> u32 {
+        le32(&self.raw, 0x00)
+    }
+    pub(crate) fn set_inodes_count(&mut self, v: u32) {
+        put32(&mut self.raw, 0x00, v)
+    }
+    pub(crate) fn blocks_count(&self) -> u64 {
+        self.lo_hi(0x04, 0x150)
+    }
+    pub(crate) fn set_blocks_count(&mut self, v: u64) {
+        self.set_lo_hi(0x04, 0x150, v)
+    }
+    pub(crate) fn r_blocks_count(&self) -> u64 {
+        self.lo_hi(0x08, 0x154)
+    }
+    pub(crate) fn set_r_blocks_count(&mut self, v: u64) {
+        self.set_lo_hi(0x08, 0x154, v)
+    }
+    pub(crate) fn free_blocks_count(&self) -> u64 {
+        self.lo_hi(0x0c, 0x158)
+    }
+    pub(crate) fn set_free_blocks_count(&mut self, v: u64) {
+        self.set_lo_hi(0x0c, 0x158, v)
+    }
+    pub(crate) fn free_inodes_count(&self) -> u32 {
+        le32(&self.raw, 0x10)
+    }
+    pub(crate) fn set_free_inodes_count(&mut self, v: u32) {
+        put32(&mut self.raw, 0x10, v)
+    }
+    pub(crate) fn first_data_block(&self) -> u32 {
+        le32(&self.raw, 0x14)
+    }
+    pub(crate) fn log_block_size(&self) -> u32 {
+        le32(&self.raw, 0x18)
+    }
+    pub(crate) fn block_size(&self) -> u32 {
+        1024 << self.log_block_size()
+    }
+    pub(crate) fn blocks_per_group(&self) -> u32 {
+        le32(&self.raw, 0x20)
+    }
+    pub(crate) fn inodes_per_group(&self) -> u32 {
+        le32(&self.raw, 0x28)
+    }
+    pub(crate) fn write_time(&self) -> u32 {
+        le32(&self.raw, 0x30)
+    }
+    pub(crate) fn magic(&self) -> u16 {
+        le16(&self.raw, 0x38)
+    }
+    pub(crate) fn rev_level(&self) -> u32 {
+        le32(&self.raw, 0x4c)
+    }
+    pub(crate) fn first_ino(&self) -> u32 {
+        match self.rev_level() {
+            0 => GOOD_OLD_FIRST_INO,
+            _ => le32(&self.raw, 0x54),
+        }
+    }
+    pub(crate) fn inode_size(&self) -> usize {
+        match self.rev_level() {
+            0 => GOOD_OLD_INODE_SIZE,
+            _ => le16(&self.raw, 0x58) as usize,
+        }
+    }
+    pub(crate) fn feature_compat(&self) -> u32 {
+        le32(&self.raw, 0x5c)
+    }
+    pub(crate) fn feature_incompat(&self) -> u32 {
+        le32(&self.raw, 0x60)
+    }
+    pub(crate) fn feature_ro_compat(&self) -> u32 {
+        le32(&self.raw, 0x64)
+    }
+    pub(crate) fn set_feature_compat(&mut self, v: u32) {
+        put32(&mut self.raw, 0x5c, v)
+    }
+    pub(crate) fn has_compat(&self, f: u32) -> bool {
+        self.feature_compat() & f != 0
+    }
+    pub(crate) fn has_incompat(&self, f: u32) -> bool {
+        self.feature_incompat() & f != 0
+    }
+    pub(crate) fn has_ro_compat(&self, f: u32) -> bool {
+        self.feature_ro_compat() & f != 0
+    }
+    pub(crate) fn uuid(&self) -> [u8; 16] {
+        self.raw[0x68..0x78].try_into().unwrap()
+    }
+    /// Volume name, up to the first NUL.
+    pub(crate) fn label(&self) -> &[u8] {
+        let name = &self.raw[0x78..0x88];
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        &name[..len]
+    }
+    pub(crate) fn reserved_gdt_blocks(&self) -> u32 {
+        le16(&self.raw, 0xce) as u32
+    }
+    pub(crate) fn set_reserved_gdt_blocks(&mut self, v: u32) {
+        put16(&mut self.raw, 0xce, v as u16)
+    }
+    pub(crate) fn journal_inum(&self) -> u32 {
+        le32(&self.raw, 0xe0)
+    }
+    pub(crate) fn hash_seed(&self) -> [u32; 4] {
+        core::array::from_fn(|i| le32(&self.raw, 0xec + i * 4))
+    }
+    pub(crate) fn unsigned_hash(&self) -> bool {
+        le32(&self.raw, 0x160) & FLAGS_UNSIGNED_HASH != 0
+    }
+    pub(crate) fn last_orphan(&self) -> u32 {
+        le32(&self.raw, 0xe8)
+    }
+    pub(crate) fn set_last_orphan(&mut self, v: u32) {
+        put32(&mut self.raw, 0xe8, v)
+    }
+    pub(crate) fn desc_size(&self) -> usize {
+        let size = le16(&self.raw, 0xfe) as usize;
+        if self.has_incompat(INCOMPAT_64BIT) && size >= 64 {
+            size
+        } else {
+            32
+        }
+    }
+    pub(crate) fn first_meta_bg(&self) -> u32 {
+        le32(&self.raw, 0x104)
+    }
+    pub(crate) fn backup_bgs(&self) -> [u32; 2] {
+        [le32(&self.raw, 0x24c), le32(&self.raw, 0x250)]
+    }
+    pub(crate) fn set_backup_bgs(&mut self, v: [u32; 2]) {
+        put32(&mut self.raw, 0x24c, v[0]);
+        put32(&mut self.raw, 0x250, v[1]);
+    }
+    /// Refresh the copy of the journal inode's `i_block` kept in `s_jnl_blocks`.
+    pub(crate) fn set_journal_backup(&mut self, block_area: &[u8]) {
+        if self.raw[0xfd] == JNL_BACKUP_BLOCKS {
+            self.raw[0x10c..0x10c + block_area.len()].copy_from_slice(block_area);
+        }
+    }
+    /// Make `ino` the journal, keeping a copy of its mapping in `s_jnl_blocks`.
+    pub(crate) fn set_journal_inode(&mut self, ino: u32, inode: &Inode) {
+        self.raw[0xd0..0xe8].fill(0);
+        put32(&mut self.raw, 0xe0, ino);
+        self.raw[0xfd] = JNL_BACKUP_BLOCKS;
+        self.raw[0x10c..0x148].copy_from_slice(inode.block_area());
+        put32(&mut self.raw, 0x148, (inode.size() >> 32) as u32);
+        put32(&mut self.raw, 0x14c, inode.size() as u32);
+        self.set_feature_compat(self.feature_compat() | COMPAT_HAS_JOURNAL);
+    }
+    /// Clear `has_journal` and every field describing the journal.
+    pub(crate) fn clear_journal(&mut self) {
+        self.raw[0xd0..0xe8].fill(0);
+        self.raw[0xfd] = 0;
+        self.raw[0x10c..0x150].fill(0);
+        self.set_feature_compat(self.feature_compat() & !COMPAT_HAS_JOURNAL);
+    }
+    /// Renumber the inodes referenced by the superblock (quota files, `lost+found`).
+    pub(crate) fn remap_inodes(&mut self, map: &dyn Fn(u32) -> u32) {
+        for offset in [0x240, 0x244, 0x268, 0x26c] {
+            let ino = le32(&self.raw, offset);
+            if ino != 0 {
+                put32(&mut self.raw, offset, map(ino));
+            }
+        }
+    }
+    pub(crate) fn checksum(&self) -> u32 {
+        le32(&self.raw, 0x3fc)
+    }
+
+    /// Seed of all metadata checksums except the superblock's own.
+    pub(crate) fn csum_seed(&self) -> u32 {
+        if self.has_incompat(INCOMPAT_CSUM_SEED) {
+            le32(&self.raw, 0x270)
+        } else {
+            crc32c(!0, &self.uuid())
+        }
+    }
+    pub(crate) fn metadata_csum(&self) -> bool {
+        self.has_ro_compat(RO_COMPAT_METADATA_CSUM)
+    }
+    /// Whether group descriptors carry a checksum and support the `*_UNINIT` flags.
+    pub(crate) fn group_csum(&self) -> bool {
+        self.metadata_csum() || self.has_ro_compat(RO_COMPAT_GDT_CSUM)
+    }
+    pub(crate) fn compute_checksum(&self) -> u32 {
+        crc32c(!0, &self.raw[..0x3fc])
+    }
+    pub(crate) fn update_checksum(&mut self) {
+        if self.metadata_csum() {
+            let csum = self.compute_checksum();
+            put32(&mut self.raw, 0x3fc, csum);
+        }
+    }
+
+    pub(crate) fn group_count(&self) -> u32 {
+        let data_blocks = self.blocks_count() - self.first_data_block() as u64;
+        data_blocks.div_ceil(self.blocks_per_group() as u64) as u32
+    }
+    pub(crate) fn descs_per_block(&self) -> u32 {
+        self.block_size() / self.desc_size() as u32
+    }
+    /// Number of blocks holding the group descriptor table (without reserved blocks).
+    pub(crate) fn desc_blocks(&self) -> u32 {
+        self.group_count().div_ceil(self.descs_per_block())
+    }
+    pub(crate) fn inode_table_blocks(&self) -> u32 {
+        (self.inodes_per_group() * self.inode_size() as u32).div_ceil(self.block_size())
+    }
+    pub(crate) fn group_first_block(&self, group: u32) -> u64 {
+        self.first_data_block() as u64 + group as u64 * self.blocks_per_group() as u64
+    }
+    /// Number of blocks of `group`, the last group may be shorter.
+    pub(crate) fn group_blocks(&self, group: u32) -> u32 {
+        let first = self.group_first_block(group);
+        (self.blocks_count() - first).min(self.blocks_per_group() as u64) as u32
+    }
+
+    /// Whether `group` holds a backup of the superblock and the descriptor table.
+    pub(crate) fn group_has_super(&self, group: u32) -> bool {
+        if group == 0 {
+            return true;
+        }
+        if self.has_compat(COMPAT_SPARSE_SUPER2) {
+            return self.backup_bgs().contains(&group);
+        }
+        if group <= 1 || !self.has_ro_compat(RO_COMPAT_SPARSE_SUPER) {
+            return true;
+        }
+        if group & 1 == 0 {
+            return false;
+        }
+        [3u32, 5, 7].iter().any(|&base| {
+            let mut n = base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        })
+    }
+
+    /// Blocks of `group` taken by the superblock and descriptor table copies,
+    /// c.f. `ext2fs_super_and_bgd_loc2`.
+    pub(crate) fn group_super_blocks(&self, group: u32) -> Vec<u64> {
+        let mut start = self.group_first_block(group);
+        if start == 0 && self.block_size() == 1024 {
+            start = 1;
+        }
+        let meta_bg = self.has_incompat(INCOMPAT_META_BG);
+        let old_desc_blocks = if meta_bg {
+            self.first_meta_bg()
+        } else {
+            self.desc_blocks() + self.reserved_gdt_blocks()
+        };
+        let has_super = self.group_has_super(group);
+        let per_meta = self.descs_per_block();
+        let mut blocks = Vec::new();
+        if has_super {
+            blocks.push(start);
+        }
+        if !meta_bg || group / per_meta < self.first_meta_bg() {
+            if has_super {
+                blocks.extend((0..old_desc_blocks as u64).map(|i| start + 1 + i));
+            }
+        } else {
+            let index = group % per_meta;
+            if index == 0 || index == 1 || index == per_meta - 1 {
+                blocks.push(start + has_super as u64);
+            }
+        }
+        blocks
+    }
+
+    /// Block holding the primary copy of the descriptor of `group`.
+    pub(crate) fn group_desc_block(&self, group: u32) -> u64 {
+        let per_block = self.descs_per_block();
+        let index = group / per_block;
+        if !self.has_incompat(INCOMPAT_META_BG) || index < self.first_meta_bg() {
+            return self.first_data_block() as u64 + 1 + index as u64;
+        }
+        let first = index * per_block;
+        self.group_first_block(first) + self.group_has_super(first) as u64
+    }
+
+    fn lo_hi(&self, lo: usize, hi: usize) -> u64 {
+        let mut v = le32(&self.raw, lo) as u64;
+        if self.has_incompat(INCOMPAT_64BIT) {
+            v |= (le32(&self.raw, hi) as u64) << 32;
+        }
+        v
+    }
+    fn set_lo_hi(&mut self, lo: usize, hi: usize, v: u64) {
+        put32(&mut self.raw, lo, v as u32);
+        if self.has_incompat(INCOMPAT_64BIT) {
+            put32(&mut self.raw, hi, (v >> 32) as u32);
+        }
+    }
+}
+
+/// A block group descriptor of 32 or 64 bytes.
+#[derive(Clone)]
+pub(crate) struct GroupDesc {
+    pub(crate) raw: [u8; 64],
+    size: usize,
+}
+
+impl GroupDesc {
+    pub(crate) fn new(size: usize) -> Self {
+        Self { raw: [0; 64], size }
+    }
+    fn lo_hi32(&self, lo: usize, hi: usize) -> u64 {
+        let mut v = le32(&self.raw, lo) as u64;
+        if self.size >= 64 {
+            v |= (le32(&self.raw, hi) as u64) << 32;
+        }
+        v
+    }
+    fn lo_hi16(&self, lo: usize, hi: usize) -> u32 {
+        let mut v = le16(&self.raw, lo) as u32;
+        if self.size >= 64 {
+            v |= (le16(&self.raw, hi) as u32) << 16;
+        }
+        v
+    }
+    fn set_lo_hi32(&mut self, lo: usize, hi: usize, v: u64) {
+        put32(&mut self.raw, lo, v as u32);
+        if self.size >= 64 {
+            put32(&mut self.raw, hi, (v >> 32) as u32);
+        }
+    }
+    fn set_lo_hi16(&mut self, lo: usize, hi: usize, v: u32) {
+        put16(&mut self.raw, lo, v as u16);
+        if self.size >= 64 {
+            put16(&mut self.raw, hi, (v >> 16) as u16);
+        }
+    }
+
+    pub(crate) fn block_bitmap(&self) -> u64 {
+        self.lo_hi32(0x00, 0x20)
+    }
+    pub(crate) fn set_block_bitmap(&mut self, v: u64) {
+        self.set_lo_hi32(0x00, 0x20, v)
+    }
+    pub(crate) fn inode_bitmap(&self) -> u64 {
+        self.lo_hi32(0x04, 0x24)
+    }
+    pub(crate) fn set_inode_bitmap(&mut self, v: u64) {
+        self.set_lo_hi32(0x04, 0x24, v)
+    }
+    pub(crate) fn inode_table(&self) -> u64 {
+        self.lo_hi32(0x08, 0x28)
+    }
+    pub(crate) fn set_inode_table(&mut self, v: u64) {
+        self.set_lo_hi32(0x08, 0x28, v)
+    }
+    pub(crate) fn free_blocks(&self) -> u32 {
+        self.lo_hi16(0x0c, 0x2c)
+    }
+    pub(crate) fn set_free_blocks(&mut self, v: u32) {
+        self.set_lo_hi16(0x0c, 0x2c, v)
+    }
+    pub(crate) fn free_inodes(&self) -> u32 {
+        self.lo_hi16(0x0e, 0x2e)
+    }
+    pub(crate) fn set_free_inodes(&mut self, v: u32) {
+        self.set_lo_hi16(0x0e, 0x2e, v)
+    }
+    pub(crate) fn used_dirs(&self) -> u32 {
+        self.lo_hi16(0x10, 0x30)
+    }
+    pub(crate) fn set_used_dirs(&mut self, v: u32) {
+        self.set_lo_hi16(0x10, 0x30, v)
+    }
+    pub(crate) fn flags(&self) -> u16 {
+        le16(&self.raw, 0x12)
+    }
+    pub(crate) fn set_flags(&mut self, v: u16) {
+        put16(&mut self.raw, 0x12, v)
+    }
+    pub(crate) fn itable_unused(&self) -> u32 {
+        self.lo_hi16(0x1c, 0x32)
+    }
+    pub(crate) fn set_itable_unused(&mut self, v: u32) {
+        self.set_lo_hi16(0x1c, 0x32, v)
+    }
+    pub(crate) fn checksum(&self) -> u16 {
+        le16(&self.raw, 0x1e)
+    }
+    pub(crate) fn set_checksum(&mut self, v: u16) {
+        put16(&mut self.raw, 0x1e, v)
+    }
+    /// Stored checksum of the block bitmap and whether it has all 32 bits.
+    pub(crate) fn block_bitmap_csum(&self) -> (u32, bool) {
+        (self.lo_hi16(0x18, 0x38), self.size >= 64)
+    }
+    pub(crate) fn set_block_bitmap_csum(&mut self, v: u32) {
+        self.set_lo_hi16(0x18, 0x38, v)
+    }
+    pub(crate) fn inode_bitmap_csum(&self) -> (u32, bool) {
+        (self.lo_hi16(0x1a, 0x3a), self.size >= 64)
+    }
+    pub(crate) fn set_inode_bitmap_csum(&mut self, v: u32) {
+        self.set_lo_hi16(0x1a, 0x3a, v)
+    }
+}
+
+/// A raw inode of `s_inode_size` bytes.
+#[derive(Clone)]
+pub(crate) struct Inode {
+    pub(crate) raw: Vec<u8>,
+}
+
+impl Inode {
+    /// A regular file with a single link and all timestamps at `time`.
+    pub(crate) fn new_file(inode_size: usize, perm: u16, time: u32) -> Self {
+        let mut raw = vec![0u8; inode_size];
+        put16(&mut raw, 0x00, (S_IFREG | perm as u32) as u16);
+        for offset in [0x08, 0x0c, 0x10] {
+            put32(&mut raw, offset, time);
+        }
+        put16(&mut raw, 0x1a, 1);
+        if inode_size > GOOD_OLD_INODE_SIZE {
+            let extra = (inode_size - GOOD_OLD_INODE_SIZE).min(32);
+            put16(&mut raw, 0x80, extra as u16);
+            if extra >= 0x94 - GOOD_OLD_INODE_SIZE {
+                put32(&mut raw, 0x90, time);
+            }
+        }
+        Self { raw }
+    }
+    /// A directory with the links of its `.` and its entry, without blocks yet.
+    pub(crate) fn new_dir(inode_size: usize, perm: u16, time: u32) -> Self {
+        let mut inode = Self::new_file(inode_size, perm, time);
+        put16(&mut inode.raw, 0x00, (S_IFDIR | perm as u32) as u16);
+        inode.set_links_count(2);
+        inode
+    }
+    pub(crate) fn mode(&self) -> u16 {
+        le16(&self.raw, 0x00)
+    }
+    pub(crate) fn file_type(&self) -> FileType {
+        FileType {
+            mode: self.mode() as u32 & S_IFMT,
+        }
+    }
+    pub(crate) fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+    pub(crate) fn size(&self) -> u64 {
+        le32(&self.raw, 0x04) as u64 | (le32(&self.raw, 0x6c) as u64) << 32
+    }
+    pub(crate) fn set_size(&mut self, v: u64) {
+        put32(&mut self.raw, 0x04, v as u32);
+        put32(&mut self.raw, 0x6c, (v >> 32) as u32);
+    }
+    pub(crate) fn dtime(&self) -> u32 {
+        le32(&self.raw, 0x14)
+    }
+    pub(crate) fn set_dtime(&mut self, v: u32) {
+        put32(&mut self.raw, 0x14, v)
+    }
+    pub(crate) fn links_count(&self) -> u16 {
+        le16(&self.raw, 0x1a)
+    }
+    pub(crate) fn set_links_count(&mut self, v: u16) {
+        put16(&mut self.raw, 0x1a, v)
+    }
+    /// Number of 512 byte sectors (or file system blocks for huge files).
+    pub(crate) fn blocks(&self) -> u64 {
+        le32(&self.raw, 0x1c) as u64 | (le16(&self.raw, 0x74) as u64) << 32
+    }
+    /// Set the number of blocks owned by the inode (data, tree and xattr blocks).
+    pub(crate) fn set_block_count(&mut self, blocks: u64, block_size: u32) {
+        let v = match self.flags() & INODE_FLAG_HUGE_FILE {
+            0 => blocks * (block_size as u64 / 512),
+            _ => blocks,
+        };
+        put32(&mut self.raw, 0x1c, v as u32);
+        put16(&mut self.raw, 0x74, (v >> 32) as u16);
+    }
+    pub(crate) fn flags(&self) -> u32 {
+        le32(&self.raw, 0x20)
+    }
+    pub(crate) fn set_flags(&mut self, v: u32) {
+        put32(&mut self.raw, 0x20, v)
+    }
+    pub(crate) fn block_area(&self) -> &[u8] {
+        &self.raw[0x28..0x64]
+    }
+    pub(crate) fn block_area_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[0x28..0x64]
+    }
+    pub(crate) fn generation(&self) -> u32 {
+        le32(&self.raw, 0x64)
+    }
+    pub(crate) fn file_acl(&self) -> u64 {
+        le32(&self.raw, 0x68) as u64 | (le16(&self.raw, 0x76) as u64) << 32
+    }
+    pub(crate) fn set_file_acl(&mut self, v: u64) {
+        put32(&mut self.raw, 0x68, v as u32);
+        put16(&mut self.raw, 0x76, (v >> 32) as u16);
+    }
+    pub(crate) fn extra_isize(&self) -> usize {
+        if self.raw.len() > GOOD_OLD_INODE_SIZE {
+            le16(&self.raw, 0x80) as usize
+        } else {
+            0
+        }
+    }
+    /// Whether the upper half of the checksum fits in the extra inode space.
+    fn has_checksum_hi(&self) -> bool {
+        GOOD_OLD_INODE_SIZE + self.extra_isize() >= 0x84 && self.raw.len() >= 0x84
+    }
+    pub(crate) fn checksum(&self) -> u32 {
+        let mut v = le16(&self.raw, 0x7c) as u32;
+        if self.has_checksum_hi() {
+            v |= (le16(&self.raw, 0x82) as u32) << 16;
+        }
+        v
+    }
+    /// Map `physical` as logical block `logical` (the block after the current end of
+    /// the file), if the mapping fits into the inode itself.
+    pub(crate) fn append_block(&mut self, logical: u64, physical: u64) -> bool {
+        let extents = self.flags() & INODE_FLAG_EXTENTS != 0;
+        let area = self.block_area_mut();
+        if !extents {
+            if logical >= N_DIRECT_BLOCKS as u64 || physical > u32::MAX as u64 {
+                return false;
+            }
+            put32(area, logical as usize * 4, physical as u32);
+            return true;
+        }
+        let entries = le16(area, 2) as usize;
+        if le16(area, 0) != EXTENT_MAGIC || le16(area, 6) != 0 || entries > 4 {
+            return false;
+        }
+        if entries > 0 {
+            let last = 12 + 12 * (entries - 1);
+            let len = le16(area, last + 4) as u64;
+            let start = (le16(area, last + 6) as u64) << 32 | le32(area, last + 8) as u64;
+            if len < 32768 && le32(area, last) as u64 + len == logical && start + len == physical {
+                put16(area, last + 4, len as u16 + 1);
+                return true;
+            }
+        }
+        if entries >= le16(area, 4) as usize {
+            return false;
+        }
+        let off = 12 + 12 * entries;
+        put32(area, off, logical as u32);
+        put16(area, off + 4, 1);
+        put16(area, off + 6, (physical >> 32) as u16);
+        put32(area, off + 8, physical as u32);
+        put16(area, 2, entries as u16 + 1);
+        true
+    }
+    pub(crate) fn is_unused_slot(&self) -> bool {
+        self.raw.iter().all(|&b| b == 0)
+    }
+    /// Whether the inode owns data blocks (as opposed to inline data, device numbers
+    /// or a fast symlink target stored in `i_block`).
+    pub(crate) fn has_data_blocks(&self, block_size: u32) -> bool {
+        if self.flags() & INODE_FLAG_INLINE_DATA != 0 {
+            return false;
+        }
+        let ty = self.file_type();
+        if ty.is_symlink() {
+            let ea_sectors = match self.file_acl() {
+                0 => 0,
+                _ => block_size as u64 / 512,
+            };
+            self.flags() & INODE_FLAG_EXTENTS != 0 || self.blocks() > ea_sectors
+        } else {
+            ty.is_dir() || ty.is_file()
+        }
+    }
+}
+
+/// A run of contiguous blocks of an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Extent {
+    pub(crate) logical: u64,
+    pub(crate) physical: u64,
+    pub(crate) len: u64,
+    pub(crate) uninit: bool,
+}
+
+/// Blocks referenced by an inode.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct BlockMap {
+    pub(crate) extents: Vec<Extent>,
+    /// Extent tree nodes and indirect blocks.
+    pub(crate) meta: Vec<u64>,
+    /// Pointers to blocks outside of the file system, which were not followed.
+    pub(crate) out_of_range: Vec<u64>,
+    /// Extent tree nodes whose checksum does not match.
+    pub(crate) bad_csum: Vec<u64>,
+    /// The extent tree (or a node of it) has no valid header.
+    pub(crate) corrupt: bool,
+}
+
+impl BlockMap {
+    fn push(&mut self, logical: u64, physical: u64, len: u64, uninit: bool) {
+        if let Some(last) = self.extents.last_mut() {
+            if last.logical + last.len == logical
+                && last.physical + last.len == physical
+                && last.uninit == uninit
+            {
+                last.len += len;
+                return;
+            }
+        }
+        self.extents.push(Extent {
+            logical,
+            physical,
+            len,
+            uninit,
+        });
+    }
+}
+
+/// Append `e` to `extents`, merging it with the last one and splitting it at the
+/// longest length an extent can describe.
+pub(crate) fn push_extent(extents: &mut Vec<Extent>, mut e: Extent) {
+    let max = if e.uninit { 32767 } else { 32768 };
+    if let Some(last) = extents.last_mut() {
+        if last.logical + last.len == e.logical
+            && last.physical + last.len == e.physical
+            && last.uninit == e.uninit
+        {
+            let n = (max - last.len).min(e.len);
+            last.len += n;
+            e.logical += n;
+            e.physical += n;
+            e.len -= n;
+        }
+    }
+    while e.len > 0 {
+        let n = e.len.min(max);
+        extents.push(Extent { len: n, ..e });
+        e.logical += n;
+        e.physical += n;
+        e.len -= n;
+    }
+}
+
+/// A directory entry as found on disk.
+#[derive(Debug, Clone)]
+pub(crate) struct RawDirEntry {
+    pub(crate) inode: u32,
+    pub(crate) file_type: u8,
+    pub(crate) name: Vec<u8>,
+    /// Offset of the entry within its block.
+    pub(crate) offset: usize,
+}
+
+/// Parse the entries of a linear directory block (or of the inline area). The flag
+/// tells whether the record lengths lined up; if not, the entries before the first
+/// broken record are returned.
+pub(crate) fn parse_dir_block(block: &[u8], filetype: bool) -> (Vec<RawDirEntry>, bool) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= block.len() {
+        let inode = le32(block, offset);
+        let rec_len = le16(block, offset + 4) as usize;
+        let (name_len, file_type) = if filetype {
+            (block[offset + 6] as usize, block[offset + 7])
+        } else {
+            (le16(block, offset + 6) as usize, 0)
+        };
+        if rec_len < 8 || !rec_len.is_multiple_of(4) || offset + rec_len > block.len() {
+            return (entries, false);
+        }
+        if inode != 0 {
+            if 8 + name_len > rec_len {
+                return (entries, false);
+            }
+            entries.push(RawDirEntry {
+                inode,
+                file_type,
+                name: block[offset + 8..offset + 8 + name_len].to_vec(),
+                offset,
+            });
+        }
+        offset += rec_len;
+    }
+    let complete = offset == block.len();
+    (entries, complete)
+}
+
+fn write_extent_header(node: &mut [u8], entries: usize, max: usize, depth: u16) {
+    put16(node, 0, EXTENT_MAGIC);
+    put16(node, 2, entries as u16);
+    put16(node, 4, max as u16);
+    put16(node, 6, depth);
+}
+
+/// Whether the record at `offset` is the checksum tail of a directory leaf block.
+fn is_dir_tail(block: &[u8], offset: usize) -> bool {
+    offset + DIR_TAIL_SIZE == block.len()
+        && le32(block, offset) == 0
+        && le16(block, offset + 4) as usize == DIR_TAIL_SIZE
+        && block[offset + 6] == 0
+        && block[offset + 7] == DIR_TAIL_FILE_TYPE
+}
+
+/// Add an entry to a directory block by splitting the slack of an existing record.
+/// Returns false if no record has enough room.
+pub(crate) fn insert_dir_entry(
+    block: &mut [u8],
+    ino: u32,
+    name: &[u8],
+    file_type: u8,
+    filetype: bool,
+) -> bool {
+    let needed = (8 + name.len()).next_multiple_of(4);
+    let mut offset = 0;
+    while offset + 8 <= block.len() && !is_dir_tail(block, offset) {
+        let rec_len = le16(block, offset + 4) as usize;
+        if rec_len < 8 || offset + rec_len > block.len() {
+            return false;
+        }
+        let used = match le32(block, offset) {
+            0 => 0,
+            _ if filetype => (8 + block[offset + 6] as usize).next_multiple_of(4),
+            _ => (8 + le16(block, offset + 6) as usize).next_multiple_of(4),
+        };
+        if rec_len >= used + needed {
+            if used != 0 {
+                put16(block, offset + 4, used as u16);
+            }
+            let new = offset + used;
+            put32(block, new, ino);
+            put16(block, new + 4, (rec_len - used) as u16);
+            if filetype {
+                block[new + 6] = name.len() as u8;
+                block[new + 7] = file_type;
+            } else {
+                put16(block, new + 6, name.len() as u16);
+            }
+            block[new + 8..new + 8 + name.len()].copy_from_slice(name);
+            return true;
+        }
+        offset += rec_len;
+    }
+    false
+}
+
+/// Offset of the count and limit of an htree root or interior node, c.f.
+/// `ext4_dx_csum_verify`. Only meaningful for blocks without a leaf checksum tail.
+fn dx_count_offset(block: &[u8]) -> Option<usize> {
+    let bs = block.len();
+    if le32(block, 0) == 0 && le16(block, 4) as usize == bs {
+        return Some(8);
+    }
+    let root = le16(block, 4) == 12
+        && block[6] == 1
+        && block[8] == b'.'
+        && le16(block, 16) as usize == bs - 12
+        && block[18] == 2
+        && &block[20..22] == b"..";
+    root.then_some(32)
+}
+
+/// Location of the tail and computed checksum of an htree root or interior node.
+fn dx_checksum(seed: u32, block: &[u8]) -> Option<(usize, u32)> {
+    let offset = dx_count_offset(block)?;
+    let limit = le16(block, offset) as usize;
+    let count = le16(block, offset + 2) as usize;
+    let tail = offset + limit * 8;
+    if count > limit || tail + 8 > block.len() {
+        return None;
+    }
+    let mut crc = crc32c(seed, &block[..offset + count * 8]);
+    crc = crc32c(crc, &block[tail..tail + 4]);
+    crc = crc32c(crc, &[0; 4]);
+    Some((tail, crc))
+}
+
+/// A directory block without entries, ending in a checksum tail if `csum` is set.
+pub(crate) fn empty_dir_block(block_size: usize, csum: bool) -> Vec<u8> {
+    let mut block = vec![0u8; block_size];
+    let end = match csum {
+        true => block_size - DIR_TAIL_SIZE,
+        false => block_size,
+    };
+    put16(&mut block, 4, end as u16);
+    if csum {
+        put16(&mut block, end + 4, DIR_TAIL_SIZE as u16);
+        block[end + 7] = DIR_TAIL_FILE_TYPE;
+    }
+    block
+}
+
+/// A set of bits backed by 64 bit words.
+#[derive(Debug, Clone)]
+pub(crate) struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub(crate) fn new(len: u64) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64) as usize],
+        }
+    }
+    pub(crate) fn get(&self, i: u64) -> bool {
+        self.words[(i / 64) as usize] & (1 << (i % 64)) != 0
+    }
+    /// Number of bits set.
+    pub(crate) fn count(&self) -> u64 {
+        self.words.iter().map(|w| w.count_ones() as u64).sum()
+    }
+    /// Set bit `i`, returning whether it was already set.
+    pub(crate) fn set(&mut self, i: u64) -> bool {
+        let word = &mut self.words[(i / 64) as usize];
+        let old = *word & (1 << (i % 64)) != 0;
+        *word |= 1 << (i % 64);
+        old
+    }
+}
+
+/// Hands out the blocks not marked in a [BitSet], in order from a starting block to
+/// the end and then from the first data block.
+pub(crate) struct BlockAllocator {
+    taken: BitSet,
+    first: u64,
+    limit: u64,
+    next: u64,
+    wrapped: bool,
+}
+
+impl BlockAllocator {
+    /// Allocate from blocks `first..limit` not in `taken`, starting at `start`.
+    pub(crate) fn new(taken: BitSet, first: u64, limit: u64, start: u64) -> Self {
+        Self {
+            taken,
+            first,
+            limit,
+            next: start.max(first),
+            wrapped: false,
+        }
+    }
+
+    /// Between one and `max` contiguous blocks.
+    pub(crate) fn run(&mut self, max: u64) -> Result<(u64, u64)> {
+        loop {
+            while self.next < self.limit && self.taken.get(self.next) {
+                self.next += 1;
+            }
+            if self.next < self.limit {
+                break;
+            }
+            if self.wrapped {
+                return Err(Error::NoSpace);
+            }
+            self.wrapped = true;
+            self.next = self.first;
+        }
+        let start = self.next;
+        while self.next < self.limit && self.next - start < max && !self.taken.set(self.next) {
+            self.next += 1;
+        }
+        Ok((start, self.next - start))
+    }
+
+    pub(crate) fn block(&mut self) -> Result<u64> {
+        Ok(self.run(1)?.0)
+    }
+}
+
+/// Bit `i` of an on-disk bitmap.
+pub(crate) fn bitmap_get(bitmap: &[u8], i: usize) -> bool {
+    bitmap[i / 8] & (1 << (i % 8)) != 0
+}
+
+pub(crate) fn bitmap_set(bitmap: &mut [u8], i: usize, value: bool) {
+    if value {
+        bitmap[i / 8] |= 1 << (i % 8);
+    } else {
+        bitmap[i / 8] &= !(1 << (i % 8));
+    }
+}
+
+/// Read `buf` at byte `offset` of the partition described by `config`, whatever the
+/// block size of the device.
+pub(crate) fn read_at<T: BlockDeviceInterface>(
+    dev: &mut T,
+    config: &BlockDeviceConfig,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    if offset + buf.len() as u64 > config.part_size {
+        return Err(Error::Io);
+    }
+    let bs = config.block_size as u64;
+    let start = config.part_offset + offset;
+    let first = start / bs;
+    let count = (start + buf.len() as u64).div_ceil(bs) - first;
+    if start.is_multiple_of(bs) && (buf.len() as u64).is_multiple_of(bs) {
+        dev.read_block(buf, first, count as u32)?;
+    } else {
+        let mut tmp = vec![0u8; (count * bs) as usize];
+        dev.read_block(&mut tmp, first, count as u32)?;
+        let skip = (start - first * bs) as usize;
+        buf.copy_from_slice(&tmp[skip..skip + buf.len()]);
+    }
+    Ok(())
+}
+
+/// Write `buf` at byte `offset` of the partition described by `config`.
+pub(crate) fn write_at<T: BlockDeviceInterface>(
+    dev: &mut T,
+    config: &BlockDeviceConfig,
+    offset: u64,
+    buf: &[u8],
+) -> Result<()> {
+    if offset + buf.len() as u64 > config.part_size {
+        return Err(Error::Io);
+    }
+    let bs = config.block_size as u64;
+    let start = config.part_offset + offset;
+    let first = start / bs;
+    let count = (start + buf.len() as u64).div_ceil(bs) - first;
+    if start.is_multiple_of(bs) && (buf.len() as u64).is_multiple_of(bs) {
+        dev.write_block(buf, first, count as u32)?;
+    } else {
+        let mut tmp = vec![0u8; (count * bs) as usize];
+        dev.read_block(&mut tmp, first, count as u32)?;
+        let skip = (start - first * bs) as usize;
+        tmp[skip..skip + buf.len()].copy_from_slice(buf);
+        dev.write_block(&tmp, first, count as u32)?;
+    }
+    Ok(())
+}
+
+/// Open the device, filling in the partition size of whole-device configurations.
+pub(crate) fn open_device<T: BlockDeviceInterface>(dev: &mut T) -> Result<BlockDeviceConfig> {
+    let mut config = dev.open()?;
+    if config.block_size == 0 {
+        return Err(Error::InvalidArgument);
+    }
+    if config.part_size == 0 {
+        config.part_size = config.block_count * config.block_size as u64;
+    }
+    Ok(config)
+}
+
+/// An unmounted file system on a block device.
+pub(crate) struct Disk<'a, T: BlockDeviceInterface> {
+    dev: &'a mut T,
+    config: BlockDeviceConfig,
+    pub(crate) sb: Superblock,
+    pub(crate) groups: Vec<GroupDesc>,
+}
+
+impl<'a, T: BlockDeviceInterface> Disk<'a, T> {
+    /// Open the device and read the primary superblock.
+    pub(crate) fn open(bdev: &'a mut BlockDevice<T>) -> Result<Self> {
+        let dev: &'a mut T = bdev;
+        let config = open_device(dev)?;
+        let mut disk = Disk {
+            dev,
+            config,
+            sb: Superblock {
+                raw: [0; SUPERBLOCK_SIZE],
+            },
+            groups: Vec::new(),
+        };
+        let mut raw = [0u8; SUPERBLOCK_SIZE];
+        disk.read(SUPERBLOCK_OFFSET, &mut raw)?;
+        disk.sb = Superblock { raw };
+        disk.validate_superblock()?;
+        Ok(disk)
+    }
+
+    fn validate_superblock(&self) -> Result<()> {
+        let sb = &self.sb;
+        // External journal devices share the magic but hold no file system.
+        if sb.magic() != EXT4_MAGIC || sb.has_incompat(INCOMPAT_JOURNAL_DEV) {
+            return Err(Error::NotSupported);
+        }
+        let inode_size = sb.inode_size();
+        let valid = sb.log_block_size() <= 6
+            && sb.blocks_per_group() != 0
+            && sb.blocks_per_group() <= sb.block_size() * 8
+            && sb.inodes_per_group() != 0
+            && sb.inodes_per_group() <= sb.block_size() * 8
+            && inode_size >= GOOD_OLD_INODE_SIZE
+            && inode_size.is_power_of_two()
+            && inode_size <= sb.block_size() as usize
+            && sb.blocks_count() > sb.first_data_block() as u64
+            && sb.inodes_count() <= sb.inodes_per_group() * sb.group_count();
+        if !valid {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Size of the device (or partition) in bytes.
+    pub(crate) fn device_size(&self) -> u64 {
+        self.config.part_size
+    }
+
+    pub(crate) fn block_size(&self) -> u32 {
+        self.sb.block_size()
+    }
+
+    pub(crate) fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read_at(self.dev, &self.config, offset, buf)
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        write_at(self.dev, &self.config, offset, buf)
+    }
+
+    pub(crate) fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let bs = self.block_size();
+        let mut buf = vec![0u8; bs as usize];
+        self.read(block * bs as u64, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+        self.write(block * self.block_size() as u64, buf)
+    }
+
+    /// Flush the device.
+    pub(crate) fn close(self) -> Result<()> {
+        self.dev.close()
+    }
+
+    /// Whether `block` lies inside the file system.
+    pub(crate) fn valid_block(&self, block: u64) -> bool {
+        block >= self.sb.first_data_block() as u64 && block < self.sb.blocks_count()
+    }
+
+    /// Read the whole group descriptor table.
+    pub(crate) fn load_groups(&mut self) -> Result<()> {
+        let size = self.sb.desc_size();
+        let per_block = self.sb.descs_per_block();
+        let mut groups = Vec::with_capacity(self.sb.group_count() as usize);
+        let mut block = Vec::new();
+        for group in 0..self.sb.group_count() {
+            if group % per_block == 0 {
+                block = self.read_block(self.sb.group_desc_block(group))?;
+            }
+            let off = (group % per_block) as usize * size;
+            let mut raw = [0u8; 64];
+            raw[..size].copy_from_slice(&block[off..off + size]);
+            groups.push(GroupDesc { raw, size });
+        }
+        self.groups = groups;
+        Ok(())
+    }
+
+    /// Write the superblock and the descriptor of every group back to the primary
+    /// location, refreshing their checksums.
+    pub(crate) fn flush_metadata(&mut self) -> Result<()> {
+        for group in 0..self.groups.len() as u32 {
+            self.update_group_checksum(group);
+        }
+        let per_block = self.sb.descs_per_block();
+        for index in 0..self.sb.desc_blocks() {
+            let block = self.desc_block(index);
+            self.write_block(self.sb.group_desc_block(index * per_block), &block)?;
+        }
+        self.write_superblock()
+    }
+
+    /// Block `index` of the descriptor table, zero padded after the last group.
+    fn desc_block(&self, index: u32) -> Vec<u8> {
+        let size = self.sb.desc_size();
+        let per_block = self.sb.descs_per_block() as usize;
+        let mut block = vec![0u8; self.block_size() as usize];
+        let first = index as usize * per_block;
+        let groups = &self.groups[first..self.groups.len().min(first + per_block)];
+        for (i, gd) in groups.iter().enumerate() {
+            block[i * size..(i + 1) * size].copy_from_slice(&gd.raw[..size]);
+        }
+        block
+    }
+
+    /// Write the backup copies of the superblock and the descriptor table, as
+    /// last written by [flush_metadata](Self::flush_metadata).
+    pub(crate) fn write_backups(&mut self) -> Result<()> {
+        let sb = self.sb.clone();
+        let bs = self.block_size() as u64;
+        let meta_bg = sb.has_incompat(INCOMPAT_META_BG);
+        let per_meta = sb.descs_per_block();
+        let old_desc_blocks = match meta_bg {
+            true => sb.first_meta_bg().min(sb.desc_blocks()),
+            false => sb.desc_blocks(),
+        };
+        for group in 1..sb.group_count() {
+            let start = sb.group_first_block(group);
+            let has_super = sb.group_has_super(group);
+            if has_super {
+                let mut backup = sb.clone();
+                put16(&mut backup.raw, 0x5a, group as u16);
+                backup.update_checksum();
+                self.write(start * bs, &backup.raw)?;
+                for index in 0..old_desc_blocks {
+                    let block = self.desc_block(index);
+                    self.write_block(start + 1 + index as u64, &block)?;
+                }
+            }
+            let index = group / per_meta;
+            let slot = group % per_meta;
+            if meta_bg && index >= sb.first_meta_bg() && (slot == 1 || slot == per_meta - 1) {
+                let block = self.desc_block(index);
+                self.write_block(start + has_super as u64, &block)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks in use according to the block bitmaps, which needs [Self::load_groups].
+    ///
+    /// A group with an uninitialized bitmap only uses its superblock and descriptor
+    /// copies. The bitmaps and inode table of every group are counted as used
+    /// wherever they lie, and so are the blocks before the first data block.
+    pub(crate) fn used_blocks(&mut self) -> Result<BitSet> {
+        let sb = self.sb.clone();
+        let mut used = BitSet::new(sb.blocks_count());
+        for block in 0..sb.first_data_block() as u64 {
+            used.set(block);
+        }
+        for group in 0..sb.group_count() {
+            let gd = self.groups[group as usize].clone();
+            let first = sb.group_first_block(group);
+            if sb.group_csum() && gd.flags() & BG_BLOCK_UNINIT != 0 {
+                for block in sb.group_super_blocks(group) {
+                    used.set(block);
+                }
+            } else {
+                let bitmap = self.read_block(gd.block_bitmap())?;
+                for i in 0..sb.group_blocks(group) {
+                    if bitmap_get(&bitmap, i as usize) {
+                        used.set(first + i as u64);
+                    }
+                }
+            }
+            let table = gd.inode_table()..gd.inode_table() + sb.inode_table_blocks() as u64;
+            for block in [gd.block_bitmap(), gd.inode_bitmap()]
+                .into_iter()
+                .chain(table)
+            {
+                if self.valid_block(block) {
+                    used.set(block);
+                }
+            }
+        }
+        Ok(used)
+    }
+
+    pub(crate) fn write_superblock(&mut self) -> Result<()> {
+        self.sb.update_checksum();
+        let raw = self.sb.raw;
+        self.write(SUPERBLOCK_OFFSET, &raw)
+    }
+
+    /// Checksum of a group descriptor, if the file system has them.
+    pub(crate) fn group_checksum(&self, group: u32) -> Option<u16> {
+        let size = self.sb.desc_size();
+        let raw = &self.groups[group as usize].raw[..size];
+        if self.sb.metadata_csum() {
+            let mut crc = crc32c(self.sb.csum_seed(), &group.to_le_bytes());
+            crc = crc32c(crc, &raw[..0x1e]);
+            crc = crc32c(crc, &[0, 0]);
+            crc = crc32c(crc, &raw[0x20..]);
+            Some(crc as u16)
+        } else if self.sb.has_ro_compat(RO_COMPAT_GDT_CSUM) {
+            let mut crc = crc16(!0, &self.sb.uuid());
+            crc = crc16(crc, &group.to_le_bytes());
+            crc = crc16(crc, &raw[..0x1e]);
+            crc = crc16(crc, &raw[0x20..]);
+            Some(crc)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn update_group_checksum(&mut self, group: u32) {
+        if let Some(csum) = self.group_checksum(group) {
+            self.groups[group as usize].set_checksum(csum);
+        }
+    }
+
+    pub(crate) fn block_bitmap_checksum(&self, bitmap: &[u8]) -> u32 {
+        let len = self.sb.blocks_per_group() as usize / 8;
+        crc32c(self.sb.csum_seed(), &bitmap[..len])
+    }
+
+    pub(crate) fn inode_bitmap_checksum(&self, bitmap: &[u8]) -> u32 {
+        let len = self.sb.inodes_per_group() as usize / 8;
+        crc32c(self.sb.csum_seed(), &bitmap[..len])
+    }
+
+    /// Location in bytes of inode `ino`.
+    pub(crate) fn inode_offset(&self, ino: u32) -> u64 {
+        let group = (ino - 1) / self.sb.inodes_per_group();
+        let index = (ino - 1) % self.sb.inodes_per_group();
+        self.groups[group as usize].inode_table() * self.block_size() as u64
+            + index as u64 * self.sb.inode_size() as u64
+    }
+
+    pub(crate) fn read_inode(&mut self, ino: u32) -> Result<Inode> {
+        if ino == 0 || ino > self.sb.inodes_count() {
+            return Err(Error::InvalidArgument);
+        }
+        let mut raw = vec![0u8; self.sb.inode_size()];
+        self.read(self.inode_offset(ino), &mut raw)?;
+        Ok(Inode { raw })
+    }
+
+    pub(crate) fn write_inode(&mut self, ino: u32, inode: &mut Inode) -> Result<()> {
+        self.update_inode_checksum(ino, inode);
+        self.write(self.inode_offset(ino), &inode.raw)
+    }
+
+    pub(crate) fn update_inode_checksum(&self, ino: u32, inode: &mut Inode) {
+        if self.sb.metadata_csum() {
+            let csum = self.inode_checksum(ino, inode);
+            put16(&mut inode.raw, 0x7c, csum as u16);
+            if inode.has_checksum_hi() {
+                put16(&mut inode.raw, 0x82, (csum >> 16) as u16);
+            }
+        }
+    }
+
+    /// Read the inode table of `group` as `(ino, inode)` pairs, skipping the part
+    /// that is marked as never initialized.
+    pub(crate) fn read_inode_table(&mut self, group: u32) -> Result<Vec<(u32, Inode)>> {
+        let used = self.initialized_inodes(group);
+        let gd = &self.groups[group as usize];
+        let ipg = self.sb.inodes_per_group();
+        let isize = self.sb.inode_size();
+        let mut table = vec![0u8; used as usize * isize];
+        if used != 0 {
+            self.read(gd.inode_table() * self.block_size() as u64, &mut table)?;
+        }
+        Ok(table
+            .chunks(isize)
+            .enumerate()
+            .map(|(i, raw)| {
+                let ino = group * ipg + i as u32 + 1;
+                (ino, Inode { raw: raw.to_vec() })
+            })
+            .filter(|(ino, _)| *ino <= self.sb.inodes_count())
+            .collect())
+    }
+
+    /// Number of inodes at the start of the inode table of `group` that are not marked
+    /// as never initialized.
+    pub(crate) fn initialized_inodes(&self, group: u32) -> u32 {
+        let gd = &self.groups[group as usize];
+        let ipg = self.sb.inodes_per_group();
+        if !self.sb.group_csum() {
+            ipg
+        } else if gd.flags() & BG_INODE_UNINIT != 0 {
+            0
+        } else {
+            ipg.saturating_sub(gd.itable_unused())
+        }
+    }
+
+    /// Per-inode seed of the checksums of the inode and the blocks it owns.
+    pub(crate) fn inode_csum_seed(&self, ino: u32, inode: &Inode) -> u32 {
+        let crc = crc32c(self.sb.csum_seed(), &ino.to_le_bytes());
+        crc32c(crc, &inode.generation().to_le_bytes())
+    }
+
+    pub(crate) fn inode_checksum(&self, ino: u32, inode: &Inode) -> u32 {
+        let raw = &inode.raw;
+        let mut crc = self.inode_csum_seed(ino, inode);
+        crc = crc32c(crc, &raw[..0x7c]);
+        crc = crc32c(crc, &[0, 0]);
+        crc = crc32c(crc, &raw[0x7e..GOOD_OLD_INODE_SIZE]);
+        if raw.len() > GOOD_OLD_INODE_SIZE {
+            crc = crc32c(crc, &raw[GOOD_OLD_INODE_SIZE..0x82]);
+            if inode.has_checksum_hi() {
+                crc = crc32c(crc, &[0, 0]);
+                crc = crc32c(crc, &raw[0x84..]);
+            } else {
+                crc = crc32c(crc, &raw[0x82..]);
+            }
+        }
+        if inode.has_checksum_hi() {
+            crc
+        } else {
+            crc & 0xffff
+        }
+    }
+
+    /// Checksum of an extent tree node, stored right after its last slot.
+    fn extent_checksum(&self, seed: u32, node: &[u8]) -> Option<(u32, u32)> {
+        let end = 12 + 12 * le16(node, 4) as usize;
+        (end + 4 <= node.len()).then(|| (le32(node, end), crc32c(seed, &node[..end])))
+    }
+
+    pub(crate) fn update_extent_checksum(&self, seed: u32, node: &mut [u8]) {
+        if let Some((_, csum)) = self.extent_checksum(seed, node) {
+            let end = 12 + 12 * le16(node, 4) as usize;
+            put32(node, end, csum);
+        }
+    }
+
+    /// Stored and computed checksum of a directory leaf block with a checksum tail.
+    pub(crate) fn dir_block_checksum(&self, seed: u32, block: &[u8]) -> Option<(u32, u32)> {
+        let tail = block.len() - DIR_TAIL_SIZE;
+        is_dir_tail(block, tail).then(|| (le32(block, tail + 8), crc32c(seed, &block[..tail])))
+    }
+
+    /// Refresh the checksum of a directory leaf block or of an htree node.
+    pub(crate) fn update_dir_block_checksum(&self, seed: u32, block: &mut [u8]) {
+        if let Some((_, csum)) = self.dir_block_checksum(seed, block) {
+            let len = block.len();
+            put32(block, len - 4, csum);
+        } else if let Some((tail, csum)) = dx_checksum(seed, block) {
+            put32(block, tail + 4, csum);
+        }
+    }
+
+    /// Stored and computed checksum of an extended attribute block.
+    pub(crate) fn xattr_block_checksum(&self, block_nr: u64, block: &[u8]) -> Option<(u32, u32)> {
+        if le32(block, 0) != XATTR_MAGIC {
+            return None;
+        }
+        let mut crc = crc32c(self.sb.csum_seed(), &block_nr.to_le_bytes());
+        crc = crc32c(crc, &block[..0x10]);
+        crc = crc32c(crc, &[0; 4]);
+        crc = crc32c(crc, &block[0x14..]);
+        Some((le32(block, 0x10), crc))
+    }
+
+    pub(crate) fn update_xattr_block_checksum(&self, block_nr: u64, block: &mut [u8]) {
+        if let Some((_, csum)) = self.xattr_block_checksum(block_nr, block) {
+            put32(block, 0x10, csum);
+        }
+    }
+
+    /// Collect the blocks referenced by `inode`, following extent trees or indirect
+    /// blocks. Extent node checksums are verified if the file system has them.
+    pub(crate) fn map_inode(&mut self, ino: u32, inode: &Inode) -> Result<BlockMap> {
+        let mut map = BlockMap::default();
+        if !inode.has_data_blocks(self.block_size()) {
+            return Ok(map);
+        }
+        let area = inode.block_area().to_vec();
+        if inode.flags() & INODE_FLAG_EXTENTS != 0 {
+            let seed = self.inode_csum_seed(ino, inode);
+            self.map_extent_node(seed, &area, None, &mut map)?;
+        } else {
+            for i in 0..N_DIRECT_BLOCKS {
+                let block = le32(&area, i * 4) as u64;
+                if block == 0 {
+                    continue;
+                }
+                if self.valid_block(block) {
+                    map.push(i as u64, block, 1, false);
+                } else {
+                    map.out_of_range.push(block);
+                }
+            }
+            let per_block = self.block_size() as u64 / 4;
+            let mut logical = N_DIRECT_BLOCKS as u64;
+            for level in 1..=3u32 {
+                let block = le32(&area, (N_DIRECT_BLOCKS + level as usize - 1) * 4) as u64;
+                if block != 0 {
+                    self.map_indirect(block, level, logical, &mut map)?;
+                }
+                logical += per_block.pow(level);
+            }
+        }
+        Ok(map)
+    }
+
+    /// Number of blocks owned by `inode` as accounted in `i_blocks`.
+    pub(crate) fn count_blocks(&mut self, ino: u32, inode: &Inode) -> Result<u64> {
+        let map = self.map_inode(ino, inode)?;
+        let data: u64 = map.extents.iter().map(|e| e.len).sum();
+        Ok(data + map.meta.len() as u64 + (inode.file_acl() != 0) as u64)
+    }
+
+    fn map_extent_node(
+        &mut self,
+        seed: u32,
+        node: &[u8],
+        expected_depth: Option<u16>,
+        map: &mut BlockMap,
+    ) -> Result<()> {
+        let entries = le16(node, 2) as usize;
+        let max = le16(node, 4) as usize;
+        let depth = le16(node, 6);
+        if le16(node, 0) != EXTENT_MAGIC
+            || entries > max
+            || 12 + 12 * max > node.len()
+            || depth > EXTENT_MAX_DEPTH
+            || expected_depth.is_some_and(|d| d != depth)
+        {
+            map.corrupt = true;
+            return Ok(());
+        }
+        for i in 0..entries {
+            let entry = &node[12 + 12 * i..24 + 12 * i];
+            if depth == 0 {
+                let logical = le32(entry, 0) as u64;
+                let raw_len = le16(entry, 4);
+                let (len, uninit) = match raw_len > 32768 {
+                    true => (raw_len - 32768, true),
+                    false => (raw_len, false),
+                };
+                let start = (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64;
+                if len == 0 {
+                    continue;
+                }
+                if self.valid_block(start) && self.valid_block(start + len as u64 - 1) {
+                    map.push(logical, start, len as u64, uninit);
+                } else {
+                    map.out_of_range.push(start);
+                }
+            } else {
+                let leaf = le32(entry, 4) as u64 | (le16(entry, 8) as u64) << 32;
+                if !self.valid_block(leaf) {
+                    map.out_of_range.push(leaf);
+                    continue;
+                }
+                map.meta.push(leaf);
+                let child = self.read_block(leaf)?;
+                if self.sb.metadata_csum() {
+                    if let Some((stored, computed)) = self.extent_checksum(seed, &child) {
+                        if stored != computed {
+                            map.bad_csum.push(leaf);
+                        }
+                    }
+                }
+                self.map_extent_node(seed, &child, Some(depth - 1), map)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn map_indirect(
+        &mut self,
+        block: u64,
+        level: u32,
+        logical: u64,
+        map: &mut BlockMap,
+    ) -> Result<()> {
+        if !self.valid_block(block) {
+            map.out_of_range.push(block);
+            return Ok(());
+        }
+        map.meta.push(block);
+        let data = self.read_block(block)?;
+        let per_block = self.block_size() as u64 / 4;
+        let span = per_block.pow(level - 1);
+        for i in 0..per_block {
+            let ptr = le32(&data, i as usize * 4) as u64;
+            if ptr == 0 {
+                continue;
+            }
+            let logical = logical + i * span;
+            if level > 1 {
+                self.map_indirect(ptr, level - 1, logical, map)?;
+            } else if self.valid_block(ptr) {
+                map.push(logical, ptr, 1, false);
+            } else {
+                map.out_of_range.push(ptr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the references of `inode` to the block runs for which `bad(start, len)`
+    /// holds: extents and whole extent subtrees are removed, indirect pointers are
+    /// zeroed. Returns the dropped runs; the caller writes the inode back.
+    pub(crate) fn prune_inode(
+        &mut self,
+        ino: u32,
+        inode: &mut Inode,
+        bad: &dyn Fn(u64, u64) -> bool,
+    ) -> Result<Vec<(u64, u64)>> {
+        let mut dropped = Vec::new();
+        if !inode.has_data_blocks(self.block_size()) {
+            return Ok(dropped);
+        }
+        let mut area = inode.block_area().to_vec();
+        if inode.flags() & INODE_FLAG_EXTENTS != 0 {
+            let seed = self.inode_csum_seed(ino, inode);
+            self.prune_extent_node(seed, &mut area, None, bad, &mut dropped)?;
+        } else {
+            for i in 0..N_DIRECT_BLOCKS + 3 {
+                let block = le32(&area, i * 4) as u64;
+                if block == 0 {
+                    continue;
+                }
+                if bad(block, 1) {
+                    put32(&mut area, i * 4, 0);
+                    dropped.push((block, 1));
+                } else if i >= N_DIRECT_BLOCKS {
+                    let level = (i - N_DIRECT_BLOCKS + 1) as u32;
+                    self.prune_indirect(block, level, bad, &mut dropped)?;
+                }
+            }
+        }
+        inode.block_area_mut().copy_from_slice(&area);
+        Ok(dropped)
+    }
+
+    /// Remove the bad entries of an extent node, returning whether it changed.
+    fn prune_extent_node(
+        &mut self,
+        seed: u32,
+        node: &mut [u8],
+        expected_depth: Option<u16>,
+        bad: &dyn Fn(u64, u64) -> bool,
+        dropped: &mut Vec<(u64, u64)>,
+    ) -> Result<bool> {
+        let mut entries = le16(node, 2) as usize;
+        let max = le16(node, 4) as usize;
+        let depth = le16(node, 6);
+        if le16(node, 0) != EXTENT_MAGIC
+            || entries > max
+            || 12 + 12 * max > node.len()
+            || depth > EXTENT_MAX_DEPTH
+            || expected_depth.is_some_and(|d| d != depth)
+        {
+            return Ok(false);
+        }
+        let mut changed = false;
+        let mut i = 0;
+        while i < entries {
+            let off = 12 + 12 * i;
+            let (start, len) = if depth == 0 {
+                let raw_len = le16(node, off + 4) as u64;
+                let len = if raw_len > 32768 {
+                    raw_len - 32768
+                } else {
+                    raw_len
+                };
+                (
+                    (le16(node, off + 6) as u64) << 32 | le32(node, off + 8) as u64,
+                    len,
+                )
+            } else {
+                (
+                    le32(node, off + 4) as u64 | (le16(node, off + 8) as u64) << 32,
+                    1,
+                )
+            };
+            if len != 0 && bad(start, len) {
+                node.copy_within(off + 12..12 + 12 * entries, off);
+                entries -= 1;
+                node[12 + 12 * entries..24 + 12 * entries].fill(0);
+                dropped.push((start, len));
+                changed = true;
+                continue;
+            }
+            if depth > 0 {
+                let mut child = self.read_block(start)?;
+                if self.prune_extent_node(seed, &mut child, Some(depth - 1), bad, dropped)? {
+                    self.update_extent_checksum(seed, &mut child);
+                    self.write_block(start, &child)?;
+                }
+            }
+            i += 1;
+        }
+        put16(node, 2, entries as u16);
+        Ok(changed)
+    }
+
+    fn prune_indirect(
+        &mut self,
+        block: u64,
+        level: u32,
+        bad: &dyn Fn(u64, u64) -> bool,
+        dropped: &mut Vec<(u64, u64)>,
+    ) -> Result<()> {
+        let mut data = self.read_block(block)?;
+        let mut changed = false;
+        for i in 0..data.len() / 4 {
+            let ptr = le32(&data, i * 4) as u64;
+            if ptr == 0 {
+                continue;
+            }
+            if bad(ptr, 1) {
+                put32(&mut data, i * 4, 0);
+                dropped.push((ptr, 1));
+                changed = true;
+            } else if level > 1 {
+                self.prune_indirect(ptr, level - 1, bad, dropped)?;
+            }
+        }
+        if changed {
+            self.write_block(block, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the extent tree of `inode` by a new one mapping `extents`, which must be
+    /// sorted and within the length limits of a single extent. Index and leaf blocks
+    /// come from `alloc`; the old tree is not freed.
+    pub(crate) fn write_extent_tree(
+        &mut self,
+        ino: u32,
+        inode: &mut Inode,
+        extents: &[Extent],
+        alloc: &mut dyn FnMut() -> Result<u64>,
+    ) -> Result<()> {
+        let seed = self.inode_csum_seed(ino, inode);
+        let bs = self.block_size() as usize;
+        let root_max = (inode.block_area().len() - 12) / 12;
+        let node_max = (bs - 12) / 12;
+        let mut entries: Vec<[u8; 12]> = extents
+            .iter()
+            .map(|e| {
+                let mut entry = [0u8; 12];
+                put32(&mut entry, 0, e.logical as u32);
+                put16(
+                    &mut entry,
+                    4,
+                    e.len as u16 + if e.uninit { 32768 } else { 0 },
+                );
+                put16(&mut entry, 6, (e.physical >> 32) as u16);
+                put32(&mut entry, 8, e.physical as u32);
+                entry
+            })
+            .collect();
+        let mut depth = 0;
+        while entries.len() > root_max {
+            let mut parents = Vec::new();
+            for chunk in entries.chunks(node_max) {
+                let block = alloc()?;
+                let mut node = vec![0u8; bs];
+                write_extent_header(&mut node, chunk.len(), node_max, depth);
+                for (i, entry) in chunk.iter().enumerate() {
+                    node[12 + 12 * i..24 + 12 * i].copy_from_slice(entry);
+                }
+                self.update_extent_checksum(seed, &mut node);
+                self.write_block(block, &node)?;
+                let mut index = [0u8; 12];
+                index[..4].copy_from_slice(&chunk[0][..4]);
+                put32(&mut index, 4, block as u32);
+                put16(&mut index, 8, (block >> 32) as u16);
+                parents.push(index);
+            }
+            entries = parents;
+            depth += 1;
+        }
+        let area = inode.block_area_mut();
+        area.fill(0);
+        write_extent_header(area, entries.len(), root_max, depth);
+        for (i, entry) in entries.iter().enumerate() {
+            area[12 + 12 * i..24 + 12 * i].copy_from_slice(entry);
+        }
+        Ok(())
+    }
+
+    /// Move the blocks of an indirectly mapped `inode` at or above `limit`, including
+    /// indirect blocks, to blocks from `alloc`. Returns the number of blocks moved.
+    pub(crate) fn relocate_indirect(
+        &mut self,
+        inode: &mut Inode,
+        limit: u64,
+        alloc: &mut dyn FnMut() -> Result<u64>,
+    ) -> Result<u64> {
+        let mut moved = 0;
+        let mut area = inode.block_area().to_vec();
+        for i in 0..N_DIRECT_BLOCKS + 3 {
+            let block = le32(&area, i * 4) as u64;
+            if block == 0 {
+                continue;
+            }
+            let level = i.saturating_sub(N_DIRECT_BLOCKS - 1) as u32;
+            let new = self.relocate_tree_block(block, level, limit, alloc, &mut moved)?;
+            put32(&mut area, i * 4, new as u32);
+        }
+        inode.block_area_mut().copy_from_slice(&area);
+        Ok(moved)
+    }
+
+    fn relocate_tree_block(
+        &mut self,
+        block: u64,
+        level: u32,
+        limit: u64,
+        alloc: &mut dyn FnMut() -> Result<u64>,
+        moved: &mut u64,
+    ) -> Result<u64> {
+        if level == 0 && block < limit {
+            return Ok(block);
+        }
+        let mut data = self.read_block(block)?;
+        let mut changed = false;
+        if level > 0 {
+            for i in 0..data.len() / 4 {
+                let ptr = le32(&data, i * 4) as u64;
+                if ptr == 0 {
+                    continue;
+                }
+                let new = self.relocate_tree_block(ptr, level - 1, limit, alloc, moved)?;
+                if new != ptr {
+                    put32(&mut data, i * 4, new as u32);
+                    changed = true;
+                }
+            }
+        }
+        if block < limit {
+            if changed {
+                self.write_block(block, &data)?;
+            }
+            return Ok(block);
+        }
+        let new = alloc()?;
+        self.write_block(new, &data)?;
+        *moved += 1;
+        Ok(new)
+    }
+
+    /// Map `blocks`, one per logical block from 0, through the direct and indirect
+    /// pointers of `inode`, taking the indirect blocks from `alloc`.
+    pub(crate) fn write_indirect_map(
+        &mut self,
+        inode: &mut Inode,
+        blocks: &[u64],
+        alloc: &mut dyn FnMut() -> Result<u64>,
+    ) -> Result<()> {
+        let per_block = self.block_size() as usize / 4;
+        let mut area = vec![0u8; N_DIRECT_BLOCKS * 4 + 12];
+        let direct = blocks.len().min(N_DIRECT_BLOCKS);
+        for (i, &block) in blocks[..direct].iter().enumerate() {
+            put32(&mut area, i * 4, block as u32);
+        }
+        let mut rest = &blocks[direct..];
+        for level in 1..=3u32 {
+            if rest.is_empty() {
+                break;
+            }
+            let n = rest.len().min(per_block.pow(level));
+            let block = self.write_indirect_block(&rest[..n], level, alloc)?;
+            put32(
+                &mut area,
+                (N_DIRECT_BLOCKS + level as usize - 1) * 4,
+                block as u32,
+            );
+            rest = &rest[n..];
+        }
+        if !rest.is_empty() {
+            return Err(Error::FileTooBig);
+        }
+        inode.block_area_mut().copy_from_slice(&area);
+        Ok(())
+    }
+
+    fn write_indirect_block(
+        &mut self,
+        blocks: &[u64],
+        level: u32,
+        alloc: &mut dyn FnMut() -> Result<u64>,
+    ) -> Result<u64> {
+        let block = alloc()?;
+        let per_block = self.block_size() as usize / 4;
+        let mut data = vec![0u8; self.block_size() as usize];
+        for (i, chunk) in blocks.chunks(per_block.pow(level - 1)).enumerate() {
+            let ptr = match level {
+                1 => chunk[0],
+                _ => self.write_indirect_block(chunk, level - 1, alloc)?,
+            };
+            put32(&mut data, i * 4, ptr as u32);
+        }
+        self.write_block(block, &data)?;
+        Ok(block)
+    }
+}
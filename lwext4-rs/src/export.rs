@@ -0,0 +1,262 @@
+use crate::error::{Error, Result};
+use crate::types::{dev_split, MetaDataExt, Metadata};
+use crate::{BlockDeviceInterface, FileSystem};
+use embedded_io::Read as _;
+use log::info;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// What part of an entry could not be reproduced on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportIssueKind {
+    /// The entry itself could not be created or its contents could not be copied.
+    Create,
+    /// The permission bits could not be applied.
+    Permissions,
+    /// The owner and group could not be applied.
+    Ownership { uid: u32, gid: u32 },
+    /// The access and modification times could not be applied.
+    Timestamps,
+    /// The named extended attribute could not be read or applied.
+    Xattr(String),
+    /// The entry is a socket, which cannot be recreated from an image.
+    Socket,
+}
+
+/// An entry of the image that was not (fully) reproduced on the host.
+#[derive(Debug, Clone)]
+pub struct ExportIssue {
+    /// Path of the entry inside the image.
+    pub path: String,
+    pub kind: ExportIssueKind,
+    pub error: Error,
+}
+
+/// Summary of an [export_to](FileSystem::export_to) run.
+#[derive(Debug, Clone, Default)]
+pub struct ExportReport {
+    pub dirs: usize,
+    pub files: usize,
+    pub symlinks: usize,
+    pub hard_links: usize,
+    pub special_files: usize,
+    /// Bytes of file content written to the host.
+    pub bytes: u64,
+    pub issues: Vec<ExportIssue>,
+}
+
+impl ExportReport {
+    fn issue(&mut self, path: &str, kind: ExportIssueKind, error: Error) {
+        self.issues.push(ExportIssue {
+            path: path.to_string(),
+            kind,
+            error,
+        });
+    }
+}
+
+struct Exporter<'a, T: BlockDeviceInterface> {
+    fs: &'a FileSystem<T>,
+    block_size: usize,
+    /// Host path of the first exported name of every multiply linked inode.
+    links: BTreeMap<u64, PathBuf>,
+    report: ExportReport,
+}
+
+impl<T: BlockDeviceInterface> FileSystem<T> {
+    /// Recreate the whole file system under `host_dir` on the host.
+    ///
+    /// File contents (with all-zero blocks left as holes), symbolic links, hard links,
+    /// device nodes, fifos, permissions, ownership, timestamps and extended attributes
+    /// are reproduced where the host allows it. Everything that could not be reproduced
+    /// is listed in the returned report instead of aborting the export.
+    pub fn export_to<P: AsRef<Path>>(&self, host_dir: P) -> Result<ExportReport> {
+        let host_dir = host_dir.as_ref();
+        std::fs::create_dir_all(host_dir)?;
+        let root = self.mount_handle().mount_point.as_str().to_string();
+        let mut exporter = Exporter {
+            fs: self,
+            block_size: self.mount_handle().stats()?.block_size as usize,
+            links: BTreeMap::new(),
+            report: ExportReport::default(),
+        };
+        info!("Exporting {} to {}", root, host_dir.display());
+        exporter.export_dir(&root, host_dir)?;
+        let meta = self.metadata(&root)?;
+        exporter.apply_metadata(&root, host_dir, &meta);
+        Ok(exporter.report)
+    }
+}
+
+impl<'a, T: BlockDeviceInterface> Exporter<'a, T> {
+    fn export_dir(&mut self, dir: &str, host_dir: &Path) -> Result<()> {
+        let entries = self
+            .fs
+            .readdir(dir)?
+            .filter(|e| e.name() != "." && e.name() != "..")
+            .map(|e| (e.name().to_string(), e.path()))
+            .collect::<Vec<_>>();
+        for (name, path) in entries {
+            let host_path = host_dir.join(&name);
+            let res = self
+                .fs
+                .metadata(&path)
+                .and_then(|meta| self.export_entry(&path, &host_path, &meta));
+            if let Err(e) = res {
+                self.report.issue(&path, ExportIssueKind::Create, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn export_entry(&mut self, path: &str, host_path: &Path, meta: &Metadata) -> Result<()> {
+        let ty = meta.file_type();
+        if !ty.is_dir() && meta.nlink() > 1 {
+            if let Some(first) = self.links.get(&meta.ino()) {
+                std::fs::hard_link(first, host_path)?;
+                self.report.hard_links += 1;
+                return Ok(());
+            }
+        }
+        if ty.is_dir() {
+            std::fs::create_dir(host_path)?;
+            self.report.dirs += 1;
+            self.export_dir(&(path.to_string() + "/"), host_path)?;
+        } else if ty.is_file() {
+            self.export_file(path, host_path, meta.size())?;
+            self.report.files += 1;
+        } else if ty.is_symlink() {
+            symlink(self.fs.read_link(path)?, host_path)?;
+            self.report.symlinks += 1;
+        } else if ty.is_socket() {
+            self.report
+                .issue(path, ExportIssueKind::Socket, Error::NotSupported);
+            return Ok(());
+        } else {
+            make_node(host_path, meta)?;
+            self.report.special_files += 1;
+        }
+        // later names link to this one, so it is only recorded once it exists
+        if !ty.is_dir() && meta.nlink() > 1 {
+            self.links.insert(meta.ino(), host_path.to_path_buf());
+        }
+        self.apply_metadata(path, host_path, meta);
+        Ok(())
+    }
+
+    /// Copy the contents of a regular file, seeking over all-zero blocks so that
+    /// they end up as holes on the host.
+    fn export_file(&mut self, path: &str, host_path: &Path, size: u64) -> Result<()> {
+        let mut src = self.fs.file_builder().read(true).open(path)?;
+        let mut dst = std::fs::File::create(host_path)?;
+        let mut buf = vec![0u8; self.block_size * 16];
+        loop {
+            let read = src.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            for chunk in buf[..read].chunks(self.block_size) {
+                if chunk.iter().all(|&b| b == 0) {
+                    dst.seek(SeekFrom::Current(chunk.len() as i64))?;
+                } else {
+                    dst.write_all(chunk)?;
+                }
+            }
+            self.report.bytes += read as u64;
+        }
+        dst.set_len(size)?;
+        Ok(())
+    }
+
+    fn apply_metadata(&mut self, path: &str, host_path: &Path, meta: &Metadata) {
+        for name in self.fs.list_xattr(path).unwrap_or_default() {
+            let name = String::from_utf8_lossy(&name).to_string();
+            let res = self
+                .fs
+                .get_xattr(path, &name)
+                .and_then(|value| Ok(xattr::set(host_path, &name, &value)?));
+            if let Err(e) = res {
+                self.report.issue(path, ExportIssueKind::Xattr(name), e);
+            }
+        }
+        if let Err(e) = lchown(host_path, Some(meta.uid()), Some(meta.gid())) {
+            let kind = ExportIssueKind::Ownership {
+                uid: meta.uid(),
+                gid: meta.gid(),
+            };
+            self.report.issue(path, kind, e.into());
+        }
+        if !meta.is_symlink() {
+            let perm = std::fs::Permissions::from_mode(meta.mode() & 0o7777);
+            if let Err(e) = std::fs::set_permissions(host_path, perm) {
+                self.report
+                    .issue(path, ExportIssueKind::Permissions, e.into());
+            }
+        }
+        if let Err(e) = set_times(host_path, meta) {
+            self.report.issue(path, ExportIssueKind::Timestamps, e);
+        }
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::InvalidArgument)
+}
+
+/// Create a fifo or a device node with the device number stored in the inode.
+fn make_node(host_path: &Path, meta: &Metadata) -> Result<()> {
+    let ty = meta.file_type();
+    let kind = if ty.is_fifo() {
+        libc::S_IFIFO
+    } else if ty.is_char_device() {
+        libc::S_IFCHR
+    } else if ty.is_block_device() {
+        libc::S_IFBLK
+    } else {
+        return Err(Error::NotSupported);
+    };
+    let (major, minor) = dev_split(meta.rdev());
+    let path = c_path(host_path)?;
+    let r = unsafe {
+        libc::mknod(
+            path.as_ptr(),
+            kind | (meta.mode() & 0o7777) as libc::mode_t,
+            libc::makedev(major, minor),
+        )
+    };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Set the access and modification times without following symbolic links.
+fn set_times(host_path: &Path, meta: &Metadata) -> Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime() as _,
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime() as _,
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    ];
+    let path = c_path(host_path)?;
+    let r = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
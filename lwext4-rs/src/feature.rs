@@ -0,0 +1,173 @@
+use crate::block::{BlockDevice, BlockDeviceInterface};
+use crate::disk::*;
+use crate::error::Result;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+/// Incompatible features lwext4 can mount, c.f. `CONFIG_SUPPORTED_FINCOM` for the
+/// ext4 feature set. `needs_recovery` and `mmp` are ignored by lwext4 itself, so `mmp`
+/// is also in `READ_ONLY_INCOMPAT`.
+const SUPPORTED_INCOMPAT: u32 = 0x0002 // filetype
+    | 0x0004 // needs_recovery
+    | 0x0010 // meta_bg
+    | 0x0040 // extent
+    | 0x0080 // 64bit
+    | 0x0100 // mmp
+    | 0x0200; // flex_bg
+
+/// Incompatible features lwext4 mounts but must not write: it does not implement the
+/// multi-mount protection `mmp` asks for, so a writer could corrupt an image another
+/// host has mounted.
+const READ_ONLY_INCOMPAT: u32 = 0x0100; // mmp
+
+/// Read-only compatible features lwext4 can write, c.f. `CONFIG_SUPPORTED_FRO_COM`.
+const SUPPORTED_RO_COMPAT: u32 = 0x0001 // sparse_super
+    | 0x0002 // large_file
+    | 0x0008 // huge_file
+    | 0x0010 // uninit_bg
+    | 0x0020 // dir_nlink
+    | 0x0040 // extra_isize
+    | 0x0400; // metadata_csum
+
+/// Names `mke2fs` and `tune2fs` use for the feature bits.
+const INCOMPAT_NAMES: &[(u32, &str)] = &[
+    (0x0001, "compression"),
+    (0x0002, "filetype"),
+    (0x0004, "needs_recovery"),
+    (0x0008, "journal_dev"),
+    (0x0010, "meta_bg"),
+    (0x0040, "extent"),
+    (0x0080, "64bit"),
+    (0x0100, "mmp"),
+    (0x0200, "flex_bg"),
+    (0x0400, "ea_inode"),
+    (0x1000, "dirdata"),
+    (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"),
+    (0x8000, "inline_data"),
+    (0x10000, "encrypt"),
+    (0x20000, "casefold"),
+];
+
+const RO_COMPAT_NAMES: &[(u32, &str)] = &[
+    (0x0001, "sparse_super"),
+    (0x0002, "large_file"),
+    (0x0008, "huge_file"),
+    (0x0010, "uninit_bg"),
+    (0x0020, "dir_nlink"),
+    (0x0040, "extra_isize"),
+    (0x0080, "snapshot"),
+    (0x0100, "quota"),
+    (0x0200, "bigalloc"),
+    (0x0400, "metadata_csum"),
+    (0x0800, "replica"),
+    (0x1000, "read-only"),
+    (0x2000, "project"),
+    (0x4000, "shared_blocks"),
+    (0x8000, "verity"),
+    (0x10000, "orphan_present"),
+];
+
+/// Feature flags of a file system, checked against what lwext4 supports before
+/// [mounting](crate::MountHandle::mount).
+///
+/// Unsupported incompatible features prevent mounting at all. `mmp` and unsupported
+/// read-only compatible features only prevent writing.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, FeatureReport};
+/// let file = std::fs::File::open("ext4.img").unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let features = FeatureReport::read(&mut blk).unwrap();
+/// if !features.is_mountable() {
+///     println!("cannot mount, unsupported {}", features);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureReport {
+    pub compat: u32,
+    pub incompat: u32,
+    pub ro_compat: u32,
+}
+
+impl FeatureReport {
+    /// Read the feature flags from the superblock on `bdev`.
+    pub fn read<T: BlockDeviceInterface>(bdev: &mut BlockDevice<T>) -> Result<Self> {
+        let disk = Disk::open(bdev)?;
+        Ok(Self::from_superblock(&disk.sb))
+    }
+
+    pub(crate) fn from_superblock(sb: &Superblock) -> Self {
+        Self {
+            compat: sb.feature_compat(),
+            incompat: sb.feature_incompat(),
+            ro_compat: sb.feature_ro_compat(),
+        }
+    }
+
+    /// Whether lwext4 can mount the file system, possibly only read-only.
+    pub fn is_mountable(&self) -> bool {
+        self.incompat & !SUPPORTED_INCOMPAT == 0
+    }
+
+    /// Whether lwext4 can mount the file system read-write.
+    pub fn is_writable(&self) -> bool {
+        self.is_mountable()
+            && self.incompat & READ_ONLY_INCOMPAT == 0
+            && self.ro_compat & !SUPPORTED_RO_COMPAT == 0
+    }
+
+    /// Names of the incompatible features lwext4 does not support.
+    pub fn unsupported_incompat(&self) -> Vec<String> {
+        feature_names(self.incompat & !SUPPORTED_INCOMPAT, INCOMPAT_NAMES, 'I')
+    }
+
+    /// Names of the incompatible features lwext4 only mounts read-only.
+    pub fn read_only_incompat(&self) -> Vec<String> {
+        feature_names(self.incompat & READ_ONLY_INCOMPAT, INCOMPAT_NAMES, 'I')
+    }
+
+    /// Names of the read-only compatible features lwext4 does not support.
+    pub fn unsupported_ro_compat(&self) -> Vec<String> {
+        feature_names(self.ro_compat & !SUPPORTED_RO_COMPAT, RO_COMPAT_NAMES, 'R')
+    }
+}
+
+/// Lists the unsupported features, e.g. `features inline_data metadata_csum_seed`.
+impl Display for FeatureReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let names = [
+            self.unsupported_incompat(),
+            self.read_only_incompat(),
+            self.unsupported_ro_compat(),
+        ]
+        .concat();
+        match names.is_empty() {
+            true => write!(f, "no features"),
+            false => write!(f, "features {}", names.join(" ")),
+        }
+    }
+}
+
+/// Name the bits set in `mask`, with the `FEATURE_I17` form of e2fsprogs for bits
+/// without a name.
+fn feature_names(mask: u32, names: &[(u32, &str)], kind: char) -> Vec<String> {
+    (0..32)
+        .map(|bit| 1u32 << bit)
+        .filter(|flag| mask & flag != 0)
+        .map(|flag| match names.iter().find(|(f, _)| *f == flag) {
+            Some((_, name)) => name.to_string(),
+            None => format!("FEATURE_{}{}", kind, flag.trailing_zeros()),
+        })
+        .collect()
+}
@@ -114,7 +114,8 @@ impl File {
     }
     /// Get the metadata of a file
     pub fn metadata(&self) -> Result<Metadata> {
-        raw_metadata(&self.path)
+        // the file system of the mount point the file was opened on
+        raw_metadata(unsafe { &mut (*self.raw.mp).fs }, &self.path)
     }
 
     /// Set the file size
@@ -180,16 +181,37 @@ impl File {
     }
 }
 
-pub fn raw_metadata(path: &CName) -> Result<Metadata> {
+/// The metadata of `path` on the file system `fs`.
+///
+/// lwext4 copies a whole `ext4_inode`, past the end of inodes without the extra
+/// fields, which are cleared for them.
+pub fn raw_metadata(fs: *mut ext4_fs, path: &CName) -> Result<Metadata> {
     let mut meta = Metadata(FileAttr::empty());
     let mut inode_nm = 0u32;
     unsafe {
         ext4_raw_inode_fill(path.as_ptr(), &mut inode_nm as _, &mut meta.0.raw as _);
+        if !has_extra_time(fs, &meta.0.raw) {
+            let raw = &mut meta.0.raw;
+            raw.extra_isize = 0;
+            raw.checksum_hi = 0;
+            raw.ctime_extra = 0;
+            raw.mtime_extra = 0;
+            raw.atime_extra = 0;
+            raw.crtime = 0;
+            raw.crtime_extra = 0;
+            raw.version_hi = 0;
+        }
     }
     meta.set_ino(inode_nm as _);
     Ok(meta)
 }
 
+/// Whether an inode has the extra time fields, which 128 byte inodes end before.
+pub(crate) unsafe fn has_extra_time(fs: *mut ext4_fs, inode: &ext4_inode) -> bool {
+    // the extra fields up to the access time, 16 bytes past the first 128
+    u16::from_le((*fs).sb.inode_size) > 128 && u16::from_le(inode.extra_isize) >= 16
+}
+
 impl Drop for File {
     fn drop(&mut self) {
         unsafe {
@@ -1,11 +1,13 @@
 use crate::block::CName;
 use crate::dir::ReadDir;
 use crate::error::{errno_to_result, Error, Result};
-use crate::file::{raw_metadata, OpenOptions};
+use crate::file::{has_extra_time, raw_metadata, OpenOptions};
 use crate::types::{FileType, Metadata, Permissions};
+use crate::zero::{self, ZeroReport};
 use crate::{BlockDeviceInterface, FileTimes, MetaDataExt, MountHandle, Time};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::mem::zeroed;
 use core::ptr::null_mut;
 use log::info;
 use lwext4_sys::ext4::*;
@@ -45,7 +47,7 @@ impl<T: BlockDeviceInterface> FileSystem<T> {
     /// Get the metadata of a file
     pub fn metadata<P: AsRef<str>>(&self, path: P) -> Result<Metadata> {
         let path = CName::new(path.as_ref().to_string())?;
-        raw_metadata(&path)
+        raw_metadata(self.mp.raw_fs(), &path)
     }
 
     /// Open a directory at the provided path
@@ -265,7 +267,7 @@ impl<T: BlockDeviceInterface> FileSystem<T> {
     pub fn chown<P: AsRef<str>>(&self, path: P, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
         let path = CName::new(path.as_ref().to_string())?;
         let (uid, gid) = if uid.is_none() && gid.is_none() {
-            let meta = raw_metadata(&path)?;
+            let meta = raw_metadata(self.mp.raw_fs(), &path)?;
             let uid = if uid.is_none() { Some(meta.uid()) } else { uid };
             let gid = if gid.is_none() { Some(meta.gid()) } else { gid };
             (uid, gid)
@@ -276,24 +278,105 @@ impl<T: BlockDeviceInterface> FileSystem<T> {
         unsafe { errno_to_result(ext4_owner_set(path.as_ptr(), uid.unwrap(), gid.unwrap())) }
     }
     /// Modify the times of a file
+    ///
+    /// Nanoseconds are kept where the inode has room for them, as inodes larger than
+    /// 128 bytes do, see [keeps_nanos](Self::keeps_nanos). Elsewhere any but zero fail
+    /// with [Error::NotSupported]. Times past 2106, or 2446 with room for nanoseconds,
+    /// fail with [Error::InvalidArgument].
     pub fn set_times<P: AsRef<str>>(&self, path: P, times: FileTimes) -> Result<()> {
+        if self.mp.read_only() {
+            return Err(Error::ReadOnly);
+        }
         let path = CName::new(path.as_ref().to_string())?;
-        if let Some(a) = times.accessed {
-            unsafe {
-                errno_to_result(ext4_atime_set(path.as_ptr(), a.into()))?;
-            }
+        let mut ino = 0u32;
+        let fs = self.mp.raw_fs();
+        unsafe {
+            let mut raw: ext4_inode = zeroed();
+            errno_to_result(ext4_raw_inode_fill(path.as_ptr(), &mut ino, &mut raw))?;
+            self.transaction(|| {
+                let mut inode_ref: ext4_inode_ref = zeroed();
+                errno_to_result(ext4_fs_get_inode_ref(fs, ino, &mut inode_ref))?;
+                let inode = &mut *inode_ref.inode;
+                let extra = has_extra_time(fs, inode);
+                let fields = [times.accessed, times.modified, times.created]
+                    .into_iter()
+                    .map(|t| t.map(|t| t.to_extra(extra)).transpose())
+                    .collect::<Result<Vec<_>>>();
+                if let Ok(fields) = &fields {
+                    (inode.access_time, inode.atime_extra) =
+                        time_fields(inode.access_time, inode.atime_extra, fields[0]);
+                    (inode.modification_time, inode.mtime_extra) =
+                        time_fields(inode.modification_time, inode.mtime_extra, fields[1]);
+                    (inode.change_inode_time, inode.ctime_extra) =
+                        time_fields(inode.change_inode_time, inode.ctime_extra, fields[2]);
+                    inode_ref.dirty = true;
+                }
+                let put = errno_to_result(ext4_fs_put_inode_ref(&mut inode_ref));
+                fields.and(put)
+            })
         }
-        if let Some(m) = times.modified {
-            unsafe {
-                errno_to_result(ext4_mtime_set(path.as_ptr(), m.into()))?;
-            }
+    }
+
+    /// Whether the inode of the specified path has room for the nanoseconds of its
+    /// times, see [set_times](Self::set_times).
+    pub fn keeps_nanos<P: AsRef<str>>(&self, path: P) -> Result<bool> {
+        let path = CName::new(path.as_ref().to_string())?;
+        let mut ino = 0u32;
+        unsafe {
+            let mut raw: ext4_inode = zeroed();
+            errno_to_result(ext4_raw_inode_fill(path.as_ptr(), &mut ino, &mut raw))?;
+            Ok(has_extra_time(self.mp.raw_fs(), &raw))
         }
-        if let Some(c) = times.created {
-            unsafe {
-                errno_to_result(ext4_ctime_set(path.as_ptr(), c.into()))?;
+    }
+
+    /// Run `f` in a journal transaction, committed if it succeeds and aborted
+    /// otherwise, as the calls of lwext4 do with `ext4_trans_start` and
+    /// `ext4_trans_stop`. The mount point lock they take as well is only installed by
+    /// `ext4_mount_setup_locks`, which is never called, and a [FileSystem] cannot be
+    /// shared between threads.
+    unsafe fn transaction<R>(&self, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        let fs = self.mp.raw_fs();
+        let journal = (*fs).jbd_journal;
+        if journal.is_null() || !(*fs).curr_trans.is_null() {
+            return f();
+        }
+        let trans = jbd_journal_new_trans(journal);
+        if trans.is_null() {
+            return Err(Error::OutOfMemory);
+        }
+        (*fs).curr_trans = trans;
+        match f() {
+            Ok(r) => {
+                let commit = errno_to_result(jbd_journal_commit_trans(journal, trans));
+                (*fs).curr_trans = null_mut();
+                commit.map(|_| r)
+            }
+            Err(e) => {
+                jbd_journal_free_trans(journal, trans, true);
+                (*fs).curr_trans = null_mut();
+                Err(e)
             }
         }
-        Ok(())
+    }
+
+    /// Discard, or overwrite with zeros where the device cannot discard, every
+    /// block free in the block bitmaps, so that deleted data does not survive in
+    /// the image and compresses or sparsifies well.
+    pub fn zero_free_space(&mut self) -> Result<ZeroReport> {
+        if self.mp.read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let mount_point = self.mp.mount_point.as_ptr();
+        unsafe {
+            errno_to_result(ext4_journal_stop(mount_point))?;
+            errno_to_result(ext4_cache_write_back(mount_point, false))?;
+        }
+        let report = zero::zero_free_blocks(self.mp.device_mut());
+        unsafe {
+            errno_to_result(ext4_cache_write_back(mount_point, true))?;
+            errno_to_result(ext4_journal_start(mount_point))?;
+        }
+        report
     }
 
     /// Set the modified time of a file
@@ -305,3 +388,13 @@ impl<T: BlockDeviceInterface> FileSystem<T> {
         Ok(())
     }
 }
+
+/// The sec and extra fields of an inode, as stored, with a time split by
+/// [Time::to_extra] stored in them.
+fn time_fields(secs: u32, extra: u32, time: Option<(u32, Option<u32>)>) -> (u32, u32) {
+    match time {
+        Some((secs, Some(extra))) => (secs.to_le(), extra.to_le()),
+        Some((secs, None)) => (secs.to_le(), extra),
+        None => (secs, extra),
+    }
+}
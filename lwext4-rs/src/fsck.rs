@@ -0,0 +1,1760 @@
+use crate::block::{BlockDevice, BlockDeviceInterface};
+use crate::disk::*;
+use crate::error::{Error, Result};
+use crate::types::FileType;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use log::{info, warn};
+
+/// Link counts of directories with more subdirectories than fit in `i_links_count`
+/// are stored as 1 when the file system has `dir_nlink`.
+const DIR_NLINK_OVERFLOW: u16 = 1;
+/// Upper bound of the orphan list walk, protecting against loops.
+const MAX_ORPHANS: usize = 1 << 16;
+
+/// An inconsistency found by [Fsck].
+///
+/// Inode 0 in an owner list stands for the file system metadata itself
+/// (superblocks, descriptor tables, bitmaps and inode tables).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// The journal holds transactions that were never replayed.
+    JournalNeedsRecovery,
+    SuperblockChecksum {
+        stored: u32,
+        computed: u32,
+    },
+    GroupDescriptorChecksum {
+        group: u32,
+        stored: u16,
+        computed: u16,
+    },
+    /// A bitmap or inode table of the group lies outside of the file system.
+    GroupMetadataOutOfRange {
+        group: u32,
+        block: u64,
+    },
+    BlockBitmapChecksum {
+        group: u32,
+    },
+    InodeBitmapChecksum {
+        group: u32,
+    },
+    InodeChecksum {
+        ino: u32,
+    },
+    ExtentBlockChecksum {
+        ino: u32,
+        block: u64,
+    },
+    DirBlockChecksum {
+        ino: u32,
+        block: u64,
+    },
+    XattrBlockChecksum {
+        ino: u32,
+        block: u64,
+    },
+    CorruptExtentTree {
+        ino: u32,
+    },
+    CorruptDirBlock {
+        ino: u32,
+        block: u64,
+    },
+    /// The inode references a block outside of the file system.
+    BlockOutOfRange {
+        ino: u32,
+        block: u64,
+    },
+    /// `count` blocks starting at `start` are claimed by all of `owners`.
+    MultiplyClaimedBlocks {
+        start: u64,
+        count: u64,
+        owners: Vec<u32>,
+    },
+    /// The block bitmap disagrees with the blocks actually in use.
+    BlockBitmapDifference {
+        start: u64,
+        count: u64,
+        marked_used: bool,
+    },
+    /// The inode bitmap disagrees with the inodes actually in use.
+    InodeBitmapDifference {
+        start: u32,
+        count: u32,
+        marked_used: bool,
+    },
+    GroupFreeBlocks {
+        group: u32,
+        stored: u32,
+        counted: u32,
+    },
+    GroupFreeInodes {
+        group: u32,
+        stored: u32,
+        counted: u32,
+    },
+    GroupUsedDirs {
+        group: u32,
+        stored: u32,
+        counted: u32,
+    },
+    SuperblockFreeBlocks {
+        stored: u64,
+        counted: u64,
+    },
+    SuperblockFreeInodes {
+        stored: u32,
+        counted: u32,
+    },
+    LinkCount {
+        ino: u32,
+        stored: u16,
+        counted: u32,
+    },
+    /// A directory entry holds an inode number that is out of range or reserved.
+    EntryInvalidInode {
+        dir: u32,
+        name: String,
+        ino: u32,
+    },
+    /// A directory entry points at an inode that is not in use.
+    EntryUnusedInode {
+        dir: u32,
+        name: String,
+        ino: u32,
+    },
+    /// A second entry pointing at a directory, which can only have one.
+    DirectoryHardLink {
+        dir: u32,
+        name: String,
+        ino: u32,
+    },
+    EntryFileType {
+        dir: u32,
+        name: String,
+        ino: u32,
+        stored: u8,
+        expected: u8,
+    },
+    /// An inode in use that no directory entry points at.
+    UnattachedInode {
+        ino: u32,
+    },
+    /// The top of a directory tree that cannot be reached from the root.
+    UnconnectedDirectory {
+        ino: u32,
+        parent: u32,
+    },
+    /// An inode on the orphan list, i.e. deleted while still open.
+    OrphanInode {
+        ino: u32,
+    },
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        use FsckProblem::*;
+        match self {
+            JournalNeedsRecovery => write!(f, "journal needs recovery"),
+            SuperblockChecksum { stored, computed } => write!(
+                f,
+                "superblock checksum {:#010x} does not match {:#010x}",
+                stored, computed
+            ),
+            GroupDescriptorChecksum {
+                group,
+                stored,
+                computed,
+            } => write!(
+                f,
+                "group {} descriptor checksum {:#06x} does not match {:#06x}",
+                group, stored, computed
+            ),
+            GroupMetadataOutOfRange { group, block } => {
+                write!(f, "group {} metadata block {} out of range", group, block)
+            }
+            BlockBitmapChecksum { group } => write!(f, "group {} block bitmap checksum", group),
+            InodeBitmapChecksum { group } => write!(f, "group {} inode bitmap checksum", group),
+            InodeChecksum { ino } => write!(f, "inode {} checksum", ino),
+            ExtentBlockChecksum { ino, block } => {
+                write!(f, "inode {} extent block {} checksum", ino, block)
+            }
+            DirBlockChecksum { ino, block } => {
+                write!(f, "directory {} block {} checksum", ino, block)
+            }
+            XattrBlockChecksum { ino, block } => {
+                write!(f, "inode {} xattr block {} checksum", ino, block)
+            }
+            CorruptExtentTree { ino } => write!(f, "inode {} has a corrupt extent tree", ino),
+            CorruptDirBlock { ino, block } => {
+                write!(f, "directory {} block {} is corrupt", ino, block)
+            }
+            BlockOutOfRange { ino, block } => {
+                write!(f, "inode {} references block {} out of range", ino, block)
+            }
+            MultiplyClaimedBlocks {
+                start,
+                count,
+                owners,
+            } => write!(
+                f,
+                "blocks {} claimed by inodes {:?}",
+                Span(*start, *count),
+                owners
+            ),
+            BlockBitmapDifference {
+                start,
+                count,
+                marked_used,
+            } => write!(
+                f,
+                "blocks {} marked {} in bitmap",
+                Span(*start, *count),
+                if *marked_used { "used" } else { "free" }
+            ),
+            InodeBitmapDifference {
+                start,
+                count,
+                marked_used,
+            } => write!(
+                f,
+                "inodes {} marked {} in bitmap",
+                Span(*start as u64, *count as u64),
+                if *marked_used { "used" } else { "free" }
+            ),
+            GroupFreeBlocks {
+                group,
+                stored,
+                counted,
+            } => write!(
+                f,
+                "group {} free blocks count {}, counted {}",
+                group, stored, counted
+            ),
+            GroupFreeInodes {
+                group,
+                stored,
+                counted,
+            } => write!(
+                f,
+                "group {} free inodes count {}, counted {}",
+                group, stored, counted
+            ),
+            GroupUsedDirs {
+                group,
+                stored,
+                counted,
+            } => write!(
+                f,
+                "group {} directories count {}, counted {}",
+                group, stored, counted
+            ),
+            SuperblockFreeBlocks { stored, counted } => {
+                write!(f, "free blocks count {}, counted {}", stored, counted)
+            }
+            SuperblockFreeInodes { stored, counted } => {
+                write!(f, "free inodes count {}, counted {}", stored, counted)
+            }
+            LinkCount {
+                ino,
+                stored,
+                counted,
+            } => write!(
+                f,
+                "inode {} link count {}, counted {}",
+                ino, stored, counted
+            ),
+            EntryInvalidInode { dir, name, ino } => write!(
+                f,
+                "entry '{}' in directory {} has invalid inode {}",
+                name, dir, ino
+            ),
+            EntryUnusedInode { dir, name, ino } => write!(
+                f,
+                "entry '{}' in directory {} points at unused inode {}",
+                name, dir, ino
+            ),
+            DirectoryHardLink { dir, name, ino } => write!(
+                f,
+                "entry '{}' in directory {} is a link to directory {}",
+                name, dir, ino
+            ),
+            EntryFileType {
+                dir,
+                name,
+                ino,
+                stored,
+                expected,
+            } => write!(
+                f,
+                "entry '{}' in directory {} (inode {}) has file type {}, expected {}",
+                name, dir, ino, stored, expected
+            ),
+            UnattachedInode { ino } => write!(f, "inode {} is not in any directory", ino),
+            UnconnectedDirectory { ino, parent } => write!(
+                f,
+                "directory {} (parent {}) is not connected to the root",
+                ino, parent
+            ),
+            OrphanInode { ino } => write!(f, "inode {} is on the orphan list", ino),
+        }
+    }
+}
+
+/// A change made by [Fsck] in repair mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckRepair {
+    SuperblockChecksum,
+    GroupDescriptorChecksum {
+        group: u32,
+    },
+    InodeChecksum {
+        ino: u32,
+    },
+    ExtentBlockChecksum {
+        ino: u32,
+        block: u64,
+    },
+    DirBlockChecksum {
+        ino: u32,
+        block: u64,
+    },
+    XattrBlockChecksum {
+        ino: u32,
+        block: u64,
+    },
+    /// Took the inode off the orphan list, releasing it if it has no links left.
+    ReleasedOrphan {
+        ino: u32,
+    },
+    /// Dropped the references of the inode to `count` blocks starting at `start`.
+    ClearedBlocks {
+        ino: u32,
+        start: u64,
+        count: u64,
+    },
+    ClearedXattrBlock {
+        ino: u32,
+        block: u64,
+    },
+    ClearedEntry {
+        dir: u32,
+        name: String,
+        ino: u32,
+    },
+    EntryFileType {
+        dir: u32,
+        name: String,
+        ino: u32,
+        file_type: u8,
+    },
+    /// Created the missing `lost+found` below the root as inode `ino`.
+    CreatedLostFound {
+        ino: u32,
+    },
+    /// Linked the inode into `lost+found` as `name`.
+    MovedToLostFound {
+        ino: u32,
+        name: String,
+    },
+    LinkCount {
+        ino: u32,
+        from: u16,
+        to: u16,
+    },
+    BlockBitmap {
+        group: u32,
+    },
+    InodeBitmap {
+        group: u32,
+    },
+    /// Rewrote the free block, free inode and directory counts of the group.
+    GroupCounts {
+        group: u32,
+    },
+    SuperblockCounts,
+}
+
+impl Display for FsckRepair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        use FsckRepair::*;
+        match self {
+            SuperblockChecksum => write!(f, "rewrote superblock checksum"),
+            GroupDescriptorChecksum { group } => {
+                write!(f, "rewrote group {} descriptor checksum", group)
+            }
+            InodeChecksum { ino } => write!(f, "rewrote inode {} checksum", ino),
+            ExtentBlockChecksum { ino, block } => {
+                write!(f, "rewrote inode {} extent block {} checksum", ino, block)
+            }
+            DirBlockChecksum { ino, block } => {
+                write!(f, "rewrote directory {} block {} checksum", ino, block)
+            }
+            XattrBlockChecksum { ino, block } => {
+                write!(f, "rewrote inode {} xattr block {} checksum", ino, block)
+            }
+            ReleasedOrphan { ino } => write!(f, "released orphan inode {}", ino),
+            ClearedBlocks { ino, start, count } => write!(
+                f,
+                "cleared references of inode {} to blocks {}",
+                ino,
+                Span(*start, *count)
+            ),
+            ClearedXattrBlock { ino, block } => {
+                write!(f, "cleared inode {} xattr block {}", ino, block)
+            }
+            ClearedEntry { dir, name, ino } => write!(
+                f,
+                "cleared entry '{}' (inode {}) in directory {}",
+                name, ino, dir
+            ),
+            EntryFileType {
+                dir,
+                name,
+                ino,
+                file_type,
+            } => write!(
+                f,
+                "set file type of entry '{}' (inode {}) in directory {} to {}",
+                name, ino, dir, file_type
+            ),
+            CreatedLostFound { ino } => write!(f, "created /lost+found as inode {}", ino),
+            MovedToLostFound { ino, name } => {
+                write!(f, "moved inode {} to /lost+found/{}", ino, name)
+            }
+            LinkCount { ino, from, to } => {
+                write!(f, "set inode {} link count from {} to {}", ino, from, to)
+            }
+            BlockBitmap { group } => write!(f, "rebuilt group {} block bitmap", group),
+            InodeBitmap { group } => write!(f, "rebuilt group {} inode bitmap", group),
+            GroupCounts { group } => write!(f, "rewrote group {} counts", group),
+            SuperblockCounts => write!(f, "rewrote superblock free counts"),
+        }
+    }
+}
+
+/// An inclusive range of block or inode numbers, formatted like e2fsck does.
+struct Span(u64, u64);
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.1 {
+            1 => write!(f, "{}", self.0),
+            n => write!(f, "{}-{}", self.0, self.0 + n - 1),
+        }
+    }
+}
+
+/// Result of an offline file system check.
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub groups: u32,
+    pub blocks_count: u64,
+    pub blocks_used: u64,
+    pub inodes_count: u32,
+    pub inodes_used: u32,
+    pub directories: u32,
+    /// Problems found before any repair.
+    pub problems: Vec<FsckProblem>,
+    /// Changes made in repair mode.
+    pub repairs: Vec<FsckRepair>,
+    /// Problems left after the repairs, the same as `problems` in check-only mode.
+    pub remaining: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    /// Whether no problem was found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Whether the file system is consistent after the repairs, if any.
+    pub fn is_consistent(&self) -> bool {
+        self.remaining.is_empty()
+    }
+}
+
+/// Offline consistency checker for an unmounted file system, similar to `e2fsck -n`,
+/// or `e2fsck -y` in [repair](Fsck::repair) mode.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, Fsck};
+/// let file = std::fs::File::open("ext2.img").unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let report = Fsck::new().check(&mut blk).unwrap();
+/// for problem in &report.problems {
+///     println!("{}", problem);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Fsck {
+    checksums: bool,
+    repair: bool,
+}
+
+impl Default for Fsck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fsck {
+    pub fn new() -> Self {
+        Self {
+            checksums: true,
+            repair: false,
+        }
+    }
+
+    /// Whether to verify metadata checksums (if the file system has them), defaults to true.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Whether to repair the problems found, defaults to false.
+    ///
+    /// Bitmaps and free counts are rebuilt, link counts fixed, orphans released,
+    /// disconnected inodes moved to `lost+found` (created if missing), bad checksums
+    /// rewritten and entries or block references pointing at invalid, unused or
+    /// multiply claimed targets cleared. Every change is listed in
+    /// [FsckReport::repairs]. Nothing is touched if the journal needs recovery, which
+    /// has to happen first.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Check the file system on `bdev`, modifying it only in repair mode.
+    pub fn check<T: BlockDeviceInterface>(&self, bdev: &mut BlockDevice<T>) -> Result<FsckReport> {
+        let mut disk = Disk::open(bdev)?;
+        if disk.sb.has_ro_compat(RO_COMPAT_BIGALLOC) {
+            return Err(Error::NotSupported);
+        }
+        disk.load_groups()?;
+        let mut checker = Checker::new(&mut disk, self.checksums, false);
+        checker.run()?;
+        let problems = checker.report.problems.clone();
+        let recover = problems.contains(&FsckProblem::JournalNeedsRecovery);
+        if self.repair && recover {
+            warn!("fsck: journal needs recovery, not repairing");
+        }
+        if !self.repair || problems.is_empty() || recover {
+            let mut report = checker.report;
+            report.remaining = problems;
+            info!(
+                "fsck: {} problems in {} groups",
+                report.problems.len(),
+                report.groups
+            );
+            return Ok(report);
+        }
+        // Repairs that only need the findings of the first pass.
+        checker.fix_checksums()?;
+        checker.release_orphans()?;
+        checker.clear_block_refs()?;
+        checker.fix_entries()?;
+        let mut repairs = checker.repairs;
+        // Reconnecting needs the reachability after the entries were fixed.
+        let mut checker = Checker::new(&mut disk, self.checksums, false);
+        checker.run()?;
+        checker.reconnect()?;
+        repairs.append(&mut checker.repairs);
+        // Link counts, bitmaps and free counts are rewritten while checking.
+        let mut checker = Checker::new(&mut disk, self.checksums, true);
+        checker.run()?;
+        repairs.append(&mut checker.repairs);
+        let mut checker = Checker::new(&mut disk, self.checksums, false);
+        checker.run()?;
+        let mut report = checker.report;
+        report.remaining = core::mem::replace(&mut report.problems, problems);
+        report.repairs = repairs;
+        disk.close()?;
+        info!(
+            "fsck: {} problems, {} repairs, {} left",
+            report.problems.len(),
+            report.repairs.len(),
+            report.remaining.len()
+        );
+        Ok(report)
+    }
+}
+
+/// Blocks and inodes in use on a consistent file system, as found by a check.
+pub(crate) struct Usage {
+    /// Blocks claimed by anything (metadata or inodes).
+    pub(crate) claimed: BitSet,
+    /// Blocks taken by the file system metadata of all groups.
+    pub(crate) overhead: BitSet,
+    /// Inodes in use, including reserved inodes.
+    pub(crate) used: BitSet,
+}
+
+/// Check the file system on `disk`, which must have its groups loaded, and collect
+/// what is in use. Fails with [Error::InvalidArgument] if any problem is found.
+pub(crate) fn scan_usage<T: BlockDeviceInterface>(disk: &mut Disk<'_, T>) -> Result<Usage> {
+    let mut checker = Checker::new(disk, true, false);
+    checker.run()?;
+    if let Some(problem) = checker.report.problems.first() {
+        warn!(
+            "fsck: {} problems, first: {}",
+            checker.report.problems.len(),
+            problem
+        );
+        return Err(Error::InvalidArgument);
+    }
+    Ok(Usage {
+        claimed: checker.claimed,
+        overhead: checker.overhead,
+        used: checker.used,
+    })
+}
+
+/// Rewrite the bitmaps, free counts and link counts of `disk` from what is reachable,
+/// after its metadata was changed in place.
+pub(crate) fn rebuild_allocation<T: BlockDeviceInterface>(disk: &mut Disk<'_, T>) -> Result<()> {
+    Checker::new(disk, true, true).run()
+}
+
+/// What is known about an inode in use after the inode scan.
+struct InodeInfo {
+    ty: FileType,
+    links: u16,
+    /// The data blocks of directories, parsed in the directory pass.
+    dir_blocks: Vec<Extent>,
+    /// The `i_block` area of directories with inline data.
+    inline: Option<Vec<u8>>,
+    /// Target of the `..` entry of a directory.
+    parent: u32,
+    /// Block and offset of the `..` entry of a directory.
+    dotdot: Option<(u64, usize)>,
+}
+
+/// How to fix a directory entry in repair mode.
+enum EntryAction {
+    Clear,
+    SetType(u8),
+}
+
+struct EntryFix {
+    dir: u32,
+    block: u64,
+    offset: usize,
+    name: String,
+    ino: u32,
+    action: EntryAction,
+}
+
+/// Coalesces single bitmap differences into ranges.
+#[derive(Default)]
+struct Differences {
+    ranges: Vec<(u64, u64, bool)>,
+}
+
+impl Differences {
+    fn push(&mut self, i: u64, marked_used: bool) {
+        if let Some((start, count, used)) = self.ranges.last_mut() {
+            if *start + *count == i && *used == marked_used {
+                *count += 1;
+                return;
+            }
+        }
+        self.ranges.push((i, 1, marked_used));
+    }
+}
+
+struct Checker<'a, 'b, T: BlockDeviceInterface> {
+    disk: &'b mut Disk<'a, T>,
+    checksums: bool,
+    report: FsckReport,
+    /// Blocks taken by the file system metadata of all groups.
+    overhead: BitSet,
+    /// Blocks claimed by anything (metadata or inodes).
+    claimed: BitSet,
+    dups: BTreeSet<u64>,
+    /// Inodes in use, including reserved inodes and orphans.
+    used: BitSet,
+    inodes: BTreeMap<u32, InodeInfo>,
+    orphans: BTreeSet<u32>,
+    xattr_blocks: BTreeSet<u64>,
+    /// Number of directory entries pointing at every inode.
+    refs: BTreeMap<u32, u32>,
+    /// Subdirectories of every directory, from its entries.
+    children: BTreeMap<u32, Vec<u32>>,
+    /// Directories with an entry in their parent, to find a second one.
+    linked_dirs: BTreeSet<u32>,
+    /// The `lost+found` directory below the root, if any.
+    lost_found: Option<u32>,
+    /// Fix link counts, bitmaps and free counts while checking them.
+    fix: bool,
+    entry_fixes: Vec<EntryFix>,
+    repairs: Vec<FsckRepair>,
+}
+
+impl<'a, 'b, T: BlockDeviceInterface> Checker<'a, 'b, T> {
+    fn new(disk: &'b mut Disk<'a, T>, checksums: bool, fix: bool) -> Self {
+        let sb = &disk.sb;
+        let report = FsckReport {
+            groups: sb.group_count(),
+            blocks_count: sb.blocks_count(),
+            inodes_count: sb.inodes_count(),
+            ..Default::default()
+        };
+        let blocks = sb.blocks_count();
+        let inodes = sb.inodes_count() as u64 + 1;
+        Self {
+            disk,
+            checksums,
+            report,
+            overhead: BitSet::new(blocks),
+            claimed: BitSet::new(blocks),
+            dups: BTreeSet::new(),
+            used: BitSet::new(inodes),
+            inodes: BTreeMap::new(),
+            orphans: BTreeSet::new(),
+            xattr_blocks: BTreeSet::new(),
+            refs: BTreeMap::new(),
+            children: BTreeMap::new(),
+            linked_dirs: BTreeSet::new(),
+            lost_found: None,
+            fix,
+            entry_fixes: Vec::new(),
+            repairs: Vec::new(),
+        }
+    }
+
+    fn problem(&mut self, problem: FsckProblem) {
+        self.report.problems.push(problem);
+    }
+
+    fn claim(&mut self, block: u64) {
+        if self.claimed.set(block) {
+            self.dups.insert(block);
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        self.check_superblock();
+        self.check_groups();
+        self.read_orphans()?;
+        self.scan_inodes()?;
+        if !self.dups.is_empty() {
+            self.report_dups()?;
+        }
+        self.check_directories()?;
+        self.check_links()?;
+        self.check_bitmaps()
+    }
+
+    fn check_superblock(&mut self) {
+        let sb = self.disk.sb.clone();
+        if sb.has_incompat(INCOMPAT_RECOVER) {
+            self.problem(FsckProblem::JournalNeedsRecovery);
+        }
+        if self.checksums && sb.metadata_csum() && sb.checksum() != sb.compute_checksum() {
+            let problem = FsckProblem::SuperblockChecksum {
+                stored: sb.checksum(),
+                computed: sb.compute_checksum(),
+            };
+            self.problem(problem);
+        }
+    }
+
+    /// Verify the descriptors and claim the blocks of the per-group metadata.
+    fn check_groups(&mut self) {
+        let sb = self.disk.sb.clone();
+        let table_blocks = sb.inode_table_blocks() as u64;
+        for group in 0..sb.group_count() {
+            if self.checksums {
+                if let Some(computed) = self.disk.group_checksum(group) {
+                    let stored = self.disk.groups[group as usize].checksum();
+                    if stored != computed {
+                        self.problem(FsckProblem::GroupDescriptorChecksum {
+                            group,
+                            stored,
+                            computed,
+                        });
+                    }
+                }
+            }
+            let mut blocks = sb.group_super_blocks(group);
+            let gd = &self.disk.groups[group as usize];
+            blocks.push(gd.block_bitmap());
+            blocks.push(gd.inode_bitmap());
+            blocks.extend((0..table_blocks).map(|i| gd.inode_table() + i));
+            for block in blocks {
+                if !self.disk.valid_block(block) {
+                    self.problem(FsckProblem::GroupMetadataOutOfRange { group, block });
+                    continue;
+                }
+                self.overhead.set(block);
+                self.claim(block);
+            }
+        }
+    }
+
+    fn read_orphans(&mut self) -> Result<()> {
+        let mut ino = self.disk.sb.last_orphan();
+        while ino != 0 && ino <= self.disk.sb.inodes_count() && self.orphans.len() < MAX_ORPHANS {
+            if !self.orphans.insert(ino) {
+                break;
+            }
+            self.problem(FsckProblem::OrphanInode { ino });
+            ino = self.disk.read_inode(ino)?.dtime();
+        }
+        Ok(())
+    }
+
+    fn is_used(&self, ino: u32, inode: &Inode) -> bool {
+        if ino < self.disk.sb.first_ino() {
+            return true;
+        }
+        (inode.links_count() > 0 && inode.mode() != 0) || self.orphans.contains(&ino)
+    }
+
+    /// Find the inodes in use and claim the blocks they reference.
+    fn scan_inodes(&mut self) -> Result<()> {
+        let first_ino = self.disk.sb.first_ino();
+        for group in 0..self.disk.sb.group_count() {
+            if !self
+                .disk
+                .valid_block(self.disk.groups[group as usize].inode_table())
+            {
+                continue;
+            }
+            for (ino, inode) in self.disk.read_inode_table(group)? {
+                if !self.is_used(ino, &inode) {
+                    continue;
+                }
+                self.used.set(ino as u64);
+                if inode.is_unused_slot() {
+                    continue;
+                }
+                let csum_ok = !(self.checksums && self.disk.sb.metadata_csum())
+                    || inode.checksum() == self.disk.inode_checksum(ino, &inode);
+                if !csum_ok {
+                    self.problem(FsckProblem::InodeChecksum { ino });
+                }
+                if ino < first_ino && ino != ROOT_INO {
+                    self.claim_reserved(ino, &inode)?;
+                    continue;
+                }
+                let map = self.claim_inode(ino, &inode)?;
+                if inode.is_dir() {
+                    self.report.directories += 1;
+                }
+                let inline = (inode.is_dir() && inode.flags() & INODE_FLAG_INLINE_DATA != 0)
+                    .then(|| inode.block_area().to_vec());
+                self.inodes.insert(
+                    ino,
+                    InodeInfo {
+                        ty: inode.file_type(),
+                        links: inode.links_count(),
+                        dir_blocks: if inode.is_dir() { map.extents } else { vec![] },
+                        inline,
+                        parent: 0,
+                        dotdot: None,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Claim the blocks of a reserved inode other than the root directory.
+    fn claim_reserved(&mut self, ino: u32, inode: &Inode) -> Result<()> {
+        if ino == RESIZE_INO {
+            // The resize inode maps the reserved descriptor blocks, which are already
+            // claimed as group metadata; only its double indirect block is its own.
+            let dind = le32(inode.block_area(), 13 * 4) as u64;
+            if dind != 0 && self.disk.valid_block(dind) {
+                self.claim(dind);
+            }
+            return Ok(());
+        }
+        self.claim_inode(ino, inode)?;
+        Ok(())
+    }
+
+    fn claim_inode(&mut self, ino: u32, inode: &Inode) -> Result<BlockMap> {
+        let map = self.disk.map_inode(ino, inode)?;
+        if map.corrupt {
+            self.problem(FsckProblem::CorruptExtentTree { ino });
+        }
+        for &block in &map.out_of_range {
+            self.problem(FsckProblem::BlockOutOfRange { ino, block });
+        }
+        if self.checksums {
+            for &block in &map.bad_csum {
+                self.problem(FsckProblem::ExtentBlockChecksum { ino, block });
+            }
+        }
+        for &block in &map.meta {
+            self.claim(block);
+        }
+        for extent in &map.extents {
+            for block in extent.physical..extent.physical + extent.len {
+                self.claim(block);
+            }
+        }
+        let acl = inode.file_acl();
+        if acl != 0 {
+            if !self.disk.valid_block(acl) {
+                self.problem(FsckProblem::BlockOutOfRange { ino, block: acl });
+            } else if self.xattr_blocks.insert(acl) {
+                // Extended attribute blocks are shared between inodes with equal attributes.
+                self.claim(acl);
+                if self.checksums && self.disk.sb.metadata_csum() {
+                    let data = self.disk.read_block(acl)?;
+                    if let Some((stored, computed)) = self.disk.xattr_block_checksum(acl, &data) {
+                        if stored != computed {
+                            self.problem(FsckProblem::XattrBlockChecksum { ino, block: acl });
+                        }
+                    }
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Find the owners of multiply claimed blocks, c.f. pass 1b of e2fsck.
+    fn report_dups(&mut self) -> Result<()> {
+        let mut owners: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+        for &block in &self.dups {
+            let list = owners.entry(block).or_default();
+            if self.overhead.get(block) {
+                list.push(0);
+            }
+        }
+        let first_ino = self.disk.sb.first_ino();
+        for ino in 1..=self.disk.sb.inodes_count() {
+            if !self.used.get(ino as u64) || ino == RESIZE_INO {
+                continue;
+            }
+            let inode = self.disk.read_inode(ino)?;
+            if inode.is_unused_slot() && ino < first_ino {
+                continue;
+            }
+            let map = self.disk.map_inode(ino, &inode)?;
+            let extents = map
+                .extents
+                .iter()
+                .flat_map(|e| e.physical..e.physical + e.len);
+            let mut blocks: Vec<u64> = map.meta.iter().copied().chain(extents).collect();
+            if inode.file_acl() != 0 {
+                blocks.push(inode.file_acl());
+            }
+            for block in blocks {
+                if let Some(list) = owners.get_mut(&block) {
+                    if list.last() != Some(&ino) {
+                        list.push(ino);
+                    }
+                }
+            }
+        }
+        let mut ranges: Vec<(u64, u64, Vec<u32>)> = Vec::new();
+        for (block, list) in owners {
+            if let Some((start, count, last)) = ranges.last_mut() {
+                if *start + *count == block && *last == list {
+                    *count += 1;
+                    continue;
+                }
+            }
+            ranges.push((block, 1, list));
+        }
+        for (start, count, owners) in ranges {
+            self.problem(FsckProblem::MultiplyClaimedBlocks {
+                start,
+                count,
+                owners,
+            });
+        }
+        Ok(())
+    }
+
+    /// Walk all directories, checking their entries and counting references.
+    fn check_directories(&mut self) -> Result<()> {
+        let dirs: Vec<u32> = self
+            .inodes
+            .iter()
+            .filter(|(_, info)| info.ty.is_dir())
+            .map(|(&ino, _)| ino)
+            .collect();
+        let filetype = self.disk.sb.has_incompat(INCOMPAT_FILETYPE);
+        for dir in dirs {
+            let info = &self.inodes[&dir];
+            if let Some(area) = info.inline.clone() {
+                // Inline directories store the parent first and have no "." entry.
+                let parent = le32(&area, 0);
+                self.add_entry(dir, b"..", parent, 0, false, None);
+                self.add_entry(dir, b".", dir, 0, false, None);
+                let (entries, complete) = parse_dir_block(&area[4..], filetype);
+                if !complete {
+                    self.problem(FsckProblem::CorruptDirBlock { ino: dir, block: 0 });
+                }
+                for e in entries {
+                    self.add_entry(dir, &e.name, e.inode, e.file_type, filetype, None);
+                }
+                continue;
+            }
+            let blocks: Vec<u64> = info
+                .dir_blocks
+                .iter()
+                .filter(|e| !e.uninit)
+                .flat_map(|e| e.physical..e.physical + e.len)
+                .collect();
+            let seed = match self.checksums && self.disk.sb.metadata_csum() {
+                true => {
+                    let inode = self.disk.read_inode(dir)?;
+                    Some(self.disk.inode_csum_seed(dir, &inode))
+                }
+                false => None,
+            };
+            for block in blocks {
+                let data = self.disk.read_block(block)?;
+                if let Some(seed) = seed {
+                    if let Some((stored, computed)) = self.disk.dir_block_checksum(seed, &data) {
+                        if stored != computed {
+                            self.problem(FsckProblem::DirBlockChecksum { ino: dir, block });
+                        }
+                    }
+                }
+                let (entries, complete) = parse_dir_block(&data, filetype);
+                if !complete {
+                    self.problem(FsckProblem::CorruptDirBlock { ino: dir, block });
+                }
+                for e in entries {
+                    let location = Some((block, e.offset));
+                    self.add_entry(dir, &e.name, e.inode, e.file_type, filetype, location);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Account for a directory entry. `location` is the block and offset of the entry,
+    /// if it can be fixed in place.
+    fn add_entry(
+        &mut self,
+        dir: u32,
+        name: &[u8],
+        ino: u32,
+        file_type: u8,
+        check_type: bool,
+        location: Option<(u64, usize)>,
+    ) {
+        let display = String::from_utf8_lossy(name).to_string();
+        let sb = &self.disk.sb;
+        let (problem, action) =
+            if ino > sb.inodes_count() || (ino < sb.first_ino() && ino != ROOT_INO) {
+                let problem = FsckProblem::EntryInvalidInode {
+                    dir,
+                    name: display.clone(),
+                    ino,
+                };
+                (problem, EntryAction::Clear)
+            } else if self.inodes.get(&ino).is_some_and(|t| t.ty.is_dir())
+                && !matches!(name, b"." | b"..")
+                && !self.linked_dirs.insert(ino)
+            {
+                let problem = FsckProblem::DirectoryHardLink {
+                    dir,
+                    name: display.clone(),
+                    ino,
+                };
+                (problem, EntryAction::Clear)
+            } else if let Some(target) = self.inodes.get(&ino) {
+                let expected = target.ty.to_ext4();
+                let target_is_dir = target.ty.is_dir();
+                *self.refs.entry(ino).or_default() += 1;
+                match name {
+                    b"." => {}
+                    b".." => {
+                        let info = self.inodes.get_mut(&dir).unwrap();
+                        info.parent = ino;
+                        info.dotdot = location;
+                    }
+                    _ if target_is_dir => {
+                        self.children.entry(dir).or_default().push(ino);
+                        if dir == ROOT_INO && name == b"lost+found" {
+                            self.lost_found = Some(ino);
+                        }
+                    }
+                    _ => {}
+                }
+                if !check_type || file_type == expected {
+                    return;
+                }
+                let problem = FsckProblem::EntryFileType {
+                    dir,
+                    name: display.clone(),
+                    ino,
+                    stored: file_type,
+                    expected,
+                };
+                (problem, EntryAction::SetType(expected))
+            } else {
+                let problem = FsckProblem::EntryUnusedInode {
+                    dir,
+                    name: display.clone(),
+                    ino,
+                };
+                (problem, EntryAction::Clear)
+            };
+        self.problem(problem);
+        if let Some((block, offset)) = location {
+            self.entry_fixes.push(EntryFix {
+                dir,
+                block,
+                offset,
+                name: display,
+                ino,
+                action,
+            });
+        }
+    }
+
+    /// Compare link counts with the references found and look for inodes that are
+    /// not reachable from the root directory.
+    fn check_links(&mut self) -> Result<()> {
+        let mut reachable = BTreeSet::new();
+        let mut queue = vec![ROOT_INO];
+        while let Some(dir) = queue.pop() {
+            if !reachable.insert(dir) {
+                continue;
+            }
+            if let Some(children) = self.children.get(&dir) {
+                queue.extend(children.iter().copied());
+            }
+        }
+        let dir_nlink = self.disk.sb.has_ro_compat(RO_COMPAT_DIR_NLINK);
+        let mut problems = Vec::new();
+        let unreachable: BTreeSet<u32> = self
+            .inodes
+            .iter()
+            .filter(|(ino, info)| info.ty.is_dir() && !reachable.contains(ino))
+            .map(|(&ino, _)| ino)
+            .collect();
+        let below_unreachable: BTreeSet<u32> = unreachable
+            .iter()
+            .filter_map(|dir| self.children.get(dir))
+            .flatten()
+            .copied()
+            .collect();
+        for (&ino, info) in &self.inodes {
+            if self.orphans.contains(&ino) {
+                continue;
+            }
+            let counted = self.refs.get(&ino).copied().unwrap_or(0);
+            if info.ty.is_dir() && unreachable.contains(&ino) {
+                if !below_unreachable.contains(&ino) {
+                    problems.push(FsckProblem::UnconnectedDirectory {
+                        ino,
+                        parent: info.parent,
+                    });
+                }
+            } else if counted == 0 {
+                problems.push(FsckProblem::UnattachedInode { ino });
+                continue;
+            }
+            let overflow = info.ty.is_dir() && dir_nlink && info.links == DIR_NLINK_OVERFLOW;
+            if counted != info.links as u32 && counted != 0 && !overflow {
+                problems.push(FsckProblem::LinkCount {
+                    ino,
+                    stored: info.links,
+                    counted,
+                });
+            }
+        }
+        if self.fix {
+            for problem in &problems {
+                if let FsckProblem::LinkCount { ino, counted, .. } = *problem {
+                    let mut inode = self.disk.read_inode(ino)?;
+                    let from = inode.links_count();
+                    let to = counted.min(u16::MAX as u32) as u16;
+                    inode.set_links_count(to);
+                    self.disk.write_inode(ino, &mut inode)?;
+                    self.repairs.push(FsckRepair::LinkCount { ino, from, to });
+                }
+            }
+        }
+        self.report.problems.extend(problems);
+        Ok(())
+    }
+
+    /// Compare the bitmaps and free counts with what the scan found in use, and in
+    /// fix mode rewrite the groups that disagree.
+    fn check_bitmaps(&mut self) -> Result<()> {
+        let sb = self.disk.sb.clone();
+        let ipg = sb.inodes_per_group();
+        let bs = sb.block_size() as usize;
+        let csum = self.checksums && sb.metadata_csum();
+        let mut block_diffs = Differences::default();
+        let mut inode_diffs = Differences::default();
+        let mut total_free_blocks = 0u64;
+        let mut total_free_inodes = 0u32;
+        for group in 0..sb.group_count() {
+            let gd = self.disk.groups[group as usize].clone();
+            let first = sb.group_first_block(group);
+            let count = sb.group_blocks(group);
+
+            let uninit = sb.group_csum() && gd.flags() & BG_BLOCK_UNINIT != 0;
+            let valid = self.disk.valid_block(gd.block_bitmap());
+            let mut stale = false;
+            let bitmap = if !uninit && valid {
+                let bitmap = self.disk.read_block(gd.block_bitmap())?;
+                let (stored, full) = gd.block_bitmap_csum();
+                let computed = self.disk.block_bitmap_checksum(&bitmap);
+                if csum && stored != if full { computed } else { computed & 0xffff } {
+                    self.problem(FsckProblem::BlockBitmapChecksum { group });
+                    stale = true;
+                }
+                Some(bitmap)
+            } else {
+                None
+            };
+            let mut used = 0;
+            for i in 0..count {
+                let block = first + i as u64;
+                let in_use = self.claimed.get(block);
+                used += in_use as u32;
+                let marked = match &bitmap {
+                    Some(bitmap) => bitmap_get(bitmap, i as usize),
+                    None => self.overhead.get(block),
+                };
+                if marked != in_use {
+                    block_diffs.push(block, marked);
+                    stale = true;
+                }
+            }
+            if self.fix && stale && valid {
+                let mut bitmap = vec![0u8; bs];
+                for i in 0..bs * 8 {
+                    let in_use = i >= count as usize || self.claimed.get(first + i as u64);
+                    bitmap_set(&mut bitmap, i, in_use);
+                }
+                let checksum = self.disk.block_bitmap_checksum(&bitmap);
+                let gd = &mut self.disk.groups[group as usize];
+                gd.set_flags(gd.flags() & !BG_BLOCK_UNINIT);
+                if sb.metadata_csum() {
+                    gd.set_block_bitmap_csum(checksum);
+                }
+                let location = gd.block_bitmap();
+                self.disk.write_block(location, &bitmap)?;
+                self.repairs.push(FsckRepair::BlockBitmap { group });
+            }
+            let free_blocks = count - used;
+            total_free_blocks += free_blocks as u64;
+            if gd.free_blocks() != free_blocks {
+                self.problem(FsckProblem::GroupFreeBlocks {
+                    group,
+                    stored: gd.free_blocks(),
+                    counted: free_blocks,
+                });
+            }
+
+            let uninit = sb.group_csum() && gd.flags() & BG_INODE_UNINIT != 0;
+            let valid = self.disk.valid_block(gd.inode_bitmap());
+            let mut stale = false;
+            let bitmap = if !uninit && valid {
+                let bitmap = self.disk.read_block(gd.inode_bitmap())?;
+                let (stored, full) = gd.inode_bitmap_csum();
+                let computed = self.disk.inode_bitmap_checksum(&bitmap);
+                if csum && stored != if full { computed } else { computed & 0xffff } {
+                    self.problem(FsckProblem::InodeBitmapChecksum { group });
+                    stale = true;
+                }
+                Some(bitmap)
+            } else {
+                None
+            };
+            let mut used = 0;
+            let mut dirs = 0;
+            for i in 0..ipg {
+                let ino = group * ipg + i + 1;
+                if ino > sb.inodes_count() {
+                    break;
+                }
+                let in_use = self.used.get(ino as u64);
+                used += in_use as u32;
+                dirs += self.inodes.get(&ino).is_some_and(|info| info.ty.is_dir()) as u32;
+                let marked = match &bitmap {
+                    Some(bitmap) => bitmap_get(bitmap, i as usize),
+                    None => false,
+                };
+                if marked != in_use {
+                    inode_diffs.push(ino as u64, marked);
+                    stale = true;
+                }
+            }
+            if self.fix && stale && valid {
+                let mut bitmap = vec![0u8; bs];
+                for i in 0..bs * 8 {
+                    let in_use = i >= ipg as usize
+                        || self.used.get(group as u64 * ipg as u64 + i as u64 + 1);
+                    bitmap_set(&mut bitmap, i, in_use);
+                }
+                let checksum = self.disk.inode_bitmap_checksum(&bitmap);
+                let gd = &mut self.disk.groups[group as usize];
+                gd.set_flags(gd.flags() & !BG_INODE_UNINIT);
+                if sb.metadata_csum() {
+                    gd.set_inode_bitmap_csum(checksum);
+                }
+                let location = gd.inode_bitmap();
+                self.disk.write_block(location, &bitmap)?;
+                self.repairs.push(FsckRepair::InodeBitmap { group });
+            }
+            let free_inodes = ipg - used;
+            total_free_inodes += free_inodes;
+            if gd.free_inodes() != free_inodes {
+                self.problem(FsckProblem::GroupFreeInodes {
+                    group,
+                    stored: gd.free_inodes(),
+                    counted: free_inodes,
+                });
+            }
+            if gd.used_dirs() != dirs {
+                self.problem(FsckProblem::GroupUsedDirs {
+                    group,
+                    stored: gd.used_dirs(),
+                    counted: dirs,
+                });
+            }
+            let counts = (gd.free_blocks(), gd.free_inodes(), gd.used_dirs());
+            if self.fix && counts != (free_blocks, free_inodes, dirs) {
+                let gd = &mut self.disk.groups[group as usize];
+                gd.set_free_blocks(free_blocks);
+                gd.set_free_inodes(free_inodes);
+                gd.set_used_dirs(dirs);
+                self.repairs.push(FsckRepair::GroupCounts { group });
+            }
+        }
+        self.report.blocks_used = sb.blocks_count() - total_free_blocks;
+        self.report.inodes_used = sb.inodes_count() - total_free_inodes;
+        for (start, count, marked_used) in block_diffs.ranges {
+            self.problem(FsckProblem::BlockBitmapDifference {
+                start,
+                count,
+                marked_used,
+            });
+        }
+        for (start, count, marked_used) in inode_diffs.ranges {
+            self.problem(FsckProblem::InodeBitmapDifference {
+                start: start as u32,
+                count: count as u32,
+                marked_used,
+            });
+        }
+        if sb.free_blocks_count() != total_free_blocks {
+            self.problem(FsckProblem::SuperblockFreeBlocks {
+                stored: sb.free_blocks_count(),
+                counted: total_free_blocks,
+            });
+        }
+        if sb.free_inodes_count() != total_free_inodes {
+            self.problem(FsckProblem::SuperblockFreeInodes {
+                stored: sb.free_inodes_count(),
+                counted: total_free_inodes,
+            });
+        }
+        if self.fix {
+            let counts = (sb.free_blocks_count(), sb.free_inodes_count());
+            if counts != (total_free_blocks, total_free_inodes) {
+                self.disk.sb.set_free_blocks_count(total_free_blocks);
+                self.disk.sb.set_free_inodes_count(total_free_inodes);
+                self.repairs.push(FsckRepair::SuperblockCounts);
+            }
+            self.disk.flush_metadata()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the checksums found to be wrong.
+    fn fix_checksums(&mut self) -> Result<()> {
+        let mut flush = false;
+        for problem in self.report.problems.clone() {
+            let repair = match problem {
+                FsckProblem::SuperblockChecksum { .. } => {
+                    flush = true;
+                    FsckRepair::SuperblockChecksum
+                }
+                FsckProblem::GroupDescriptorChecksum { group, .. } => {
+                    flush = true;
+                    FsckRepair::GroupDescriptorChecksum { group }
+                }
+                FsckProblem::InodeChecksum { ino } => {
+                    let mut inode = self.disk.read_inode(ino)?;
+                    self.disk.write_inode(ino, &mut inode)?;
+                    FsckRepair::InodeChecksum { ino }
+                }
+                FsckProblem::ExtentBlockChecksum { ino, block } => {
+                    let seed = self.seed(ino)?;
+                    let mut data = self.disk.read_block(block)?;
+                    self.disk.update_extent_checksum(seed, &mut data);
+                    self.disk.write_block(block, &data)?;
+                    FsckRepair::ExtentBlockChecksum { ino, block }
+                }
+                FsckProblem::DirBlockChecksum { ino, block } => {
+                    let seed = self.seed(ino)?;
+                    let mut data = self.disk.read_block(block)?;
+                    self.disk.update_dir_block_checksum(seed, &mut data);
+                    self.disk.write_block(block, &data)?;
+                    FsckRepair::DirBlockChecksum { ino, block }
+                }
+                FsckProblem::XattrBlockChecksum { ino, block } => {
+                    let mut data = self.disk.read_block(block)?;
+                    self.disk.update_xattr_block_checksum(block, &mut data);
+                    self.disk.write_block(block, &data)?;
+                    FsckRepair::XattrBlockChecksum { ino, block }
+                }
+                _ => continue,
+            };
+            self.repairs.push(repair);
+        }
+        if flush {
+            self.disk.flush_metadata()?;
+        }
+        Ok(())
+    }
+
+    fn seed(&mut self, ino: u32) -> Result<u32> {
+        let inode = self.disk.read_inode(ino)?;
+        Ok(self.disk.inode_csum_seed(ino, &inode))
+    }
+
+    /// Empty the orphan list. Unlinked inodes get a deletion time, so that the
+    /// rebuilt bitmaps release them and their blocks.
+    fn release_orphans(&mut self) -> Result<()> {
+        if self.orphans.is_empty() {
+            return Ok(());
+        }
+        let now = self.disk.sb.write_time().max(1);
+        for ino in self.orphans.clone() {
+            let mut inode = self.disk.read_inode(ino)?;
+            inode.set_dtime(if inode.links_count() == 0 { now } else { 0 });
+            self.disk.write_inode(ino, &mut inode)?;
+            self.repairs.push(FsckRepair::ReleasedOrphan { ino });
+        }
+        self.disk.sb.set_last_orphan(0);
+        self.disk.write_superblock()
+    }
+
+    /// Drop references to blocks outside of the file system, and to multiply claimed
+    /// blocks from every owner but the first (from all of them if the blocks are
+    /// file system metadata).
+    fn clear_block_refs(&mut self) -> Result<()> {
+        let mut victims: BTreeMap<u32, Vec<(u64, u64)>> = BTreeMap::new();
+        for problem in &self.report.problems {
+            match problem {
+                FsckProblem::BlockOutOfRange { ino, .. } => {
+                    victims.entry(*ino).or_default();
+                }
+                FsckProblem::MultiplyClaimedBlocks {
+                    start,
+                    count,
+                    owners,
+                } => {
+                    let skip = if owners.contains(&0) { 0 } else { 1 };
+                    for &ino in owners.iter().filter(|&&ino| ino != 0).skip(skip) {
+                        victims.entry(ino).or_default().push((*start, *count));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let first = self.disk.sb.first_data_block() as u64;
+        let blocks = self.disk.sb.blocks_count();
+        for (ino, ranges) in victims {
+            let bad = |start: u64, len: u64| {
+                start < first
+                    || start + len > blocks
+                    || ranges
+                        .iter()
+                        .any(|&(s, n)| start < s + n && s < start + len)
+            };
+            let mut inode = self.disk.read_inode(ino)?;
+            let dropped = self.disk.prune_inode(ino, &mut inode, &bad)?;
+            for &(start, count) in &dropped {
+                self.repairs
+                    .push(FsckRepair::ClearedBlocks { ino, start, count });
+            }
+            let acl = inode.file_acl();
+            let clear_acl = acl != 0 && bad(acl, 1);
+            if clear_acl {
+                inode.set_file_acl(0);
+                self.repairs
+                    .push(FsckRepair::ClearedXattrBlock { ino, block: acl });
+            }
+            if !dropped.is_empty() || clear_acl {
+                let count = self.disk.count_blocks(ino, &inode)?;
+                inode.set_block_count(count, self.disk.block_size());
+                self.disk.write_inode(ino, &mut inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear entries pointing at invalid or unused inodes and correct file types.
+    fn fix_entries(&mut self) -> Result<()> {
+        let mut fixes = core::mem::take(&mut self.entry_fixes);
+        fixes.sort_by_key(|fix| fix.block);
+        let csum = self.disk.sb.metadata_csum();
+        let mut i = 0;
+        while i < fixes.len() {
+            let (dir, block) = (fixes[i].dir, fixes[i].block);
+            let mut data = self.disk.read_block(block)?;
+            while i < fixes.len() && fixes[i].block == block {
+                let fix = &fixes[i];
+                let repair = match fix.action {
+                    EntryAction::Clear => {
+                        put32(&mut data, fix.offset, 0);
+                        FsckRepair::ClearedEntry {
+                            dir: fix.dir,
+                            name: fix.name.clone(),
+                            ino: fix.ino,
+                        }
+                    }
+                    EntryAction::SetType(file_type) => {
+                        data[fix.offset + 7] = file_type;
+                        FsckRepair::EntryFileType {
+                            dir: fix.dir,
+                            name: fix.name.clone(),
+                            ino: fix.ino,
+                            file_type,
+                        }
+                    }
+                };
+                self.repairs.push(repair);
+                i += 1;
+            }
+            if csum {
+                let seed = self.seed(dir)?;
+                self.disk.update_dir_block_checksum(seed, &mut data);
+            }
+            self.disk.write_block(block, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Link unattached inodes and the tops of unconnected directory trees into
+    /// `lost+found`, creating it if missing. Inodes that cannot be linked are left
+    /// unattached and remain as problems.
+    fn reconnect(&mut self) -> Result<()> {
+        let lost: Vec<u32> = self
+            .report
+            .problems
+            .iter()
+            .filter_map(|problem| match *problem {
+                FsckProblem::UnattachedInode { ino } => Some(ino),
+                FsckProblem::UnconnectedDirectory { ino, .. } => Some(ino),
+                _ => None,
+            })
+            .collect();
+        if lost.is_empty() {
+            return Ok(());
+        }
+        let lost_found = match self.lost_found {
+            Some(ino) => Some(ino),
+            None => self.create_lost_found()?,
+        };
+        let Some(lost_found) = lost_found else {
+            warn!(
+                "fsck: cannot create lost+found, {} inodes left unattached",
+                lost.len()
+            );
+            return Ok(());
+        };
+        let filetype = self.disk.sb.has_incompat(INCOMPAT_FILETYPE);
+        for (i, &ino) in lost.iter().enumerate() {
+            let name = format!("#{}", ino);
+            let info = &self.inodes[&ino];
+            let file_type = if filetype { info.ty.to_ext4() } else { 0 };
+            let is_dir = info.ty.is_dir();
+            if !self.link_into(lost_found, ino, name.as_bytes(), file_type)? {
+                warn!(
+                    "fsck: cannot link into lost+found, {} inodes left unattached",
+                    lost.len() - i
+                );
+                break;
+            }
+            if is_dir {
+                self.set_parent(ino, lost_found)?;
+            }
+            self.repairs
+                .push(FsckRepair::MovedToLostFound { ino, name });
+        }
+        Ok(())
+    }
+
+    /// Create `lost+found` below the root as a directory of one block, like `mke2fs`
+    /// does. Returns None if no inode or block is free or the root takes no entry.
+    fn create_lost_found(&mut self) -> Result<Option<u32>> {
+        let sb = self.disk.sb.clone();
+        let bs = sb.block_size();
+        let first = sb.first_data_block() as u64;
+        let Some(block) = (first..sb.blocks_count()).find(|&b| !self.claimed.get(b)) else {
+            return Ok(None);
+        };
+        let Some(ino) = self.free_inode() else {
+            return Ok(None);
+        };
+        self.claim(block);
+        let filetype = sb.has_incompat(INCOMPAT_FILETYPE);
+        let ty = FileType::from_char('d');
+        let file_type = if filetype { ty.to_ext4() } else { 0 };
+        if !self.link_into(ROOT_INO, ino, b"lost+found", file_type)? {
+            return Ok(None);
+        }
+        // the inode may lie just past the initialized part of its table
+        let ipg = sb.inodes_per_group();
+        let (group, index) = ((ino - 1) / ipg, (ino - 1) % ipg);
+        if index >= self.disk.initialized_inodes(group) {
+            let gd = &mut self.disk.groups[group as usize];
+            gd.set_flags(gd.flags() & !BG_INODE_UNINIT);
+            gd.set_itable_unused(ipg - index - 1);
+        }
+        let mut inode = Inode::new_dir(sb.inode_size(), 0o700, sb.write_time());
+        let extent = Extent {
+            logical: 0,
+            physical: block,
+            len: 1,
+            uninit: false,
+        };
+        if sb.has_incompat(INCOMPAT_EXTENTS) {
+            inode.set_flags(INODE_FLAG_EXTENTS);
+            let mut alloc = || Err(Error::NoSpace);
+            self.disk
+                .write_extent_tree(ino, &mut inode, &[extent], &mut alloc)?;
+        } else {
+            inode.append_block(0, block);
+        }
+        inode.set_size(bs as u64);
+        inode.set_block_count(1, bs);
+        let mut data = empty_dir_block(bs as usize, sb.metadata_csum());
+        insert_dir_entry(&mut data, ino, b".", file_type, filetype);
+        insert_dir_entry(&mut data, ROOT_INO, b"..", file_type, filetype);
+        let seed = self.disk.inode_csum_seed(ino, &inode);
+        self.disk.update_dir_block_checksum(seed, &mut data);
+        self.disk.write_block(block, &data)?;
+        self.disk.write_inode(ino, &mut inode)?;
+        let mut root = self.disk.read_inode(ROOT_INO)?;
+        if root.links_count() != DIR_NLINK_OVERFLOW {
+            root.set_links_count(root.links_count().saturating_add(1));
+            self.disk.write_inode(ROOT_INO, &mut root)?;
+        }
+        self.used.set(ino as u64);
+        self.inodes.insert(
+            ino,
+            InodeInfo {
+                ty,
+                links: 2,
+                dir_blocks: vec![extent],
+                inline: None,
+                parent: ROOT_INO,
+                dotdot: Some((block, 12)),
+            },
+        );
+        self.repairs.push(FsckRepair::CreatedLostFound { ino });
+        Ok(Some(ino))
+    }
+
+    /// The first free inode after the reserved ones, in the initialized part of an
+    /// inode table or right after it.
+    fn free_inode(&self) -> Option<u32> {
+        let sb = &self.disk.sb;
+        let ipg = sb.inodes_per_group();
+        (0..sb.group_count())
+            .filter(|&group| {
+                let table = self.disk.groups[group as usize].inode_table();
+                self.disk.valid_block(table)
+            })
+            .flat_map(|group| {
+                let first = group * ipg + 1;
+                first..first + (self.disk.initialized_inodes(group) + 1).min(ipg)
+            })
+            .find(|&ino| {
+                ino >= sb.first_ino() && ino <= sb.inodes_count() && !self.used.get(ino as u64)
+            })
+    }
+
+    /// Add an entry to directory `dir`, growing it by a block if none has room.
+    /// Directories with inline data or an htree index are refused, as a plain entry
+    /// would corrupt them.
+    fn link_into(&mut self, dir: u32, ino: u32, name: &[u8], file_type: u8) -> Result<bool> {
+        let filetype = self.disk.sb.has_incompat(INCOMPAT_FILETYPE);
+        let csum = self.disk.sb.metadata_csum();
+        let mut inode = self.disk.read_inode(dir)?;
+        if inode.flags() & (INODE_FLAG_INLINE_DATA | INODE_FLAG_INDEX) != 0 {
+            return Ok(false);
+        }
+        let seed = self.disk.inode_csum_seed(dir, &inode);
+        let blocks: Vec<u64> = self.inodes[&dir]
+            .dir_blocks
+            .iter()
+            .filter(|e| !e.uninit)
+            .flat_map(|e| e.physical..e.physical + e.len)
+            .collect();
+        for block in blocks {
+            let mut data = self.disk.read_block(block)?;
+            if insert_dir_entry(&mut data, ino, name, file_type, filetype) {
+                self.disk.update_dir_block_checksum(seed, &mut data);
+                self.disk.write_block(block, &data)?;
+                return Ok(true);
+            }
+        }
+        let bs = self.disk.block_size() as u64;
+        let first = self.disk.sb.first_data_block() as u64;
+        let Some(block) = (first..self.disk.sb.blocks_count()).find(|&b| !self.claimed.get(b))
+        else {
+            return Ok(false);
+        };
+        let logical = inode.size().div_ceil(bs);
+        if !inode.append_block(logical, block) {
+            return Ok(false);
+        }
+        self.claim(block);
+        let mut data = empty_dir_block(bs as usize, csum);
+        insert_dir_entry(&mut data, ino, name, file_type, filetype);
+        self.disk.update_dir_block_checksum(seed, &mut data);
+        self.disk.write_block(block, &data)?;
+        inode.set_size((logical + 1) * bs);
+        let count = self.disk.count_blocks(dir, &inode)?;
+        inode.set_block_count(count, bs as u32);
+        self.disk.write_inode(dir, &mut inode)?;
+        let map = self.disk.map_inode(dir, &inode)?;
+        self.inodes.get_mut(&dir).unwrap().dir_blocks = map.extents;
+        Ok(true)
+    }
+
+    /// Point the `..` entry of directory `dir` at `parent`.
+    fn set_parent(&mut self, dir: u32, parent: u32) -> Result<()> {
+        let info = &self.inodes[&dir];
+        if info.inline.is_some() {
+            let mut inode = self.disk.read_inode(dir)?;
+            put32(inode.block_area_mut(), 0, parent);
+            return self.disk.write_inode(dir, &mut inode);
+        }
+        let Some((block, offset)) = info.dotdot else {
+            return Ok(());
+        };
+        let mut data = self.disk.read_block(block)?;
+        put32(&mut data, offset, parent);
+        let seed = self.seed(dir)?;
+        self.disk.update_dir_block_checksum(seed, &mut data);
+        self.disk.write_block(block, &data)
+    }
+}
@@ -0,0 +1,451 @@
+use crate::block::{BlockDeviceConfig, BlockDeviceInterface};
+use crate::crc::crc32;
+use crate::disk::{le32, le64, open_device, put16, put32, put64, read_at, write_at};
+use crate::error::{Error, Result};
+use crate::mbr::{ALIGN_SECTORS, SECTOR_SIZE};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Formatter};
+use core::str::FromStr;
+use log::warn;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: usize = 92;
+const ENTRY_SIZE: usize = 128;
+/// Entries `GptBuilder` writes, the minimum the specification requires room for.
+const ENTRY_COUNT: usize = 128;
+/// Upper bound on the entry array read, against corrupt headers.
+const MAX_ENTRIES_BYTES: usize = 1024 * 1024;
+const NAME_UNITS: usize = 36;
+
+/// A GUID in the mixed-endian byte order GPT stores it.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(
+        0xc12a7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+    pub const BASIC_DATA: Guid = Guid::from_fields(
+        0xebd0a0a2,
+        0xb9e5,
+        0x4433,
+        [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7],
+    );
+    pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(
+        0x0fc63daf,
+        0x8483,
+        0x4772,
+        [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+    );
+    pub const LINUX_SWAP: Guid = Guid::from_fields(
+        0x0657fd6d,
+        0xa4ab,
+        0x43c4,
+        [0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f, 0x4f],
+    );
+
+    /// The GUID written `aaaaaaaa-bbbb-cccc-dddd-dddddddddddd`.
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            le32(g, 0),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]])
+        )?;
+        for (i, byte) in g[8..].iter().enumerate() {
+            if i == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+impl FromStr for Guid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('-').collect();
+        let lengths = parts.iter().map(|p| p.len()).collect::<Vec<_>>();
+        if lengths != [8, 4, 4, 4, 12] || !s.is_ascii() {
+            return Err(Error::InvalidArgument);
+        }
+        let hex = |s: &str| u64::from_str_radix(s, 16).map_err(|_| Error::InvalidArgument);
+        let mut d = [0u8; 8];
+        d[..2].copy_from_slice(&(hex(parts[3])? as u16).to_be_bytes());
+        d[2..].copy_from_slice(&hex(parts[4])?.to_be_bytes()[2..]);
+        Ok(Guid::from_fields(
+            hex(parts[0])? as u32,
+            hex(parts[1])? as u16,
+            hex(parts[2])? as u16,
+            d,
+        ))
+    }
+}
+
+/// A partition found by [scan_gpt].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// Index of the entry plus one, as Linux numbers partitions.
+    pub number: u32,
+    pub kind: Guid,
+    pub guid: Guid,
+    /// First and last sector, inclusive.
+    pub first: u64,
+    pub last: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    /// Offset of the partition in bytes.
+    pub fn offset(&self) -> u64 {
+        self.first * SECTOR_SIZE
+    }
+
+    /// Size of the partition in bytes.
+    pub fn size(&self) -> u64 {
+        (self.last - self.first + 1) * SECTOR_SIZE
+    }
+}
+
+/// A GUID partition table, as read by [scan_gpt] or written by [GptBuilder].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptTable {
+    pub disk_guid: Guid,
+    /// Sectors partitions may use, inclusive.
+    pub first_usable: u64,
+    pub last_usable: u64,
+    /// Used entries, in table order.
+    pub partitions: Vec<GptPartition>,
+    /// Whether the primary header or entries were damaged and the backup was used.
+    pub from_backup: bool,
+}
+
+/// Read the GUID partition table on `dev`, with 512 byte sectors.
+///
+/// Headers and entry arrays are checked against their CRCs. If the primary copy at
+/// the start of the device is damaged, the backup at the end is used instead and
+/// [GptTable::from_backup] is set. Fails with [Error::NoEntry] if neither is valid.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{scan_gpt, BlockDeviceConfig, DefaultInterface, Guid};
+/// let file = std::fs::File::open("disk.img").unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut dev = DefaultInterface::new(file, config);
+/// for partition in scan_gpt(&mut dev).unwrap().partitions {
+///     if partition.kind == Guid::LINUX_FILESYSTEM {
+///         println!("{} {} {}", partition.number, partition.guid, partition.name);
+///     }
+/// }
+/// ```
+pub fn scan_gpt<T: BlockDeviceInterface>(dev: &mut T) -> Result<GptTable> {
+    let config = open_device(dev)?;
+    if config.part_size < 3 * SECTOR_SIZE {
+        return Err(Error::NoEntry);
+    }
+    if let Ok(table) = read_header(dev, &config, 1) {
+        return Ok(table);
+    }
+    // A primary header that is intact but points at damaged entries still knows where
+    // the backup is, which is the last sector otherwise.
+    let mut raw = [0u8; SECTOR_SIZE as usize];
+    read_at(dev, &config, SECTOR_SIZE, &mut raw)?;
+    let alternate = match &raw[..8] == SIGNATURE && header_crc_ok(&raw) {
+        true => le64(&raw, 32),
+        false => config.part_size / SECTOR_SIZE - 1,
+    };
+    let mut table = read_header(dev, &config, alternate)?;
+    warn!(
+        "gpt: primary table damaged, using the backup at sector {}",
+        alternate
+    );
+    table.from_backup = true;
+    Ok(table)
+}
+
+fn header_crc_ok(raw: &[u8]) -> bool {
+    let size = le32(raw, 12) as usize;
+    if !(HEADER_SIZE..=raw.len()).contains(&size) {
+        return false;
+    }
+    let mut header = raw[..size].to_vec();
+    put32(&mut header, 16, 0);
+    !crc32(!0, &header) == le32(raw, 16)
+}
+
+/// Read and check the header at `lba` and its entries.
+fn read_header<T: BlockDeviceInterface>(
+    dev: &mut T,
+    config: &BlockDeviceConfig,
+    lba: u64,
+) -> Result<GptTable> {
+    let sectors = config.part_size / SECTOR_SIZE;
+    if lba >= sectors {
+        return Err(Error::NoEntry);
+    }
+    let mut raw = [0u8; SECTOR_SIZE as usize];
+    read_at(dev, config, lba * SECTOR_SIZE, &mut raw)?;
+    if &raw[..8] != SIGNATURE || !header_crc_ok(&raw) || le64(&raw, 24) != lba {
+        return Err(Error::NoEntry);
+    }
+    let first_usable = le64(&raw, 40);
+    let last_usable = le64(&raw, 48);
+    let entries_lba = le64(&raw, 72);
+    let count = le32(&raw, 80) as usize;
+    let entry_size = le32(&raw, 84) as usize;
+    let bytes = count.checked_mul(entry_size).ok_or(Error::NoEntry)?;
+    if entry_size < ENTRY_SIZE
+        || !entry_size.is_multiple_of(8)
+        || bytes > MAX_ENTRIES_BYTES
+        || first_usable > last_usable
+        || last_usable >= sectors
+    {
+        return Err(Error::NoEntry);
+    }
+    let mut entries = vec![0u8; bytes];
+    read_at(dev, config, entries_lba * SECTOR_SIZE, &mut entries)?;
+    if !crc32(!0, &entries) != le32(&raw, 88) {
+        return Err(Error::NoEntry);
+    }
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let kind = Guid(entry[0..16].try_into().unwrap());
+        if kind.is_zero() {
+            continue;
+        }
+        let units = entry[56..56 + NAME_UNITS * 2]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&u| u != 0);
+        let partition = GptPartition {
+            number: i as u32 + 1,
+            kind,
+            guid: Guid(entry[16..32].try_into().unwrap()),
+            first: le64(entry, 32),
+            last: le64(entry, 40),
+            attributes: le64(entry, 48),
+            name: char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        };
+        if partition.first > partition.last || partition.last > last_usable {
+            return Err(Error::InvalidArgument);
+        }
+        partitions.push(partition);
+    }
+    Ok(GptTable {
+        disk_guid: Guid(raw[56..72].try_into().unwrap()),
+        first_usable,
+        last_usable,
+        partitions,
+        from_backup: false,
+    })
+}
+
+#[derive(Debug, Clone)]
+struct PartitionSpec {
+    kind: Guid,
+    guid: Guid,
+    name: String,
+    size: Option<u64>,
+}
+
+/// Writes a fresh GUID partition table with a protective MBR, like `sgdisk -o -n`.
+///
+/// Partitions are laid out in order from the first MiB, each aligned to 1MiB. The
+/// GUIDs are the caller's to pick, they only need to be unique.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, GptBuilder, Guid};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("disk.img")
+///     .unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut dev = DefaultInterface::new(file, config);
+/// let disk: Guid = "5B3E4F6A-0C1D-4E2F-9A8B-7C6D5E4F3A2B".parse().unwrap();
+/// let root: Guid = "0C3D9B7E-1F2A-4D5E-8A6B-7C8D9E0F1A2B".parse().unwrap();
+/// let table = GptBuilder::new(disk)
+///     .partition(Guid::LINUX_FILESYSTEM, root, "rootfs", None)
+///     .write(&mut dev)
+///     .unwrap();
+/// println!("root at {}", table.partitions[0].offset());
+/// ```
+#[derive(Debug, Clone)]
+pub struct GptBuilder {
+    disk_guid: Guid,
+    partitions: Vec<PartitionSpec>,
+}
+
+impl GptBuilder {
+    pub fn new(disk_guid: Guid) -> Self {
+        Self {
+            disk_guid,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Add a partition of `size` bytes, rounded up to whole sectors, or of the rest of
+    /// the device if `None`. `name` is cut to 36 UTF-16 units.
+    pub fn partition(mut self, kind: Guid, guid: Guid, name: &str, size: Option<u64>) -> Self {
+        self.partitions.push(PartitionSpec {
+            kind,
+            guid,
+            name: name.into(),
+            size,
+        });
+        self
+    }
+
+    /// Write the protective MBR and both copies of the table, overwriting whatever
+    /// partition table `dev` had.
+    ///
+    /// Fails with [Error::NoSpace] if the partitions do not fit and with
+    /// [Error::InvalidArgument] if there are more than 128 or one has a zero size.
+    pub fn write<T: BlockDeviceInterface>(&self, dev: &mut T) -> Result<GptTable> {
+        let config = open_device(dev)?;
+        let sectors = config.part_size / SECTOR_SIZE;
+        let entry_sectors = (ENTRY_COUNT * ENTRY_SIZE) as u64 / SECTOR_SIZE;
+        let first_usable = 2 + entry_sectors;
+        if self.partitions.len() > ENTRY_COUNT || sectors < 2 * first_usable + ALIGN_SECTORS {
+            return Err(Error::InvalidArgument);
+        }
+        let last_usable = sectors - 2 - entry_sectors;
+
+        let mut partitions = Vec::new();
+        let mut next = first_usable.next_multiple_of(ALIGN_SECTORS);
+        for (i, spec) in self.partitions.iter().enumerate() {
+            let count = match spec.size {
+                Some(0) => return Err(Error::InvalidArgument),
+                Some(size) => size.div_ceil(SECTOR_SIZE),
+                None => (last_usable + 1).saturating_sub(next),
+            };
+            if count == 0 || next + count - 1 > last_usable {
+                return Err(Error::NoSpace);
+            }
+            let name: Vec<u16> = spec.name.encode_utf16().take(NAME_UNITS).collect();
+            partitions.push(GptPartition {
+                number: i as u32 + 1,
+                kind: spec.kind,
+                guid: spec.guid,
+                first: next,
+                last: next + count - 1,
+                attributes: 0,
+                name: String::from_utf16_lossy(&name),
+            });
+            next = (next + count).next_multiple_of(ALIGN_SECTORS);
+        }
+
+        let mut entries = vec![0u8; ENTRY_COUNT * ENTRY_SIZE];
+        for (p, entry) in partitions.iter().zip(entries.chunks_exact_mut(ENTRY_SIZE)) {
+            entry[0..16].copy_from_slice(&p.kind.0);
+            entry[16..32].copy_from_slice(&p.guid.0);
+            put64(entry, 32, p.first);
+            put64(entry, 40, p.last);
+            put64(entry, 48, p.attributes);
+            for (i, unit) in p.name.encode_utf16().enumerate() {
+                put16(entry, 56 + i * 2, unit);
+            }
+        }
+        let entries_crc = !crc32(!0, &entries);
+        let header = |lba: u64, alternate: u64, entries_lba: u64| {
+            let mut raw = [0u8; SECTOR_SIZE as usize];
+            raw[..8].copy_from_slice(SIGNATURE);
+            put32(&mut raw, 8, REVISION);
+            put32(&mut raw, 12, HEADER_SIZE as u32);
+            put64(&mut raw, 24, lba);
+            put64(&mut raw, 32, alternate);
+            put64(&mut raw, 40, first_usable);
+            put64(&mut raw, 48, last_usable);
+            raw[56..72].copy_from_slice(&self.disk_guid.0);
+            put64(&mut raw, 72, entries_lba);
+            put32(&mut raw, 80, ENTRY_COUNT as u32);
+            put32(&mut raw, 84, ENTRY_SIZE as u32);
+            put32(&mut raw, 88, entries_crc);
+            let crc = !crc32(!0, &raw[..HEADER_SIZE]);
+            put32(&mut raw, 16, crc);
+            raw
+        };
+
+        // The backup goes first, so that a table is valid if writing is interrupted.
+        write_at(dev, &config, (last_usable + 1) * SECTOR_SIZE, &entries)?;
+        write_at(
+            dev,
+            &config,
+            (sectors - 1) * SECTOR_SIZE,
+            &header(sectors - 1, 1, last_usable + 1),
+        )?;
+        write_at(dev, &config, 2 * SECTOR_SIZE, &entries)?;
+        write_at(dev, &config, SECTOR_SIZE, &header(1, sectors - 1, 2))?;
+        write_at(dev, &config, 0, &protective_mbr(sectors))?;
+        dev.close()?;
+        Ok(GptTable {
+            disk_guid: self.disk_guid,
+            first_usable,
+            last_usable,
+            partitions,
+            from_backup: false,
+        })
+    }
+}
+
+/// An MBR with a single partition of type `0xee` covering the disk, so that tools
+/// which only know MBR leave it alone.
+fn protective_mbr(sectors: u64) -> [u8; SECTOR_SIZE as usize] {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    let entry = &mut mbr[0x1be..0x1ce];
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = 0xee;
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    put32(entry, 8, 1);
+    put32(entry, 12, (sectors - 1).min(u32::MAX as u64) as u32);
+    mbr[0x1fe..].copy_from_slice(&[0x55, 0xaa]);
+    mbr
+}
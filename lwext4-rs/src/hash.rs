@@ -0,0 +1,171 @@
+//! Hashes of names used by ext4 metadata: htree directory indexes and extended
+//! attribute entries, c.f. `ext2fs_dirhash` and `ext2fs_ext_attr_hash_entry`.
+
+pub(crate) const DX_HASH_HALF_MD4: u8 = 1;
+pub(crate) const DX_HASH_TEA: u8 = 2;
+/// Added to the hash versions above when `s_flags` asks for unsigned chars.
+pub(crate) const DX_HASH_UNSIGNED: u8 = 3;
+
+/// Seed used when the superblock has none.
+const DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+const TEA_DELTA: u32 = 0x9e37_79b9;
+const MD4_K2: u32 = 0o13240474631;
+const MD4_K3: u32 = 0o15666365641;
+/// Input word of each of the 8 steps of the 3 rounds of half MD4.
+const MD4_WORDS: [[usize; 8]; 3] = [
+    [0, 1, 2, 3, 4, 5, 6, 7],
+    [1, 3, 5, 7, 0, 2, 4, 6],
+    [3, 7, 2, 6, 1, 5, 0, 4],
+];
+/// Rotation of the steps of each round, repeating every 4 steps.
+const MD4_SHIFTS: [[u32; 4]; 3] = [[3, 7, 11, 19], [3, 5, 9, 13], [3, 9, 11, 15]];
+/// Hash reserved for the end of a directory in 32 bit `telldir` cookies.
+const HTREE_EOF_32BIT: u32 = 0x7fff_ffff;
+
+/// Major hash of `name` in an htree directory, with the low bit cleared. The legacy
+/// hash (version 0) is used for unknown versions too.
+pub(crate) fn dx_hash(name: &[u8], version: u8, seed: [u32; 4]) -> u32 {
+    let unsigned = version >= DX_HASH_UNSIGNED;
+    let mut buf = match seed.iter().any(|&w| w != 0) {
+        true => seed,
+        false => DEFAULT_SEED,
+    };
+    let hash = match version % DX_HASH_UNSIGNED {
+        DX_HASH_HALF_MD4 => {
+            let mut input = [0u32; 8];
+            for (i, chunk) in name.chunks(32).enumerate() {
+                str2hashbuf(chunk, name.len() - i * 32, &mut input, unsigned);
+                half_md4_transform(&mut buf, &input);
+            }
+            buf[1]
+        }
+        DX_HASH_TEA => {
+            let mut input = [0u32; 4];
+            for (i, chunk) in name.chunks(16).enumerate() {
+                str2hashbuf(chunk, name.len() - i * 16, &mut input, unsigned);
+                tea_transform(&mut buf, &input);
+            }
+            buf[0]
+        }
+        _ => legacy_hash(name, unsigned),
+    } & !1;
+    match hash == HTREE_EOF_32BIT << 1 {
+        true => (HTREE_EOF_32BIT - 1) << 1,
+        false => hash,
+    }
+}
+
+fn char_value(c: u8, unsigned: bool) -> u32 {
+    match unsigned {
+        true => c as u32,
+        false => c as i8 as i32 as u32,
+    }
+}
+
+/// Pack the start of `chunk` into words, padded with a pattern of `len`, the length
+/// of the rest of the name.
+fn str2hashbuf(chunk: &[u8], len: usize, buf: &mut [u32], unsigned: bool) {
+    let mut pad = (len as u32) | ((len as u32) << 8);
+    pad |= pad << 16;
+    let mut val = pad;
+    let chunk = &chunk[..chunk.len().min(buf.len() * 4)];
+    let mut words = 0;
+    for (i, &c) in chunk.iter().enumerate() {
+        val = char_value(c, unsigned).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[words] = val;
+            val = pad;
+            words += 1;
+        }
+    }
+    if words < buf.len() {
+        buf[words] = val;
+        words += 1;
+    }
+    for word in &mut buf[words..] {
+        *word = pad;
+    }
+}
+
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3_fe2du32, 0x37ab_e8f9u32);
+    for &c in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(c, unsigned).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(TEA_DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ b1.wrapping_add(sum) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ b0.wrapping_add(sum) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    type Round = fn(u32, u32, u32) -> u32;
+    let rounds: [(Round, u32); 3] = [(f, 0), (g, MD4_K2), (h, MD4_K3)];
+    let [mut a, mut b, mut c, mut d] = *buf;
+    for (round, (func, k)) in rounds.into_iter().enumerate() {
+        for (step, word) in MD4_WORDS[round].into_iter().enumerate() {
+            let shift = MD4_SHIFTS[round][step % 4];
+            let x = input[word].wrapping_add(k);
+            a = a
+                .wrapping_add(func(b, c, d))
+                .wrapping_add(x)
+                .rotate_left(shift);
+            // The next step updates d, then c, then b, as in the MD4 rounds.
+            (a, b, c, d) = (d, a, b, c);
+        }
+    }
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+/// Hash of an extended attribute entry over its name and its value, padded to a
+/// multiple of 4 bytes.
+pub(crate) fn xattr_entry_hash(name: &[u8], value: &[u8]) -> u32 {
+    let mut hash = 0u32;
+    for &c in name {
+        hash = hash.rotate_left(5) ^ c as u32;
+    }
+    for word in value.chunks(4) {
+        let mut padded = [0u8; 4];
+        padded[..word.len()].copy_from_slice(word);
+        hash = hash.rotate_left(16) ^ u32::from_le_bytes(padded);
+    }
+    hash
+}
+
+/// Hash of an extended attribute block over the hashes of its entries, or 0 if one
+/// of them has none.
+pub(crate) fn xattr_block_hash(entry_hashes: impl IntoIterator<Item = u32>) -> u32 {
+    let mut hash = 0u32;
+    for entry in entry_hashes {
+        if entry == 0 {
+            return 0;
+        }
+        hash = hash.rotate_left(16) ^ entry;
+    }
+    hash
+}
@@ -0,0 +1,59 @@
+//! Helpers shared by the modules that compare or copy entries between the host and
+//! a mounted image.
+use crate::error::Result;
+use crate::types::{dev_join, FileType};
+use crate::{BlockDeviceInterface, FileSystem};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+/// The type of a host entry as stored in an inode.
+pub(crate) fn host_type(ty: std::fs::FileType) -> FileType {
+    FileType::from_char(if ty.is_dir() {
+        'd'
+    } else if ty.is_symlink() {
+        'l'
+    } else if ty.is_char_device() {
+        'c'
+    } else if ty.is_block_device() {
+        'b'
+    } else if ty.is_fifo() {
+        'p'
+    } else if ty.is_socket() {
+        's'
+    } else {
+        '-'
+    })
+}
+
+/// Device number of a host device node as stored in an inode, 0 for other types.
+pub(crate) fn host_dev(host: &std::fs::Metadata, ty: FileType) -> u32 {
+    match ty.is_char_device() || ty.is_block_device() {
+        true => dev_join(libc::major(host.rdev()), libc::minor(host.rdev())),
+        false => 0,
+    }
+}
+
+/// The path lwext4 finds the image directory `dir`, given without its trailing `/`,
+/// by: the mount itself is only found by its name with the trailing `/`.
+pub(crate) fn image_dir<T: BlockDeviceInterface>(fs: &FileSystem<T>, dir: &str) -> String {
+    let root = fs.mount_handle().mount_point.as_str();
+    match dir == root.trim_end_matches('/') {
+        true => dir.to_string() + "/",
+        false => dir.to_string(),
+    }
+}
+
+/// Fill `buf` from `read` until it is full or the end is reached, returning the
+/// bytes read.
+pub(crate) fn read_full(
+    mut read: impl FnMut(&mut [u8]) -> Result<usize>,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
@@ -0,0 +1,602 @@
+use crate::block::{BlockDevice, BlockDeviceConfig, BlockDeviceInterface};
+use crate::disk::*;
+use crate::error::{Error, Result};
+use crate::hash::{dx_hash, xattr_block_hash, xattr_entry_hash, DX_HASH_UNSIGNED};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use log::warn;
+
+/// Blocks read and written per request.
+const CHUNK_BLOCKS: u64 = 256;
+
+/// Characters of replacement names, in order of preference.
+const NAME_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+/// Candidate names tried for an entry of an htree directory before giving up on
+/// keeping it in its leaf block.
+const MAX_NAME_TRIES: u64 = 1 << 16;
+
+const XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
+const XATTR_INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+const XATTR_INDEX_SYSTEM: u8 = 7;
+const XATTR_INDEX_ENCRYPTION: u8 = 9;
+
+/// Summary of an [ImageCopy].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImageCopyReport {
+    /// File system block size in bytes.
+    pub block_size: u32,
+    /// Blocks of the file system.
+    pub blocks: u64,
+    /// Blocks copied.
+    pub copied: u64,
+    /// Directory entries renamed by [ImageCopy::anonymize].
+    pub names: u64,
+}
+
+/// Copy of the blocks of a file system that are worth keeping, similar to `e2image -r`.
+///
+/// By default the blocks in use according to the block bitmaps are copied. With
+/// [metadata_only](Self::metadata_only) only superblocks, group descriptors, bitmaps,
+/// inode tables, directories, extent tree and indirect blocks, extended attribute
+/// blocks and the journal are, which is enough to check the file system or list its
+/// files but leaves their contents zeroed.
+///
+/// Blocks not copied are left as they are on the destination, which should be a new
+/// (ideally [sparse](crate::SparseFileInterface)) file at least as large as the file
+/// system.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, ImageCopy, SparseFileInterface};
+/// let src = std::fs::File::open("ext4.img").unwrap();
+/// let len = src.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let dst = std::fs::File::create("backup.img").unwrap();
+/// dst.set_len(len).unwrap();
+/// let mut src = DefaultInterface::new_device(src, config);
+/// let mut dst = SparseFileInterface::new_device(dst, config);
+/// let report = ImageCopy::new().copy(&mut src, &mut dst).unwrap();
+/// println!("{} of {} blocks copied", report.copied, report.blocks);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ImageCopy {
+    metadata_only: bool,
+    anonymize: bool,
+}
+
+impl ImageCopy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy the file system metadata only, not the contents of regular files.
+    pub fn metadata_only(mut self, metadata_only: bool) -> Self {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    /// Scramble what the metadata says about the files, so that the copy can be
+    /// shared, e.g. in a bug report. Implies [metadata_only](Self::metadata_only).
+    ///
+    /// Names in directories are replaced by others of the same length that fall into
+    /// the same htree leaf block, symbolic link targets become `x`s (keeping the `/`s),
+    /// inline file data is zeroed and so are the values of extended attributes, except
+    /// for POSIX ACLs and encryption contexts. The layout is unchanged. Only the
+    /// superblock of the journal is copied, as its log holds copies of metadata blocks,
+    /// so a file system whose journal needs recovery is refused with
+    /// [Error::InvalidArgument].
+    pub fn anonymize(mut self, anonymize: bool) -> Self {
+        self.anonymize = anonymize;
+        self
+    }
+
+    /// Copy the file system on `src`, which must not be mounted, to the same offsets
+    /// of `dst`. Fails with [Error::NoSpace] if `dst` is too small.
+    pub fn copy<S: BlockDeviceInterface, D: BlockDeviceInterface>(
+        &self,
+        src: &mut BlockDevice<S>,
+        dst: &mut BlockDevice<D>,
+    ) -> Result<ImageCopyReport> {
+        let mut disk = Disk::open(src)?;
+        if self.anonymize && disk.sb.has_incompat(INCOMPAT_RECOVER) {
+            warn!("image: journal needs recovery first");
+            return Err(Error::InvalidArgument);
+        }
+        disk.load_groups()?;
+        let blocks = if self.metadata_only || self.anonymize {
+            metadata_blocks(&mut disk, self.anonymize)?
+        } else {
+            disk.used_blocks()?
+        };
+        let bs = disk.block_size() as u64;
+        let count = disk.sb.blocks_count();
+        let dst: &mut D = dst;
+        let config = open_device(dst)?;
+        if config.part_size < count * bs {
+            return Err(Error::NoSpace);
+        }
+
+        let mut report = ImageCopyReport {
+            block_size: bs as u32,
+            blocks: count,
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; (CHUNK_BLOCKS * bs) as usize];
+        let mut block = 0;
+        while block < count {
+            if !blocks.get(block) {
+                block += 1;
+                continue;
+            }
+            let mut n = 1;
+            while n < CHUNK_BLOCKS && block + n < count && blocks.get(block + n) {
+                n += 1;
+            }
+            let data = &mut buf[..(n * bs) as usize];
+            disk.read(block * bs, data)?;
+            write_at(dst, &config, block * bs, data)?;
+            report.copied += n;
+            block += n;
+        }
+        if self.anonymize {
+            report.names = anonymize(&mut disk, dst, &config)?;
+        }
+        dst.close()?;
+        Ok(report)
+    }
+}
+
+/// Copy the blocks in use of the file system on `src` to `dst`, see [ImageCopy].
+pub fn image_copy<S: BlockDeviceInterface, D: BlockDeviceInterface>(
+    src: &mut BlockDevice<S>,
+    dst: &mut BlockDevice<D>,
+) -> Result<ImageCopyReport> {
+    ImageCopy::new().copy(src, dst)
+}
+
+/// Call `f` with each inode in use: the reserved ones and those set in the inode
+/// bitmaps.
+fn for_each_inode<'a, T: BlockDeviceInterface>(
+    disk: &mut Disk<'a, T>,
+    mut f: impl FnMut(&mut Disk<'a, T>, u32, Inode) -> Result<()>,
+) -> Result<()> {
+    let sb = disk.sb.clone();
+    for group in 0..sb.group_count() {
+        let gd = disk.groups[group as usize].clone();
+        if !disk.valid_block(gd.inode_table()) || !disk.valid_block(gd.inode_bitmap()) {
+            continue;
+        }
+        let inodes = disk.read_inode_table(group)?;
+        if inodes.is_empty() {
+            continue;
+        }
+        let bitmap = disk.read_block(gd.inode_bitmap())?;
+        for (ino, inode) in inodes {
+            let reserved = ino < sb.first_ino();
+            let index = ((ino - 1) % sb.inodes_per_group()) as usize;
+            if !inode.is_unused_slot() && (reserved || bitmap_get(&bitmap, index)) {
+                f(disk, ino, inode)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The group metadata, the blocks of directories, symbolic links and reserved inodes
+/// such as the journal (only its superblock if `skip_journal_log`), and the
+/// extent tree, indirect and attribute blocks of all inodes in use.
+fn metadata_blocks<T: BlockDeviceInterface>(
+    disk: &mut Disk<'_, T>,
+    skip_journal_log: bool,
+) -> Result<BitSet> {
+    let sb = disk.sb.clone();
+    let mut meta = BitSet::new(sb.blocks_count());
+    for block in 0..sb.first_data_block() as u64 {
+        meta.set(block);
+    }
+    for group in 0..sb.group_count() {
+        let gd = disk.groups[group as usize].clone();
+        let table = gd.inode_table()..gd.inode_table() + sb.inode_table_blocks() as u64;
+        for block in sb
+            .group_super_blocks(group)
+            .into_iter()
+            .chain([gd.block_bitmap(), gd.inode_bitmap()])
+            .chain(table)
+        {
+            if disk.valid_block(block) {
+                meta.set(block);
+            }
+        }
+    }
+    let journal = match sb.has_compat(COMPAT_HAS_JOURNAL) {
+        true => sb.journal_inum(),
+        false => 0,
+    };
+    for_each_inode(disk, |disk, ino, inode| {
+        if inode.file_acl() != 0 && disk.valid_block(inode.file_acl()) {
+            meta.set(inode.file_acl());
+        }
+        let map = disk.map_inode(ino, &inode)?;
+        for &block in &map.meta {
+            meta.set(block);
+        }
+        // Regular files other than reserved inodes keep only their block maps.
+        if ino < sb.first_ino() || !inode.file_type().is_file() {
+            for e in &map.extents {
+                let len = match skip_journal_log && ino == journal {
+                    true => (e.logical == 0) as u64,
+                    false => e.len,
+                };
+                for block in e.physical..e.physical + len {
+                    meta.set(block);
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(meta)
+}
+
+/// Scramble the names, link targets and attribute values on `dst`, which holds the
+/// metadata of `disk`, and return the number of names replaced.
+fn anonymize<T: BlockDeviceInterface, D: BlockDeviceInterface>(
+    disk: &mut Disk<'_, T>,
+    dst: &mut D,
+    config: &BlockDeviceConfig,
+) -> Result<u64> {
+    let sb = disk.sb.clone();
+    let bs = sb.block_size() as u64;
+    let filetype = sb.has_incompat(INCOMPAT_FILETYPE);
+    let mut renamed = 0;
+    let mut xattr_blocks = BTreeSet::new();
+    for_each_inode(disk, |disk, ino, mut inode| {
+        let ty = inode.file_type();
+        let flags = inode.flags();
+        // Names and targets of encrypted inodes are not readable already.
+        let encrypted = flags & INODE_FLAG_ENCRYPT != 0;
+        let inline = flags & INODE_FLAG_INLINE_DATA != 0;
+        if ty.is_symlink() && !encrypted && inode.has_data_blocks(bs as u32) {
+            let map = disk.map_inode(ino, &inode)?;
+            if let Some(e) = map.extents.first().filter(|e| e.logical == 0) {
+                let mut block = disk.read_block(e.physical)?;
+                let len = (inode.size() as usize).min(block.len());
+                scramble_target(&mut block[..len]);
+                write_at(dst, config, e.physical * bs, &block)?;
+            }
+        }
+        let mut names =
+            (ty.is_dir() && !encrypted).then(|| Names::new(ino, flags & INODE_FLAG_CASEFOLD != 0));
+        if let Some(names) = names.as_mut().filter(|_| inode.has_data_blocks(bs as u32)) {
+            let map = disk.map_inode(ino, &inode)?;
+            let index = match flags & INODE_FLAG_INDEX != 0 {
+                true => dx_leaves(disk, &map)?,
+                false => None,
+            };
+            if index.is_none() && flags & INODE_FLAG_INDEX != 0 {
+                warn!("image: unknown htree index in directory {}", ino);
+            }
+            let seed = disk.inode_csum_seed(ino, &inode);
+            for e in &map.extents {
+                for i in 0..e.len {
+                    let mut block = disk.read_block(e.physical + i)?;
+                    let hash = index.as_ref().map(|(version, leaves)| HashRange {
+                        version: *version,
+                        seed: sb.hash_seed(),
+                        range: leaves
+                            .get(&(e.logical + i))
+                            .copied()
+                            .unwrap_or((0, 1 << 32)),
+                    });
+                    if names.scramble(&mut block, filetype, hash.as_ref()) {
+                        if sb.metadata_csum() {
+                            disk.update_dir_block_checksum(seed, &mut block);
+                        }
+                        write_at(dst, config, (e.physical + i) * bs, &block)?;
+                    }
+                }
+            }
+        }
+
+        // Data kept in the inode itself.
+        let mut changed = false;
+        if let Some(names) = names.as_mut().filter(|_| inline) {
+            changed |= names.scramble(&mut inode.block_area_mut()[4..], filetype, None);
+        } else if ty.is_symlink() && !encrypted && (inline || !inode.has_data_blocks(bs as u32)) {
+            let len = (inode.size() as usize).min(inode.block_area().len());
+            scramble_target(&mut inode.block_area_mut()[..len]);
+            changed = true;
+        } else if inline && !encrypted {
+            inode.block_area_mut().fill(0);
+            changed = true;
+        }
+        let mut scramble_value = |index: u8, name: &[u8], value: &mut [u8]| match index {
+            XATTR_INDEX_POSIX_ACL_ACCESS | XATTR_INDEX_POSIX_ACL_DEFAULT => false,
+            XATTR_INDEX_ENCRYPTION => false,
+            XATTR_INDEX_SYSTEM if name == b"data" => match names.as_mut() {
+                Some(names) => names.scramble(value, filetype, None),
+                None if encrypted => false,
+                None if ty.is_symlink() => {
+                    scramble_target(value);
+                    true
+                }
+                None => {
+                    value.fill(0);
+                    true
+                }
+            },
+            _ => {
+                value.fill(0);
+                true
+            }
+        };
+        let start = GOOD_OLD_INODE_SIZE + inode.extra_isize();
+        if start + 4 <= inode.raw.len() && le32(&inode.raw, start) == XATTR_MAGIC {
+            let (scrambled, _) =
+                scramble_xattrs(&mut inode.raw[start + 4..], 0, &mut scramble_value);
+            changed |= scrambled;
+        }
+        let acl = inode.file_acl();
+        if acl != 0 && disk.valid_block(acl) && xattr_blocks.insert(acl) {
+            let mut block = disk.read_block(acl)?;
+            if le32(&block, 0) == XATTR_MAGIC {
+                let (scrambled, hashes) = scramble_xattrs(&mut block, 32, &mut scramble_value);
+                if scrambled {
+                    put32(&mut block, 0x0c, xattr_block_hash(hashes));
+                    if sb.metadata_csum() {
+                        disk.update_xattr_block_checksum(acl, &mut block);
+                    }
+                    write_at(dst, config, acl * bs, &block)?;
+                }
+            }
+        }
+
+        if changed {
+            disk.update_inode_checksum(ino, &mut inode);
+            write_at(dst, config, disk.inode_offset(ino), &inode.raw)?;
+        }
+        if let Some(names) = names {
+            renamed += names.renamed;
+        }
+        Ok(())
+    })?;
+    Ok(renamed)
+}
+
+/// Replace the bytes of a symbolic link target other than `/` by `x`.
+fn scramble_target(target: &mut [u8]) {
+    for c in target.iter_mut().filter(|c| **c != b'/' && **c != 0) {
+        *c = b'x';
+    }
+}
+
+/// Pass the values of the extended attribute entries in `area`, starting at `first`,
+/// to `f`, refreshing the hashes of the entries it changed. Value offsets count from
+/// the start of `area`. Returns whether any value changed and the entry hashes.
+fn scramble_xattrs(
+    area: &mut [u8],
+    first: usize,
+    f: &mut impl FnMut(u8, &[u8], &mut [u8]) -> bool,
+) -> (bool, Vec<u32>) {
+    let mut changed = false;
+    let mut hashes = Vec::new();
+    let mut offset = first;
+    while offset + 16 <= area.len() && le32(area, offset) != 0 {
+        let index = area[offset + 1];
+        let value_offset = le16(area, offset + 2) as usize;
+        let value_inum = le32(area, offset + 4);
+        let value_size = le32(area, offset + 8) as usize;
+        let name_end = offset + 16 + area[offset] as usize;
+        if name_end > area.len() {
+            break;
+        }
+        let name = area[offset + 16..name_end].to_vec();
+        let value_end = value_offset + value_size;
+        if value_inum == 0
+            && value_size != 0
+            && value_end <= area.len()
+            && f(index, &name, &mut area[value_offset..value_end])
+        {
+            changed = true;
+            if le32(area, offset + 12) != 0 {
+                let padded = value_end.next_multiple_of(4).min(area.len());
+                let hash = xattr_entry_hash(&name, &area[value_offset..padded]);
+                put32(area, offset + 12, hash);
+            }
+        }
+        hashes.push(le32(area, offset + 12));
+        offset = name_end.next_multiple_of(4);
+    }
+    (changed, hashes)
+}
+
+/// Hashes a name must have to stay in its htree leaf block.
+struct HashRange {
+    version: u8,
+    seed: [u32; 4],
+    /// `[lo, hi)` of the major hash.
+    range: (u64, u64),
+}
+
+impl HashRange {
+    fn fits(&self, name: &[u8]) -> bool {
+        let hash = dx_hash(name, self.version, self.seed) as u64;
+        self.range.0 <= hash && hash < self.range.1
+    }
+}
+
+/// Range `[lo, hi)` of the major hashes each leaf block of an htree holds, by
+/// logical block.
+type DxLeaves = BTreeMap<u64, (u64, u64)>;
+
+/// Hash version of an htree directory and the hash ranges of its leaf blocks. None
+/// if the index is not understood.
+fn dx_leaves<T: BlockDeviceInterface>(
+    disk: &mut Disk<'_, T>,
+    map: &BlockMap,
+) -> Result<Option<(u8, DxLeaves)>> {
+    let Some(root) = physical_block(map, 0) else {
+        return Ok(None);
+    };
+    let root = disk.read_block(root)?;
+    // dx_root_info after the "." and ".." entries.
+    let (version, info_length, levels) = (root[0x1c], root[0x1d], root[0x1e]);
+    if le32(&root, 0x18) != 0 || info_length != 8 || levels > 2 {
+        return Ok(None);
+    }
+    let version = match version < DX_HASH_UNSIGNED && disk.sb.unsigned_hash() {
+        true => version + DX_HASH_UNSIGNED,
+        false => version,
+    };
+    let mut leaves = BTreeMap::new();
+    match dx_node(disk, map, &root, 0x20, levels, (0, 1 << 32), &mut leaves)? {
+        true => Ok(Some((version, leaves))),
+        false => Ok(None),
+    }
+}
+
+/// Walk the htree node `node` whose entries start at `offset`, covering hashes in
+/// `range`, recording the range of each leaf block. Returns false if it is corrupt.
+fn dx_node<T: BlockDeviceInterface>(
+    disk: &mut Disk<'_, T>,
+    map: &BlockMap,
+    node: &[u8],
+    offset: usize,
+    depth: u8,
+    range: (u64, u64),
+    leaves: &mut DxLeaves,
+) -> Result<bool> {
+    let limit = le16(node, offset) as usize;
+    let count = le16(node, offset + 2) as usize;
+    if count == 0 || count > limit || offset + count * 8 > node.len() {
+        return Ok(false);
+    }
+    for i in 0..count {
+        let entry = offset + i * 8;
+        let lo = match i {
+            0 => range.0,
+            _ => le32(node, entry) as u64,
+        };
+        let hi = match i + 1 < count {
+            true => le32(node, entry + 8) as u64,
+            false => range.1,
+        };
+        let block = (le32(node, entry + 4) & 0x0fff_ffff) as u64;
+        if depth == 0 {
+            leaves.insert(block, (lo, hi));
+            continue;
+        }
+        let Some(physical) = physical_block(map, block) else {
+            return Ok(false);
+        };
+        let child = disk.read_block(physical)?;
+        if !dx_node(disk, map, &child, 8, depth - 1, (lo, hi), leaves)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn physical_block(map: &BlockMap, logical: u64) -> Option<u64> {
+    map.extents
+        .iter()
+        .find(|e| e.logical <= logical && logical < e.logical + e.len)
+        .map(|e| e.physical + logical - e.logical)
+}
+
+/// Replacement names for the entries of one directory: unique, of the lengths of
+/// the names they replace and drawn from an alphabet that is safe for case folding
+/// if the directory folds case.
+struct Names {
+    alphabet: Vec<u8>,
+    used: BTreeSet<Vec<u8>>,
+    /// Next candidate to try, by name length.
+    next: BTreeMap<usize, u64>,
+    ino: u32,
+    renamed: u64,
+}
+
+impl Names {
+    fn new(ino: u32, casefold: bool) -> Self {
+        let mut alphabet = NAME_CHARS.to_vec();
+        alphabet.extend((0x21..0x7f).filter(|c: &u8| !c.is_ascii_alphanumeric() && *c != b'/'));
+        if !casefold {
+            alphabet.extend(b'A'..=b'Z');
+            alphabet.extend((0x01..=0x20).chain(0x7f..=0xff));
+        }
+        let mut used = BTreeSet::from([b".".to_vec(), b"..".to_vec()]);
+        if ino == ROOT_INO {
+            used.insert(b"lost+found".to_vec());
+        }
+        Self {
+            alphabet,
+            used,
+            next: BTreeMap::new(),
+            ino,
+            renamed: 0,
+        }
+    }
+
+    /// Rename the entries of a directory block (or inline area), keeping their
+    /// hashes within `hash` if given. Returns whether any name changed.
+    fn scramble(&mut self, block: &mut [u8], filetype: bool, hash: Option<&HashRange>) -> bool {
+        let (entries, _) = parse_dir_block(block, filetype);
+        let mut changed = false;
+        for entry in entries {
+            if self.keep(&entry.name) {
+                continue;
+            }
+            let len = entry.name.len();
+            let name = self
+                .pick(len, |name| hash.is_none_or(|hash| hash.fits(name)))
+                .or_else(|| {
+                    warn!(
+                        "image: no name of {} bytes stays in the htree leaf of directory {}",
+                        len, self.ino
+                    );
+                    self.pick(len, |_| true)
+                });
+            if let Some(name) = name {
+                block[entry.offset + 8..entry.offset + 8 + len].copy_from_slice(&name);
+                self.renamed += 1;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Whether `name` is kept as it is: "." and ".." and the root's "lost+found".
+    fn keep(&self, name: &[u8]) -> bool {
+        name == b"." || name == b".." || (self.ino == ROOT_INO && name == b"lost+found")
+    }
+
+    /// The first unused name of `len` bytes accepted by `fits`, trying candidates in
+    /// turn from where the last search for this length stopped.
+    fn pick(&mut self, len: usize, fits: impl Fn(&[u8]) -> bool) -> Option<Vec<u8>> {
+        let base = self.alphabet.len() as u64;
+        let total = base.checked_pow(len as u32).unwrap_or(u64::MAX);
+        let start = self.next.get(&len).copied().unwrap_or(0);
+        for i in 0..total.min(MAX_NAME_TRIES) {
+            let n = (start + i) % total;
+            let mut rest = n;
+            let name: Vec<u8> = (0..len)
+                .map(|_| {
+                    let c = self.alphabet[(rest % base) as usize];
+                    rest /= base;
+                    c
+                })
+                .collect();
+            if !self.used.contains(&name) && fits(&name) {
+                self.next.insert(len, n + 1);
+                self.used.insert(name.clone());
+                return Some(name);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,221 @@
+use crate::block::{BlockDevice, BlockDeviceInterface};
+use crate::disk::*;
+use crate::error::{Error, Result};
+use crate::fsck::{rebuild_allocation, scan_usage};
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn};
+
+/// Magic number of the journal superblock, which is big-endian unlike the rest.
+const JBD2_MAGIC: u32 = 0xc03b_3998;
+/// `h_blocktype` of a version 2 journal superblock.
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+/// Size limits `mke2fs` and `tune2fs` enforce, in blocks.
+const MIN_JOURNAL_BLOCKS: u32 = 1024;
+const MAX_JOURNAL_BLOCKS: u32 = 10_240_000;
+/// Number of journal blocks zeroed per write.
+const ZERO_CHUNK_BLOCKS: u64 = 64;
+
+/// Adds or removes the internal journal of an unmounted file system, like
+/// `tune2fs -j` and `tune2fs -O ^has_journal`.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, Journal};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("ext2.img")
+///     .unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let blocks = Journal::new().add(&mut blk).unwrap();
+/// println!("journal of {} blocks", blocks);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    blocks: Option<u32>,
+}
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size of the journal in file system blocks, between 1024 and 10240000. Defaults
+    /// to the size `mke2fs` picks for the file system.
+    pub fn blocks(mut self, blocks: u32) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    /// Create a journal in inode 8 and set `has_journal`, returning its size in blocks.
+    ///
+    /// The file system must be consistent and have no journal yet. The journal is
+    /// placed in the free blocks from the middle group on, mapped by extents if the
+    /// file system has them and by indirect blocks otherwise, and zeroed.
+    pub fn add<T: BlockDeviceInterface>(&self, bdev: &mut BlockDevice<T>) -> Result<u32> {
+        let mut disk = Disk::open(bdev)?;
+        if disk.sb.has_compat(COMPAT_HAS_JOURNAL) {
+            return Err(Error::FileExists);
+        }
+        disk.load_groups()?;
+        let usage = scan_usage(&mut disk)?;
+        let sb = disk.sb.clone();
+        let blocks = match self.blocks {
+            Some(blocks) => blocks,
+            None => default_journal_blocks(sb.blocks_count()).ok_or(Error::NoSpace)?,
+        };
+        if !(MIN_JOURNAL_BLOCKS..=MAX_JOURNAL_BLOCKS).contains(&blocks) {
+            return Err(Error::InvalidArgument);
+        }
+        let bs = sb.block_size() as u64;
+        let free = sb.blocks_count() - usage.claimed.count();
+        let extents = sb.has_incompat(INCOMPAT_EXTENTS);
+        let meta = match extents {
+            true => blocks as u64 / (bs / 12),
+            false => 3 + blocks as u64 / (bs / 4),
+        };
+        if blocks as u64 + meta > free {
+            warn!(
+                "journal: {} blocks needed, {} free",
+                blocks as u64 + meta,
+                free
+            );
+            return Err(Error::NoSpace);
+        }
+
+        let first = sb.first_data_block() as u64;
+        let middle = sb.group_first_block((sb.group_count() - 1) / 2);
+        let mut alloc = BlockAllocator::new(usage.claimed, first, sb.blocks_count(), middle);
+        let mut runs = Vec::new();
+        let mut logical = 0;
+        while logical < blocks as u64 {
+            let (start, count) = alloc.run(blocks as u64 - logical)?;
+            let extent = Extent {
+                logical,
+                physical: start,
+                len: count,
+                uninit: false,
+            };
+            push_extent(&mut runs, extent);
+            logical += count;
+        }
+        let zeros = vec![0u8; (ZERO_CHUNK_BLOCKS * bs) as usize];
+        for run in &runs {
+            let mut done = 0;
+            while done < run.len {
+                let n = (run.len - done).min(ZERO_CHUNK_BLOCKS);
+                disk.write_block(run.physical + done, &zeros[..(n * bs) as usize])?;
+                done += n;
+            }
+        }
+        let jsb = journal_superblock(&sb, blocks);
+        disk.write_block(runs[0].physical, &jsb)?;
+
+        let mut inode = Inode::new_file(sb.inode_size(), 0o600, sb.write_time());
+        inode.set_size(blocks as u64 * bs);
+        if extents {
+            inode.set_flags(INODE_FLAG_EXTENTS);
+            disk.write_extent_tree(JOURNAL_INO, &mut inode, &runs, &mut || alloc.block())?;
+        } else {
+            let list: Vec<u64> = runs
+                .iter()
+                .flat_map(|run| run.physical..run.physical + run.len)
+                .collect();
+            disk.write_indirect_map(&mut inode, &list, &mut || alloc.block())?;
+        }
+        let count = disk.count_blocks(JOURNAL_INO, &inode)?;
+        inode.set_block_count(count, sb.block_size());
+        disk.write_inode(JOURNAL_INO, &mut inode)?;
+        disk.sb.set_journal_inode(JOURNAL_INO, &inode);
+        rebuild_allocation(&mut disk)?;
+        disk.write_backups()?;
+        disk.close()?;
+        info!("journal: added {} blocks in {} runs", blocks, runs.len());
+        Ok(blocks)
+    }
+
+    /// Remove the journal and clear `has_journal`, freeing the blocks of the journal
+    /// inode. The file system must be consistent and the journal empty.
+    pub fn remove<T: BlockDeviceInterface>(&self, bdev: &mut BlockDevice<T>) -> Result<()> {
+        let mut disk = Disk::open(bdev)?;
+        if !disk.sb.has_compat(COMPAT_HAS_JOURNAL) {
+            return Err(Error::NoEntry);
+        }
+        if disk.sb.has_incompat(INCOMPAT_RECOVER) {
+            warn!("journal: needs recovery first");
+            return Err(Error::InvalidArgument);
+        }
+        disk.load_groups()?;
+        scan_usage(&mut disk)?;
+        let ino = disk.sb.journal_inum();
+        if ino != 0 {
+            let inode = disk.read_inode(ino)?;
+            let map = disk.map_inode(ino, &inode)?;
+            if let Some(first) = map.extents.first().filter(|e| e.logical == 0) {
+                let jsb = disk.read_block(first.physical)?;
+                if be32(&jsb, 0x00) == JBD2_MAGIC && be32(&jsb, 0x1c) != 0 {
+                    warn!("journal: not empty, needs recovery first");
+                    return Err(Error::InvalidArgument);
+                }
+            }
+            let mut empty = Inode {
+                raw: vec![0u8; disk.sb.inode_size()],
+            };
+            disk.write_inode(ino, &mut empty)?;
+        }
+        disk.sb.clear_journal();
+        rebuild_allocation(&mut disk)?;
+        disk.write_backups()?;
+        disk.close()?;
+        info!("journal: removed inode {}", ino);
+        Ok(())
+    }
+}
+
+/// Journal size `mke2fs` picks for a file system of `blocks`, c.f.
+/// `ext2fs_default_journal_size`.
+fn default_journal_blocks(blocks: u64) -> Option<u32> {
+    let size = match blocks {
+        0..2048 => return None,
+        2048..32768 => 1024,
+        32768..262144 => 4096,
+        262144..524288 => 8192,
+        524288..4194304 => 16384,
+        4194304..8388608 => 32768,
+        8388608..16777216 => 65536,
+        16777216..33554432 => 131072,
+        _ => 262144,
+    };
+    Some(size)
+}
+
+/// An empty version 2 journal superblock for an internal journal of `blocks`.
+fn journal_superblock(sb: &Superblock, blocks: u32) -> Vec<u8> {
+    let mut jsb = vec![0u8; sb.block_size() as usize];
+    put_be32(&mut jsb, 0x00, JBD2_MAGIC);
+    put_be32(&mut jsb, 0x04, JBD2_SUPERBLOCK_V2);
+    put_be32(&mut jsb, 0x0c, sb.block_size());
+    put_be32(&mut jsb, 0x10, blocks);
+    // The log starts right after the superblock, at sequence 1.
+    put_be32(&mut jsb, 0x14, 1);
+    put_be32(&mut jsb, 0x18, 1);
+    jsb[0x30..0x40].copy_from_slice(&sb.uuid());
+    put_be32(&mut jsb, 0x40, 1);
+    jsb
+}
+
+fn be32(buf: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn put_be32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_be_bytes());
+}
@@ -6,31 +6,83 @@ mod block;
 mod dir;
 mod error;
 
+#[cfg(feature = "compressed")]
+mod compressed;
+#[cfg(feature = "std")]
+mod diff;
+#[cfg(feature = "std")]
+mod export;
+#[cfg(feature = "std")]
+mod host;
+#[cfg(feature = "std")]
+mod sparse_file;
 #[cfg(feature = "std")]
 mod standard;
+#[cfg(feature = "std")]
+mod sync;
 
 extern crate alloc;
 extern crate core;
 
+#[cfg(feature = "compressed")]
+pub use compressed::CompressedInterface;
+#[cfg(feature = "std")]
+pub use diff::{DiffAttr, DiffChange, DiffEntry, DiffReport, TreeDiff};
+#[cfg(feature = "std")]
+pub use export::{ExportIssue, ExportIssueKind, ExportReport};
+#[cfg(feature = "std")]
+pub use sparse_file::SparseFileInterface;
 #[cfg(feature = "std")]
 pub use standard::*;
+#[cfg(feature = "std")]
+pub use sync::{HostSync, SyncIssue, SyncIssueKind, SyncReport};
 
 mod fs;
 
+mod clone;
+mod concat;
+mod crc;
 mod debug;
+mod disk;
+mod feature;
 mod file;
+mod fsck;
+mod gpt;
+mod hash;
+mod image;
+mod journal;
+mod mbr;
 mod mkfs;
+mod partition;
+mod probe;
+mod resize;
+mod sparse;
+mod tar;
 mod types;
+mod zero;
 
 pub use block::{
     BlockDevice, BlockDeviceConfig, BlockDeviceInterface, MountHandle, RegisterHandle,
 };
+pub use clone::{clone_filesystem, CloneIssue, CloneIssueKind, CloneReport};
+pub use concat::Concat;
 pub use debug::*;
 pub use dir::{DirEntry, ReadDir};
 pub use error::{Error, Result};
+pub use feature::FeatureReport;
 pub use file::File;
 pub use fs::FileSystem;
+pub use fsck::{Fsck, FsckProblem, FsckRepair, FsckReport};
+pub use gpt::{scan_gpt, GptBuilder, GptPartition, GptTable, Guid};
+pub use image::{image_copy, ImageCopy, ImageCopyReport};
+pub use journal::Journal;
+pub use mbr::{scan_mbr, MbrBuilder, MbrPartition};
 pub use mkfs::{BuildExtFs, FsBuilder};
+pub use partition::{Partition, SharedDevice, SharedGuard};
+pub use probe::{find_filesystem, probe, FsSpec, ProbeDevice, ProbeInfo};
+pub use resize::{Resize, ResizeReport};
+pub use sparse::{export_sparse, import_sparse, SparseReport};
 pub use types::{
     DebugFlags, FileTimes, FileType, FsType, MetaDataExt, Metadata, MountStats, Permissions, Time,
 };
+pub use zero::ZeroReport;
@@ -0,0 +1,248 @@
+use crate::block::{BlockDeviceConfig, BlockDeviceInterface};
+use crate::disk::{le32, open_device, put32, read_at, write_at};
+use crate::error::{Error, Result};
+use alloc::vec::Vec;
+
+/// MBR addresses are in 512 byte sectors whatever the device block size.
+pub(crate) const SECTOR_SIZE: u64 = 512;
+/// Partitions are created aligned to 1MiB, like `fdisk` and `parted` do.
+pub(crate) const ALIGN_SECTORS: u64 = 2048;
+const DISK_ID_OFFSET: usize = 0x1b8;
+const ENTRIES_OFFSET: usize = 0x1be;
+const ENTRY_SIZE: usize = 16;
+const SIGNATURE_OFFSET: usize = 0x1fe;
+/// Longest chain of extended boot records followed, against loops.
+const MAX_LOGICAL: u32 = 128;
+
+/// A partition found by [scan_mbr].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// 1 to 4 for primary partitions, 5 on for logical ones, as Linux numbers them.
+    pub number: u32,
+    /// Partition type, e.g. `0x83` for Linux or `0x0c` for FAT32.
+    pub kind: u8,
+    pub bootable: bool,
+    /// First sector.
+    pub start: u64,
+    /// Length in sectors.
+    pub sectors: u64,
+}
+
+impl MbrPartition {
+    pub const FAT32: u8 = 0x0c;
+    pub const LINUX_SWAP: u8 = 0x82;
+    pub const LINUX: u8 = 0x83;
+    pub const EFI_SYSTEM: u8 = 0xef;
+
+    /// Offset of the partition in bytes.
+    pub fn offset(&self) -> u64 {
+        self.start * SECTOR_SIZE
+    }
+
+    /// Size of the partition in bytes.
+    pub fn size(&self) -> u64 {
+        self.sectors * SECTOR_SIZE
+    }
+}
+
+/// List the partitions of the MBR on `dev`, the primary ones then the logical ones
+/// of an extended partition, which is not listed itself.
+///
+/// Fails with [Error::NoEntry] if the device has no MBR signature. A GPT disk shows
+/// a single protective partition of type `0xee`.
+pub fn scan_mbr<T: BlockDeviceInterface>(dev: &mut T) -> Result<Vec<MbrPartition>> {
+    let config = open_device(dev)?;
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (i, entry) in read_table(dev, &config, 0)?.into_iter().enumerate() {
+        match entry {
+            Some(e) if is_extended(e.kind) => extended = Some(e),
+            Some(e) => partitions.push(MbrPartition {
+                number: i as u32 + 1,
+                ..e
+            }),
+            None => {}
+        }
+    }
+    let Some(extended) = extended else {
+        return Ok(partitions);
+    };
+    // Each extended boot record holds a logical partition, relative to itself, and a
+    // link to the next record, relative to the extended partition.
+    let mut ebr = extended.start;
+    for number in 5..5 + MAX_LOGICAL {
+        let [logical, next, ..] = read_table(dev, &config, ebr)?;
+        if let Some(e) = logical {
+            partitions.push(MbrPartition {
+                number,
+                start: ebr + e.start,
+                ..e
+            });
+        }
+        match next {
+            Some(next) if is_extended(next.kind) => ebr = extended.start + next.start,
+            _ => return Ok(partitions),
+        }
+    }
+    Err(Error::InvalidArgument)
+}
+
+fn is_extended(kind: u8) -> bool {
+    matches!(kind, 0x05 | 0x0f | 0x85)
+}
+
+/// The four entries of the table in `sector`, `None` for unused ones.
+fn read_table<T: BlockDeviceInterface>(
+    dev: &mut T,
+    config: &BlockDeviceConfig,
+    sector: u64,
+) -> Result<[Option<MbrPartition>; 4]> {
+    let mut raw = [0u8; SECTOR_SIZE as usize];
+    read_at(dev, config, sector * SECTOR_SIZE, &mut raw)?;
+    if raw[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+        return Err(Error::NoEntry);
+    }
+    let mut table = [None; 4];
+    for (i, slot) in table.iter_mut().enumerate() {
+        let entry = &raw[ENTRIES_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        let sectors = le32(entry, 12) as u64;
+        if entry[4] == 0 || sectors == 0 {
+            continue;
+        }
+        *slot = Some(MbrPartition {
+            number: 0,
+            kind: entry[4],
+            bootable: entry[0] & 0x80 != 0,
+            start: le32(entry, 8) as u64,
+            sectors,
+        });
+    }
+    Ok(table)
+}
+
+/// Writes a fresh MBR with up to four primary partitions, like `fdisk` does.
+///
+/// Partitions are laid out in order from the first MiB, each aligned to 1MiB. The
+/// boot code in the first sector is kept.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, MbrBuilder, MbrPartition};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("sdcard.img")
+///     .unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut dev = DefaultInterface::new(file, config);
+/// let partitions = MbrBuilder::new()
+///     .partition(MbrPartition::FAT32, true, Some(64 << 20))
+///     .partition(MbrPartition::LINUX, false, None)
+///     .write(&mut dev)
+///     .unwrap();
+/// println!("root at {}", partitions[1].offset());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MbrBuilder {
+    disk_id: u32,
+    partitions: Vec<(u8, bool, Option<u64>)>,
+}
+
+impl MbrBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disk identifier at offset `0x1b8`, 0 by default.
+    pub fn disk_id(mut self, disk_id: u32) -> Self {
+        self.disk_id = disk_id;
+        self
+    }
+
+    /// Add a partition of type `kind` and `size` bytes, rounded up to whole sectors, or
+    /// of the rest of the device if `None`.
+    pub fn partition(mut self, kind: u8, bootable: bool, size: Option<u64>) -> Self {
+        self.partitions.push((kind, bootable, size));
+        self
+    }
+
+    /// Write the partition table, overwriting whatever `dev` had, and return the
+    /// partitions as [scan_mbr] would.
+    ///
+    /// Fails with [Error::NoSpace] if the partitions do not fit in the device or in
+    /// the 2TiB an MBR can address, and with [Error::InvalidArgument] if there are
+    /// more than four or one has a zero size or type.
+    pub fn write<T: BlockDeviceInterface>(&self, dev: &mut T) -> Result<Vec<MbrPartition>> {
+        let config = open_device(dev)?;
+        let sectors = (config.part_size / SECTOR_SIZE).min(1 << 32);
+        if self.partitions.len() > 4 {
+            return Err(Error::InvalidArgument);
+        }
+        let mut partitions = Vec::new();
+        let mut next = ALIGN_SECTORS;
+        for (i, &(kind, bootable, size)) in self.partitions.iter().enumerate() {
+            let count = match size {
+                Some(0) => return Err(Error::InvalidArgument),
+                Some(size) => size.div_ceil(SECTOR_SIZE),
+                None => sectors.saturating_sub(next),
+            };
+            if kind == 0 {
+                return Err(Error::InvalidArgument);
+            }
+            if count == 0 || next + count > sectors {
+                return Err(Error::NoSpace);
+            }
+            partitions.push(MbrPartition {
+                number: i as u32 + 1,
+                kind,
+                bootable,
+                start: next,
+                sectors: count,
+            });
+            next = (next + count).next_multiple_of(ALIGN_SECTORS);
+        }
+
+        let mut mbr = [0u8; SECTOR_SIZE as usize];
+        read_at(dev, &config, 0, &mut mbr)?;
+        mbr[DISK_ID_OFFSET..SIGNATURE_OFFSET].fill(0);
+        put32(&mut mbr, DISK_ID_OFFSET, self.disk_id);
+        for p in &partitions {
+            let entry =
+                &mut mbr[ENTRIES_OFFSET + (p.number as usize - 1) * ENTRY_SIZE..][..ENTRY_SIZE];
+            entry[0] = if p.bootable { 0x80 } else { 0 };
+            entry[1..4].copy_from_slice(&chs(p.start));
+            entry[4] = p.kind;
+            entry[5..8].copy_from_slice(&chs(p.start + p.sectors - 1));
+            put32(entry, 8, p.start as u32);
+            put32(entry, 12, p.sectors as u32);
+        }
+        mbr[SIGNATURE_OFFSET..].copy_from_slice(&[0x55, 0xaa]);
+        write_at(dev, &config, 0, &mbr)?;
+        dev.close()?;
+        Ok(partitions)
+    }
+}
+
+/// Cylinder/head/sector address of `lba` with the usual 255 heads and 63 sectors per
+/// track, saturated past what CHS can address.
+fn chs(lba: u64) -> [u8; 3] {
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = lba / SECTORS % HEADS;
+    let sector = lba % SECTORS + 1;
+    [
+        head as u8,
+        (sector as u8) | ((cylinder >> 2) as u8 & 0xc0),
+        cylinder as u8,
+    ]
+}
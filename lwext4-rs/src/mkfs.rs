@@ -170,6 +170,7 @@ impl<T: BlockDeviceInterface> BuildExtFs<T> {
         }
     }
 }
+#[derive(Debug, Clone)]
 pub struct FsBuilder {
     block_size: u32,
     ty: Option<FsType>,
@@ -0,0 +1,230 @@
+use crate::block::{BlockDevice, BlockDeviceConfig, BlockDeviceInterface};
+use crate::disk::open_device;
+use crate::error::{Error, Result};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// One [BlockDeviceInterface] shared by several block devices, typically the
+/// partitions of a disk, so that they can be registered and mounted at the same time.
+///
+/// Every access to the interface goes through a lock, and the partitions handed out
+/// never overlap, so that the block cache lwext4 keeps for each of them is the only
+/// one for its blocks. A range is free again once its partition is dropped.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{scan_mbr, BlockDeviceConfig, DefaultInterface, RegisterHandle, SharedDevice};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("sdcard.img")
+///     .unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let disk = SharedDevice::new(DefaultInterface::new(file, config));
+/// let partitions = scan_mbr(&mut *disk.lock()).unwrap();
+/// let boot = disk.partition(partitions[0].offset(), partitions[0].size()).unwrap();
+/// let root = disk.partition(partitions[1].offset(), partitions[1].size()).unwrap();
+/// let boot = RegisterHandle::register(boot, "sda1".to_string()).unwrap();
+/// let root = RegisterHandle::register(root, "sda2".to_string()).unwrap();
+/// ```
+pub struct SharedDevice<T: BlockDeviceInterface> {
+    inner: Arc<Lock<Shared<T>>>,
+}
+
+struct Shared<T> {
+    dev: T,
+    /// Byte ranges of the partitions handed out.
+    views: Vec<(u64, u64)>,
+}
+
+impl<T: BlockDeviceInterface> Clone for SharedDevice<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: BlockDeviceInterface> SharedDevice<T> {
+    pub fn new(dev: T) -> Self {
+        Self {
+            inner: Arc::new(Lock::new(Shared {
+                dev,
+                views: Vec::new(),
+            })),
+        }
+    }
+
+    /// Lock the interface for direct use, e.g. to [scan](crate::scan_mbr) its
+    /// partition table. The partitions wait until the guard is dropped, so it must not
+    /// be held while using one of them on the same thread.
+    pub fn lock(&self) -> SharedGuard<'_, T> {
+        SharedGuard(self.inner.lock())
+    }
+
+    /// A block device for the `size` bytes at byte `offset`, which must be aligned to
+    /// the block size of the interface.
+    ///
+    /// Fails with [Error::InvalidArgument] if the range is misaligned or beyond the
+    /// device, and with [Error::FileExists] if it overlaps a partition still in use.
+    pub fn partition(&self, offset: u64, size: u64) -> Result<Pin<Box<BlockDevice<Partition<T>>>>> {
+        let mut shared = self.inner.lock();
+        let config = open_device(&mut shared.dev)?;
+        let bs = config.block_size as u64;
+        let aligned = offset.is_multiple_of(bs) && size.is_multiple_of(bs);
+        if !aligned || size == 0 || offset + size > config.part_size {
+            return Err(Error::InvalidArgument);
+        }
+        let overlaps = |&(o, s): &(u64, u64)| offset < o + s && o < offset + size;
+        if shared.views.iter().any(overlaps) {
+            return Err(Error::FileExists);
+        }
+        shared.views.push((offset, size));
+        Ok(BlockDevice::new(Partition {
+            dev: self.clone(),
+            offset,
+            size,
+        }))
+    }
+}
+
+/// Exclusive access to the interface of a [SharedDevice].
+pub struct SharedGuard<'a, T: BlockDeviceInterface>(LockGuard<'a, Shared<T>>);
+
+impl<T: BlockDeviceInterface> Deref for SharedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0.dev
+    }
+}
+
+impl<T: BlockDeviceInterface> DerefMut for SharedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0.dev
+    }
+}
+
+/// A range of a [SharedDevice], usable as a block device of its own.
+///
+/// The block ids passed through are those of the whole device, the partition is
+/// described by the `part_offset` and `part_size` of its configuration.
+pub struct Partition<T: BlockDeviceInterface> {
+    dev: SharedDevice<T>,
+    offset: u64,
+    size: u64,
+}
+
+impl<T: BlockDeviceInterface> Partition<T> {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<T: BlockDeviceInterface> Drop for Partition<T> {
+    fn drop(&mut self) {
+        let range = (self.offset, self.size);
+        self.dev.inner.lock().views.retain(|&view| view != range);
+    }
+}
+
+impl<T: BlockDeviceInterface> BlockDeviceInterface for Partition<T> {
+    fn open(&mut self) -> Result<BlockDeviceConfig> {
+        let config = open_device(&mut *self.dev.lock())?;
+        Ok(BlockDeviceConfig {
+            part_offset: config.part_offset + self.offset,
+            part_size: self.size,
+            ..config
+        })
+    }
+
+    fn read_block(&mut self, buf: &mut [u8], block_id: u64, block_count: u32) -> Result<usize> {
+        self.dev.lock().read_block(buf, block_id, block_count)
+    }
+
+    fn write_block(&mut self, buf: &[u8], block_id: u64, block_count: u32) -> Result<usize> {
+        self.dev.lock().write_block(buf, block_id, block_count)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.dev.lock().close()
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        self.dev.lock().lock()
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        self.dev.lock().unlock()
+    }
+
+    fn discard(&mut self, block_id: u64, block_count: u64) -> Result<()> {
+        self.dev.lock().discard(block_id, block_count)
+    }
+}
+
+/// A spin lock, which is all `no_std` offers. Block I/O holds it for one request.
+struct Lock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Lock<T> {}
+unsafe impl<T: Send> Sync for Lock<T> {}
+
+impl<T> Lock<T> {
+    fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn lock(&self) -> LockGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        LockGuard(self)
+    }
+}
+
+struct LockGuard<'a, T>(&'a Lock<T>);
+
+impl<T> Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0.value.get() }
+    }
+}
+
+impl<T> DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.0.value.get() }
+    }
+}
+
+impl<T> Drop for LockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.0.locked.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::block::{BlockDevice, BlockDeviceInterface, RegisterHandle};
+use crate::disk::*;
+use crate::error::{Error, Result};
+use crate::types::FsType;
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+
+/// Incompatible and read-only compatible features an ext3 file system can have, any
+/// other makes it ext4, as `blkid` decides.
+const EXT3_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_META_BG;
+const EXT3_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+/// What [probe] found on a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeInfo {
+    pub fs_type: FsType,
+    pub uuid: [u8; 16],
+    /// Volume name, empty if unset.
+    pub label: String,
+    /// Size of the file system in bytes.
+    pub size: u64,
+    pub block_size: u32,
+    /// Whether the journal has to be replayed, which mounting with journal recovery does.
+    pub needs_recovery: bool,
+}
+
+/// Identify the ext2/3/4 file system on `bdev` from its superblock, without mounting.
+///
+/// Fails with [Error](crate::Error)::NotSupported if the device does not hold one, which includes
+/// external journal devices.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{probe, BlockDeviceConfig, DefaultInterface};
+/// let file = std::fs::File::open("ext4.img").unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let info = probe(&mut blk).unwrap();
+/// println!("{:?} {:?} {} bytes", info.fs_type, info.label, info.size);
+/// ```
+pub fn probe<T: BlockDeviceInterface>(bdev: &mut BlockDevice<T>) -> Result<ProbeInfo> {
+    let disk = Disk::open(bdev)?;
+    Ok(ProbeInfo::from_superblock(&disk.sb))
+}
+
+impl ProbeInfo {
+    pub(crate) fn from_superblock(sb: &Superblock) -> Self {
+        let fs_type = if sb.feature_incompat() & !EXT3_INCOMPAT != 0
+            || sb.feature_ro_compat() & !EXT3_RO_COMPAT != 0
+        {
+            FsType::Ext4
+        } else if sb.has_compat(COMPAT_HAS_JOURNAL) {
+            FsType::Ext3
+        } else {
+            FsType::Ext2
+        };
+        Self {
+            fs_type,
+            uuid: sb.uuid(),
+            label: String::from_utf8_lossy(sb.label()).into_owned(),
+            size: sb.blocks_count() * sb.block_size() as u64,
+            block_size: sb.block_size(),
+            needs_recovery: sb.has_incompat(INCOMPAT_RECOVER),
+        }
+    }
+}
+
+/// A file system named the way `fstab` does, `LABEL=rootfs` or
+/// `UUID=0c3d9b7e-1f2a-4d5e-8a6b-7c8d9e0f1a2b`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsSpec {
+    Label(String),
+    Uuid([u8; 16]),
+}
+
+impl FsSpec {
+    /// Whether the probed file system is the one named.
+    pub fn matches(&self, info: &ProbeInfo) -> bool {
+        match self {
+            FsSpec::Label(label) => info.label == *label,
+            FsSpec::Uuid(uuid) => info.uuid == *uuid,
+        }
+    }
+}
+
+impl FromStr for FsSpec {
+    type Err = Error;
+
+    /// Parse `LABEL=...` or `UUID=...`, the UUID in hex with optional dashes.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(label) = s.strip_prefix("LABEL=") {
+            return Ok(FsSpec::Label(label.into()));
+        }
+        let hex = s.strip_prefix("UUID=").ok_or(Error::InvalidArgument)?;
+        let digits: String = hex.chars().filter(|&c| c != '-').collect();
+        if digits.len() != 32 || !digits.is_ascii() {
+            return Err(Error::InvalidArgument);
+        }
+        let mut uuid = [0u8; 16];
+        for (i, byte) in uuid.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)
+                .map_err(|_| Error::InvalidArgument)?;
+        }
+        Ok(FsSpec::Uuid(uuid))
+    }
+}
+
+impl Display for FsSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FsSpec::Label(label) => write!(f, "LABEL={}", label),
+            FsSpec::Uuid(uuid) => {
+                write!(f, "UUID=")?;
+                for (i, byte) in uuid.iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A device [find_filesystem] can probe, whatever block device interface it has.
+///
+/// Implemented by block devices and registered handles, and by mutable references to
+/// them, so that `&mut dyn ProbeDevice` mixes whole disks with the partitions of a
+/// [SharedDevice](crate::SharedDevice).
+pub trait ProbeDevice {
+    /// Identify the file system on the device, see [probe].
+    fn probe(&mut self) -> Result<ProbeInfo>;
+}
+
+impl<T: BlockDeviceInterface> ProbeDevice for BlockDevice<T> {
+    fn probe(&mut self) -> Result<ProbeInfo> {
+        probe(self)
+    }
+}
+
+impl<T: BlockDeviceInterface> ProbeDevice for RegisterHandle<T> {
+    fn probe(&mut self) -> Result<ProbeInfo> {
+        RegisterHandle::probe(self)
+    }
+}
+
+impl<P: ProbeDevice + ?Sized> ProbeDevice for &mut P {
+    fn probe(&mut self) -> Result<ProbeInfo> {
+        (**self).probe()
+    }
+}
+
+/// Find the device (or partition) holding the file system named by `spec`, like
+/// `blkid -t`, returning its index in `devices` and what was probed.
+///
+/// Devices that do not hold an ext2/3/4 file system are skipped. With several
+/// matches, the first one wins.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{find_filesystem, FsSpec, MountHandle, RegisterHandle};
+/// # fn devices() -> Vec<RegisterHandle<lwext4_rs::DefaultInterface<std::fs::File>>> { vec![] }
+/// let mut handles = devices();
+/// let spec = "LABEL=rootfs".parse::<FsSpec>().unwrap();
+/// let (index, _) = find_filesystem(&mut handles, &spec).unwrap();
+/// let root = handles.swap_remove(index);
+/// let mount = MountHandle::mount(root, "/".to_string(), true, false).unwrap();
+/// ```
+///
+/// Devices with different interfaces are passed as trait objects:
+/// ```no_run
+/// use lwext4_rs::{find_filesystem, FsSpec, ProbeDevice, RegisterHandle};
+/// # use lwext4_rs::{DefaultInterface, Partition};
+/// # fn devices() -> (RegisterHandle<DefaultInterface<std::fs::File>>, RegisterHandle<Partition<DefaultInterface<std::fs::File>>>) { unimplemented!() }
+/// let (mut usb, mut sda1) = devices();
+/// let spec = "LABEL=rootfs".parse::<FsSpec>().unwrap();
+/// let found = find_filesystem([&mut usb as &mut dyn ProbeDevice, &mut sda1], &spec);
+/// ```
+pub fn find_filesystem<I>(devices: I, spec: &FsSpec) -> Option<(usize, ProbeInfo)>
+where
+    I: IntoIterator,
+    I::Item: ProbeDevice,
+{
+    devices
+        .into_iter()
+        .enumerate()
+        .find_map(|(index, mut device)| {
+            let info = device.probe().ok()?;
+            spec.matches(&info).then_some((index, info))
+        })
+}
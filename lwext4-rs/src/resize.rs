@@ -0,0 +1,820 @@
+use crate::block::{BlockDevice, BlockDeviceInterface};
+use crate::disk::*;
+use crate::error::{Error, Result};
+use crate::fsck::{rebuild_allocation, scan_usage, Usage};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn};
+
+/// Smallest number of free blocks a new last group must have, c.f. `resize2fs`.
+const MIN_LAST_GROUP_FREE: u64 = 50;
+/// Number of blocks zeroed or copied per request.
+const CHUNK_BLOCKS: u64 = 64;
+/// Blocks added to the minimum size on top of the data, for rebuilt extent trees.
+const SHRINK_SLACK_BLOCKS: u64 = 64;
+
+/// Summary of a [Resize] run.
+#[derive(Debug, Clone, Default)]
+pub struct ResizeReport {
+    pub old_blocks: u64,
+    pub new_blocks: u64,
+    pub old_groups: u32,
+    pub new_groups: u32,
+    /// File system block size in bytes.
+    pub block_size: u32,
+    /// Blocks moved out of the removed groups when shrinking.
+    pub moved_blocks: u64,
+    /// Inodes renumbered into the remaining groups when shrinking.
+    pub moved_inodes: u32,
+}
+
+/// Offline resizer for an unmounted file system, similar to `resize2fs`.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, DefaultInterface, Resize};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("ext4.img")
+///     .unwrap();
+/// file.set_len(256 << 20).unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let report = Resize::new().run(&mut blk).unwrap();
+/// println!("{} -> {} blocks", report.old_blocks, report.new_blocks);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Resize {
+    blocks: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl Resize {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Target size in file system blocks. Without a target, [grow](Self::grow) fills
+    /// the device and [shrink](Self::shrink) goes down to the [minimum](Self::minimum).
+    pub fn blocks(mut self, blocks: u64) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    /// Target size in bytes, rounded down to whole blocks; [blocks](Self::blocks) wins.
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    fn target(&self, sb: &Superblock) -> Option<u64> {
+        self.blocks
+            .or(self.bytes.map(|bytes| bytes / sb.block_size() as u64))
+    }
+
+    /// Grow or shrink the file system on `bdev` to the target size, growing to the size
+    /// of the device if there is none.
+    pub fn run<T: BlockDeviceInterface>(&self, bdev: &mut BlockDevice<T>) -> Result<ResizeReport> {
+        let disk = Disk::open(bdev)?;
+        let shrink = self
+            .target(&disk.sb)
+            .is_some_and(|target| target < disk.sb.blocks_count());
+        disk.close()?;
+        match shrink {
+            true => self.shrink(bdev),
+            false => self.grow(bdev),
+        }
+    }
+
+    /// Grow the file system on `bdev` to the target size.
+    ///
+    /// The last group is extended and new groups are added behind it. The descriptor
+    /// table grows into the reserved GDT blocks, or into new meta groups with
+    /// `meta_bg`; the superblock, its backups and the free counts are updated. A last
+    /// group too small to hold its own metadata is left out, so the result may be
+    /// slightly smaller than the target.
+    pub fn grow<T: BlockDeviceInterface>(&self, bdev: &mut BlockDevice<T>) -> Result<ResizeReport> {
+        let mut disk = Disk::open(bdev)?;
+        check_resizable(&disk.sb)?;
+        disk.load_groups()?;
+        let old = disk.sb.clone();
+        let device_blocks = disk.device_size() / old.block_size() as u64;
+        let target = self.target(&old).unwrap_or(device_blocks);
+        if target > device_blocks {
+            return Err(Error::NoSpace);
+        }
+        if target < old.blocks_count() {
+            return Err(Error::InvalidArgument);
+        }
+        let new_blocks = fit_last_group(&old, target);
+        let mut report = ResizeReport {
+            old_blocks: old.blocks_count(),
+            new_blocks: old.blocks_count(),
+            old_groups: old.group_count(),
+            new_groups: old.group_count(),
+            block_size: old.block_size(),
+            ..Default::default()
+        };
+        if new_blocks <= old.blocks_count() {
+            return Ok(report);
+        }
+        if new_blocks > u32::MAX as u64 && !old.has_incompat(INCOMPAT_64BIT) {
+            return Err(Error::FileTooBig);
+        }
+
+        let mut sb = old.clone();
+        sb.set_blocks_count(new_blocks);
+        let groups = sb.group_count();
+        let inodes = groups as u64 * sb.inodes_per_group() as u64;
+        if inodes > u32::MAX as u64 {
+            return Err(Error::FileTooBig);
+        }
+        if !sb.has_incompat(INCOMPAT_META_BG) && sb.desc_blocks() > old.desc_blocks() {
+            let needed = sb.desc_blocks() - old.desc_blocks();
+            if needed > old.reserved_gdt_blocks() {
+                warn!(
+                    "resize: {} more descriptor blocks needed, {} reserved",
+                    needed,
+                    old.reserved_gdt_blocks()
+                );
+                return Err(Error::NoSpace);
+            }
+            sb.set_reserved_gdt_blocks(old.reserved_gdt_blocks() - needed);
+        }
+        disk.sb = sb;
+
+        let mut free_blocks = old.free_blocks_count() + extend_last_group(&mut disk, &old)?;
+        for group in old.group_count()..groups {
+            free_blocks += add_group(&mut disk, group)? as u64;
+        }
+        let ipg = disk.sb.inodes_per_group();
+        let free_inodes = old.free_inodes_count() + (groups - old.group_count()) * ipg;
+        let sb = &mut disk.sb;
+        sb.set_inodes_count(inodes as u32);
+        sb.set_free_blocks_count(free_blocks);
+        sb.set_free_inodes_count(free_inodes);
+        let reserved = old.r_blocks_count() as u128 * new_blocks as u128;
+        sb.set_r_blocks_count((reserved / old.blocks_count() as u128) as u64);
+        if disk.sb.has_compat(COMPAT_RESIZE_INODE) {
+            rebuild_resize_inode(&mut disk)?;
+        }
+        disk.flush_metadata()?;
+        disk.write_backups()?;
+        disk.close()?;
+
+        report.new_blocks = new_blocks;
+        report.new_groups = groups;
+        info!(
+            "resize: {} -> {} blocks, {} -> {} groups",
+            report.old_blocks, report.new_blocks, report.old_groups, report.new_groups
+        );
+        Ok(report)
+    }
+
+    /// Smallest size in blocks the file system on `bdev` can be
+    /// [shrunk](Self::shrink) to. The file system must be consistent.
+    pub fn minimum<T: BlockDeviceInterface>(&self, bdev: &mut BlockDevice<T>) -> Result<u64> {
+        let mut disk = Disk::open(bdev)?;
+        check_resizable(&disk.sb)?;
+        disk.load_groups()?;
+        let usage = scan_usage(&mut disk)?;
+        Ok(minimum_blocks(&disk, &usage))
+    }
+
+    /// Shrink the file system on `bdev` to the target size, or to its
+    /// [minimum](Self::minimum) without one.
+    ///
+    /// The file system must be consistent, [Fsck](crate::Fsck) finding no problem.
+    /// Inodes of the removed groups are renumbered into free slots of the remaining
+    /// ones, rewriting the directory entries pointing at them. Blocks in the removed
+    /// range are moved down, rebuilding extent trees or rewriting indirect blocks, and
+    /// the bitmaps and free counts are recomputed. The device is not truncated; the
+    /// file system ends at [ResizeReport::new_blocks].
+    pub fn shrink<T: BlockDeviceInterface>(
+        &self,
+        bdev: &mut BlockDevice<T>,
+    ) -> Result<ResizeReport> {
+        let mut disk = Disk::open(bdev)?;
+        check_resizable(&disk.sb)?;
+        disk.load_groups()?;
+        let usage = scan_usage(&mut disk)?;
+        let old = disk.sb.clone();
+        let minimum = minimum_blocks(&disk, &usage);
+        let target = self.target(&old).unwrap_or(minimum);
+        if target > old.blocks_count() {
+            return Err(Error::InvalidArgument);
+        }
+        let new_blocks = trim_last_group(&old, target);
+        if new_blocks < minimum {
+            warn!("resize: at least {} blocks needed", minimum);
+            return Err(Error::NoSpace);
+        }
+        let mut report = ResizeReport {
+            old_blocks: old.blocks_count(),
+            new_blocks: old.blocks_count(),
+            old_groups: old.group_count(),
+            new_groups: old.group_count(),
+            block_size: old.block_size(),
+            ..Default::default()
+        };
+        if new_blocks == old.blocks_count() {
+            return Ok(report);
+        }
+
+        let layout = shrunk_layout(&old, new_blocks);
+        let groups = layout.group_count();
+        let keep = kept_metadata(&disk, &layout)?;
+        let first = old.first_data_block() as u64;
+        let taken = taken_blocks(&usage, &keep, new_blocks);
+        let mut alloc = BlockAllocator::new(taken, first, new_blocks, first);
+        let map = renumber_inodes(&mut disk, &usage, groups)?;
+        let count = groups * old.inodes_per_group();
+        let mut inos: Vec<u32> = (1..=count)
+            .filter(|&ino| usage.used.get(ino as u64))
+            .chain(map.values().copied())
+            .collect();
+        inos.sort_unstable();
+        if !map.is_empty() {
+            rewrite_entries(&mut disk, &inos, &map)?;
+        }
+        report.moved_inodes = map.len() as u32;
+        report.moved_blocks = move_blocks(&mut disk, &inos, &mut alloc, new_blocks)?;
+
+        let sb = &mut disk.sb;
+        sb.set_blocks_count(new_blocks);
+        sb.set_inodes_count(count);
+        sb.set_reserved_gdt_blocks(layout.reserved_gdt_blocks());
+        sb.set_backup_bgs(layout.backup_bgs());
+        sb.remap_inodes(&|ino| map.get(&ino).copied().unwrap_or(ino));
+        let reserved = old.r_blocks_count() as u128 * new_blocks as u128;
+        sb.set_r_blocks_count((reserved / old.blocks_count() as u128) as u64);
+        disk.groups.truncate(groups as usize);
+        pad_last_group(&mut disk)?;
+        if disk.sb.has_compat(COMPAT_RESIZE_INODE) {
+            rebuild_resize_inode(&mut disk)?;
+        }
+        rebuild_allocation(&mut disk)?;
+        disk.write_backups()?;
+        disk.close()?;
+
+        report.new_blocks = new_blocks;
+        report.new_groups = groups;
+        info!(
+            "resize: {} -> {} blocks, {} -> {} groups, {} blocks and {} inodes moved",
+            report.old_blocks,
+            report.new_blocks,
+            report.old_groups,
+            report.new_groups,
+            report.moved_blocks,
+            report.moved_inodes
+        );
+        Ok(report)
+    }
+}
+
+fn check_resizable(sb: &Superblock) -> Result<()> {
+    if sb.has_ro_compat(RO_COMPAT_BIGALLOC) {
+        return Err(Error::NotSupported);
+    }
+    if sb.has_incompat(INCOMPAT_RECOVER) || sb.last_orphan() != 0 {
+        warn!("resize: file system needs recovery or a check first");
+        return Err(Error::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Blocks of `group` taken by its own metadata when laid out by [add_group].
+fn group_overhead(sb: &Superblock, group: u32) -> u64 {
+    sb.group_super_blocks(group).len() as u64 + 2 + sb.inode_table_blocks() as u64
+}
+
+/// Cut a new last group that would be too small from `target`.
+fn fit_last_group(old: &Superblock, target: u64) -> u64 {
+    let rem = (target - old.first_data_block() as u64) % old.blocks_per_group() as u64;
+    let mut sb = old.clone();
+    sb.set_blocks_count(target);
+    let last = sb.group_count() - 1;
+    if rem == 0 || last < old.group_count() {
+        return target;
+    }
+    match rem < group_overhead(&sb, last) + MIN_LAST_GROUP_FREE {
+        true => target - rem,
+        false => target,
+    }
+}
+
+/// Hand the blocks added to the old last group to its bitmap, returning their number.
+fn extend_last_group<T: BlockDeviceInterface>(disk: &mut Disk<T>, old: &Superblock) -> Result<u64> {
+    let group = old.group_count() - 1;
+    let old_count = old.group_blocks(group);
+    let new_count = disk.sb.group_blocks(group);
+    if new_count == old_count {
+        return Ok(0);
+    }
+    let gd = disk.groups[group as usize].clone();
+    if !(disk.sb.group_csum() && gd.flags() & BG_BLOCK_UNINIT != 0) {
+        let mut bitmap = disk.read_block(gd.block_bitmap())?;
+        for i in old_count..new_count {
+            bitmap_set(&mut bitmap, i as usize, false);
+        }
+        disk.write_block(gd.block_bitmap(), &bitmap)?;
+        if disk.sb.metadata_csum() {
+            let csum = disk.block_bitmap_checksum(&bitmap);
+            disk.groups[group as usize].set_block_bitmap_csum(csum);
+        }
+    }
+    let added = new_count - old_count;
+    disk.groups[group as usize].set_free_blocks(gd.free_blocks() + added);
+    Ok(added as u64)
+}
+
+/// Lay out a new group: backups first, then the bitmaps and the inode table.
+/// Returns the number of free blocks of the group.
+fn add_group<T: BlockDeviceInterface>(disk: &mut Disk<T>, group: u32) -> Result<u32> {
+    let sb = disk.sb.clone();
+    let bs = sb.block_size() as usize;
+    let ipg = sb.inodes_per_group();
+    let start = sb.group_first_block(group);
+    let count = sb.group_blocks(group);
+    let meta = start + sb.group_super_blocks(group).len() as u64;
+    let table_blocks = sb.inode_table_blocks() as u64;
+    let used = group_overhead(&sb, group) as u32;
+
+    let mut gd = GroupDesc::new(sb.desc_size());
+    gd.set_block_bitmap(meta);
+    gd.set_inode_bitmap(meta + 1);
+    gd.set_inode_table(meta + 2);
+    gd.set_free_blocks(count - used);
+    gd.set_free_inodes(ipg);
+    if sb.group_csum() {
+        gd.set_itable_unused(ipg);
+        gd.set_flags(BG_INODE_ZEROED);
+    }
+
+    let mut bitmap = vec![0u8; bs];
+    for i in 0..bs * 8 {
+        bitmap_set(&mut bitmap, i, i < used as usize || i >= count as usize);
+    }
+    disk.write_block(meta, &bitmap)?;
+    if sb.metadata_csum() {
+        gd.set_block_bitmap_csum(disk.block_bitmap_checksum(&bitmap));
+    }
+    let mut bitmap = vec![0u8; bs];
+    for i in ipg as usize..bs * 8 {
+        bitmap_set(&mut bitmap, i, true);
+    }
+    disk.write_block(meta + 1, &bitmap)?;
+    if sb.metadata_csum() {
+        gd.set_inode_bitmap_csum(disk.inode_bitmap_checksum(&bitmap));
+    }
+    let zeros = vec![0u8; bs * CHUNK_BLOCKS as usize];
+    let mut block = 0;
+    while block < table_blocks {
+        let n = (table_blocks - block).min(CHUNK_BLOCKS);
+        disk.write_block(meta + 2 + block, &zeros[..n as usize * bs])?;
+        block += n;
+    }
+    disk.groups.push(gd);
+    Ok(count - used)
+}
+
+/// Recreate the mapping of the resize inode for the current reserved GDT blocks and
+/// backup groups, c.f. `ext2fs_create_resize_inode`.
+fn rebuild_resize_inode<T: BlockDeviceInterface>(disk: &mut Disk<T>) -> Result<()> {
+    let sb = disk.sb.clone();
+    let mut inode = disk.read_inode(RESIZE_INO)?;
+    let dind = le32(inode.block_area(), 13 * 4) as u64;
+    if dind == 0 || !disk.valid_block(dind) {
+        warn!("resize: resize inode has no double indirect block");
+        return Err(Error::InvalidArgument);
+    }
+    let bs = sb.block_size() as usize;
+    let per_block = bs / 4;
+    let backups: Vec<u32> = (1..sb.group_count())
+        .filter(|&g| sb.group_has_super(g))
+        .take(per_block)
+        .collect();
+    let first = sb.first_data_block() as u64 + 1 + sb.desc_blocks() as u64;
+    let mut dind_buf = vec![0u8; bs];
+    for i in 0..sb.reserved_gdt_blocks() as u64 {
+        let gdt_block = first + i;
+        let slot = (sb.desc_blocks() as usize + i as usize) % per_block;
+        put32(&mut dind_buf, slot * 4, gdt_block as u32);
+        let mut buf = vec![0u8; bs];
+        for (k, &group) in backups.iter().enumerate() {
+            let backup = gdt_block + group as u64 * sb.blocks_per_group() as u64;
+            put32(&mut buf, k * 4, backup as u32);
+        }
+        disk.write_block(gdt_block, &buf)?;
+    }
+    disk.write_block(dind, &dind_buf)?;
+    let blocks = 1 + sb.reserved_gdt_blocks() as u64 * (1 + backups.len() as u64);
+    inode.set_block_count(blocks, sb.block_size());
+    disk.write_inode(RESIZE_INO, &mut inode)
+}
+
+/// Smallest size that holds the inodes and blocks in use, keeping the metadata of the
+/// remaining groups in place, c.f. `calculate_minimum_resize_size`.
+fn minimum_blocks<T: BlockDeviceInterface>(disk: &Disk<T>, usage: &Usage) -> u64 {
+    let sb = &disk.sb;
+    let bpg = sb.blocks_per_group() as u64;
+    let data = usage.claimed.count() - usage.overhead.count();
+    let needed = data + data / 100 + SHRINK_SLACK_BLOCKS;
+    let inodes = usage.used.count().div_ceil(sb.inodes_per_group() as u64);
+    let mut groups = inodes.max(1) as u32;
+    let mut overhead: u64 = (0..groups).map(|g| group_overhead(sb, g)).sum();
+    while groups < sb.group_count() && groups as u64 * bpg < overhead + needed {
+        overhead += group_overhead(sb, groups);
+        groups += 1;
+    }
+    let last = groups - 1;
+    let last_group = last as u64 * bpg + group_overhead(sb, last) + MIN_LAST_GROUP_FREE;
+    let mut blocks = sb.first_data_block() as u64 + (overhead + needed).max(last_group);
+    let table_blocks = sb.inode_table_blocks() as u64;
+    for gd in &disk.groups[..groups as usize] {
+        let end = (gd.inode_table() + table_blocks)
+            .max(gd.block_bitmap() + 1)
+            .max(gd.inode_bitmap() + 1);
+        blocks = blocks.max(end);
+    }
+    blocks.min(sb.blocks_count())
+}
+
+/// Cut a last group that would be too small from a smaller `target`, c.f. `adjust_fs_info`.
+fn trim_last_group(old: &Superblock, target: u64) -> u64 {
+    let rem = (target - old.first_data_block() as u64) % old.blocks_per_group() as u64;
+    let mut sb = old.clone();
+    sb.set_blocks_count(target);
+    let last = sb.group_count() - 1;
+    match last > 0 && rem != 0 && rem < group_overhead(&sb, last) + MIN_LAST_GROUP_FREE {
+        true => target - rem,
+        false => target,
+    }
+}
+
+/// The superblock after shrinking to `blocks`, as far as the layout is concerned: the
+/// descriptor blocks no longer needed become reserved GDT blocks for the resize inode.
+fn shrunk_layout(old: &Superblock, blocks: u64) -> Superblock {
+    let mut sb = old.clone();
+    sb.set_blocks_count(blocks);
+    let groups = sb.group_count();
+    if sb.has_compat(COMPAT_RESIZE_INODE) && !sb.has_incompat(INCOMPAT_META_BG) {
+        let freed = old.desc_blocks() - sb.desc_blocks();
+        let max = sb.block_size() / 4;
+        sb.set_reserved_gdt_blocks((old.reserved_gdt_blocks() + freed).min(max));
+    }
+    sb.set_backup_bgs(old.backup_bgs().map(|g| if g < groups { g } else { 0 }));
+    sb
+}
+
+/// Metadata blocks of the groups left in `layout`, which must lie within it.
+fn kept_metadata<T: BlockDeviceInterface>(disk: &Disk<T>, layout: &Superblock) -> Result<BitSet> {
+    let blocks = layout.blocks_count();
+    let table_blocks = layout.inode_table_blocks() as u64;
+    let mut keep = BitSet::new(blocks);
+    for group in 0..layout.group_count() {
+        let gd = &disk.groups[group as usize];
+        let mut meta = layout.group_super_blocks(group);
+        meta.push(gd.block_bitmap());
+        meta.push(gd.inode_bitmap());
+        meta.extend((0..table_blocks).map(|i| gd.inode_table() + i));
+        for block in meta {
+            if block >= blocks {
+                warn!("resize: metadata of group {} at block {}", group, block);
+                return Err(Error::NotSupported);
+            }
+            keep.set(block);
+        }
+    }
+    Ok(keep)
+}
+
+/// Blocks below `limit` that are taken after shrinking: those in use before, except
+/// for the metadata of removed groups.
+fn taken_blocks(usage: &Usage, keep: &BitSet, limit: u64) -> BitSet {
+    let mut taken = BitSet::new(limit);
+    for block in 0..limit {
+        let freed = usage.overhead.get(block) && !keep.get(block);
+        if usage.claimed.get(block) && !freed {
+            taken.set(block);
+        }
+    }
+    taken
+}
+
+/// Move the inodes in use beyond the first `groups` groups to free slots of those,
+/// returning the old and new numbers.
+fn renumber_inodes<T: BlockDeviceInterface>(
+    disk: &mut Disk<T>,
+    usage: &Usage,
+    groups: u32,
+) -> Result<BTreeMap<u32, u32>> {
+    let count = groups * disk.sb.inodes_per_group();
+    let mut map = BTreeMap::new();
+    let mut next = disk.sb.first_ino();
+    for ino in count + 1..=disk.sb.inodes_count() {
+        if !usage.used.get(ino as u64) {
+            continue;
+        }
+        while next <= count && usage.used.get(next as u64) {
+            next += 1;
+        }
+        if next > count {
+            return Err(Error::NoSpace);
+        }
+        let mut inode = disk.read_inode(ino)?;
+        claim_inode_slot(disk, next)?;
+        disk.write_inode(next, &mut inode)?;
+        reseal_blocks(disk, next, &inode)?;
+        map.insert(ino, next);
+        next += 1;
+    }
+    Ok(map)
+}
+
+/// Make the table slot of `ino` visible with `uninit_bg`: the inode bitmap is
+/// initialized and the slots up to `ino` are zeroed and taken off `itable_unused`.
+fn claim_inode_slot<T: BlockDeviceInterface>(disk: &mut Disk<T>, ino: u32) -> Result<()> {
+    if !disk.sb.group_csum() {
+        return Ok(());
+    }
+    let ipg = disk.sb.inodes_per_group();
+    let group = ((ino - 1) / ipg) as usize;
+    let index = (ino - 1) % ipg;
+    let bs = disk.block_size() as usize;
+    let gd = disk.groups[group].clone();
+    let mut unused = gd.itable_unused();
+    if gd.flags() & BG_INODE_UNINIT != 0 {
+        let mut bitmap = vec![0u8; bs];
+        for i in ipg as usize..bs * 8 {
+            bitmap_set(&mut bitmap, i, true);
+        }
+        disk.write_block(gd.inode_bitmap(), &bitmap)?;
+        let csum = disk.inode_bitmap_checksum(&bitmap);
+        let gd = &mut disk.groups[group];
+        gd.set_flags(gd.flags() & !BG_INODE_UNINIT);
+        if disk.sb.metadata_csum() {
+            gd.set_inode_bitmap_csum(csum);
+        }
+        unused = ipg;
+    }
+    let visible = ipg.saturating_sub(unused);
+    if index < visible {
+        return Ok(());
+    }
+    if gd.flags() & BG_INODE_ZEROED == 0 {
+        let isize = disk.sb.inode_size() as u64;
+        let zeros = vec![0u8; ((index - visible) as u64 * isize) as usize];
+        let offset = gd.inode_table() * bs as u64 + visible as u64 * isize;
+        disk.write(offset, &zeros)?;
+    }
+    disk.groups[group].set_itable_unused(ipg - index - 1);
+    Ok(())
+}
+
+/// Rewrite the checksums of the extent tree nodes and directory blocks of an inode
+/// that was given the number `ino`.
+fn reseal_blocks<T: BlockDeviceInterface>(
+    disk: &mut Disk<T>,
+    ino: u32,
+    inode: &Inode,
+) -> Result<()> {
+    if !disk.sb.metadata_csum() {
+        return Ok(());
+    }
+    let seed = disk.inode_csum_seed(ino, inode);
+    let map = disk.map_inode(ino, inode)?;
+    if inode.flags() & INODE_FLAG_EXTENTS != 0 {
+        for &block in &map.meta {
+            let mut node = disk.read_block(block)?;
+            disk.update_extent_checksum(seed, &mut node);
+            disk.write_block(block, &node)?;
+        }
+    }
+    if inode.is_dir() {
+        for block in dir_blocks(&map) {
+            let mut data = disk.read_block(block)?;
+            disk.update_dir_block_checksum(seed, &mut data);
+            disk.write_block(block, &data)?;
+        }
+    }
+    Ok(())
+}
+
+fn dir_blocks(map: &BlockMap) -> Vec<u64> {
+    map.extents
+        .iter()
+        .filter(|e| !e.uninit)
+        .flat_map(|e| e.physical..e.physical + e.len)
+        .collect()
+}
+
+/// Point the entries of the directories among `inos` at the renumbered inodes.
+fn rewrite_entries<T: BlockDeviceInterface>(
+    disk: &mut Disk<T>,
+    inos: &[u32],
+    map: &BTreeMap<u32, u32>,
+) -> Result<()> {
+    let filetype = disk.sb.has_incompat(INCOMPAT_FILETYPE);
+    let remap = |buf: &mut [u8], offset: usize| match map.get(&le32(buf, offset)) {
+        Some(&ino) => {
+            put32(buf, offset, ino);
+            true
+        }
+        None => false,
+    };
+    for &ino in inos {
+        let mut inode = disk.read_inode(ino)?;
+        if !inode.is_dir() {
+            continue;
+        }
+        if inode.flags() & INODE_FLAG_INLINE_DATA != 0 {
+            // The parent comes first, followed by the entries.
+            let area = inode.block_area_mut();
+            let mut changed = remap(area, 0);
+            let (entries, _) = parse_dir_block(&area[4..], filetype);
+            for e in entries {
+                changed |= remap(&mut area[4..], e.offset);
+            }
+            if changed {
+                disk.write_inode(ino, &mut inode)?;
+            }
+            continue;
+        }
+        let seed = disk.inode_csum_seed(ino, &inode);
+        let blocks = dir_blocks(&disk.map_inode(ino, &inode)?);
+        for block in blocks {
+            let mut data = disk.read_block(block)?;
+            let (entries, _) = parse_dir_block(&data, filetype);
+            let mut changed = false;
+            for e in entries {
+                changed |= remap(&mut data, e.offset);
+            }
+            if changed {
+                if disk.sb.metadata_csum() {
+                    disk.update_dir_block_checksum(seed, &mut data);
+                }
+                disk.write_block(block, &data)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Move everything the inodes `inos` own at or above `limit` below it, returning the
+/// number of blocks moved.
+fn move_blocks<T: BlockDeviceInterface>(
+    disk: &mut Disk<T>,
+    inos: &[u32],
+    alloc: &mut BlockAllocator,
+    limit: u64,
+) -> Result<u64> {
+    let mut moved = 0;
+    // Extended attribute blocks may be shared.
+    let mut xattr: BTreeMap<u64, u64> = BTreeMap::new();
+    for &ino in inos {
+        let mut inode = disk.read_inode(ino)?;
+        if ino == RESIZE_INO {
+            // Only the double indirect block is the resize inode's own; it is
+            // rewritten with the reserved GDT blocks later.
+            if le32(inode.block_area(), 13 * 4) as u64 >= limit {
+                let block = alloc.block()?;
+                put32(inode.block_area_mut(), 13 * 4, block as u32);
+                disk.write_inode(ino, &mut inode)?;
+                moved += 1;
+            }
+            continue;
+        }
+        let map = disk.map_inode(ino, &inode)?;
+        let beyond = map.meta.iter().any(|&b| b >= limit)
+            || map.extents.iter().any(|e| e.physical + e.len > limit);
+        let acl = inode.file_acl();
+        if !beyond && acl < limit {
+            continue;
+        }
+        if ino == BAD_INO {
+            warn!("resize: bad blocks in the removed range");
+            return Err(Error::NotSupported);
+        }
+        if beyond && inode.flags() & INODE_FLAG_EXTENTS != 0 {
+            moved += move_extents(disk, ino, &mut inode, &map, alloc, limit)?;
+        } else if beyond {
+            moved += disk.relocate_indirect(&mut inode, limit, &mut || alloc.block())?;
+        }
+        if acl >= limit {
+            let block = match xattr.get(&acl) {
+                Some(&block) => block,
+                None => {
+                    let block = alloc.block()?;
+                    let mut data = disk.read_block(acl)?;
+                    if disk.sb.metadata_csum() {
+                        disk.update_xattr_block_checksum(block, &mut data);
+                    }
+                    disk.write_block(block, &data)?;
+                    xattr.insert(acl, block);
+                    moved += 1;
+                    block
+                }
+            };
+            inode.set_file_acl(block);
+        }
+        disk.write_inode(ino, &mut inode)?;
+        if ino == disk.sb.journal_inum() {
+            let area = inode.block_area().to_vec();
+            disk.sb.set_journal_backup(&area);
+        }
+    }
+    Ok(moved)
+}
+
+/// Rebuild the extent tree of `inode` with the runs at or above `limit` copied below it.
+fn move_extents<T: BlockDeviceInterface>(
+    disk: &mut Disk<T>,
+    ino: u32,
+    inode: &mut Inode,
+    map: &BlockMap,
+    alloc: &mut BlockAllocator,
+    limit: u64,
+) -> Result<u64> {
+    let mut extents = Vec::new();
+    let mut moved = map.meta.iter().filter(|&&b| b >= limit).count() as u64;
+    for e in &map.extents {
+        let keep = limit.saturating_sub(e.physical).min(e.len);
+        if keep > 0 {
+            push_extent(&mut extents, Extent { len: keep, ..*e });
+        }
+        let mut done = keep;
+        while done < e.len {
+            let (start, count) = alloc.run(e.len - done)?;
+            // Uninitialized extents read as zeros, their contents need no copy.
+            if !e.uninit {
+                copy_blocks(disk, e.physical + done, start, count)?;
+            }
+            let extent = Extent {
+                logical: e.logical + done,
+                physical: start,
+                len: count,
+                uninit: e.uninit,
+            };
+            push_extent(&mut extents, extent);
+            done += count;
+        }
+        moved += e.len - keep;
+    }
+    disk.write_extent_tree(ino, inode, &extents, &mut || alloc.block())?;
+    let blocks = disk.count_blocks(ino, inode)?;
+    inode.set_block_count(blocks, disk.block_size());
+    Ok(moved)
+}
+
+fn copy_blocks<T: BlockDeviceInterface>(
+    disk: &mut Disk<T>,
+    from: u64,
+    to: u64,
+    count: u64,
+) -> Result<()> {
+    let bs = disk.block_size() as u64;
+    let mut buf = vec![0u8; (count.min(CHUNK_BLOCKS) * bs) as usize];
+    let mut done = 0;
+    while done < count {
+        let n = (count - done).min(CHUNK_BLOCKS);
+        let buf = &mut buf[..(n * bs) as usize];
+        disk.read((from + done) * bs, buf)?;
+        disk.write_block(to + done, buf)?;
+        done += n;
+    }
+    Ok(())
+}
+
+/// Mark the blocks past the end of the new last group as used in its bitmap. The last
+/// group may not have an uninitialized bitmap; its blocks in use are marked when the
+/// bitmaps are rebuilt.
+fn pad_last_group<T: BlockDeviceInterface>(disk: &mut Disk<T>) -> Result<()> {
+    let sb = disk.sb.clone();
+    let last = sb.group_count() - 1;
+    let gd = disk.groups[last as usize].clone();
+    let mut bitmap = match sb.group_csum() && gd.flags() & BG_BLOCK_UNINIT != 0 {
+        true => {
+            disk.groups[last as usize].set_flags(gd.flags() & !BG_BLOCK_UNINIT);
+            vec![0u8; sb.block_size() as usize]
+        }
+        false => disk.read_block(gd.block_bitmap())?,
+    };
+    for i in sb.group_blocks(last) as usize..bitmap.len() * 8 {
+        bitmap_set(&mut bitmap, i, true);
+    }
+    disk.write_block(gd.block_bitmap(), &bitmap)?;
+    if sb.metadata_csum() {
+        let csum = disk.block_bitmap_checksum(&bitmap);
+        disk.groups[last as usize].set_block_bitmap_csum(csum);
+    }
+    Ok(())
+}
@@ -0,0 +1,297 @@
+//! The Android sparse image format, as read by `fastboot` and written by `img2simg`:
+//! a header followed by chunks that are raw data, a repeated 4 byte pattern or
+//! blocks left as they are ("don't care").
+use crate::block::{BlockDevice, BlockDeviceInterface};
+use crate::disk::*;
+use crate::error::{Error, Result};
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_io::{Read, Write};
+
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const MAJOR_VERSION: u16 = 1;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+/// Largest raw chunk written, in bytes, as `img2simg` splits them.
+const MAX_RAW_CHUNK: u64 = 64 << 20;
+/// Blocks read at a time.
+const BATCH_BYTES: usize = 1 << 20;
+
+/// What a sparse image held or was made of, in blocks of [SparseReport::block_size].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SparseReport {
+    pub block_size: u32,
+    /// Blocks of the whole image, used or not.
+    pub blocks: u32,
+    pub chunks: u32,
+    /// Blocks stored as they are.
+    pub raw: u32,
+    /// Blocks stored as a 4 byte pattern, e.g. zeroed blocks in use.
+    pub fill: u32,
+    /// Blocks not stored.
+    pub dont_care: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Raw,
+    Fill(u32),
+    DontCare,
+}
+
+/// Write the file system on `bdev` as a sparse image to `writer`.
+///
+/// Blocks free in the block bitmaps become "don't care" chunks without reading them,
+/// as do the blocks of the device past the end of the file system. Blocks in use that
+/// repeat a 4 byte pattern, such as an empty journal, become fill chunks. The image
+/// uses the block size of the file system and covers the whole device.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{export_sparse, BlockDeviceConfig, DefaultInterface};
+/// let file = std::fs::File::open("system.img").unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let mut image = Vec::new();
+/// let report = export_sparse(&mut blk, &mut image).unwrap();
+/// println!("{} of {} blocks stored", report.raw, report.blocks);
+/// std::fs::write("system.simg", image).unwrap();
+/// ```
+pub fn export_sparse<T: BlockDeviceInterface, W: Write>(
+    bdev: &mut BlockDevice<T>,
+    mut writer: W,
+) -> Result<SparseReport> {
+    let mut disk = Disk::open(bdev)?;
+    disk.load_groups()?;
+    let used = disk.used_blocks()?;
+    let bs = disk.block_size();
+    let fs_blocks = disk.sb.blocks_count();
+    let total = disk.device_size() / bs as u64;
+    if total > u32::MAX as u64 {
+        return Err(Error::FileTooBig);
+    }
+
+    // Plan the chunks first, the header has their number. Raw blocks are read again
+    // when written, rather than held.
+    let batch = (BATCH_BYTES / bs as usize).max(1) as u64;
+    let max_raw = (MAX_RAW_CHUNK / bs as u64) as u32;
+    let mut plan: Vec<(Chunk, u32)> = Vec::new();
+    let mut push = |chunk: Chunk, count: u32| match plan.last_mut() {
+        Some((last, n)) if *last == chunk && (chunk != Chunk::Raw || *n < max_raw) => *n += count,
+        _ => plan.push((chunk, count)),
+    };
+    let mut buf = vec![0u8; (batch * bs as u64) as usize];
+    let mut block = 0;
+    while block < total {
+        if block >= fs_blocks || !used.get(block) {
+            push(Chunk::DontCare, 1);
+            block += 1;
+            continue;
+        }
+        let mut count = 1;
+        while count < batch && block + count < fs_blocks && used.get(block + count) {
+            count += 1;
+        }
+        let data = &mut buf[..(count * bs as u64) as usize];
+        disk.read(block * bs as u64, data)?;
+        for data in data.chunks(bs as usize) {
+            push(fill_pattern(data).map_or(Chunk::Raw, Chunk::Fill), 1);
+        }
+        block += count;
+    }
+
+    let mut report = SparseReport {
+        block_size: bs,
+        blocks: total as u32,
+        chunks: plan.len() as u32,
+        ..Default::default()
+    };
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    put32(&mut header, 0, SPARSE_MAGIC);
+    put16(&mut header, 4, MAJOR_VERSION);
+    put16(&mut header, 8, FILE_HEADER_SIZE as u16);
+    put16(&mut header, 10, CHUNK_HEADER_SIZE as u16);
+    put32(&mut header, 12, bs);
+    put32(&mut header, 16, report.blocks);
+    put32(&mut header, 20, report.chunks);
+    writer.write_all(&header).map_err(|_| Error::Io)?;
+
+    let mut block = 0u64;
+    for (chunk, count) in plan {
+        let (kind, payload) = match chunk {
+            Chunk::Raw => (CHUNK_RAW, count as u64 * bs as u64),
+            Chunk::Fill(_) => (CHUNK_FILL, 4),
+            Chunk::DontCare => (CHUNK_DONT_CARE, 0),
+        };
+        let mut chunk_header = [0u8; CHUNK_HEADER_SIZE];
+        put16(&mut chunk_header, 0, kind);
+        put32(&mut chunk_header, 4, count);
+        put32(
+            &mut chunk_header,
+            8,
+            (CHUNK_HEADER_SIZE as u64 + payload) as u32,
+        );
+        writer.write_all(&chunk_header).map_err(|_| Error::Io)?;
+        match chunk {
+            Chunk::Raw => {
+                report.raw += count;
+                let end = block + count as u64;
+                let mut at = block;
+                while at < end {
+                    let n = (end - at).min(batch);
+                    let data = &mut buf[..(n * bs as u64) as usize];
+                    disk.read(at * bs as u64, data)?;
+                    writer.write_all(data).map_err(|_| Error::Io)?;
+                    at += n;
+                }
+            }
+            Chunk::Fill(pattern) => {
+                report.fill += count;
+                writer
+                    .write_all(&pattern.to_le_bytes())
+                    .map_err(|_| Error::Io)?;
+            }
+            Chunk::DontCare => report.dont_care += count,
+        }
+        block += count as u64;
+    }
+    writer.flush().map_err(|_| Error::Io)?;
+    Ok(report)
+}
+
+/// The 4 byte pattern `block` repeats, if it does.
+fn fill_pattern(block: &[u8]) -> Option<u32> {
+    let pattern = &block[..4];
+    block
+        .chunks_exact(4)
+        .all(|word| word == pattern)
+        .then(|| le32(pattern, 0))
+}
+
+/// Write the sparse image read from `reader` to `bdev`, like `simg2img` but in place.
+///
+/// Blocks of "don't care" chunks are left as they are on the device. CRC32 chunks
+/// are skipped without checking. Fails with [Error::InvalidArgument] if the image is
+/// malformed, including a block size that is not a power of two from 1KiB to 64KiB
+/// as ext4 has, and with [Error::NoSpace] if it is larger than the device.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{import_sparse, BlockDeviceConfig, DefaultInterface};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .open("system.img")
+///     .unwrap();
+/// let len = file.metadata().unwrap().len();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: len / 512,
+///     part_size: len,
+///     part_offset: 0,
+/// };
+/// let mut blk = DefaultInterface::new_device(file, config);
+/// let image = std::fs::read("system.simg").unwrap();
+/// import_sparse(image.as_slice(), &mut blk).unwrap();
+/// ```
+pub fn import_sparse<R: Read, T: BlockDeviceInterface>(
+    mut reader: R,
+    bdev: &mut BlockDevice<T>,
+) -> Result<SparseReport> {
+    let dev: &mut T = bdev;
+    let config = open_device(dev)?;
+    let mut read = |buf: &mut [u8]| reader.read_exact(buf).map_err(|_| Error::Io);
+
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    read(&mut header)?;
+    let header_size = le16(&header, 8) as usize;
+    let chunk_header_size = le16(&header, 10) as usize;
+    let bs = le32(&header, 12);
+    let valid = le32(&header, 0) == SPARSE_MAGIC
+        && le16(&header, 4) == MAJOR_VERSION
+        && header_size >= FILE_HEADER_SIZE
+        && chunk_header_size >= CHUNK_HEADER_SIZE
+        && bs.is_power_of_two()
+        && (1024..=65536).contains(&bs);
+    if !valid {
+        return Err(Error::InvalidArgument);
+    }
+    let mut report = SparseReport {
+        block_size: bs,
+        blocks: le32(&header, 16),
+        chunks: le32(&header, 20),
+        ..Default::default()
+    };
+    if report.blocks as u64 * bs as u64 > config.part_size {
+        return Err(Error::NoSpace);
+    }
+    read(&mut vec![0u8; header_size - FILE_HEADER_SIZE])?;
+
+    let batch = (BATCH_BYTES / bs as usize).max(1) as u64;
+    let mut buf = vec![0u8; (batch * bs as u64) as usize];
+    let mut block = 0u64;
+    for _ in 0..report.chunks {
+        let mut chunk_header = vec![0u8; chunk_header_size];
+        read(&mut chunk_header)?;
+        let kind = le16(&chunk_header, 0);
+        let count = le32(&chunk_header, 4);
+        let size = (le32(&chunk_header, 8) as u64).checked_sub(chunk_header_size as u64);
+        let payload = match kind {
+            CHUNK_RAW => count as u64 * bs as u64,
+            CHUNK_FILL | CHUNK_CRC32 => 4,
+            CHUNK_DONT_CARE => 0,
+            _ => return Err(Error::InvalidArgument),
+        };
+        let blocks = if kind == CHUNK_CRC32 { 0 } else { count as u64 };
+        if size != Some(payload) || block + blocks > report.blocks as u64 {
+            return Err(Error::InvalidArgument);
+        }
+        let end = block + blocks;
+        match kind {
+            CHUNK_RAW => {
+                report.raw += count;
+                while block < end {
+                    let n = (end - block).min(batch);
+                    let data = &mut buf[..(n * bs as u64) as usize];
+                    read(data)?;
+                    write_at(dev, &config, block * bs as u64, data)?;
+                    block += n;
+                }
+            }
+            CHUNK_FILL => {
+                report.fill += count;
+                let mut pattern = [0u8; 4];
+                read(&mut pattern)?;
+                for word in buf.chunks_exact_mut(4) {
+                    word.copy_from_slice(&pattern);
+                }
+                while block < end {
+                    let n = (end - block).min(batch);
+                    let data = &buf[..(n * bs as u64) as usize];
+                    write_at(dev, &config, block * bs as u64, data)?;
+                    block += n;
+                }
+            }
+            CHUNK_DONT_CARE => {
+                report.dont_care += count;
+                block = end;
+            }
+            _ => read(&mut [0u8; 4])?,
+        }
+    }
+    if block != report.blocks as u64 {
+        return Err(Error::InvalidArgument);
+    }
+    dev.close()?;
+    Ok(report)
+}
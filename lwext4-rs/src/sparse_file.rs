@@ -0,0 +1,166 @@
+use crate::block::{BlockDevice, BlockDeviceConfig, BlockDeviceInterface};
+use crate::error::{Error, Result};
+use crate::zero;
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::pin::Pin;
+
+/// A block device on a sparse file, which only takes disk space for blocks holding
+/// data.
+///
+/// Writes of all-zero blocks punch holes instead, so that a freshly formatted
+/// image is mostly holes. [discard](BlockDeviceInterface::discard) punches holes for a range
+/// and [SparseFileInterface::trim] for the blocks free in the file system, like
+/// `fstrim`. Where holes cannot be punched, zeros are written.
+///
+/// # Example
+/// ```no_run
+/// use lwext4_rs::{BlockDeviceConfig, FsBuilder, SparseFileInterface};
+/// let file = std::fs::OpenOptions::new()
+///     .read(true)
+///     .write(true)
+///     .create(true)
+///     .open("test.img")
+///     .unwrap();
+/// file.set_len(8 << 30).unwrap();
+/// let config = BlockDeviceConfig {
+///     block_size: 512,
+///     block_count: (8 << 30) / 512,
+///     part_size: 8 << 30,
+///     part_offset: 0,
+/// };
+/// let blk = SparseFileInterface::new_device(file, config);
+/// let fs = FsBuilder::new().block_size(4096).build(blk).unwrap();
+/// let blk = fs.take_device();
+/// println!("{} bytes on disk", blk.allocated_size().unwrap());
+/// ```
+pub struct SparseFileInterface {
+    file: File,
+    config: BlockDeviceConfig,
+    /// Cleared once punching a hole failed, zeros are written from then on.
+    punch: bool,
+}
+
+impl SparseFileInterface {
+    pub fn new(file: File, config: BlockDeviceConfig) -> Self {
+        Self {
+            file,
+            config,
+            punch: true,
+        }
+    }
+
+    pub fn new_device(file: File, config: BlockDeviceConfig) -> Pin<Box<BlockDevice<Self>>> {
+        BlockDevice::new(Self::new(file, config))
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    /// Size of the file as seen by readers.
+    pub fn apparent_size(&self) -> Result<u64> {
+        Ok(self.file.metadata().map_err(|_| Error::Io)?.len())
+    }
+
+    /// Disk space taken by the file.
+    pub fn allocated_size(&self) -> Result<u64> {
+        Ok(self.file.metadata().map_err(|_| Error::Io)?.blocks() * 512)
+    }
+
+    /// Discard the blocks free in the block bitmaps of the file system on `bdev`,
+    /// which must not be mounted, and return the number of bytes discarded.
+    pub fn trim(bdev: &mut BlockDevice<Self>) -> Result<u64> {
+        // every free run is discarded, which punches a hole for it
+        let report = zero::zero_free_blocks(bdev)?;
+        Ok((report.discarded + report.zeroed) * report.block_size as u64)
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.punch {
+            use std::os::fd::AsRawFd;
+            let ret = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                )
+            };
+            if ret == 0 {
+                return Ok(());
+            }
+            self.punch = false;
+        }
+        let zeros = vec![0u8; len.min(1 << 20) as usize];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(zeros.len() as u64);
+            self.file
+                .write_all_at(&zeros[..n as usize], offset + done)
+                .map_err(|_| Error::Io)?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDeviceInterface for SparseFileInterface {
+    fn open(&mut self) -> Result<BlockDeviceConfig> {
+        Ok(self.config)
+    }
+
+    fn read_block(&mut self, buf: &mut [u8], block_id: u64, _block_count: u32) -> Result<usize> {
+        let offset = block_id * self.config.block_size as u64;
+        self.file
+            .read_exact_at(buf, offset)
+            .map_err(|_| Error::Io)?;
+        Ok(buf.len())
+    }
+
+    fn write_block(&mut self, buf: &[u8], block_id: u64, _block_count: u32) -> Result<usize> {
+        let bs = self.config.block_size as usize;
+        let offset = block_id * bs as u64;
+        // Runs of zero blocks become holes, the others are written as they are.
+        let mut start = 0;
+        while start < buf.len() {
+            let zero = is_zero(&buf[start..start + bs]);
+            let mut end = start + bs;
+            while end < buf.len() && is_zero(&buf[end..end + bs]) == zero {
+                end += bs;
+            }
+            let at = offset + start as u64;
+            if zero {
+                self.punch_hole(at, (end - start) as u64)?;
+            } else {
+                self.file
+                    .write_all_at(&buf[start..end], at)
+                    .map_err(|_| Error::Io)?;
+            }
+            start = end;
+        }
+        Ok(buf.len())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.file.sync_data().map_err(|_| Error::Io)
+    }
+
+    fn lock(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn discard(&mut self, block_id: u64, block_count: u64) -> Result<()> {
+        let bs = self.config.block_size as u64;
+        self.punch_hole(block_id * bs, block_count * bs)
+    }
+}
+
+fn is_zero(block: &[u8]) -> bool {
+    block.iter().all(|&b| b == 0)
+}
@@ -7,8 +7,11 @@ pub type DefaultBlockDevice<T> = BlockDevice<DefaultInterface<T>>;
 pub struct DefaultInterface<T: Read + Write + Seek>(T, BlockDeviceConfig);
 
 impl<T: Read + Write + Seek> DefaultInterface<T> {
+    pub fn new(inner: T, config: BlockDeviceConfig) -> Self {
+        Self(inner, config)
+    }
     pub fn new_device(inner: T, config: BlockDeviceConfig) -> Pin<Box<BlockDevice<Self>>> {
-        BlockDevice::new(Self(inner, config))
+        BlockDevice::new(Self::new(inner, config))
     }
 }
 
@@ -0,0 +1,499 @@
+use crate::error::{Error, Result};
+use crate::host::{self, host_dev, host_type, read_full};
+use crate::types::{FileTimes, FileType, MetaDataExt, Metadata, Permissions, Time};
+use crate::{BlockDeviceInterface, FileSystem};
+use embedded_io::{Read as _, Seek as _, SeekFrom, Write as _};
+use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Blocks of the image compared or copied per read of a regular file.
+const CHUNK_BLOCKS: usize = 16;
+
+/// What part of an entry could not be brought up to date on the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncIssueKind {
+    /// The entry could not be created, or its contents could not be compared or
+    /// written. Names that are not UTF-8 are reported as [Error::InvalidArgument].
+    Create,
+    /// The entry is gone from the host but could not be removed from the image.
+    Delete,
+    /// The permission bits could not be applied.
+    Permissions,
+    /// The owner and group could not be applied.
+    Ownership { uid: u32, gid: u32 },
+    /// The access and modification times could not be applied. Times before 1970,
+    /// which [Time] cannot hold, and those past the end of the inode's seconds are
+    /// reported as [Error::InvalidArgument].
+    Timestamps,
+    /// The named extended attribute could not be read or applied.
+    Xattr(String),
+}
+
+/// An entry that was not (fully) brought up to date on the image.
+#[derive(Debug, Clone)]
+pub struct SyncIssue {
+    /// Path of the entry inside the image.
+    pub path: String,
+    pub kind: SyncIssueKind,
+    pub error: Error,
+}
+
+/// Summary of a [HostSync] run.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    /// Entries that were missing on the image, hard links included.
+    pub created: usize,
+    /// Regular files whose contents were written, and symbolic links, device nodes
+    /// and entries of another type that were replaced.
+    pub updated: usize,
+    /// Entries whose owner, mode, times or extended attributes were updated.
+    pub attributes: usize,
+    /// Entries removed because they are gone from the host.
+    pub deleted: usize,
+    /// Entries whose contents were already up to date.
+    pub unchanged: usize,
+    /// Bytes of file content written to the image.
+    pub bytes: u64,
+    pub issues: Vec<SyncIssue>,
+}
+
+impl SyncReport {
+    fn issue(&mut self, path: &str, kind: SyncIssueKind, error: Error) {
+        self.issues.push(SyncIssue {
+            path: path.to_string(),
+            kind,
+            error,
+        });
+    }
+}
+
+/// Bring a directory of a mounted image up to date with a host directory, like
+/// `rsync -a`.
+///
+/// Regular files are compared by size and modification time, and optionally by
+/// contents, and only those that differ are written: a file of the same size is
+/// patched in place, chunk by chunk, others are rewritten. Symbolic links, device
+/// nodes and fifos are replaced when they differ, and entries whose type changed are
+/// removed first. Owner, mode, times and extended attributes are updated in place
+/// where they differ; attributes that are gone from the host are left on the image,
+/// as lwext4 cannot remove them. Hard links between host files are recreated.
+///
+/// ```no_run
+/// # use lwext4_rs::{FileSystem, DefaultInterface, HostSync};
+/// # fn sync(fs: &FileSystem<DefaultInterface<std::fs::File>>) {
+/// let report = HostSync::new()
+///     .checksum(true)
+///     .delete(true)
+///     .sync(fs, "rootfs/", "/")
+///     .unwrap();
+/// println!("{} created, {} updated", report.created, report.updated);
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HostSync {
+    checksum: bool,
+    delete: bool,
+}
+
+impl HostSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also compare the contents of regular files whose size and modification time
+    /// agree, catching changes that kept both.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Remove entries of the image that are gone from the host. `lost+found` is kept
+    /// when syncing to the root of the file system.
+    pub fn delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    /// Sync `host_dir` into `image_dir`, a path below the mount point of `fs` that is
+    /// created if it is missing. Everything that could not be brought up to date is
+    /// listed in the returned report instead of aborting the sync.
+    pub fn sync<T: BlockDeviceInterface, P: AsRef<Path>, Q: AsRef<str>>(
+        &self,
+        fs: &FileSystem<T>,
+        host_dir: P,
+        image_dir: Q,
+    ) -> Result<SyncReport> {
+        let host_dir = host_dir.as_ref();
+        let image_dir = image_dir.as_ref().trim_end_matches('/').to_string();
+        let root = fs.mount_handle().mount_point.as_str();
+        let mut syncer = Syncer {
+            fs,
+            options: self,
+            block_size: fs.mount_handle().stats()?.block_size as usize,
+            root: root.trim_end_matches('/').to_string(),
+            links: BTreeMap::new(),
+            report: SyncReport::default(),
+        };
+        let host = std::fs::metadata(host_dir)?;
+        if !host.is_dir() {
+            return Err(Error::NotDirectory);
+        }
+        let dir_path = host::image_dir(fs, &image_dir);
+        if !fs.exists(&dir_path)? {
+            fs.create_dir(&dir_path)?;
+            syncer.report.created += 1;
+        }
+        info!("Syncing {} to {}", host_dir.display(), dir_path);
+        syncer.sync_dir(host_dir, &image_dir)?;
+        let meta = fs.metadata(&dir_path)?;
+        syncer.sync_metadata(host_dir, &dir_path, &host, &meta);
+        Ok(syncer.report)
+    }
+}
+
+impl<T: BlockDeviceInterface> FileSystem<T> {
+    /// Sync `host_dir` into `image_dir` by size and modification time, without
+    /// removing anything, see [HostSync].
+    pub fn sync_from_host<P: AsRef<Path>, Q: AsRef<str>>(
+        &self,
+        host_dir: P,
+        image_dir: Q,
+    ) -> Result<SyncReport> {
+        HostSync::new().sync(self, host_dir, image_dir)
+    }
+}
+
+struct Syncer<'a, T: BlockDeviceInterface> {
+    fs: &'a FileSystem<T>,
+    options: &'a HostSync,
+    block_size: usize,
+    /// Mount point without its trailing `/`.
+    root: String,
+    /// Image path of the first synced name of every multiply linked host inode.
+    links: BTreeMap<(u64, u64), String>,
+    report: SyncReport,
+}
+
+impl<'a, T: BlockDeviceInterface> Syncer<'a, T> {
+    /// Sync the entries of `host_dir` into `dir`, given without its trailing `/`.
+    fn sync_dir(&mut self, host_dir: &Path, dir: &str) -> Result<()> {
+        let mut host_entries = Vec::new();
+        for entry in std::fs::read_dir(host_dir)? {
+            let entry = entry?;
+            match entry.file_name().into_string() {
+                Ok(name) => host_entries.push(name),
+                Err(name) => {
+                    let path = format!("{}/{}", dir, name.to_string_lossy());
+                    self.report
+                        .issue(&path, SyncIssueKind::Create, Error::InvalidArgument);
+                }
+            }
+        }
+        host_entries.sort();
+        let mut image = BTreeMap::new();
+        for entry in self.fs.readdir(dir.to_string() + "/")? {
+            if entry.name() != "." && entry.name() != ".." {
+                let meta = self.fs.metadata(entry.path())?;
+                image.insert(entry.name().to_string(), meta);
+            }
+        }
+        if self.options.delete {
+            let keep: BTreeSet<&str> = host_entries.iter().map(|n| n.as_str()).collect();
+            for (name, meta) in &image {
+                if keep.contains(name.as_str()) || (dir == self.root && name == "lost+found") {
+                    continue;
+                }
+                let path = format!("{}/{}", dir, name);
+                match self.remove(&path, meta) {
+                    Ok(()) => self.report.deleted += 1,
+                    Err(e) => self.report.issue(&path, SyncIssueKind::Delete, e),
+                }
+            }
+        }
+        for name in host_entries {
+            let host_path = host_dir.join(&name);
+            let path = format!("{}/{}", dir, name);
+            let res = std::fs::symlink_metadata(&host_path)
+                .map_err(Error::from)
+                .and_then(|host| self.sync_entry(&host_path, &path, &host, image.remove(&name)));
+            if let Err(e) = res {
+                self.report.issue(&path, SyncIssueKind::Create, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_entry(
+        &mut self,
+        host_path: &Path,
+        path: &str,
+        host: &std::fs::Metadata,
+        mut existing: Option<Metadata>,
+    ) -> Result<()> {
+        let ty = host_type(host.file_type());
+        let mut replaced = false;
+        if let Some(meta) = &existing {
+            // a name that is linked elsewhere on the image only is split off, so that
+            // updating it does not change the other names
+            let split = !ty.is_dir() && meta.nlink() > 1 && host.nlink() == 1;
+            if meta.file_type() != ty || split {
+                self.remove(path, meta)?;
+                existing = None;
+                replaced = true;
+            }
+        }
+        if !ty.is_dir() && host.nlink() > 1 {
+            let key = (host.dev(), host.ino());
+            if let Some(first) = self.links.get(&key) {
+                let ino = self.fs.metadata(first)?.ino();
+                match existing {
+                    Some(meta) if meta.ino() == ino => self.report.unchanged += 1,
+                    Some(meta) => {
+                        self.remove(path, &meta)?;
+                        self.fs.hard_link(first, path)?;
+                        self.report.updated += 1;
+                    }
+                    None => {
+                        self.fs.hard_link(first, path)?;
+                        match replaced {
+                            true => self.report.updated += 1,
+                            false => self.report.created += 1,
+                        }
+                    }
+                }
+                return Ok(());
+            }
+            self.links.insert(key, path.to_string());
+        }
+        let created = existing.is_none();
+        let changed = match &existing {
+            None => {
+                self.create(host_path, path, host, ty)?;
+                true
+            }
+            Some(_) if ty.is_dir() => false,
+            Some(meta) if ty.is_file() => self.sync_file(host_path, path, host, meta)?,
+            Some(meta) if ty.is_symlink() => {
+                let target = link_target(host_path)?;
+                let changed = self.fs.read_link(path)? != target;
+                if changed {
+                    self.remove(path, meta)?;
+                    self.fs.soft_link(target, path)?;
+                }
+                changed
+            }
+            Some(meta) => {
+                let changed = meta.rdev() != host_dev(host, ty);
+                if changed {
+                    self.remove(path, meta)?;
+                    self.fs.mknod(path, ty, host_dev(host, ty))?;
+                }
+                changed
+            }
+        };
+        match (created && !replaced, changed) {
+            (true, _) => self.report.created += 1,
+            (false, true) => self.report.updated += 1,
+            (false, false) => self.report.unchanged += 1,
+        }
+        if ty.is_dir() {
+            self.sync_dir(host_path, path)?;
+        }
+        // re-read, as writing the entry or its children changed its times
+        let meta = self.fs.metadata(path)?;
+        self.sync_metadata(host_path, path, host, &meta);
+        Ok(())
+    }
+
+    fn create(
+        &mut self,
+        host_path: &Path,
+        path: &str,
+        host: &std::fs::Metadata,
+        ty: FileType,
+    ) -> Result<()> {
+        if ty.is_dir() {
+            self.fs.create_dir(path)
+        } else if ty.is_file() {
+            self.write_file(host_path, path)
+        } else if ty.is_symlink() {
+            self.fs.soft_link(link_target(host_path)?, path)
+        } else {
+            self.fs.mknod(path, ty, host_dev(host, ty))
+        }
+    }
+
+    /// Bring the contents of an existing regular file up to date and tell whether
+    /// anything was written.
+    fn sync_file(
+        &mut self,
+        host_path: &Path,
+        path: &str,
+        host: &std::fs::Metadata,
+        meta: &Metadata,
+    ) -> Result<bool> {
+        if host.size() != meta.size() {
+            self.write_file(host_path, path)?;
+            return Ok(true);
+        }
+        if same_mtime(host, meta, self.fs.keeps_nanos(path)?) && !self.options.checksum {
+            return Ok(false);
+        }
+        self.patch_file(host_path, path)
+    }
+
+    fn write_file(&mut self, host_path: &Path, path: &str) -> Result<()> {
+        let mut src = std::fs::File::open(host_path)?;
+        let mut dst = self
+            .fs
+            .file_builder()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut buf = vec![0u8; self.block_size * CHUNK_BLOCKS];
+        loop {
+            let read = src.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            dst.write_all(&buf[..read])?;
+            self.report.bytes += read as u64;
+        }
+        Ok(())
+    }
+
+    /// Compare a file of the same size on both sides block by block and overwrite
+    /// the blocks that differ, telling whether there were any.
+    fn patch_file(&mut self, host_path: &Path, path: &str) -> Result<bool> {
+        let mut src = std::fs::File::open(host_path)?;
+        let mut dst = self.fs.file_builder().read(true).write(true).open(path)?;
+        let mut host_buf = vec![0u8; self.block_size * CHUNK_BLOCKS];
+        let mut image_buf = vec![0u8; self.block_size * CHUNK_BLOCKS];
+        let mut offset = 0u64;
+        let mut changed = false;
+        loop {
+            let read = read_full(|buf| Ok(src.read(buf)?), &mut host_buf)?;
+            if read == 0 {
+                break;
+            }
+            dst.read_exact(&mut image_buf[..read])
+                .map_err(|_| Error::Io)?;
+            let host_chunks = host_buf[..read].chunks(self.block_size);
+            let image_chunks = image_buf[..read].chunks(self.block_size);
+            for (i, (a, b)) in host_chunks.zip(image_chunks).enumerate() {
+                if a != b {
+                    dst.seek(SeekFrom::Start(offset + (i * self.block_size) as u64))?;
+                    dst.write_all(a)?;
+                    self.report.bytes += a.len() as u64;
+                    changed = true;
+                }
+            }
+            offset += read as u64;
+            dst.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(changed)
+    }
+
+    fn remove(&self, path: &str, meta: &Metadata) -> Result<()> {
+        match meta.is_dir() {
+            true => self.fs.remove_dir_all(path),
+            false => self.fs.remove_file(path),
+        }
+    }
+
+    /// Apply the owner, mode, extended attributes and times of the host entry where
+    /// they differ from `meta`, the entry on the image.
+    fn sync_metadata(
+        &mut self,
+        host_path: &Path,
+        path: &str,
+        host: &std::fs::Metadata,
+        meta: &Metadata,
+    ) {
+        let mut changed = false;
+        let names = xattr::list(host_path).map(|names| names.collect::<Vec<_>>());
+        for name in names.unwrap_or_default() {
+            let name = name.to_string_lossy().to_string();
+            let res = xattr::get(host_path, &name)
+                .map_err(Error::from)
+                .and_then(|value| {
+                    let value = value.unwrap_or_default();
+                    match self.fs.get_xattr(path, &name) {
+                        Ok(old) if old == value => Ok(false),
+                        _ => self.fs.set_xattr(path, &name, &value).map(|()| true),
+                    }
+                });
+            match res {
+                Ok(set) => changed |= set,
+                Err(e) => self.report.issue(path, SyncIssueKind::Xattr(name), e),
+            }
+        }
+        if (host.uid(), host.gid()) != (meta.uid(), meta.gid()) {
+            match self.fs.chown(path, Some(host.uid()), Some(host.gid())) {
+                Ok(()) => changed = true,
+                Err(e) => {
+                    let kind = SyncIssueKind::Ownership {
+                        uid: host.uid(),
+                        gid: host.gid(),
+                    };
+                    self.report.issue(path, kind, e);
+                }
+            }
+        }
+        let mode = host.mode() & 0o7777;
+        if !host.file_type().is_symlink() && mode != meta.mode() & 0o7777 {
+            match self.fs.set_permissions(path, Permissions(mode)) {
+                Ok(()) => changed = true,
+                Err(e) => self.report.issue(path, SyncIssueKind::Permissions, e),
+            }
+        }
+        let res = self.fs.keeps_nanos(path).and_then(|nanos| {
+            if same_mtime(host, meta, nanos) {
+                return Ok(false);
+            }
+            let atime = host_time(host.atime(), host.atime_nsec())?;
+            let mtime = host_time(host.mtime(), host.mtime_nsec())?;
+            let times = FileTimes::new().set_accessed(atime).set_modified(mtime);
+            // nanoseconds the inode has no room for are dropped
+            let times = if nanos { times } else { times.whole_secs() };
+            self.fs.set_times(path, times).map(|_| true)
+        });
+        match res {
+            Ok(set) => changed |= set,
+            Err(e) => self.report.issue(path, SyncIssueKind::Timestamps, e),
+        }
+        if changed {
+            self.report.attributes += 1;
+        }
+    }
+}
+
+/// Whether the modification times agree at the precision of the inode, to the
+/// nanosecond if it has room for them and to the second otherwise.
+fn same_mtime(host: &std::fs::Metadata, meta: &Metadata, nanos: bool) -> bool {
+    if !nanos {
+        return host.mtime() == meta.mtime();
+    }
+    (host.mtime(), host.mtime_nsec()) == (meta.mtime(), meta.mtime_nsec())
+}
+
+/// A host time as a [Time]. Times before 1970, which it cannot hold, are rejected
+/// with [Error::InvalidArgument], as are those the inode cannot hold when they are
+/// set.
+fn host_time(secs: i64, nanos: i64) -> Result<Time> {
+    Ok(Time {
+        epoch_secs: u64::try_from(secs).map_err(|_| Error::InvalidArgument)?,
+        nanos: Some(nanos as u32),
+    })
+}
+
+fn link_target(host_path: &Path) -> Result<String> {
+    std::fs::read_link(host_path)?
+        .into_os_string()
+        .into_string()
+        .map_err(|_| Error::InvalidArgument)
+}
@@ -0,0 +1,628 @@
+use crate::error::{Error, Result};
+use crate::types::{
+    dev_join, dev_split, FileTimes, FileType, MetaDataExt, Metadata, Permissions, Time,
+};
+use crate::{BlockDeviceInterface, FileSystem};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_io::{Read, Write};
+use log::warn;
+
+const BLOCK: usize = 512;
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+/// Largest value that fits the 12 byte size field as octal.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+/// Largest value that fits the 8 byte uid/gid fields as octal.
+const MAX_OCTAL_ID: u32 = 0o7777777;
+/// Largest pax or GNU extension record read into memory.
+const MAX_EXTENSION_SIZE: u64 = 1024 * 1024;
+
+/// One parsed tar member, with pax and GNU extension records already applied.
+struct Entry {
+    name: String,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: Time,
+    atime: Option<Time>,
+    kind: u8,
+    link: String,
+    dev_major: u32,
+    dev_minor: u32,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Records from pax (`x`) and GNU long name (`L`/`K`) members that apply to the next member.
+#[derive(Default)]
+struct Extensions {
+    path: Option<String>,
+    link: Option<String>,
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<Time>,
+    atime: Option<Time>,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Extensions {
+    fn parse_pax(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            // "<len> <key>=<value>\n", where len counts the whole record
+            let space = data
+                .iter()
+                .position(|&b| b == b' ')
+                .ok_or(Error::InvalidArgument)?;
+            let len: usize = core::str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|len| len.parse().ok())
+                .ok_or(Error::InvalidArgument)?;
+            if len <= space || len > data.len() || data[len - 1] != b'\n' {
+                return Err(Error::InvalidArgument);
+            }
+            let record = &data[space + 1..len - 1];
+            let eq = record
+                .iter()
+                .position(|&b| b == b'=')
+                .ok_or(Error::InvalidArgument)?;
+            let key = String::from_utf8_lossy(&record[..eq]);
+            let value = &record[eq + 1..];
+            let text = || String::from_utf8_lossy(value).to_string();
+            match key.as_ref() {
+                "path" => self.path = Some(text()),
+                "linkpath" => self.link = Some(text()),
+                "size" => self.size = Some(text().parse().map_err(|_| Error::InvalidArgument)?),
+                "uid" => self.uid = Some(text().parse().map_err(|_| Error::InvalidArgument)?),
+                "gid" => self.gid = Some(text().parse().map_err(|_| Error::InvalidArgument)?),
+                "mtime" => self.mtime = Some(parse_pax_time(&text())?),
+                "atime" => self.atime = Some(parse_pax_time(&text())?),
+                key => {
+                    if let Some(name) = key.strip_prefix(PAX_XATTR) {
+                        self.xattrs.push((name.to_string(), value.to_vec()));
+                    }
+                }
+            }
+            data = &data[len..];
+        }
+        Ok(())
+    }
+}
+
+/// Parse a pax time such as `1700000000.123456789`.
+fn parse_pax_time(value: &str) -> Result<Time> {
+    let (secs, frac) = match value.split_once('.') {
+        Some((secs, frac)) => (secs, Some(frac)),
+        None => (value, None),
+    };
+    let epoch_secs = secs.parse().map_err(|_| Error::InvalidArgument)?;
+    let nanos = match frac {
+        Some(frac) => {
+            let digits = &frac[..frac.len().min(9)];
+            let nanos: u32 = digits.parse().map_err(|_| Error::InvalidArgument)?;
+            Some(nanos * 10u32.pow(9 - digits.len() as u32))
+        }
+        None => None,
+    };
+    Ok(Time { epoch_secs, nanos })
+}
+
+/// Parse an octal (or GNU base-256) numeric header field.
+fn parse_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let value = field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |v, &b| (v << 8) | b as u64);
+        return Ok(value);
+    }
+    let mut value = 0u64;
+    for &b in field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ')
+    {
+        if !(b'0'..=b'7').contains(&b) {
+            return Err(Error::InvalidArgument);
+        }
+        value = (value << 3) | (b - b'0') as u64;
+    }
+    Ok(value)
+}
+
+fn parse_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+fn checksum(header: &[u8; BLOCK]) -> u32 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u32)
+        .sum()
+}
+
+fn parse_header(header: &[u8; BLOCK], ext: Extensions) -> Result<Entry> {
+    if parse_number(&header[148..156])? != checksum(header) as u64 {
+        return Err(Error::InvalidArgument);
+    }
+    let mut name = parse_str(&header[0..100]);
+    if &header[257..262] == b"ustar" {
+        let prefix = parse_str(&header[345..500]);
+        if !prefix.is_empty() {
+            name = prefix + "/" + &name;
+        }
+    }
+    Ok(Entry {
+        name: ext.path.unwrap_or(name),
+        mode: parse_number(&header[100..108])? as u32,
+        uid: ext.uid.unwrap_or(parse_number(&header[108..116])? as u32),
+        gid: ext.gid.unwrap_or(parse_number(&header[116..124])? as u32),
+        size: ext.size.unwrap_or(parse_number(&header[124..136])?),
+        mtime: ext.mtime.unwrap_or(Time::from_extra(
+            parse_number(&header[136..148])? as u32,
+            None,
+        )),
+        atime: ext.atime,
+        kind: header[156],
+        link: ext.link.unwrap_or(parse_str(&header[157..257])),
+        dev_major: parse_number(&header[329..337])? as u32,
+        dev_minor: parse_number(&header[337..345])? as u32,
+        xattrs: ext.xattrs,
+    })
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Join an archive member name onto `dest`, refusing names that escape it.
+fn join(dest: &str, name: &str) -> Result<String> {
+    let mut path = dest.trim_end_matches('/').to_string();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return Err(Error::PermissionDenied),
+            part => {
+                path.push('/');
+                path.push_str(part);
+            }
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+struct TarReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> TarReader<R> {
+    /// Fill `buf` completely, returning false if the stream ended before the first byte.
+    fn read_full(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let read = self.inner.read(&mut buf[filled..]).map_err(|_| Error::Io)?;
+            if read == 0 {
+                if filled == 0 {
+                    return Ok(false);
+                }
+                return Err(Error::Io);
+            }
+            filled += read;
+        }
+        Ok(true)
+    }
+
+    /// Read the data of an extension record, refusing sizes above [MAX_EXTENSION_SIZE].
+    fn read_data(&mut self, size: u64) -> Result<Vec<u8>> {
+        if size > MAX_EXTENSION_SIZE {
+            return Err(Error::InvalidArgument);
+        }
+        let mut data = vec![0u8; size as usize];
+        if !self.read_full(&mut data)? && size != 0 {
+            return Err(Error::Io);
+        }
+        self.skip(padding(size) as u64)?;
+        Ok(data)
+    }
+
+    fn skip(&mut self, mut size: u64) -> Result<()> {
+        let mut buf = [0u8; BLOCK];
+        while size > 0 {
+            let len = size.min(BLOCK as u64) as usize;
+            if !self.read_full(&mut buf[..len])? {
+                return Err(Error::Io);
+            }
+            size -= len as u64;
+        }
+        Ok(())
+    }
+}
+
+struct TarWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.write_all(buf).map_err(|_| Error::Io)
+    }
+
+    fn pad(&mut self, size: u64) -> Result<()> {
+        self.write(&[0u8; BLOCK][..padding(size)])
+    }
+
+    /// Write the header of one member, preceded by a pax header for anything
+    /// that does not fit the ustar fields.
+    fn header(&mut self, entry: &Entry) -> Result<()> {
+        let mut pax = Vec::new();
+        if entry.name.len() > 100 {
+            pax_record(&mut pax, "path", entry.name.as_bytes());
+        }
+        if entry.link.len() > 100 {
+            pax_record(&mut pax, "linkpath", entry.link.as_bytes());
+        }
+        if entry.size > MAX_OCTAL_SIZE {
+            pax_record(&mut pax, "size", entry.size.to_string().as_bytes());
+        }
+        if entry.uid > MAX_OCTAL_ID {
+            pax_record(&mut pax, "uid", entry.uid.to_string().as_bytes());
+        }
+        if entry.gid > MAX_OCTAL_ID {
+            pax_record(&mut pax, "gid", entry.gid.to_string().as_bytes());
+        }
+        if let Some(nanos) = entry.mtime.nanos.filter(|&n| n != 0) {
+            let mtime = format!("{}.{:09}", entry.mtime.epoch_secs, nanos);
+            pax_record(&mut pax, "mtime", mtime.as_bytes());
+        }
+        for (name, value) in &entry.xattrs {
+            pax_record(&mut pax, &format!("{}{}", PAX_XATTR, name), value);
+        }
+        if !pax.is_empty() {
+            let base = entry.name.trim_end_matches('/');
+            let base = base.rsplit('/').next().unwrap_or(base);
+            let name = format!("PaxHeaders/{}", base);
+            let header = build_header(&name, 0o644, 0, 0, pax.len() as u64, 0, b'x', "", 0, 0);
+            self.write(&header)?;
+            self.write(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+        let header = build_header(
+            &entry.name,
+            entry.mode,
+            entry.uid.min(MAX_OCTAL_ID),
+            entry.gid.min(MAX_OCTAL_ID),
+            entry.size.min(MAX_OCTAL_SIZE),
+            entry.mtime.epoch_secs,
+            entry.kind,
+            &entry.link,
+            entry.dev_major,
+            entry.dev_minor,
+        );
+        self.write(&header)
+    }
+}
+
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // the length prefix counts its own digits
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while base + len.to_string().len() != len {
+        len = base + len.to_string().len();
+    }
+    pax.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    let digits = digits.as_bytes();
+    // keep the lowest digits if the value does not fit; callers use pax for those
+    let digits = &digits[digits.len() - (field.len() - 1)..];
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+fn put_str(field: &mut [u8], value: &str) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_header(
+    name: &str,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    kind: u8,
+    link: &str,
+    dev_major: u32,
+    dev_minor: u32,
+) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    put_str(&mut header[0..100], name);
+    put_octal(&mut header[100..108], mode as u64);
+    put_octal(&mut header[108..116], uid as u64);
+    put_octal(&mut header[116..124], gid as u64);
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    put_str(&mut header[157..257], link);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    put_octal(&mut header[329..337], dev_major as u64);
+    put_octal(&mut header[337..345], dev_minor as u64);
+    let sum = checksum(&header);
+    put_octal(&mut header[148..155], sum as u64);
+    header[155] = b' ';
+    header
+}
+
+impl<T: BlockDeviceInterface> FileSystem<T> {
+    /// Unpack a tar stream (ustar, pax or GNU) into the directory `dest`.
+    ///
+    /// Regular files, directories, symbolic and hard links, device nodes and fifos are
+    /// created with the mode, owner, times and `SCHILY.xattr` extended attributes
+    /// recorded in the archive. Member names containing `..` are refused.
+    pub fn import_tar<P: AsRef<str>, R: Read>(&self, dest: P, reader: R) -> Result<()> {
+        let dest = dest.as_ref();
+        let mut tar = TarReader { inner: reader };
+        let mut ext = Extensions::default();
+        // directory times are applied last, as creating their children changes them
+        let mut dir_times = Vec::new();
+        loop {
+            let mut header = [0u8; BLOCK];
+            if !tar.read_full(&mut header)? || header.iter().all(|&b| b == 0) {
+                break;
+            }
+            // extension members describe the next member and must not consume the records
+            let entry = match header[156] {
+                b'x' | b'L' | b'K' | b'g' => parse_header(&header, Extensions::default())?,
+                _ => parse_header(&header, core::mem::take(&mut ext))?,
+            };
+            match entry.kind {
+                b'x' => {
+                    ext.parse_pax(&tar.read_data(entry.size)?)?;
+                    continue;
+                }
+                b'L' => {
+                    ext.path = Some(parse_str(&tar.read_data(entry.size)?));
+                    continue;
+                }
+                b'K' => {
+                    ext.link = Some(parse_str(&tar.read_data(entry.size)?));
+                    continue;
+                }
+                b'g' => {
+                    tar.skip(entry.size + padding(entry.size) as u64)?;
+                    continue;
+                }
+                _ => {}
+            }
+            let path = join(dest, &entry.name)?;
+            if let Some(parent) = path
+                .rsplit_once('/')
+                .map(|(p, _)| p)
+                .filter(|p| !p.is_empty())
+            {
+                if !self.exists(parent)? {
+                    self.create_dir(parent)?;
+                }
+            }
+            match entry.kind {
+                b'0' | b'7' | 0 => {
+                    let mut file = self
+                        .file_builder()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&path)?;
+                    let mut remaining = entry.size;
+                    let mut buf = [0u8; 4096];
+                    while remaining > 0 {
+                        let len = remaining.min(buf.len() as u64) as usize;
+                        if !tar.read_full(&mut buf[..len])? {
+                            return Err(Error::Io);
+                        }
+                        file.write_all(&buf[..len])?;
+                        remaining -= len as u64;
+                    }
+                    tar.skip(padding(entry.size) as u64)?;
+                }
+                b'1' => {
+                    self.remove_existing(&path)?;
+                    self.hard_link(join(dest, &entry.link)?, &path)?;
+                    tar.skip(entry.size + padding(entry.size) as u64)?;
+                    // the link shares the metadata of its target
+                    continue;
+                }
+                b'2' => {
+                    self.remove_existing(&path)?;
+                    self.soft_link(&entry.link, &path)?;
+                }
+                b'3' | b'4' | b'6' => {
+                    self.remove_existing(&path)?;
+                    let (ty, dev) = match entry.kind {
+                        b'3' => ('c', dev_join(entry.dev_major, entry.dev_minor)),
+                        b'4' => ('b', dev_join(entry.dev_major, entry.dev_minor)),
+                        _ => ('p', 0),
+                    };
+                    self.mknod(&path, FileType::from_char(ty), dev)?;
+                }
+                b'5' => {
+                    if !self.exists(&path)? {
+                        self.create_dir(&path)?;
+                    }
+                }
+                kind => {
+                    warn!(
+                        "Skipping tar member {} of unsupported type {}",
+                        entry.name, kind
+                    );
+                    tar.skip(entry.size + padding(entry.size) as u64)?;
+                    continue;
+                }
+            }
+            self.chown(&path, Some(entry.uid), Some(entry.gid))?;
+            if entry.kind != b'2' {
+                self.set_permissions(&path, Permissions(entry.mode & 0o7777))?;
+            }
+            for (name, value) in &entry.xattrs {
+                self.set_xattr(&path, name, value)?;
+            }
+            let mut times = FileTimes::new().set_modified(entry.mtime);
+            if let Some(atime) = entry.atime {
+                times = times.set_accessed(atime);
+            }
+            // nanoseconds the inode has no room for are dropped
+            if !self.keeps_nanos(&path)? {
+                times = times.whole_secs();
+            }
+            if entry.kind == b'5' {
+                dir_times.push((path, times));
+            } else {
+                self.set_times(&path, times)?;
+            }
+        }
+        for (path, times) in dir_times.into_iter().rev() {
+            self.set_times(&path, times)?;
+        }
+        Ok(())
+    }
+
+    /// Write the file or directory tree at `path` as a pax tar stream.
+    ///
+    /// Member names are relative to `path`. Hard links are stored as link members
+    /// and extended attributes as `SCHILY.xattr` records.
+    pub fn export_tar<P: AsRef<str>, W: Write>(&self, path: P, writer: W) -> Result<()> {
+        let path = path.as_ref();
+        let mut tar = TarWriter { inner: writer };
+        let mut links = BTreeMap::new();
+        let meta = self.metadata(path)?;
+        if meta.is_dir() {
+            self.tar_entry(&mut tar, &mut links, path, "./", &meta)?;
+            let dir = path.trim_end_matches('/').to_string() + "/";
+            self.tar_dir(&mut tar, &mut links, &dir, "")?;
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            self.tar_entry(&mut tar, &mut links, path, name, &meta)?;
+        }
+        tar.write(&[0u8; BLOCK * 2])?;
+        tar.inner.flush().map_err(|_| Error::Io)
+    }
+
+    /// Remove whatever non-directory entry is in the way of a new member.
+    fn remove_existing(&self, path: &str) -> Result<()> {
+        match self.remove_file(path) {
+            Ok(()) | Err(Error::NoEntry) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn tar_dir<W: Write>(
+        &self,
+        tar: &mut TarWriter<W>,
+        links: &mut BTreeMap<u64, String>,
+        dir: &str,
+        prefix: &str,
+    ) -> Result<()> {
+        let entries = self
+            .readdir(dir)?
+            .filter(|e| e.name() != "." && e.name() != "..")
+            .map(|e| (e.name().to_string(), e.path()))
+            .collect::<Vec<_>>();
+        for (name, path) in entries {
+            let meta = self.metadata(&path)?;
+            let name = format!("{}{}", prefix, name);
+            if meta.is_dir() {
+                let name = name + "/";
+                self.tar_entry(tar, links, &path, &name, &meta)?;
+                self.tar_dir(tar, links, &(path + "/"), &name)?;
+            } else {
+                self.tar_entry(tar, links, &path, &name, &meta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tar_entry<W: Write>(
+        &self,
+        tar: &mut TarWriter<W>,
+        links: &mut BTreeMap<u64, String>,
+        path: &str,
+        name: &str,
+        meta: &Metadata,
+    ) -> Result<()> {
+        let ty = meta.file_type();
+        let mut entry = Entry {
+            name: name.to_string(),
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: 0,
+            mtime: meta.modified(),
+            atime: None,
+            kind: b'0',
+            link: String::new(),
+            dev_major: 0,
+            dev_minor: 0,
+            xattrs: Vec::new(),
+        };
+        if !ty.is_dir() && meta.nlink() > 1 {
+            if let Some(first) = links.get(&meta.ino()) {
+                entry.kind = b'1';
+                entry.link = first.clone();
+                return tar.header(&entry);
+            }
+            links.insert(meta.ino(), name.to_string());
+        }
+        for xattr in self.list_xattr(path)? {
+            let name = String::from_utf8_lossy(&xattr).to_string();
+            let value = self.get_xattr(path, &name)?;
+            entry.xattrs.push((name, value));
+        }
+        if ty.is_dir() {
+            entry.kind = b'5';
+        } else if ty.is_symlink() {
+            entry.kind = b'2';
+            entry.link = self.read_link(path)?;
+        } else if ty.is_char_device() || ty.is_block_device() {
+            entry.kind = if ty.is_char_device() { b'3' } else { b'4' };
+            (entry.dev_major, entry.dev_minor) = dev_split(meta.rdev());
+        } else if ty.is_fifo() {
+            entry.kind = b'6';
+        } else if ty.is_socket() {
+            warn!("Skipping socket {}, tar cannot hold sockets", path);
+            return Ok(());
+        } else {
+            entry.size = meta.size();
+        }
+        tar.header(&entry)?;
+        if entry.kind == b'0' {
+            let mut file = self.file_builder().read(true).open(path)?;
+            let mut remaining = entry.size;
+            let mut buf = [0u8; 4096];
+            while remaining > 0 {
+                let len = remaining.min(buf.len() as u64) as usize;
+                let read = file.read(&mut buf[..len])?;
+                if read == 0 {
+                    return Err(Error::Io);
+                }
+                tar.write(&buf[..read])?;
+                remaining -= read as u64;
+            }
+            tar.pad(entry.size)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use crate::error::{Error, Result};
 use bitflags::bitflags;
 use core::fmt;
 use core::fmt::{Debug, Formatter};
@@ -113,6 +114,21 @@ impl FileType {
     }
 }
 
+/// Split a device number as stored in an inode into its major and minor numbers.
+///
+/// This decodes the new (32 bit) encoding, which agrees with the old 16 bit one
+/// for all numbers the old one can hold.
+pub(crate) fn dev_split(dev: u32) -> (u32, u32) {
+    let major = (dev & 0xfff00) >> 8;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    (major, minor)
+}
+
+/// Build the device number stored in an inode from its major and minor numbers.
+pub(crate) fn dev_join(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct MountStats(ext4_mount_stats);
@@ -486,11 +502,13 @@ impl Time {
     // "We use an encoding that preserves the times for extra epoch"
     // the lower two bits of the extra field are added to the top of the sec field,
     // the remainder are the nsec
+    //
+    // the sec field is signed, times before 1970 cannot be held by `epoch_secs` and
+    // are read unsigned, as lwext4 writes the sec field
     pub fn from_extra(epoch_secs: u32, extra: Option<u32>) -> Time {
-        let mut epoch_secs = u64::from(epoch_secs);
         match extra {
             None => Time {
-                epoch_secs,
+                epoch_secs: u64::from(epoch_secs),
                 nanos: None,
             },
             Some(extra) => {
@@ -502,16 +520,40 @@ impl Time {
                 // 0b00..00_0011
                 let nsec_mask = !0u32 << epoch_bits;
 
-                epoch_secs += u64::from(extra & epoch_mask) << 32;
+                let secs = i64::from(epoch_secs as i32) + (i64::from(extra & epoch_mask) << 32);
 
                 let nanos = (extra & nsec_mask) >> epoch_bits;
                 Time {
-                    epoch_secs,
+                    epoch_secs: u64::try_from(secs).unwrap_or(u64::from(epoch_secs)),
                     nanos: Some(nanos.clamp(0, 999_999_999)),
                 }
             }
         }
     }
+
+    // c.f. ext4_encode_extra_time
+    /// Split into the sec field and, if the inode has an extra field, its value.
+    ///
+    /// Without an extra field the seconds end in 2106 and nanoseconds cannot be kept,
+    /// with one they end in 2446. Later times fail with [Error::InvalidArgument], lost
+    /// nanoseconds with [Error::NotSupported].
+    pub(crate) fn to_extra(self, extra: bool) -> Result<(u32, Option<u32>)> {
+        let nanos = self.nanos.unwrap_or(0).min(999_999_999);
+        if !extra {
+            if nanos != 0 {
+                return Err(Error::NotSupported);
+            }
+            let secs = u32::try_from(self.epoch_secs).map_err(|_| Error::InvalidArgument)?;
+            return Ok((secs, None));
+        }
+        let max = i64::from(i32::MAX) + (3 << 32);
+        let secs = i64::try_from(self.epoch_secs)
+            .ok()
+            .filter(|&secs| secs <= max)
+            .ok_or(Error::InvalidArgument)?;
+        let epoch = ((secs - i64::from(secs as i32)) >> 32) & 3;
+        Ok((secs as u32, Some(epoch as u32 | nanos << 2)))
+    }
 }
 
 impl Into<u32> for Time {
@@ -545,9 +587,19 @@ impl FileTimes {
         self.created = Some(t);
         self
     }
+
+    /// The times without their nanoseconds, for an inode without room for them.
+    pub fn whole_secs(self) -> Self {
+        let secs = |t: Option<Time>| t.map(|t| Time { nanos: None, ..t });
+        Self {
+            accessed: secs(self.accessed),
+            modified: secs(self.modified),
+            created: secs(self.created),
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FsType {
     Ext2 = 2,
     Ext3 = 3,
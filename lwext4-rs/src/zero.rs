@@ -0,0 +1,75 @@
+use crate::block::{BlockDevice, BlockDeviceInterface};
+use crate::disk::{open_device, write_at, Disk};
+use crate::error::{Error, Result};
+use alloc::vec;
+use log::info;
+
+/// File system blocks zeroed per write.
+const CHUNK_BLOCKS: u64 = 256;
+
+/// Summary of a [zero_free_space](crate::FileSystem::zero_free_space) run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZeroReport {
+    pub block_size: u32,
+    /// Free blocks that were discarded by the device.
+    pub discarded: u64,
+    /// Free blocks that were overwritten with zeros.
+    pub zeroed: u64,
+}
+
+/// Discard or zero the blocks free in the block bitmaps of the file system on
+/// `bdev`, whose on-disk metadata must be up to date.
+pub(crate) fn zero_free_blocks<T: BlockDeviceInterface>(
+    bdev: &mut BlockDevice<T>,
+) -> Result<ZeroReport> {
+    let mut disk = Disk::open(bdev)?;
+    disk.load_groups()?;
+    let used = disk.used_blocks()?;
+    let bs = disk.block_size() as u64;
+    let blocks = disk.sb.blocks_count();
+    drop(disk);
+    let config = open_device(&mut **bdev)?;
+    let dev_bs = config.block_size as u64;
+    let mut report = ZeroReport {
+        block_size: bs as u32,
+        ..ZeroReport::default()
+    };
+    // discarding needs whole device blocks, and stops at the first refusal
+    let mut discard = config.part_offset.is_multiple_of(dev_bs) && bs.is_multiple_of(dev_bs);
+    let zeros = vec![0u8; (CHUNK_BLOCKS * bs) as usize];
+    let mut block = 0;
+    while block < blocks {
+        if used.get(block) {
+            block += 1;
+            continue;
+        }
+        let start = block;
+        while block < blocks && block - start < CHUNK_BLOCKS && !used.get(block) {
+            block += 1;
+        }
+        let count = block - start;
+        if discard {
+            let first = (config.part_offset + start * bs) / dev_bs;
+            match bdev.discard(first, count * bs / dev_bs) {
+                Ok(()) => {
+                    report.discarded += count;
+                    continue;
+                }
+                Err(Error::NotSupported) => discard = false,
+                Err(e) => return Err(e),
+            }
+        }
+        write_at(
+            &mut **bdev,
+            &config,
+            start * bs,
+            &zeros[..(count * bs) as usize],
+        )?;
+        report.zeroed += count;
+    }
+    info!(
+        "{} free blocks discarded, {} zeroed",
+        report.discarded, report.zeroed
+    );
+    Ok(report)
+}
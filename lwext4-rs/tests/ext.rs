@@ -50,10 +50,37 @@ fn main_test() {
     rename_test(&mut fs);
     attr_test(&mut fs);
     write_read_test(&mut fs);
+    export_test(&mut fs);
+    tar_test(&mut fs);
 
     remove_file_test(&mut fs);
     remove_dir_test(&mut fs);
+    drop(fs);
+    fsck_test();
+    let targets = fsck_base();
+    fsck_problems_test(targets);
+    fsck_repair_test(targets);
+    std::fs::remove_file("./fsck_base").unwrap();
+    probe_test();
+    journal_test();
+    resize_test();
+    shrink_test();
+    feature_test();
     rm_image();
+    partition_test();
+    mbr_builder_test();
+    gpt_test();
+    shared_test();
+    concat_test();
+    #[cfg(feature = "compressed")]
+    compressed_test();
+    sparse_test();
+    sparse_file_test();
+    image_test();
+    clone_test();
+    sync_test();
+    diff_test();
+    zero_test();
 }
 
 fn create_file_test(fs: &mut FS) {
@@ -261,6 +288,105 @@ fn write_read_test(fs: &mut FS) {
     assert!(res.is_ok(), "flush failed: {:?}", res.err());
 }
 
+fn export_test(fs: &mut FS) {
+    use std::os::unix::fs::MetadataExt as _;
+    // a file with a hole of zero blocks in the middle
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/sparse")
+        .unwrap();
+    file.write_all(&[0xaa; 2048]).unwrap();
+    file.write_all(&[0; 2048 * 32]).unwrap();
+    file.write_all(&[0xbb; 2048]).unwrap();
+    drop(file);
+    fs.set_xattr("/sparse", "user.export", b"kept").unwrap();
+    let times = FileTimes::new()
+        .set_accessed(Time::from_extra(1_000_000, Some(0)))
+        .set_modified(Time::from_extra(2_000_000, Some(0)));
+    fs.set_times("/sparse", times).unwrap();
+
+    let report = fs.export_to("./ext_export");
+    assert!(report.is_ok(), "export failed: {:?}", report.err());
+    let report = report.unwrap();
+    assert_eq!(report.hard_links, 50);
+    // sockets cannot be recreated, everything else may only fail for lack of the
+    // privileges to create device nodes or to change owners
+    let sockets = report
+        .issues
+        .iter()
+        .filter(|issue| issue.kind == ExportIssueKind::Socket)
+        .count();
+    assert_eq!(sockets, 10);
+    for issue in &report.issues {
+        if issue.kind != ExportIssueKind::Socket {
+            assert_eq!(issue.error, Error::OperationNotPermitted, "{:?}", issue);
+        }
+    }
+
+    let sparse = std::fs::metadata("./ext_export/sparse").unwrap();
+    assert_eq!(sparse.len(), 2048 * 34);
+    assert!(sparse.blocks() * 512 < sparse.len(), "{:?}", sparse);
+    assert_eq!(sparse.mtime(), 2_000_000);
+    assert_eq!(sparse.atime(), 1_000_000);
+    let data = std::fs::read("./ext_export/sparse").unwrap();
+    assert!(data[..2048].iter().all(|&b| b == 0xaa));
+    assert!(data[2048..2048 * 33].iter().all(|&b| b == 0));
+    assert!(data[2048 * 33..].iter().all(|&b| b == 0xbb));
+    assert_eq!(
+        xattr::get("./ext_export/sparse", "user.export").unwrap(),
+        Some(b"kept".to_vec())
+    );
+    fs.remove_file("/sparse").unwrap();
+
+    let link = std::fs::metadata("./ext_export/link").unwrap();
+    let link0 = std::fs::metadata("./ext_export/link0").unwrap();
+    assert_eq!(link.ino(), link0.ino());
+    assert_eq!(link.len(), 1024);
+    let target = std::fs::read_link("./ext_export/symlink").unwrap();
+    assert_eq!(target.to_str(), Some("/link"));
+    let file = std::fs::metadata("./ext_export/file1.txt").unwrap();
+    assert_eq!(file.mode() & 0o777, 0o644);
+    assert!(std::fs::metadata("./ext_export/rename/d2/f2").is_ok());
+    std::fs::remove_dir_all("./ext_export").unwrap();
+}
+
+fn tar_test(fs: &mut FS) {
+    let mut archive = Vec::new();
+    let res = fs.export_tar("/rename", &mut archive);
+    assert!(res.is_ok(), "export_tar failed: {:?}", res.err());
+    let res = fs.import_tar("/untar", archive.as_slice());
+    assert!(res.is_ok(), "import_tar failed: {:?}", res.err());
+    let meta = fs.metadata("/untar/d2/f2");
+    assert!(meta.is_ok());
+    assert_eq!(meta.unwrap().is_file(), true);
+    let mut archive = Vec::new();
+    let res = fs.export_tar("/link", &mut archive);
+    assert!(res.is_ok(), "export_tar failed: {:?}", res.err());
+    let res = fs.import_tar("/untar", archive.as_slice());
+    assert!(res.is_ok(), "import_tar failed: {:?}", res.err());
+    let meta = fs.metadata("/untar/link").unwrap();
+    assert_eq!(meta.size(), 1024);
+    assert_eq!(meta.uid(), 1);
+    assert_eq!(meta.permissions(), Permissions::from_mode(0o222));
+    assert_eq!(
+        fs.get_xattr("/untar/link", "user.test"),
+        Ok(b"hello".to_vec())
+    );
+    // A pax record whose size is too large to read into memory.
+    let mut header = archive[..512].to_vec();
+    header[124..136].copy_from_slice(b"77777777777\0");
+    header[156] = b'x';
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    let res = fs.import_tar("/untar", header.as_slice());
+    assert_eq!(res.err(), Some(Error::InvalidArgument));
+    let res = fs.remove_dir("/untar");
+    assert!(res.is_ok(), "remove dir failed: {:?}", res.err());
+}
+
 fn remove_file_test(fs: &mut FS) {
     let res = fs.remove_file("/link");
     assert!(res.is_ok(), "remove file failed: {:?}", res.err());
@@ -290,3 +416,1809 @@ fn remove_dir_test(fs: &mut FS) {
         assert!(res.is_ok(), "remove dir failed: {:?}", res.err());
     }
 }
+
+/// Open the image file at `path` read-write, as a device of 512 byte blocks.
+fn image_file(path: &str) -> (std::fs::File, BlockDeviceConfig) {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let len = file.metadata().unwrap().len();
+    let config = BlockDeviceConfig {
+        block_size: 512,
+        block_count: len / 512,
+        part_size: len,
+        part_offset: 0,
+    };
+    (file, config)
+}
+
+fn image_device(path: &str) -> std::pin::Pin<Box<BlockDevice<DefaultInterface<std::fs::File>>>> {
+    let (file, config) = image_file(path);
+    DefaultInterface::new_device(file, config)
+}
+
+fn fsck_test() {
+    let mut blk = image_device("./ext_image");
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+    assert!(report.directories > 0);
+    let report = Fsck::new().repair(true).check(&mut blk).unwrap();
+    assert!(
+        report.repairs.is_empty(),
+        "fsck repairs: {:#?}",
+        report.repairs
+    );
+    assert!(report.is_consistent());
+}
+
+/// Just enough of the on-disk layout to damage an image in place.
+struct RawImage {
+    file: std::fs::File,
+    block_size: u64,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    desc_size: u64,
+    /// The group descriptors have checksums.
+    group_csum: bool,
+    metadata_csum: bool,
+}
+
+impl RawImage {
+    fn open(path: &str) -> Self {
+        use std::os::unix::fs::FileExt;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let mut sb = [0u8; 1024];
+        file.read_exact_at(&mut sb, 1024).unwrap();
+        let le16 = |at: usize| u16::from_le_bytes([sb[at], sb[at + 1]]) as u64;
+        let le32 = |at: usize| u32::from_le_bytes(sb[at..at + 4].try_into().unwrap()) as u64;
+        let is_64bit = le32(0x60) & 0x80 != 0;
+        let ro_compat = le32(0x64);
+        Self {
+            file,
+            block_size: 1024 << le32(0x18),
+            blocks_count: le32(0x4) | if is_64bit { le32(0x150) << 32 } else { 0 },
+            first_data_block: le32(0x14),
+            blocks_per_group: le32(0x20),
+            inodes_per_group: le32(0x28),
+            inode_size: le16(0x58),
+            desc_size: if is_64bit { le16(0xfe) } else { 32 },
+            group_csum: ro_compat & (0x10 | 0x400) != 0,
+            metadata_csum: ro_compat & 0x400 != 0,
+        }
+    }
+
+    fn read<const N: usize>(&self, offset: u64) -> [u8; N] {
+        use std::os::unix::fs::FileExt;
+        let mut buf = [0u8; N];
+        self.file.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        use std::os::unix::fs::FileExt;
+        self.file.write_all_at(data, offset).unwrap();
+    }
+
+    fn u16(&self, offset: u64) -> u16 {
+        u16::from_le_bytes(self.read(offset))
+    }
+
+    fn u32(&self, offset: u64) -> u32 {
+        u32::from_le_bytes(self.read(offset))
+    }
+
+    fn set_u16(&self, offset: u64, value: u16) {
+        self.write(offset, &value.to_le_bytes());
+    }
+
+    fn set_u32(&self, offset: u64, value: u32) {
+        self.write(offset, &value.to_le_bytes());
+    }
+
+    fn group_of(&self, block: u64) -> u32 {
+        ((block - self.first_data_block) / self.blocks_per_group) as u32
+    }
+
+    /// Offset of the descriptor of `group`.
+    fn desc(&self, group: u32) -> u64 {
+        (self.first_data_block + 1) * self.block_size + group as u64 * self.desc_size
+    }
+
+    /// A block number in the descriptor of `group`, split at `lo` and `hi`.
+    fn desc_block(&self, group: u32, lo: u64, hi: u64) -> u64 {
+        let desc = self.desc(group);
+        let mut block = self.u32(desc + lo) as u64;
+        if self.desc_size >= 64 {
+            block |= (self.u32(desc + hi) as u64) << 32;
+        }
+        block
+    }
+
+    /// Offset of the block bitmap of `group`.
+    fn block_bitmap(&self, group: u32) -> u64 {
+        self.desc_block(group, 0x0, 0x20) * self.block_size
+    }
+
+    /// Offset of the inode bitmap of `group`.
+    fn inode_bitmap(&self, group: u32) -> u64 {
+        self.desc_block(group, 0x4, 0x24) * self.block_size
+    }
+
+    /// The first clear bit of the bitmap at `offset`.
+    fn first_clear(&self, offset: u64) -> u64 {
+        (0..self.block_size * 8)
+            .find(|&i| self.read::<1>(offset + i / 8)[0] & (1 << (i % 8)) == 0)
+            .unwrap()
+    }
+
+    fn flip_bit(&self, offset: u64, bit: u64) {
+        let byte = self.read::<1>(offset + bit / 8)[0] ^ (1 << (bit % 8));
+        self.write(offset + bit / 8, &[byte]);
+    }
+
+    fn group_free_blocks(&self, group: u32) -> u32 {
+        let desc = self.desc(group);
+        let mut free = self.u16(desc + 0xc) as u32;
+        if self.desc_size >= 64 {
+            free |= (self.u16(desc + 0x2c) as u32) << 16;
+        }
+        free
+    }
+
+    fn free_blocks(&self) -> u64 {
+        self.u32(1024 + 0xc) as u64 | (self.u32(1024 + 0x158) as u64) << 32
+    }
+
+    /// Offset of inode `ino` in its inode table.
+    fn inode(&self, ino: u32) -> u64 {
+        let index = ino as u64 - 1;
+        let group = (index / self.inodes_per_group) as u32;
+        let table = self.desc_block(group, 0x8, 0x28);
+        table * self.block_size + index % self.inodes_per_group * self.inode_size
+    }
+
+    /// Offset of the first extent of inode `ino`, whose extent tree must fit in the inode.
+    fn extent(&self, ino: u32) -> u64 {
+        let header = self.inode(ino) + 0x28;
+        assert_eq!(self.u16(header), 0xf30a);
+        assert_eq!(self.u16(header + 6), 0);
+        header + 12
+    }
+
+    fn first_block(&self, ino: u32) -> u64 {
+        let extent = self.extent(ino);
+        (self.u16(extent + 6) as u64) << 32 | self.u32(extent + 8) as u64
+    }
+
+    fn set_first_block(&self, ino: u32, block: u64) {
+        let extent = self.extent(ino);
+        self.set_u16(extent + 6, (block >> 32) as u16);
+        self.set_u32(extent + 8, block as u32);
+    }
+
+    /// Offset of the entry `name` in the first block of directory `dir`.
+    fn entry(&self, dir: u32, name: &str) -> u64 {
+        let block = self.first_block(dir) * self.block_size;
+        let mut offset = 0;
+        while offset < self.block_size {
+            let len = self.read::<1>(block + offset + 6)[0] as u64;
+            let entry_name: [u8; 255] = self.read(block + offset + 8);
+            if self.u32(block + offset) != 0 && &entry_name[..len as usize] == name.as_bytes() {
+                return block + offset;
+            }
+            offset += self.u16(block + offset + 4) as u64;
+        }
+        panic!("no entry {} in directory {}", name, dir);
+    }
+}
+
+/// Inodes of the entries fsck_base adds to ext_image, to be damaged.
+#[derive(Clone, Copy)]
+struct FsckTargets {
+    /// `/file`, three blocks long.
+    file: u32,
+    /// `/other`, one block long.
+    other: u32,
+    dir: u32,
+    /// `/dir/child`
+    child: u32,
+}
+
+/// Copy ext_image to `./fsck_base`, adding the entries to damage.
+fn fsck_base() -> FsckTargets {
+    std::fs::copy("./ext_image", "./fsck_base").unwrap();
+    let blk = image_device("./fsck_base");
+    let register_handler = RegisterHandle::register(blk, "fsck".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/fsck/".to_string(), false, false).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    let block_size = fs.mount_handle().stats().unwrap().block_size as usize;
+    for (path, blocks) in [("/fsck/file", 3), ("/fsck/other", 1)] {
+        let mut file = fs
+            .file_builder()
+            .write(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        file.write_all(&vec![0x11; blocks * block_size]).unwrap();
+    }
+    fs.create_dir("/fsck/dir").unwrap();
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/fsck/dir/child")
+        .unwrap();
+    file.write_all(b"child").unwrap();
+    drop(file);
+    let ino = |path: &str| fs.metadata(path).unwrap().ino() as u32;
+    FsckTargets {
+        file: ino("/fsck/file"),
+        other: ino("/fsck/other"),
+        dir: ino("/fsck/dir"),
+        child: ino("/fsck/dir/child"),
+    }
+}
+
+/// A fresh copy of `./fsck_base` to damage.
+fn fsck_copy() -> RawImage {
+    std::fs::copy("./fsck_base", "./fsck_image").unwrap();
+    RawImage::open("./fsck_image")
+}
+
+/// Checksums depend on the UUID and layout, so only their presence is compared.
+fn blank_checksums(problems: Vec<FsckProblem>) -> Vec<FsckProblem> {
+    problems
+        .into_iter()
+        .map(|problem| match problem {
+            FsckProblem::SuperblockChecksum { .. } => FsckProblem::SuperblockChecksum {
+                stored: 0,
+                computed: 0,
+            },
+            FsckProblem::GroupDescriptorChecksum { group, .. } => {
+                FsckProblem::GroupDescriptorChecksum {
+                    group,
+                    stored: 0,
+                    computed: 0,
+                }
+            }
+            problem => problem,
+        })
+        .collect()
+}
+
+fn fsck_problems() -> Vec<FsckProblem> {
+    let report = Fsck::new()
+        .check(&mut image_device("./fsck_image"))
+        .unwrap();
+    blank_checksums(report.problems)
+}
+
+fn fsck_problems_test(t: FsckTargets) {
+    use FsckProblem::*;
+    // a free block marked used
+    let raw = fsck_copy();
+    let group = raw.group_of(raw.first_block(t.file));
+    let bitmap = raw.block_bitmap(group);
+    let bit = raw.first_clear(bitmap);
+    raw.flip_bit(bitmap, bit);
+    let block = raw.first_data_block + group as u64 * raw.blocks_per_group + bit;
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        expected.push(BlockBitmapChecksum { group });
+    }
+    expected.push(BlockBitmapDifference {
+        start: block,
+        count: 1,
+        marked_used: true,
+    });
+    assert_eq!(fsck_problems(), expected);
+
+    // a wrong free blocks count
+    let raw = fsck_copy();
+    let free = raw.group_free_blocks(group);
+    raw.set_u16(raw.desc(group) + 0xc, free as u16 + 5);
+    let mut expected = vec![];
+    if raw.group_csum {
+        expected.push(GroupDescriptorChecksum {
+            group,
+            stored: 0,
+            computed: 0,
+        });
+    }
+    expected.push(GroupFreeBlocks {
+        group,
+        stored: free + 5,
+        counted: free,
+    });
+    assert_eq!(fsck_problems(), expected);
+
+    // a link count higher than the references
+    let raw = fsck_copy();
+    raw.set_u16(raw.inode(t.other) + 0x1a, 2);
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        expected.push(InodeChecksum { ino: t.other });
+    }
+    expected.push(LinkCount {
+        ino: t.other,
+        stored: 2,
+        counted: 1,
+    });
+    assert_eq!(fsck_problems(), expected);
+
+    // an entry pointing at a free inode, leaving its inode unattached
+    let raw = fsck_copy();
+    let free = raw.first_clear(raw.inode_bitmap(0)) as u32 + 1;
+    let entry = raw.entry(t.dir, "child");
+    raw.set_u32(entry, free);
+    let dir_block = raw.first_block(t.dir);
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        expected.push(DirBlockChecksum {
+            ino: t.dir,
+            block: dir_block,
+        });
+    }
+    expected.push(EntryUnusedInode {
+        dir: t.dir,
+        name: "child".to_string(),
+        ino: free,
+    });
+    expected.push(UnattachedInode { ino: t.child });
+    assert_eq!(fsck_problems(), expected);
+
+    // two inodes claiming the same block, and a block beyond the end of the file
+    // system, both leaving the original block of `/other` marked used
+    let raw = fsck_copy();
+    let shared = raw.first_block(t.file);
+    let lost = raw.first_block(t.other);
+    let group = raw.group_of(lost);
+    let (group_free, free) = (raw.group_free_blocks(group), raw.free_blocks());
+    let expected = |claim: FsckProblem| {
+        let mut expected = vec![];
+        if raw.metadata_csum {
+            expected.push(InodeChecksum { ino: t.other });
+        }
+        expected.push(claim);
+        expected.push(GroupFreeBlocks {
+            group,
+            stored: group_free,
+            counted: group_free + 1,
+        });
+        expected.push(BlockBitmapDifference {
+            start: lost,
+            count: 1,
+            marked_used: true,
+        });
+        expected.push(SuperblockFreeBlocks {
+            stored: free,
+            counted: free + 1,
+        });
+        expected
+    };
+    raw.set_first_block(t.other, shared);
+    let claim = MultiplyClaimedBlocks {
+        start: shared,
+        count: 1,
+        owners: vec![t.file, t.other],
+    };
+    assert_eq!(fsck_problems(), expected(claim));
+    raw.set_first_block(t.other, raw.blocks_count + 16);
+    let claim = BlockOutOfRange {
+        ino: t.other,
+        block: raw.blocks_count + 16,
+    };
+    assert_eq!(fsck_problems(), expected(claim));
+
+    // an inode left on the orphan list
+    let raw = fsck_copy();
+    raw.set_u32(1024 + 0xe8, t.other);
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        expected.push(SuperblockChecksum {
+            stored: 0,
+            computed: 0,
+        });
+    }
+    expected.push(OrphanInode { ino: t.other });
+    assert_eq!(fsck_problems(), expected);
+
+    // a corrupt descriptor and inode checksum, where the file system has them
+    let raw = fsck_copy();
+    if raw.group_csum {
+        let checksum = raw.u16(raw.desc(0) + 0x1e);
+        raw.set_u16(raw.desc(0) + 0x1e, !checksum);
+        let report = Fsck::new()
+            .check(&mut image_device("./fsck_image"))
+            .unwrap();
+        let expected = GroupDescriptorChecksum {
+            group: 0,
+            stored: !checksum,
+            computed: checksum,
+        };
+        assert_eq!(report.problems, vec![expected]);
+    }
+    if raw.metadata_csum {
+        let raw = fsck_copy();
+        let checksum = raw.u16(raw.inode(t.other) + 0x7c);
+        raw.set_u16(raw.inode(t.other) + 0x7c, !checksum);
+        assert_eq!(fsck_problems(), vec![InodeChecksum { ino: t.other }]);
+    }
+    std::fs::remove_file("./fsck_image").unwrap();
+}
+
+/// Repair `./fsck_image`, check that nothing is left and return the repairs.
+fn fsck_repairs() -> Vec<FsckRepair> {
+    let mut blk = image_device("./fsck_image");
+    let report = Fsck::new().repair(true).check(&mut blk).unwrap();
+    assert!(report.is_consistent(), "fsck left: {:#?}", report.remaining);
+    let check = Fsck::new().check(&mut blk).unwrap();
+    assert!(check.is_clean(), "fsck problems: {:#?}", check.problems);
+    report.repairs
+}
+
+fn fsck_repair_test(t: FsckTargets) {
+    use FsckRepair::*;
+    // a free block marked used
+    let raw = fsck_copy();
+    let group = raw.group_of(raw.first_block(t.file));
+    let bitmap = raw.block_bitmap(group);
+    raw.flip_bit(bitmap, raw.first_clear(bitmap));
+    assert_eq!(fsck_repairs(), vec![BlockBitmap { group }]);
+
+    // a wrong free blocks count
+    let raw = fsck_copy();
+    let free = raw.group_free_blocks(group);
+    raw.set_u16(raw.desc(group) + 0xc, free as u16 + 5);
+    let mut expected = vec![];
+    if raw.group_csum {
+        expected.push(GroupDescriptorChecksum { group });
+    }
+    expected.push(GroupCounts { group });
+    assert_eq!(fsck_repairs(), expected);
+    assert_eq!(raw.group_free_blocks(group), free);
+
+    // a link count higher than the references
+    let raw = fsck_copy();
+    raw.set_u16(raw.inode(t.other) + 0x1a, 2);
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        expected.push(InodeChecksum { ino: t.other });
+    }
+    expected.push(LinkCount {
+        ino: t.other,
+        from: 2,
+        to: 1,
+    });
+    assert_eq!(fsck_repairs(), expected);
+
+    // an inode whose only entry was cleared goes to lost+found
+    let raw = fsck_copy();
+    raw.set_u32(raw.entry(t.dir, "child"), 0);
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        expected.push(DirBlockChecksum {
+            ino: t.dir,
+            block: raw.first_block(t.dir),
+        });
+    }
+    let name = format!("#{}", t.child);
+    expected.push(MovedToLostFound {
+        ino: t.child,
+        name: name.clone(),
+    });
+    assert_eq!(fsck_repairs(), expected);
+    let lost_found = raw.u32(raw.entry(2, "lost+found"));
+    assert_eq!(raw.u32(raw.entry(lost_found, &name)), t.child);
+
+    // without lost+found, one is created below the root first
+    let raw = fsck_copy();
+    raw.write(raw.entry(2, "lost+found") + 8, b"lost+fnord");
+    raw.set_u32(raw.entry(t.dir, "child"), 0);
+    let free = raw.first_clear(raw.inode_bitmap(0)) as u32 + 1;
+    let mut expected = vec![];
+    if raw.metadata_csum {
+        for dir in [2, t.dir] {
+            expected.push(DirBlockChecksum {
+                ino: dir,
+                block: raw.first_block(dir),
+            });
+        }
+    }
+    expected.push(CreatedLostFound { ino: free });
+    expected.push(MovedToLostFound {
+        ino: t.child,
+        name: name.clone(),
+    });
+    let repairs = fsck_repairs();
+    assert_eq!(repairs[..expected.len()], expected);
+    for repair in &repairs[expected.len()..] {
+        assert!(matches!(
+            repair,
+            BlockBitmap { .. } | InodeBitmap { .. } | GroupCounts { .. } | SuperblockCounts
+        ));
+    }
+    assert_eq!(raw.u32(raw.entry(2, "lost+found")), free);
+    assert_eq!(raw.u32(raw.entry(free, &name)), t.child);
+
+    // references to a block of another inode and beyond the end of the file system
+    // are dropped, freeing the original block of `/other`
+    let raw = fsck_copy();
+    let shared = raw.first_block(t.file);
+    let group = raw.group_of(raw.first_block(t.other));
+    let expected = |start: u64| {
+        let mut expected = vec![];
+        if raw.metadata_csum {
+            expected.push(InodeChecksum { ino: t.other });
+        }
+        expected.extend([
+            ClearedBlocks {
+                ino: t.other,
+                start,
+                count: 1,
+            },
+            BlockBitmap { group },
+            GroupCounts { group },
+            SuperblockCounts,
+        ]);
+        expected
+    };
+    raw.set_first_block(t.other, shared);
+    assert_eq!(fsck_repairs(), expected(shared));
+    assert_eq!(raw.first_block(t.file), shared);
+    let raw = fsck_copy();
+    raw.set_first_block(t.other, raw.blocks_count + 16);
+    assert_eq!(fsck_repairs(), expected(raw.blocks_count + 16));
+
+    // a corrupt descriptor checksum, where the file system has them
+    let raw = fsck_copy();
+    if raw.group_csum {
+        let checksum = raw.u16(raw.desc(0) + 0x1e);
+        raw.set_u16(raw.desc(0) + 0x1e, !checksum);
+        assert_eq!(fsck_repairs(), vec![GroupDescriptorChecksum { group: 0 }]);
+        assert_eq!(raw.u16(raw.desc(0) + 0x1e), checksum);
+    }
+    std::fs::remove_file("./fsck_image").unwrap();
+}
+
+fn probe_test() {
+    let mut blk = image_device("./ext_image");
+    let len = std::fs::metadata("./ext_image").unwrap().len();
+    let info = probe(&mut blk).unwrap();
+    assert_eq!(info.fs_type, Ext4);
+    assert_eq!(info.label, "ext4fs");
+    assert_eq!(info.block_size, 2048);
+    assert!(info.size > 0 && info.size <= len);
+    assert!(!info.needs_recovery);
+
+    let uuid = FsSpec::Uuid(info.uuid).to_string();
+    assert_eq!(uuid.parse::<FsSpec>(), Ok(FsSpec::Uuid(info.uuid)));
+    assert!("PARTUUID=1234".parse::<FsSpec>().is_err());
+    let mut handles = vec![RegisterHandle::register(blk, "ext4fs".to_string()).unwrap()];
+    let label = "LABEL=ext4fs".parse().unwrap();
+    assert_eq!(
+        find_filesystem(&mut handles, &label),
+        Some((0, info.clone()))
+    );
+    let uuid = uuid.parse().unwrap();
+    assert_eq!(find_filesystem(&mut handles, &uuid), Some((0, info)));
+    let missing = "LABEL=rootfs".parse().unwrap();
+    assert_eq!(find_filesystem(&mut handles, &missing), None);
+}
+
+fn resize_test() {
+    let file = OpenOptions::new().write(true).open("./ext_image").unwrap();
+    file.set_len(1024 * 1024 * 64).unwrap();
+    drop(file);
+    let mut blk = image_device("./ext_image");
+    let report = Resize::new().grow(&mut blk).unwrap();
+    assert!(report.new_groups > report.old_groups);
+    assert_eq!(report.new_blocks, 1024 * 1024 * 64 / 2048);
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+}
+
+fn shrink_test() {
+    let mut blk = image_device("./ext_image");
+    let minimum = Resize::new().minimum(&mut blk).unwrap();
+    let report = Resize::new().shrink(&mut blk).unwrap();
+    assert_eq!(report.new_blocks, minimum);
+    assert!(report.new_groups < report.old_groups);
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+    assert_eq!(report.blocks_count, minimum);
+}
+
+fn journal_test() {
+    let mut blk = image_device("./ext_image");
+    Journal::new().remove(&mut blk).unwrap();
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+    assert!(Journal::new().remove(&mut blk).is_err());
+    let blocks = Journal::new().blocks(1024).add(&mut blk).unwrap();
+    assert_eq!(blocks, 1024);
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+}
+
+fn toggle_feature(offset: u64, flag: u32) {
+    use std::os::unix::fs::FileExt;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("./ext_image")
+        .unwrap();
+    let mut raw = [0u8; 4];
+    file.read_exact_at(&mut raw, 1024 + offset).unwrap();
+    let value = u32::from_le_bytes(raw) ^ flag;
+    file.write_all_at(&value.to_le_bytes(), 1024 + offset)
+        .unwrap();
+}
+
+fn feature_test() {
+    let mut blk = image_device("./ext_image");
+    let features = FeatureReport::read(&mut blk).unwrap();
+    assert!(features.is_writable());
+    assert_eq!(features.to_string(), "no features");
+    drop(blk);
+
+    // inline_data refuses the mount
+    toggle_feature(0x60, 0x8000);
+    let mut blk = image_device("./ext_image");
+    let features = FeatureReport::read(&mut blk).unwrap();
+    assert!(!features.is_mountable());
+    assert_eq!(features.unsupported_incompat(), vec!["inline_data"]);
+    let register_handler = RegisterHandle::register(blk, "ext4fs".to_string()).unwrap();
+    let res = MountHandle::mount(register_handler, "/".to_string(), true, false);
+    assert_eq!(res.err(), Some(Error::NotSupported));
+    toggle_feature(0x60, 0x8000);
+
+    // orphan_present only prevents writing
+    toggle_feature(0x64, 0x10000);
+    // flipping a feature by hand breaks the superblock checksum, if there is one
+    let repairs = match RawImage::open("./ext_image").metadata_csum {
+        true => vec![FsckRepair::SuperblockChecksum],
+        false => vec![],
+    };
+    let mut blk = image_device("./ext_image");
+    let report = Fsck::new().repair(true).check(&mut blk).unwrap();
+    assert_eq!(report.repairs, repairs);
+    assert!(report.is_consistent());
+    let features = FeatureReport::read(&mut blk).unwrap();
+    assert!(features.is_mountable() && !features.is_writable());
+    assert_eq!(features.unsupported_ro_compat(), vec!["orphan_present"]);
+    assert_eq!(features.to_string(), "features orphan_present");
+    let register_handler = RegisterHandle::register(blk, "ext4fs".to_string()).unwrap();
+    let mount_handler = MountHandle::mount(register_handler, "/".to_string(), true, false).unwrap();
+    assert!(mount_handler.read_only());
+    drop(mount_handler);
+    toggle_feature(0x64, 0x10000);
+    let mut blk = image_device("./ext_image");
+    let report = Fsck::new().repair(true).check(&mut blk).unwrap();
+    assert_eq!(report.repairs, repairs);
+    assert!(report.is_consistent());
+    drop(blk);
+
+    // mmp mounts, but without multi-mount protection only read-only
+    toggle_feature(0x60, 0x0100);
+    let mut blk = image_device("./ext_image");
+    let report = Fsck::new().repair(true).check(&mut blk).unwrap();
+    assert_eq!(report.repairs, repairs);
+    let features = FeatureReport::read(&mut blk).unwrap();
+    assert!(features.is_mountable() && !features.is_writable());
+    assert!(features.unsupported_incompat().is_empty());
+    assert_eq!(features.read_only_incompat(), vec!["mmp"]);
+    assert_eq!(features.to_string(), "features mmp");
+    let register_handler = RegisterHandle::register(blk, "ext4fs".to_string()).unwrap();
+    let mount_handler = MountHandle::mount(register_handler, "/".to_string(), true, false).unwrap();
+    assert!(mount_handler.read_only());
+    drop(mount_handler);
+    toggle_feature(0x60, 0x0100);
+    let mut blk = image_device("./ext_image");
+    let report = Fsck::new().repair(true).check(&mut blk).unwrap();
+    assert_eq!(report.repairs, repairs);
+    assert!(report.is_consistent());
+}
+
+fn partition_test() {
+    use std::os::unix::fs::FileExt;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("./mbr_image")
+        .unwrap();
+    file.set_len(1024 * 1024 * 16).unwrap();
+    // A FAT partition at 1MiB and a Linux one after it, as fdisk lays them out.
+    let mut mbr = [0u8; 512];
+    for (i, (kind, start, sectors)) in [(0x0cu8, 2048u32, 4096u32), (0x83, 6144, 26624)]
+        .into_iter()
+        .enumerate()
+    {
+        let entry = &mut mbr[0x1be + i * 16..0x1be + (i + 1) * 16];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+    mbr[0x1be] = 0x80;
+    mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+    file.write_all_at(&mbr, 0).unwrap();
+
+    let len = file.metadata().unwrap().len();
+    let config = BlockDeviceConfig {
+        block_size: 512,
+        block_count: len / 512,
+        part_size: len,
+        part_offset: 0,
+    };
+    let disk = SharedDevice::new(DefaultInterface::new(file, config));
+    let partitions = scan_mbr(&mut *disk.lock()).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!((partitions[0].number, partitions[0].kind), (1, 0x0c));
+    assert!(partitions[0].bootable);
+    assert_eq!(partitions[1].offset(), 6144 * 512);
+    assert_eq!(partitions[1].size(), 26624 * 512);
+
+    let root = &partitions[1];
+    let blk = disk.partition(root.offset(), root.size()).unwrap();
+    let fs = FsBuilder::new()
+        .ty(Ext4)
+        .block_size(1024)
+        .label("rootfs")
+        .build(blk)
+        .unwrap();
+    let mut blk = fs.take_device();
+    let info = probe(&mut blk).unwrap();
+    assert_eq!(info.label, "rootfs");
+    assert!(info.size <= root.size());
+    let boot = &partitions[0];
+    let mut boot_blk = disk.partition(boot.offset(), boot.size()).unwrap();
+    assert!(probe(&mut boot_blk).is_err());
+    // The file system stays inside its partition.
+    assert_eq!(scan_mbr(&mut *disk.lock()).unwrap(), partitions);
+    drop((blk, boot_blk, disk));
+    std::fs::remove_file("./mbr_image").unwrap();
+}
+
+fn mbr_builder_test() {
+    use std::os::unix::fs::FileExt;
+    create_image("./mbr_builder_image", true);
+    let (file, config) = image_file("./mbr_builder_image");
+    // Boot code and a stale table, which the builder replaces.
+    let mut sector = [0u8; 512];
+    sector[..440].fill(0xeb);
+    sector[0x1be + 3 * 16 + 4] = MbrPartition::LINUX;
+    sector[0x1be + 3 * 16 + 12] = 1;
+    file.write_all_at(&sector, 0).unwrap();
+    let sectors = config.block_count;
+    let mut dev = DefaultInterface::new(file, config);
+
+    let partitions = MbrBuilder::new()
+        .disk_id(0x1234abcd)
+        .partition(MbrPartition::FAT32, true, Some((3 << 20) + 1))
+        .partition(MbrPartition::LINUX_SWAP, false, Some(1000))
+        .partition(MbrPartition::LINUX, false, None)
+        .write(&mut dev)
+        .unwrap();
+    let layout: Vec<_> = partitions
+        .iter()
+        .map(|p| (p.number, p.kind, p.bootable, p.start, p.sectors))
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (1, MbrPartition::FAT32, true, 2048, 6145),
+            (2, MbrPartition::LINUX_SWAP, false, 10240, 2),
+            (3, MbrPartition::LINUX, false, 12288, sectors - 12288),
+        ]
+    );
+    assert!(partitions.iter().all(|p| p.offset() % (1 << 20) == 0));
+    assert_eq!(scan_mbr(&mut dev).unwrap(), partitions);
+    let (file, _) = image_file("./mbr_builder_image");
+    file.read_exact_at(&mut sector, 0).unwrap();
+    assert!(sector[..440].iter().all(|&b| b == 0xeb));
+    assert_eq!(sector[0x1b8..0x1bc], 0x1234abcdu32.to_le_bytes());
+    let flags: Vec<_> = (0..4).map(|i| sector[0x1be + i * 16]).collect();
+    assert_eq!(flags, [0x80, 0, 0, 0]);
+    assert_eq!(sector[0x1be + 3 * 16..0x1fe], [0; 16]);
+    assert_eq!(sector[0x1fe..], [0x55, 0xaa]);
+
+    let linux = |size| MbrBuilder::new().partition(MbrPartition::LINUX, false, size);
+    let errors = [
+        (linux(None).partition(0x83, false, None), Error::NoSpace),
+        (linux(Some(sectors * 512)), Error::NoSpace),
+        (linux(Some(0)), Error::InvalidArgument),
+        (
+            MbrBuilder::new().partition(0, false, None),
+            Error::InvalidArgument,
+        ),
+        (
+            (0..5).fold(MbrBuilder::new(), |b, _| {
+                b.partition(0x83, false, Some(1 << 20))
+            }),
+            Error::InvalidArgument,
+        ),
+    ];
+    for (builder, error) in errors {
+        assert_eq!(builder.write(&mut dev), Err(error));
+    }
+    // Nothing is written when the layout is rejected.
+    assert_eq!(scan_mbr(&mut dev).unwrap(), partitions);
+    drop(dev);
+    std::fs::remove_file("./mbr_builder_image").unwrap();
+}
+
+fn shared_test() {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("./shared_image")
+        .unwrap();
+    file.set_len(1024 * 1024 * 16).unwrap();
+    let len = file.metadata().unwrap().len();
+    let config = BlockDeviceConfig {
+        block_size: 512,
+        block_count: len / 512,
+        part_size: len,
+        part_offset: 0,
+    };
+    let disk = SharedDevice::new(DefaultInterface::new(file, config));
+    let partitions = MbrBuilder::new()
+        .partition(MbrPartition::LINUX, true, Some(4 << 20))
+        .partition(MbrPartition::LINUX, false, None)
+        .write(&mut *disk.lock())
+        .unwrap();
+    assert_eq!(scan_mbr(&mut *disk.lock()).unwrap(), partitions);
+
+    let mut mounts = Vec::new();
+    for (p, name) in partitions.iter().zip(["boot", "root"]) {
+        let blk = disk.partition(p.offset(), p.size()).unwrap();
+        let fs = FsBuilder::new()
+            .ty(FsType::Ext2)
+            .block_size(1024)
+            .label(name)
+            .build(blk)
+            .unwrap();
+        let blk = fs.take_device();
+        let register_handler = RegisterHandle::register(blk, name.to_string()).unwrap();
+        let mount_handler =
+            MountHandle::mount(register_handler, format!("/{}/", name), false, false).unwrap();
+        mounts.push(FileSystem::new(mount_handler).unwrap());
+    }
+    // Mounted partitions keep their ranges, the whole disk is not available.
+    assert_eq!(disk.partition(0, len).err(), Some(Error::FileExists));
+    for (fs, name) in mounts.iter().zip(["boot", "root"]) {
+        let mut file = fs
+            .file_builder()
+            .write(true)
+            .create(true)
+            .open(format!("/{}/name", name))
+            .unwrap();
+        file.write_all(name.as_bytes()).unwrap();
+    }
+    for (fs, name) in mounts.iter().zip(["boot", "root"]) {
+        let mut file = fs
+            .file_builder()
+            .read(true)
+            .open(format!("/{}/name", name))
+            .unwrap();
+        let mut buf = vec![0u8; name.len()];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, name.as_bytes());
+    }
+    drop(mounts);
+    for p in &partitions {
+        let mut blk = disk.partition(p.offset(), p.size()).unwrap();
+        let report = Fsck::new().check(&mut blk).unwrap();
+        assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+    }
+
+    // Look the partitions up by label next to a whole disk, which holds only the table.
+    let mut whole =
+        RegisterHandle::register(image_device("./shared_image"), "sdb".to_string()).unwrap();
+    let mut handles = Vec::new();
+    for (p, name) in partitions.iter().zip(["sdb1", "sdb2"]) {
+        let blk = disk.partition(p.offset(), p.size()).unwrap();
+        handles.push(RegisterHandle::register(blk, name.to_string()).unwrap());
+    }
+    let [boot, root] = &mut handles[..] else {
+        unreachable!()
+    };
+    let mut devices: [&mut dyn ProbeDevice; 3] = [&mut whole, boot, root];
+    let (index, info) = find_filesystem(&mut devices, &"LABEL=root".parse().unwrap()).unwrap();
+    assert_eq!((index, info.fs_type), (2, FsType::Ext2));
+    assert!(info.size <= partitions[1].size());
+    let uuid = FsSpec::Uuid(info.uuid);
+    assert_eq!(find_filesystem(&mut devices, &uuid), Some((2, info)));
+    let (index, _) = find_filesystem(&mut devices, &"LABEL=boot".parse().unwrap()).unwrap();
+    assert_eq!(index, 1);
+    assert_eq!(
+        find_filesystem(&mut devices, &"LABEL=rootfs".parse().unwrap()),
+        None
+    );
+    drop((handles, whole));
+
+    assert!(disk.partition(0, len).is_ok());
+    drop(disk);
+    std::fs::remove_file("./shared_image").unwrap();
+}
+
+fn gpt_test() {
+    use std::os::unix::fs::FileExt;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("./gpt_image")
+        .unwrap();
+    file.set_len(1024 * 1024 * 32).unwrap();
+    let len = file.metadata().unwrap().len();
+    let config = BlockDeviceConfig {
+        block_size: 512,
+        block_count: len / 512,
+        part_size: len,
+        part_offset: 0,
+    };
+    let disk = SharedDevice::new(DefaultInterface::new(file.try_clone().unwrap(), config));
+    assert!(scan_gpt(&mut *disk.lock()).is_err());
+    let disk_guid: Guid = "5B3E4F6A-0C1D-4E2F-9A8B-7C6D5E4F3A2B".parse().unwrap();
+    let efi_guid: Guid = "11111111-2222-3333-4444-555555555555".parse().unwrap();
+    let root_guid: Guid = "0C3D9B7E-1F2A-4D5E-8A6B-7C8D9E0F1A2B".parse().unwrap();
+    assert_eq!(
+        root_guid.to_string(),
+        "0C3D9B7E-1F2A-4D5E-8A6B-7C8D9E0F1A2B"
+    );
+    let written = GptBuilder::new(disk_guid)
+        .partition(Guid::EFI_SYSTEM, efi_guid, "EFI system", Some(4 << 20))
+        .partition(Guid::LINUX_FILESYSTEM, root_guid, "rootfs", None)
+        .write(&mut *disk.lock())
+        .unwrap();
+    let table = scan_gpt(&mut *disk.lock()).unwrap();
+    assert_eq!(table, written);
+    assert_eq!(table.partitions.len(), 2);
+    assert_eq!(table.partitions[0].offset(), 1024 * 1024);
+    assert_eq!(table.partitions[0].size(), 4 << 20);
+    assert_eq!(table.partitions[1].kind, Guid::LINUX_FILESYSTEM);
+    assert_eq!(table.partitions[1].name, "rootfs");
+    // The protective MBR shows the whole disk as one partition.
+    let mbr = scan_mbr(&mut *disk.lock()).unwrap();
+    assert_eq!(mbr[0].kind, 0xee);
+
+    let root = &table.partitions[1];
+    let blk = disk.partition(root.offset(), root.size()).unwrap();
+    let fs = FsBuilder::new()
+        .ty(Ext4)
+        .block_size(1024)
+        .label("rootfs")
+        .build(blk)
+        .unwrap();
+    let mut blk = fs.take_device();
+    assert_eq!(probe(&mut blk).unwrap().label, "rootfs");
+
+    // Damage the primary header, the backup at the end takes over.
+    file.write_all_at(b"XXXX", 512 + 40).unwrap();
+    let backup = scan_gpt(&mut *disk.lock()).unwrap();
+    assert!(backup.from_backup);
+    assert_eq!(backup.partitions, table.partitions);
+    drop((blk, disk));
+    std::fs::remove_file("./gpt_image").unwrap();
+}
+
+fn concat_test() {
+    let paths = [
+        "./concat_image.000",
+        "./concat_image.001",
+        "./concat_image.002",
+    ];
+    let sizes = [3 << 20, 5 << 20, 8 << 20];
+    for (path, size) in paths.iter().zip(sizes) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        file.set_len(size).unwrap();
+    }
+    let open_parts = || {
+        let parts = paths.iter().map(|path| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap();
+            let len = file.metadata().unwrap().len();
+            let config = BlockDeviceConfig {
+                block_size: 512,
+                block_count: len / 512,
+                part_size: len,
+                part_offset: 0,
+            };
+            DefaultInterface::new(file, config)
+        });
+        Concat::new_device(parts.collect())
+    };
+    let blk = open_parts();
+    let fs = FsBuilder::new()
+        .ty(FsType::Ext4)
+        .block_size(4096)
+        .label("concat")
+        .build(blk)
+        .unwrap();
+    let blk = fs.take_device();
+    let register_handler = RegisterHandle::register(blk, "concat".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/concat/".to_string(), false, false).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    // Larger than the first part, so the data spans at least one boundary.
+    let data: Vec<u8> = (0..6 << 20).map(|i: u32| (i % 251) as u8).collect();
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/concat/data")
+        .unwrap();
+    file.write_all(&data).unwrap();
+    drop(file);
+    let mut file = fs.file_builder().read(true).open("/concat/data").unwrap();
+    let mut buf = vec![0u8; data.len()];
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+    drop(file);
+
+    drop(fs);
+
+    let mut blk = open_parts();
+    let info = probe(&mut blk).unwrap();
+    assert_eq!(info.size, 16 << 20);
+    assert_eq!(info.label, "concat");
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+    drop(blk);
+    for path in paths {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[cfg(feature = "compressed")]
+fn compressed_test() {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("./compressed_image")
+        .unwrap();
+    file.set_len(1024 * 1024 * 8).unwrap();
+    let blk = DefaultInterface::new_device(
+        file,
+        BlockDeviceConfig {
+            block_size: 512,
+            block_count: 1024 * 1024 * 8 / 512,
+            part_size: 1024 * 1024 * 8,
+            part_offset: 0,
+        },
+    );
+    let fs = FsBuilder::new()
+        .ty(FsType::Ext4)
+        .block_size(4096)
+        .label("release")
+        .build(blk)
+        .unwrap();
+    let blk = fs.take_device();
+    let register_handler = RegisterHandle::register(blk, "release".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/release/".to_string(), false, false).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    let data: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 253) as u8).collect();
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/release/data")
+        .unwrap();
+    file.write_all(&data).unwrap();
+    drop((file, fs));
+    let image = std::fs::read("./compressed_image").unwrap();
+    std::fs::remove_file("./compressed_image").unwrap();
+
+    // Seekable zstd: one frame per chunk, then the seek table in a skippable frame.
+    let mut zstd = Vec::new();
+    let mut table = Vec::new();
+    for chunk in image.chunks(1 << 20) {
+        let frame =
+            ruzstd::encoding::compress_to_vec(chunk, ruzstd::encoding::CompressionLevel::Fastest);
+        zstd.extend_from_slice(&frame);
+        table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+    }
+    table.extend_from_slice(&((image.len() >> 20) as u32).to_le_bytes());
+    table.extend_from_slice(&[0, 0xb1, 0xea, 0x92, 0x8f]);
+    zstd.extend_from_slice(&0x184d_2a5e_u32.to_le_bytes());
+    zstd.extend_from_slice(&(table.len() as u32).to_le_bytes());
+    zstd.extend_from_slice(&table);
+
+    // Multi-member gzip, one member per chunk.
+    let mut gzip = Vec::new();
+    for chunk in image.chunks(1 << 20) {
+        gzip.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+        gzip.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(chunk, 6));
+        gzip.extend_from_slice(&gzip_crc(chunk).to_le_bytes());
+        gzip.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+    }
+
+    for (name, compressed) in [("zst", zstd), ("gz", gzip)] {
+        let path = format!("./compressed_image.{}", name);
+        std::fs::write(&path, compressed).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let mut blk = CompressedInterface::new_device(file, 4096);
+        let info = probe(&mut blk).unwrap();
+        assert_eq!(info.label, "release");
+        assert_eq!(info.size, image.len() as u64);
+        let register_handler = RegisterHandle::register(blk, name.to_string()).unwrap();
+        let mount_handler =
+            MountHandle::mount(register_handler, format!("/{}/", name), false, true).unwrap();
+        let fs = FileSystem::new(mount_handler).unwrap();
+        let mut file = fs
+            .file_builder()
+            .read(true)
+            .open(format!("/{}/data", name))
+            .unwrap();
+        let mut buf = vec![0u8; data.len()];
+        file.read_exact(&mut buf).unwrap();
+        assert!(buf == data);
+        drop((file, fs));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    let mut blk = CompressedInterface::new(std::io::Cursor::new(image), 4096);
+    assert_eq!(blk.open().err(), Some(Error::NotSupported));
+}
+
+#[cfg(feature = "compressed")]
+fn gzip_crc(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Create a 32MiB image file at `path`, or reuse the one there unless `truncate`.
+fn create_image(path: &str, truncate: bool) {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(truncate)
+        .open(path)
+        .unwrap();
+    file.set_len(1024 * 1024 * 32).unwrap();
+}
+
+fn sparse_device(
+    path: &str,
+    truncate: bool,
+) -> std::pin::Pin<Box<BlockDevice<DefaultInterface<std::fs::File>>>> {
+    create_image(path, truncate);
+    image_device(path)
+}
+
+/// Format a fresh image at `path` and mount it read-write at `mount_point`, registered
+/// under the name of the mount point.
+fn mounted_image(path: &str, ty: FsType, block_size: u32, mount_point: &str) -> FS {
+    let fs = FsBuilder::new()
+        .ty(ty)
+        .block_size(block_size)
+        .build(sparse_device(path, true))
+        .unwrap();
+    let name = mount_point.trim_matches('/').to_string();
+    let register_handler = RegisterHandle::register(fs.take_device(), name).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, mount_point.to_string(), false, false).unwrap();
+    FileSystem::new(mount_handler).unwrap()
+}
+
+/// Check the unmounted image at `path` and remove it.
+fn assert_fsck_clean_and_remove(path: &str) {
+    let report = Fsck::new().check(&mut sparse_device(path, false)).unwrap();
+    assert!(
+        report.is_clean(),
+        "{}: fsck problems: {:#?}",
+        path,
+        report.problems
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+fn sparse_test() {
+    let fs = mounted_image("./sparse_image", FsType::Ext4, 4096, "/sparse/");
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/sparse/data")
+        .unwrap();
+    file.write_all(&[0x5a; 100_000]).unwrap();
+    drop((file, fs));
+
+    let mut blk = sparse_device("./sparse_image", false);
+    let mut image = Vec::new();
+    let report = export_sparse(&mut blk, &mut image).unwrap();
+    assert_eq!(report.block_size, 4096);
+    assert_eq!(report.blocks, 32 * 256);
+    assert_eq!(report.raw + report.fill + report.dont_care, report.blocks);
+    // Mostly free, and the zeroed journal is a fill chunk.
+    assert!(report.dont_care > report.blocks / 2);
+    assert!(report.fill > 0);
+    assert!(image.len() < 1024 * 1024 * 4);
+    drop(blk);
+    assert_fsck_clean_and_remove("./sparse_image");
+
+    let mut blk = sparse_device("./sparse_copy", true);
+    assert_eq!(import_sparse(image.as_slice(), &mut blk).unwrap(), report);
+    let check = Fsck::new().check(&mut blk).unwrap();
+    assert!(check.is_clean(), "fsck problems: {:#?}", check.problems);
+    let register_handler = RegisterHandle::register(blk, "sparse_copy".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/sparse_copy/".to_string(), false, true).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    let mut file = fs
+        .file_builder()
+        .read(true)
+        .open("/sparse_copy/data")
+        .unwrap();
+    let mut buf = vec![0u8; 100_000];
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0x5a));
+    drop((file, fs));
+
+    let mut blk = sparse_device("./sparse_copy", false);
+    // A bad magic, then block sizes ext4 cannot have.
+    image[0] ^= 0xff;
+    assert_eq!(
+        import_sparse(image.as_slice(), &mut blk).err(),
+        Some(Error::InvalidArgument)
+    );
+    image[0] ^= 0xff;
+    for bs in [0u32, 512, 3072, 4100, 128 << 10] {
+        image[12..16].copy_from_slice(&bs.to_le_bytes());
+        assert_eq!(
+            import_sparse(image.as_slice(), &mut blk).err(),
+            Some(Error::InvalidArgument),
+            "block size {}",
+            bs
+        );
+    }
+    drop(blk);
+    assert_fsck_clean_and_remove("./sparse_copy");
+}
+
+fn sparse_file_device() -> std::pin::Pin<Box<BlockDevice<SparseFileInterface>>> {
+    let (file, config) = image_file("./sparse_file_image");
+    SparseFileInterface::new_device(file, config)
+}
+
+fn sparse_file_test() {
+    create_image("./sparse_file_image", true);
+    let fs = FsBuilder::new()
+        .ty(FsType::Ext4)
+        .block_size(4096)
+        .journal(true)
+        .build(sparse_file_device())
+        .unwrap();
+    let blk = fs.take_device();
+    assert_eq!(blk.apparent_size().unwrap(), 1024 * 1024 * 32);
+    let formatted = blk.allocated_size().unwrap();
+    assert!(formatted < 1024 * 1024 * 8, "{} bytes allocated", formatted);
+
+    let register_handler = RegisterHandle::register(blk, "sparse_file".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/sparse_file/".to_string(), false, false).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    let data: Vec<u8> = (0..8 << 20).map(|i: u32| (i % 249) as u8 | 1).collect();
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/sparse_file/data")
+        .unwrap();
+    file.write_all(&data).unwrap();
+    drop(file);
+    fs.remove_file("/sparse_file/data").unwrap();
+    drop(fs);
+
+    let mut blk = sparse_file_device();
+    let written = blk.allocated_size().unwrap();
+    assert!(written >= formatted + data.len() as u64);
+    let trimmed = SparseFileInterface::trim(&mut blk).unwrap();
+    assert!(trimmed > data.len() as u64);
+    assert!(blk.allocated_size().unwrap() <= written - data.len() as u64);
+    let report = Fsck::new().check(&mut blk).unwrap();
+    assert!(report.is_clean(), "fsck problems: {:#?}", report.problems);
+    drop(blk);
+    std::fs::remove_file("./sparse_file_image").unwrap();
+}
+
+fn image_test() {
+    let fs = mounted_image("./image_src", FsType::Ext4, 4096, "/image/");
+    for i in 0..20 {
+        fs.create_dir(format!("/image/dir/d{}", i)).unwrap();
+    }
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/image/dir/data")
+        .unwrap();
+    file.write_all(&[0x5a; 1 << 20]).unwrap();
+    drop((file, fs));
+
+    let mut src = sparse_device("./image_src", false);
+    let mut dst = sparse_device("./image_copy", true);
+    let full = image_copy(&mut src, &mut dst).unwrap();
+    assert_eq!(full.blocks, 32 * 256);
+    assert!(full.copied > 256 && full.copied < full.blocks / 2);
+    let register_handler = RegisterHandle::register(dst, "image_copy".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/image_copy/".to_string(), false, true).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    let mut file = fs
+        .file_builder()
+        .read(true)
+        .open("/image_copy/dir/data")
+        .unwrap();
+    let mut buf = vec![0u8; 1 << 20];
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0x5a));
+    drop((file, fs));
+
+    let mut dst = sparse_device("./image_meta", true);
+    let meta = ImageCopy::new()
+        .metadata_only(true)
+        .copy(&mut src, &mut dst)
+        .unwrap();
+    assert!(meta.copied <= full.copied - 256, "{:?} {:?}", meta, full);
+    let check = Fsck::new().check(&mut dst).unwrap();
+    assert!(check.is_clean(), "fsck problems: {:#?}", check.problems);
+    let register_handler = RegisterHandle::register(dst, "image_meta".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/image_meta/".to_string(), false, true).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    let entries = fs.readdir("/image_meta/dir").unwrap().count();
+    assert_eq!(entries, 20 + 1 + 2);
+    let mut file = fs
+        .file_builder()
+        .read(true)
+        .open("/image_meta/dir/data")
+        .unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+    drop((file, fs));
+
+    let mut dst = sparse_device("./image_anon", true);
+    let anon = ImageCopy::new()
+        .anonymize(true)
+        .copy(&mut src, &mut dst)
+        .unwrap();
+    assert_eq!(anon.names, 1 + 20 + 1);
+    let check = Fsck::new().check(&mut dst).unwrap();
+    assert!(check.is_clean(), "fsck problems: {:#?}", check.problems);
+    let register_handler = RegisterHandle::register(dst, "image_anon".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/image_anon/".to_string(), false, true).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+    assert!(fs.metadata("/image_anon/dir").is_err());
+    assert!(fs.metadata("/image_anon/lost+found").is_ok());
+    let names: Vec<String> = fs
+        .readdir("/image_anon/")
+        .unwrap()
+        .map(|e| e.name().to_string())
+        .filter(|name| name != "." && name != ".." && name != "lost+found")
+        .collect();
+    assert_eq!(names.len(), 1);
+    assert_eq!(names[0].len(), "dir".len());
+    let entries = fs
+        .readdir(format!("/image_anon/{}", names[0]))
+        .unwrap()
+        .count();
+    assert_eq!(entries, 20 + 1 + 2);
+    drop(fs);
+
+    let mut small = DefaultInterface::new_device(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("./image_small")
+            .unwrap(),
+        BlockDeviceConfig {
+            block_size: 512,
+            block_count: 2048,
+            part_size: 1024 * 1024,
+            part_offset: 0,
+        },
+    );
+    assert_eq!(image_copy(&mut src, &mut small).err(), Some(Error::NoSpace));
+    drop((src, small));
+    std::fs::remove_file("./image_small").unwrap();
+    for path in [
+        "./image_src",
+        "./image_copy",
+        "./image_meta",
+        "./image_anon",
+    ] {
+        assert_fsck_clean_and_remove(path);
+    }
+}
+
+fn clone_test() {
+    let src = mounted_image("./clone_src", FsType::Ext2, 1024, "/clone_src/");
+    src.create_dir_all("/clone_src/etc/conf").unwrap();
+    let mut file = src
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/clone_src/etc/conf/data")
+        .unwrap();
+    file.write_all(&[0x5a; 8192]).unwrap();
+    file.write_all(&[0; 16384]).unwrap();
+    file.write_all(&[0xa5; 100]).unwrap();
+    drop(file);
+    src.hard_link("/clone_src/etc/conf/data", "/clone_src/etc/data")
+        .unwrap();
+    src.soft_link("conf/data", "/clone_src/etc/link").unwrap();
+    src.mknod("/clone_src/etc/tty", FileType::from_char('c'), 0x0405)
+        .unwrap();
+    src.mknod("/clone_src/etc/pipe", FileType::from_char('p'), 0)
+        .unwrap();
+    src.set_xattr("/clone_src/etc/conf", "user.origin", b"ext2")
+        .unwrap();
+    src.chown("/clone_src/etc/conf/data", Some(1000), Some(100))
+        .unwrap();
+    src.set_permissions("/clone_src/etc/conf/data", Permissions::from_mode(0o640))
+        .unwrap();
+    let times = FileTimes::new()
+        .set_accessed(Time::from_extra(1, None))
+        .set_modified(Time::from_extra(3, None));
+    src.set_times("/clone_src/etc/conf", times).unwrap();
+    // 128 byte inodes have no room for nanoseconds
+    assert!(!src.keeps_nanos("/clone_src/etc/conf").unwrap());
+    let times = FileTimes::new().set_modified(Time {
+        epoch_secs: 3,
+        nanos: Some(5),
+    });
+    assert_eq!(
+        src.set_times("/clone_src/etc/conf", times).err(),
+        Some(Error::NotSupported)
+    );
+
+    let dst = mounted_image("./clone_dst", FsType::Ext4, 4096, "/clone_dst/");
+    let report = clone_filesystem(&src, &dst).unwrap();
+    assert!(report.issues.is_empty(), "{:#?}", report.issues);
+    assert_eq!(report.files, 1);
+    assert_eq!(report.hard_links, 1);
+    assert_eq!(report.symlinks, 1);
+    assert_eq!(report.special_files, 2);
+    // lost+found already exists on the destination and is reused
+    assert_eq!(report.dirs, 3);
+    assert_eq!(report.bytes + report.holes, 8192 + 16384 + 100);
+
+    let meta = dst.metadata("/clone_dst/etc/data").unwrap();
+    assert_eq!(
+        meta.ino(),
+        dst.metadata("/clone_dst/etc/conf/data").unwrap().ino()
+    );
+    assert_eq!(meta.nlink(), 2);
+    assert_eq!(meta.size(), 8192 + 16384 + 100);
+    assert_eq!((meta.uid(), meta.gid()), (1000, 100));
+    assert_eq!(meta.permissions(), Permissions::from_mode(0o640));
+    let mut file = dst
+        .file_builder()
+        .read(true)
+        .open("/clone_dst/etc/data")
+        .unwrap();
+    let mut buf = vec![0u8; meta.size() as usize];
+    file.read_exact(&mut buf).unwrap();
+    assert!(buf[..8192].iter().all(|&b| b == 0x5a));
+    assert!(buf[8192..8192 + 16384].iter().all(|&b| b == 0));
+    assert!(buf[8192 + 16384..].iter().all(|&b| b == 0xa5));
+    drop(file);
+    assert_eq!(dst.read_link("/clone_dst/etc/link").unwrap(), "conf/data");
+    let meta = dst.metadata("/clone_dst/etc/tty").unwrap();
+    assert!(meta.file_type().is_char_device());
+    assert_eq!(meta.rdev(), 0x0405);
+    assert!(dst
+        .metadata("/clone_dst/etc/pipe")
+        .unwrap()
+        .file_type()
+        .is_fifo());
+    assert_eq!(
+        dst.get_xattr("/clone_dst/etc/conf", "user.origin").unwrap(),
+        b"ext2"
+    );
+    let meta = dst.metadata("/clone_dst/etc/conf").unwrap();
+    assert_eq!(meta.accessed().epoch_secs, 1);
+    assert_eq!(meta.modified().epoch_secs, 3);
+    assert!(dst.keeps_nanos("/clone_dst/etc/conf").unwrap());
+    assert_eq!(dst.mount_handle().stats().unwrap().block_size, 4096);
+    drop((src, dst));
+    assert_fsck_clean_and_remove("./clone_src");
+    assert_fsck_clean_and_remove("./clone_dst");
+}
+
+fn sync_test() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::time::{Duration, UNIX_EPOCH};
+    let host = std::path::Path::new("./sync_host");
+    let _ = std::fs::remove_dir_all(host);
+    std::fs::create_dir_all(host.join("etc")).unwrap();
+    std::fs::write(host.join("etc/hostname"), b"alpha").unwrap();
+    std::fs::write(host.join("etc/motd"), vec![0x5a; 10000]).unwrap();
+    std::fs::write(host.join("old"), b"gone soon").unwrap();
+    symlink("etc/hostname", host.join("name")).unwrap();
+
+    let fs = mounted_image("./sync_image", FsType::Ext4, 1024, "/sync/");
+    let report = fs.sync_from_host(host, "/sync/").unwrap();
+    assert!(report.issues.is_empty(), "{:#?}", report.issues);
+    assert_eq!(report.created, 5);
+    assert_eq!(report.bytes, 5 + 10000 + 9);
+    assert_eq!(fs.read_link("/sync/name").unwrap(), "etc/hostname");
+
+    // nothing changed, nothing is written
+    let report = fs.sync_from_host(host, "/sync/").unwrap();
+    assert_eq!((report.created, report.updated, report.bytes), (0, 0, 0));
+    assert_eq!((report.unchanged, report.attributes), (5, 0));
+
+    // same size and mtime, only found by comparing contents
+    let motd = host.join("etc/motd");
+    let mtime = std::fs::metadata(&motd).unwrap().modified().unwrap();
+    let mut data = vec![0x5a; 10000];
+    data[5000] = 0xa5;
+    std::fs::write(&motd, &data).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&motd)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    assert_eq!(fs.sync_from_host(host, "/sync/").unwrap().updated, 0);
+    let report = HostSync::new()
+        .checksum(true)
+        .sync(&fs, host, "/sync/")
+        .unwrap();
+    assert_eq!(report.updated, 1);
+    // only the changed block is written
+    assert_eq!(report.bytes, 1024);
+
+    std::fs::write(host.join("etc/hostname"), b"beta!!").unwrap();
+    std::fs::set_permissions(host.join("etc"), std::fs::Permissions::from_mode(0o700)).unwrap();
+    let old = UNIX_EPOCH + Duration::new(1_000_000, 123_456_789);
+    std::fs::File::options()
+        .write(true)
+        .open(host.join("etc/hostname"))
+        .unwrap()
+        .set_modified(old)
+        .unwrap();
+    std::fs::remove_file(host.join("old")).unwrap();
+    let report = HostSync::new()
+        .delete(true)
+        .sync(&fs, host, "/sync/")
+        .unwrap();
+    assert!(report.issues.is_empty(), "{:#?}", report.issues);
+    assert_eq!(report.deleted, 1);
+    assert_eq!(report.updated, 1);
+    assert!(!fs.exists("/sync/old").unwrap());
+    assert!(fs.exists("/sync/lost+found").unwrap());
+    assert_eq!(fs.metadata("/sync/etc").unwrap().mode() & 0o7777, 0o700);
+    let meta = fs.metadata("/sync/etc/hostname").unwrap();
+    assert_eq!(meta.size(), 6);
+    assert_eq!((meta.mtime(), meta.mtime_nsec()), (1_000_000, 123_456_789));
+    let mut buf = vec![0u8; 10000];
+    let mut file = fs.file_builder().read(true).open("/sync/etc/motd").unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    drop(file);
+
+    // a change of the nanoseconds alone is found
+    let hostname = host.join("etc/hostname");
+    let set_modified = |time| {
+        std::fs::File::options()
+            .write(true)
+            .open(&hostname)
+            .unwrap()
+            .set_modified(time)
+            .unwrap()
+    };
+    set_modified(UNIX_EPOCH + Duration::new(1_000_000, 5));
+    let report = fs.sync_from_host(host, "/sync/").unwrap();
+    assert_eq!((report.attributes, report.bytes), (1, 0));
+    assert_eq!(fs.metadata("/sync/etc/hostname").unwrap().mtime_nsec(), 5);
+    // past 2038 the epoch bits of the extra field carry the seconds
+    set_modified(UNIX_EPOCH + Duration::new(2_200_000_000, 7));
+    let report = fs.sync_from_host(host, "/sync/").unwrap();
+    assert_eq!(report.attributes, 1);
+    let meta = fs.metadata("/sync/etc/hostname").unwrap();
+    assert_eq!((meta.mtime(), meta.mtime_nsec()), (2_200_000_000, 7));
+    assert_eq!(fs.sync_from_host(host, "/sync/").unwrap().attributes, 0);
+    // times before 1970 cannot be held by Time
+    set_modified(UNIX_EPOCH - Duration::from_secs(86400));
+    let report = fs.sync_from_host(host, "/sync/").unwrap();
+    let issues: Vec<_> = report
+        .issues
+        .iter()
+        .map(|i| (i.path.as_str(), &i.kind, i.error))
+        .collect();
+    assert_eq!(
+        issues,
+        [(
+            "/sync/etc/hostname",
+            &SyncIssueKind::Timestamps,
+            Error::InvalidArgument
+        )]
+    );
+    assert_eq!(
+        fs.metadata("/sync/etc/hostname").unwrap().mtime(),
+        2_200_000_000
+    );
+    drop(fs);
+
+    assert_fsck_clean_and_remove("./sync_image");
+    std::fs::remove_dir_all(host).unwrap();
+}
+
+fn diff_test() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    let host = std::path::Path::new("./diff_host");
+    let _ = std::fs::remove_dir_all(host);
+    std::fs::create_dir_all(host.join("etc")).unwrap();
+    std::fs::write(host.join("etc/passwd"), b"root:x:0:0").unwrap();
+    std::fs::write(host.join("etc/shadow"), b"root:*").unwrap();
+    std::fs::write(host.join("gone"), b"").unwrap();
+    symlink("etc/passwd", host.join("link")).unwrap();
+
+    let fs = mounted_image("./diff_image", FsType::Ext4, 1024, "/diff/");
+    let report = fs.sync_from_host(host, "/diff/").unwrap();
+    assert!(report.issues.is_empty(), "{:#?}", report.issues);
+    fs.set_xattr("/diff/etc/shadow", "user.note", b"image only")
+        .unwrap();
+
+    let diff = TreeDiff::new().exclude("lost+found");
+    let report = diff.image_to_host(&fs, "/diff/", host).unwrap();
+    assert_eq!(
+        report.entries,
+        vec![DiffEntry {
+            path: "/etc/shadow".to_string(),
+            change: DiffChange::Modified(vec![DiffAttr::Xattr("user.note".to_string())]),
+        }]
+    );
+
+    std::fs::write(host.join("etc/passwd"), b"root:x:0:1").unwrap();
+    std::fs::set_permissions(
+        host.join("etc/passwd"),
+        std::fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    std::fs::write(host.join("new"), b"").unwrap();
+    std::fs::remove_file(host.join("gone")).unwrap();
+    std::fs::remove_file(host.join("link")).unwrap();
+    symlink("etc/shadow", host.join("link")).unwrap();
+    let report = diff.image_to_host(&fs, "/diff/", host).unwrap();
+    let paths: Vec<&str> = report.entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        ["/etc/passwd", "/etc/shadow", "/gone", "/link", "/new"]
+    );
+    assert_eq!(
+        report.entries[0].change,
+        DiffChange::Modified(vec![
+            DiffAttr::Mode {
+                old: 0o644,
+                new: 0o600
+            },
+            DiffAttr::Content
+        ])
+    );
+    assert_eq!(report.entries[2].change, DiffChange::Removed);
+    assert_eq!(
+        report.entries[3].change,
+        DiffChange::Modified(vec![DiffAttr::LinkTarget {
+            old: "etc/passwd".to_string(),
+            new: "etc/shadow".to_string()
+        }])
+    );
+    assert_eq!(report.entries[4].change, DiffChange::Added);
+    let text = report.to_string();
+    assert!(
+        text.contains("M /etc/passwd: mode 0644 -> 0600, content\n"),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains("- /gone\nM /link: link etc/passwd -> etc/shadow\n+ /new\n"),
+        "{}",
+        text
+    );
+    let json = report.to_json();
+    assert!(
+        json.contains(r#"{"path": "/gone", "change": "removed"}"#),
+        "{}",
+        json
+    );
+    assert!(json.contains(r#"{"attr": "mode", "old": "0644", "new": "0600"}"#));
+    assert!(!TreeDiff::new()
+        .contents(false)
+        .image_to_host(&fs, "/diff/", host)
+        .unwrap()
+        .entries
+        .iter()
+        .any(|e| e.change == DiffChange::Modified(vec![DiffAttr::Content])));
+
+    // a host name that is not UTF-8 is skipped
+    use std::os::unix::ffi::OsStrExt;
+    let bad = host.join(std::ffi::OsStr::from_bytes(b"bad\xff"));
+    std::fs::write(&bad, b"").unwrap();
+    let report = diff.image_to_host(&fs, "/diff/", host).unwrap();
+    let skipped: Vec<&str> = report.entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(skipped, paths);
+    std::fs::remove_file(bad).unwrap();
+
+    // an image compared with itself
+    let report = TreeDiff::new()
+        .times(true)
+        .images(&fs, "/diff/", &fs, "/diff")
+        .unwrap();
+    assert!(report.is_empty(), "{}", report);
+    drop(fs);
+    assert_fsck_clean_and_remove("./diff_image");
+    std::fs::remove_dir_all(host).unwrap();
+}
+
+fn zero_test() {
+    let mut fs = mounted_image("./zero_image", FsType::Ext4, 1024, "/zero/");
+    let mut file = fs
+        .file_builder()
+        .write(true)
+        .create(true)
+        .open("/zero/secret")
+        .unwrap();
+    file.write_all(&[0x5a; 200 * 1024]).unwrap();
+    drop(file);
+    fs.remove_file("/zero/secret").unwrap();
+
+    let report = fs.zero_free_space().unwrap();
+    assert_eq!(report.block_size, 1024);
+    // plain files cannot discard
+    assert_eq!(report.discarded, 0);
+    assert!(report.zeroed >= 200);
+    // the file system is still usable afterwards
+    fs.create_dir("/zero/after").unwrap();
+    drop(fs);
+
+    let image = std::fs::read("./zero_image").unwrap();
+    assert!(!image
+        .chunks(1024)
+        .any(|block| block.iter().all(|&b| b == 0x5a)));
+    assert_fsck_clean_and_remove("./zero_image");
+}
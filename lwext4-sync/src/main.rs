@@ -0,0 +1,74 @@
+use clap::{arg, command, value_parser};
+use lwext4_rs::{
+    BlockDeviceConfig, DefaultInterface, FileSystem, HostSync, MountHandle, RegisterHandle,
+};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-s --source <DIR> "host directory to sync from")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-d --dest <DIR> "directory of the image to sync into")
+                .required(false)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(arg!(-c --checksum "also compare the contents of files with the same size and mtime"))
+        .arg(arg!(--delete "remove entries of the image that are gone from the host"))
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let source = matches.get_one::<PathBuf>("source").unwrap();
+    let dest = matches
+        .get_one::<String>("dest")
+        .map(|d| d.trim_start_matches('/'))
+        .unwrap_or("");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    let blk = DefaultInterface::new_device(file, config);
+    let register_handler = RegisterHandle::register(blk, "sync".to_string()).unwrap();
+    let mount_handler =
+        MountHandle::mount(register_handler, "/".to_string(), true, false).unwrap();
+    let fs = FileSystem::new(mount_handler).unwrap();
+
+    let report = HostSync::new()
+        .checksum(matches.get_flag("checksum"))
+        .delete(matches.get_flag("delete"))
+        .sync(&fs, source, format!("/{}", dest))
+        .unwrap();
+    println!(
+        "{} created, {} updated, {} with new attributes, {} deleted, {} unchanged ({} bytes written)",
+        report.created,
+        report.updated,
+        report.attributes,
+        report.deleted,
+        report.unchanged,
+        report.bytes
+    );
+    for issue in &report.issues {
+        eprintln!("{}: {:?}: {:?}", issue.path, issue.kind, issue.error);
+    }
+    if !report.issues.is_empty() {
+        eprintln!("{} entries were not brought up to date", report.issues.len());
+    }
+}
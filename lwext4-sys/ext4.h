@@ -1,5 +1,6 @@
 #include <ext4.h>
 #include <ext4_fs.h>
+#include <ext4_journal.h>
 #include <ext4_mkfs.h>
 /**@brief   Mount point descriptor.*/
 typedef struct ext4_mountpoint {
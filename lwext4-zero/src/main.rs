@@ -0,0 +1,54 @@
+use clap::{arg, command, value_parser};
+use lwext4_rs::{
+    BlockDevice, BlockDeviceConfig, BlockDeviceInterface, DefaultInterface, FileSystem,
+    MountHandle, RegisterHandle, SparseFileInterface,
+};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+fn zero<T: BlockDeviceInterface>(blk: Pin<Box<BlockDevice<T>>>) {
+    let register_handler = RegisterHandle::register(blk, "zero".to_string()).unwrap();
+    let mount_handler = MountHandle::mount(register_handler, "/".to_string(), true, false).unwrap();
+    let mut fs = FileSystem::new(mount_handler).unwrap();
+
+    let report = fs.zero_free_space().unwrap();
+    println!(
+        "{} free blocks of {} bytes: {} discarded, {} zeroed",
+        report.discarded + report.zeroed,
+        report.block_size,
+        report.discarded,
+        report.zeroed
+    );
+}
+
+fn main() {
+    let matches = command!()
+        .arg(
+            arg!(-f --file <FILE> "img file path")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(arg!(-s --sparse "punch holes for free blocks instead of writing zeros"))
+        .get_matches();
+
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut config = BlockDeviceConfig::default();
+
+    let meta = file.metadata().unwrap();
+    let bs: u64 = 512;
+    config.block_size = bs as u32;
+    config.part_size = meta.len();
+    config.part_offset = 0;
+    config.block_count = config.part_size / bs;
+    if matches.get_flag("sparse") {
+        zero(SparseFileInterface::new_device(file, config));
+    } else {
+        zero(DefaultInterface::new_device(file, config));
+    }
+}